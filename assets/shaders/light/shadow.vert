@@ -1,17 +1,31 @@
 # version 450
 
+// Same as MAX_JOINTS in animation.rs
+#define MAX_JOINTS 32
+
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec2 texcoords;
 layout(location = 2) in vec3 normals;
+layout(location = 3) in vec4 joints;
+layout(location = 4) in vec4 weights;
 
 layout(binding = 0) uniform Data {
         mat4 model;
         mat4 view;
         mat4 proj;
+        mat4 joint_matrices[MAX_JOINTS];
 } uniforms;
 
 layout(location = 0) out vec2 outUv;
 void main() {
-        gl_Position = uniforms.proj * uniforms.view * uniforms.model * vec4(position, 1.0);
+        mat4 skin = mat4(1.0);
+        if (weights.x + weights.y + weights.z + weights.w > 0.0) {
+                skin = weights.x * uniforms.joint_matrices[int(joints.x)]
+                        + weights.y * uniforms.joint_matrices[int(joints.y)]
+                        + weights.z * uniforms.joint_matrices[int(joints.z)]
+                        + weights.w * uniforms.joint_matrices[int(joints.w)];
+        }
+
+        gl_Position = uniforms.proj * uniforms.view * uniforms.model * skin * vec4(position, 1.0);
         outUv = texcoords;
 }
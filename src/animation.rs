@@ -0,0 +1,415 @@
+// Skeletal animation.
+//
+// Skeletons and animation clips are assets (loaded from glTF skins and
+// animations). The animation state of an entity is stored in its
+// `AnimatorComponent` which only contains names and timers so that it can
+// be sent over the network. The actual joint matrices are computed on the
+// client right before rendering.
+use cgmath::{InnerSpace, Matrix4, Quaternion, SquareMatrix, Vector3};
+use log::error;
+use std::collections::HashMap;
+
+use crate::ecs::components::AnimatorComponent;
+use crate::ecs::ECS;
+use crate::time::dt_as_secs;
+use std::time::Duration;
+
+/// Max number of joints per skeleton. Needs to be the same as in
+/// main.vert and shadow.vert. The glTF importer refuses bigger skins.
+pub const MAX_JOINTS: usize = 32;
+
+pub type JointMatrices = [[[f32; 4]; 4]; MAX_JOINTS];
+
+/// Local transform of a joint, relative to its parent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JointTransform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl JointTransform {
+    pub fn identity() -> Self {
+        JointTransform {
+            translation: Vector3::new(0.0, 0.0, 0.0),
+            rotation: Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    /// Interpolate between two transforms. `amount` = 0 gives self.
+    pub fn blend(&self, other: &JointTransform, amount: f32) -> JointTransform {
+        JointTransform {
+            translation: self.translation.lerp(other.translation, amount),
+            rotation: nlerp(self.rotation, other.rotation, amount),
+            scale: self.scale.lerp(other.scale, amount),
+        }
+    }
+}
+
+/// Normalized lerp that takes the shortest path.
+fn nlerp(from: Quaternion<f32>, to: Quaternion<f32>, amount: f32) -> Quaternion<f32> {
+    let to = if from.dot(to) < 0.0 { -to } else { to };
+    (from * (1.0 - amount) + to * amount).normalize()
+}
+
+#[derive(Debug, Clone)]
+pub struct Joint {
+    pub name: String,
+
+    /// Index of the node in the file. Animation channels target nodes.
+    pub node: usize,
+
+    /// Index of the parent joint in the skeleton. None for the roots.
+    pub parent: Option<usize>,
+
+    pub inverse_bind_matrix: Matrix4<f32>,
+
+    /// Transform when there is no animation.
+    pub rest: JointTransform,
+}
+
+#[derive(Debug, Clone)]
+pub struct Skeleton {
+    /// Order of the joints is the one used by the JOINTS vertex attribute.
+    pub joints: Vec<Joint>,
+
+    /// Transform of the parent of the root joints (the armature), relative
+    /// to the skinned mesh.
+    pub root_transform: Matrix4<f32>,
+}
+
+/// A pose is the local transform of each joint of a skeleton.
+pub type Pose = Vec<JointTransform>;
+
+impl Skeleton {
+    pub fn rest_pose(&self) -> Pose {
+        self.joints.iter().map(|j| j.rest).collect()
+    }
+
+    /// Matrices that are sent to the vertex shader. Extra slots are identity.
+    pub fn joint_matrices(&self, pose: &Pose) -> JointMatrices {
+        let identity: [[f32; 4]; 4] = Matrix4::<f32>::identity().into();
+        let mut matrices = [identity; MAX_JOINTS];
+
+        let mut globals = vec![None; self.joints.len()];
+        for (i, joint) in self.joints.iter().enumerate().take(MAX_JOINTS) {
+            let global = self.global_transform(i, pose, &mut globals);
+            matrices[i] = (global * joint.inverse_bind_matrix).into();
+        }
+
+        matrices
+    }
+
+    /// Transform of a joint relative to the mesh. Parents can be anywhere in the
+    /// joint list so already computed transforms are cached in `globals`.
+    fn global_transform(
+        &self,
+        idx: usize,
+        pose: &Pose,
+        globals: &mut Vec<Option<Matrix4<f32>>>,
+    ) -> Matrix4<f32> {
+        if let Some(global) = globals[idx] {
+            return global;
+        }
+
+        let parent = match self.joints[idx].parent {
+            Some(p) => self.global_transform(p, pose, globals),
+            None => self.root_transform,
+        };
+        let global = parent * pose[idx].matrix();
+        globals[idx] = Some(global);
+        global
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ChannelValues {
+    Translations(Vec<Vector3<f32>>),
+    Rotations(Vec<Quaternion<f32>>),
+    Scales(Vec<Vector3<f32>>),
+}
+
+impl ChannelValues {
+    pub fn len(&self) -> usize {
+        match *self {
+            ChannelValues::Translations(ref v) => v.len(),
+            ChannelValues::Rotations(ref v) => v.len(),
+            ChannelValues::Scales(ref v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Keyframes for one property of one node. Only linear interpolation. There
+/// is one value per time.
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub node: usize,
+    pub times: Vec<f32>,
+    pub values: ChannelValues,
+}
+
+impl Channel {
+    /// Returns the keyframes around t and the interpolation factor between them.
+    fn keyframes(&self, t: f32) -> (usize, usize, f32) {
+        let last = self.times.len() - 1;
+        if t <= self.times[0] {
+            return (0, 0, 0.0);
+        }
+        if t >= self.times[last] {
+            return (last, last, 0.0);
+        }
+
+        // times are sorted. There are not so many keyframes so linear search is ok.
+        let next = self.times.iter().position(|kt| *kt > t).unwrap();
+        let prev = next - 1;
+        let factor = (t - self.times[prev]) / (self.times[next] - self.times[prev]);
+        (prev, next, factor)
+    }
+
+    fn apply(&self, t: f32, transform: &mut JointTransform) {
+        if self.times.is_empty() {
+            return;
+        }
+
+        let (prev, next, factor) = self.keyframes(t);
+        match self.values {
+            ChannelValues::Translations(ref v) => {
+                transform.translation = v[prev].lerp(v[next], factor);
+            }
+            ChannelValues::Rotations(ref v) => {
+                transform.rotation = nlerp(v[prev], v[next], factor);
+            }
+            ChannelValues::Scales(ref v) => {
+                transform.scale = v[prev].lerp(v[next], factor);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    pub name: String,
+    /// In seconds
+    pub duration: f32,
+    pub channels: Vec<Channel>,
+}
+
+impl AnimationClip {
+    /// Sample the clip for the given skeleton. Joints that are not animated
+    /// by this clip stay in their rest position.
+    pub fn sample(&self, skeleton: &Skeleton, time: f32, looping: bool) -> Pose {
+        let t = clip_time(time, self.duration, looping);
+        let mut pose = skeleton.rest_pose();
+        for channel in &self.channels {
+            if let Some(idx) = skeleton.joints.iter().position(|j| j.node == channel.node) {
+                channel.apply(t, &mut pose[idx]);
+            }
+        }
+
+        pose
+    }
+}
+
+/// Wrap or clamp the time to the clip duration.
+fn clip_time(time: f32, duration: f32, looping: bool) -> f32 {
+    if duration <= 0.0 {
+        0.0
+    } else if looping {
+        time % duration
+    } else {
+        time.min(duration)
+    }
+}
+
+pub fn blend_poses(from: &Pose, to: &Pose, amount: f32) -> Pose {
+    from.iter()
+        .zip(to.iter())
+        .map(|(a, b)| a.blend(b, amount))
+        .collect()
+}
+
+/// Store skeletons and clips so that they can be referenced by name in
+/// the `AnimatorComponent`.
+pub struct AnimationManager {
+    pub skeletons: HashMap<String, Skeleton>,
+    pub clips: HashMap<String, AnimationClip>,
+}
+
+impl AnimationManager {
+    pub fn new() -> Self {
+        AnimationManager {
+            skeletons: HashMap::new(),
+            clips: HashMap::new(),
+        }
+    }
+
+    /// Compute the current pose of an animator, blending with the previous
+    /// clip if a transition is in progress.
+    pub fn pose(&self, animator: &AnimatorComponent) -> Option<Pose> {
+        let skeleton = self.skeletons.get(&animator.skeleton)?;
+
+        let current = match animator.clip {
+            Some(ref clip) => self.sample(skeleton, clip, animator.time, animator.looping),
+            None => skeleton.rest_pose(),
+        };
+
+        match animator.previous_clip {
+            Some(ref previous) if animator.blend_elapsed < animator.blend_duration => {
+                let previous = self.sample(
+                    skeleton,
+                    previous,
+                    animator.previous_time,
+                    animator.previous_looping,
+                );
+                let amount = animator.blend_elapsed / animator.blend_duration;
+                Some(blend_poses(&previous, &current, amount))
+            }
+            _ => Some(current),
+        }
+    }
+
+    fn sample(&self, skeleton: &Skeleton, clip: &String, time: f32, looping: bool) -> Pose {
+        match self.clips.get(clip) {
+            Some(clip) => clip.sample(skeleton, time, looping),
+            None => {
+                error!("Animation clip {} is not loaded", clip);
+                skeleton.rest_pose()
+            }
+        }
+    }
+}
+
+/// Advance the animation timers. This runs on both server and client and does
+/// not need the animation assets.
+pub struct AnimationSystem;
+
+impl AnimationSystem {
+    pub fn new() -> Self {
+        AnimationSystem
+    }
+
+    pub fn update(&self, dt: Duration, ecs: &mut ECS) {
+        let dt = dt_as_secs(dt) as f32;
+        for animator in ecs.components.animators.iter_mut() {
+            if let Some(animator) = animator.as_mut() {
+                animator.value_mut().advance(dt);
+            }
+        }
+    }
+
+    /// Compute the joint matrices that will be used for rendering. Client-side only
+    /// as it needs the skeletons and clips.
+    pub fn compute_joint_matrices(ecs: &mut ECS, animations: &AnimationManager) {
+        for animator in ecs.components.animators.iter_mut() {
+            if let Some(animator) = animator.as_mut() {
+                let animator = animator.value_mut();
+                let matrices = animations.pose(animator).and_then(|pose| {
+                    animations
+                        .skeletons
+                        .get(&animator.skeleton)
+                        .map(|s| s.joint_matrices(&pose))
+                });
+                animator.joint_matrices = matrices.map(|m| m.to_vec()).unwrap_or(Vec::new());
+            }
+        }
+    }
+}
+
+/// Joint matrices to send to the shader. Identity when the object is not animated.
+pub fn joint_uniform(animator: Option<&AnimatorComponent>) -> JointMatrices {
+    let identity: [[f32; 4]; 4] = Matrix4::<f32>::identity().into();
+    let mut matrices = [identity; MAX_JOINTS];
+    if let Some(animator) = animator {
+        for (i, m) in animator.joint_matrices.iter().take(MAX_JOINTS).enumerate() {
+            matrices[i] = *m;
+        }
+    }
+
+    matrices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_joints() -> Skeleton {
+        Skeleton {
+            joints: vec![
+                Joint {
+                    name: "root".to_string(),
+                    node: 3,
+                    parent: None,
+                    inverse_bind_matrix: Matrix4::identity(),
+                    rest: JointTransform::identity(),
+                },
+                Joint {
+                    name: "arm".to_string(),
+                    node: 4,
+                    parent: Some(0),
+                    inverse_bind_matrix: Matrix4::identity(),
+                    rest: JointTransform::identity(),
+                },
+            ],
+            root_transform: Matrix4::identity(),
+        }
+    }
+
+    fn move_clip() -> AnimationClip {
+        AnimationClip {
+            name: "move".to_string(),
+            duration: 2.0,
+            channels: vec![Channel {
+                node: 4,
+                times: vec![0.0, 2.0],
+                values: ChannelValues::Translations(vec![
+                    Vector3::new(0.0, 0.0, 0.0),
+                    Vector3::new(2.0, 0.0, 0.0),
+                ]),
+            }],
+        }
+    }
+
+    #[test]
+    fn sample_interpolates_keyframes() {
+        let skeleton = two_joints();
+        let clip = move_clip();
+
+        let pose = clip.sample(&skeleton, 1.0, false);
+        assert_eq!(JointTransform::identity(), pose[0]);
+        assert_eq!(Vector3::new(1.0, 0.0, 0.0), pose[1].translation);
+
+        // Clamp when not looping, wrap when looping.
+        let pose = clip.sample(&skeleton, 3.0, false);
+        assert_eq!(Vector3::new(2.0, 0.0, 0.0), pose[1].translation);
+        let pose = clip.sample(&skeleton, 3.0, true);
+        assert_eq!(Vector3::new(1.0, 0.0, 0.0), pose[1].translation);
+    }
+
+    #[test]
+    fn blend_and_joint_matrices() {
+        let skeleton = two_joints();
+        let rest = skeleton.rest_pose();
+        let moved = move_clip().sample(&skeleton, 2.0, false);
+
+        let half = blend_poses(&rest, &moved, 0.5);
+        assert_eq!(Vector3::new(1.0, 0.0, 0.0), half[1].translation);
+
+        // Child joint is moved by its parent.
+        let mut pose = half.clone();
+        pose[0].translation = Vector3::new(0.0, 1.0, 0.0);
+        let matrices = skeleton.joint_matrices(&pose);
+        assert_eq!([1.0, 1.0, 0.0, 1.0], matrices[1][3]);
+        assert_eq!(Matrix4::<f32>::identity(), Matrix4::from(matrices[2]));
+    }
+}
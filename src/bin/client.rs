@@ -1,13 +1,45 @@
 use clap::{App, Arg};
 use log::info;
 use std::time::{Duration, Instant};
+use twgraph::event::{Event, GameEvent};
 use twgraph::input::{Input, KeyType};
 use vulkano::instance::Instance;
 use winit::EventsLoop;
 
 use twgraph::ecs::systems::RenderingSystem;
+use twgraph::net::conditioner::LinkConditions;
+use twgraph::net::crypto::ConnectToken;
+use twgraph::net::demo::Demo;
+use twgraph::net::discovery::DiscoveryMode;
+use twgraph::net::interpolation::InterpolationSettings;
 use twgraph::resource::Resources;
-use twgraph::scene::{ClientScene, SceneStack};
+use twgraph::scene::{BrowserScene, ClientScene, DemoScene, SceneStack};
+
+/// Validator for clap
+fn is_u64(v: String) -> Result<(), String> {
+    if let Err(_) = v.parse::<u64>() {
+        return Err("The value should represent an u64".to_string());
+    }
+
+    Ok(())
+}
+
+/// Validator for clap
+fn is_u32(v: String) -> Result<(), String> {
+    if let Err(_) = v.parse::<u32>() {
+        return Err("The value should represent an u32".to_string());
+    }
+
+    Ok(())
+}
+
+/// Validator for clap
+fn is_probability(v: String) -> Result<(), String> {
+    match v.parse::<f32>() {
+        Ok(p) if p >= 0.0 && p <= 1.0 => Ok(()),
+        _ => Err("The value should be between 0 and 1".to_string()),
+    }
+}
 
 fn main() {
     env_logger::init();
@@ -22,13 +54,156 @@ fn main() {
                 .long("connect")
                 .required(false)
                 .takes_value(true)
-                .help("IP address of the server"),
+                .help("Address of the server, e.g. localhost:8080 or [::1]:8080. Without it, the servers of the LAN are listed"),
+        )
+        .arg(
+            Arg::with_name("loopback")
+                .long("loopback")
+                .required(false)
+                .takes_value(false)
+                .help("Only list the servers of this machine"),
+        )
+        .arg(
+            Arg::with_name("token")
+                .short("t")
+                .long("token")
+                .required(false)
+                .takes_value(true)
+                .help("Connect token given by the matchmaker, if the server needs one"),
+        )
+        .arg(
+            Arg::with_name("interp_delay")
+                .long("interp-delay")
+                .required(false)
+                .takes_value(true)
+                .default_value("100")
+                .validator(is_u64)
+                .help("Remote entities are displayed that many milliseconds in the past"),
+        )
+        .arg(
+            Arg::with_name("interp_buffer")
+                .long("interp-buffer")
+                .required(false)
+                .takes_value(true)
+                .default_value("32")
+                .validator(is_u64)
+                .help("Number of server states kept for interpolation"),
+        )
+        .arg(
+            Arg::with_name("latency")
+                .long("latency")
+                .required(false)
+                .takes_value(true)
+                .default_value("0")
+                .validator(is_u32)
+                .help("Simulated latency in milliseconds, added to each packet in both directions"),
+        )
+        .arg(
+            Arg::with_name("jitter")
+                .long("jitter")
+                .required(false)
+                .takes_value(true)
+                .default_value("0")
+                .validator(is_u32)
+                .help("Simulated random delay in milliseconds, added to the latency"),
+        )
+        .arg(
+            Arg::with_name("loss")
+                .long("loss")
+                .required(false)
+                .takes_value(true)
+                .default_value("0")
+                .validator(is_probability)
+                .help("Probability that a packet is dropped, e.g. 0.05"),
+        )
+        .arg(
+            Arg::with_name("duplication")
+                .long("duplication")
+                .required(false)
+                .takes_value(true)
+                .default_value("0")
+                .validator(is_probability)
+                .help("Probability that a packet is delivered twice"),
+        )
+        .arg(
+            Arg::with_name("reordering")
+                .long("reordering")
+                .required(false)
+                .takes_value(true)
+                .default_value("0")
+                .validator(is_probability)
+                .help("Probability that a packet arrives after the next ones"),
+        )
+        .arg(
+            Arg::with_name("link_seed")
+                .long("link-seed")
+                .required(false)
+                .takes_value(true)
+                .default_value("0")
+                .validator(is_u64)
+                .help("Seed of the simulated packet loss, to replay the same run"),
+        )
+        .arg(
+            Arg::with_name("levels")
+                .long("levels")
+                .required(false)
+                .takes_value(true)
+                .default_value(".")
+                .help("Directory of the levels, the same files as the server's"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .required(false)
+                .takes_value(true)
+                .help("Record the game to a demo file"),
+        )
+        .arg(
+            Arg::with_name("play")
+                .long("play")
+                .required(false)
+                .takes_value(true)
+                .conflicts_with_all(&["connect", "token", "record"])
+                .help("Watch a demo file instead of connecting to a server"),
         )
         .get_matches();
 
-    let addr = matches.value_of("connect").unwrap_or("localhost:8080");
+    let addr = matches.value_of("connect");
+    let token = matches
+        .value_of("token")
+        .map(|path| ConnectToken::load(path).expect("Cannot read the connect token"));
 
-    info!("Start client: Will connect to {}", addr);
+    // clap has already done the validation and default value.
+    let interpolation = InterpolationSettings {
+        delay: Duration::from_millis(matches.value_of("interp_delay").unwrap().parse().unwrap()),
+        buffer_size: matches.value_of("interp_buffer").unwrap().parse().unwrap(),
+        ..InterpolationSettings::default()
+    };
+    let link_conditions = LinkConditions {
+        latency_ms: matches.value_of("latency").unwrap().parse().unwrap(),
+        jitter_ms: matches.value_of("jitter").unwrap().parse().unwrap(),
+        loss: matches.value_of("loss").unwrap().parse().unwrap(),
+        duplication: matches.value_of("duplication").unwrap().parse().unwrap(),
+        reordering: matches.value_of("reordering").unwrap().parse().unwrap(),
+    };
+    let link_seed = matches.value_of("link_seed").unwrap().parse().unwrap();
+
+    // Refuse a bad demo before opening the window.
+    let demo = matches
+        .value_of("play")
+        .map(|path| Demo::load(path).unwrap_or_else(|e| panic!("Cannot play {}: {}", path, e)));
+
+    let discovery = if matches.is_present("loopback") {
+        DiscoveryMode::Loopback
+    } else {
+        DiscoveryMode::Broadcast
+    };
+
+    match (&demo, addr) {
+        (Some(_), _) => info!("Start client: Will play a demo"),
+        (None, Some(addr)) => info!("Start client: Will connect to {}", addr),
+        (None, None) => info!("Start client: Will look for servers ({:?})", discovery),
+    }
     let layer = "VK_LAYER_LUNARG_standard_validation";
     let layers = vec![layer];
     let instance = {
@@ -45,8 +220,30 @@ fn main() {
     let mut input = Input::new(events_loop);
     let mut old_instant = Instant::now();
 
+    let client_scene = |addr: &str, render_system: &RenderingSystem| {
+        let mut scene = ClientScene::new(
+            addr,
+            token.clone(),
+            interpolation,
+            link_conditions,
+            link_seed,
+            render_system,
+        );
+        scene.set_levels_dir(matches.value_of("levels").unwrap());
+        if let Some(path) = matches.value_of("record") {
+            scene
+                .record_demo(path)
+                .unwrap_or_else(|e| panic!("Cannot record {}: {}", path, e));
+        }
+        scene
+    };
+
     let mut scenes = SceneStack::new();
-    scenes.push(ClientScene::new(addr, &render_system));
+    match (demo, addr) {
+        (Some(demo), _) => scenes.push(DemoScene::new(demo, &render_system)),
+        (None, Some(addr)) => scenes.push(client_scene(addr, &render_system)),
+        (None, None) => scenes.push(BrowserScene::new(discovery, &render_system)),
+    }
 
     let fixed_time_stamp = Duration::new(0, 16666667);
     let mut previous_clock = Instant::now();
@@ -76,7 +273,15 @@ fn main() {
 
             // Now scene specific updates.
             scene.update(frame_duration);
-            let _ = scene.process_input(Some(&input), Some(&resources), frame_duration);
+            let events = scene.process_input(Some(&input), Some(&resources), frame_duration);
+
+            // The server browser stays below the game, to go back to it.
+            for event in events.unwrap_or_default() {
+                if let Event::GameEvent(GameEvent::ConnectTo(addr)) = event {
+                    info!("Connect to {}", addr);
+                    scenes.push(client_scene(&addr.to_string(), &render_system));
+                }
+            }
 
             if input.get_key_down(KeyType::Escape) {
                 let _ = scenes.pop();
@@ -0,0 +1,225 @@
+use clap::{App, Arg};
+use log::info;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use twgraph::config::ServerConfig;
+use twgraph::net::loadtest::{Behavior, LoadTest, LoadTestSettings, LocalServer};
+
+/// Validator for clap
+fn is_u64(v: String) -> Result<(), String> {
+    if let Err(_) = v.parse::<u64>() {
+        return Err("The value should represent an u64".to_string());
+    }
+
+    Ok(())
+}
+
+/// Validator for clap
+fn is_positive(v: String) -> Result<(), String> {
+    match v.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("The value should be at least 1".to_string()),
+    }
+}
+
+/// Validator for clap
+fn is_behavior(v: String) -> Result<(), String> {
+    v.parse::<Behavior>().map(|_| ())
+}
+
+/// Many simulated players against one server, without window. Prints what
+/// they measure at regular intervals.
+fn main() -> Result<(), Box<std::error::Error>> {
+    env_logger::init();
+
+    let matches = App::new("Load test")
+        .version("0.1")
+        .author("Benoit Eudier")
+        .arg(
+            Arg::with_name("connect")
+                .short("c")
+                .long("connect")
+                .required_unless("local")
+                .takes_value(true)
+                .help("Address of the server. It needs enough max_players and connection_attempts_per_sec"),
+        )
+        .arg(
+            Arg::with_name("local")
+                .long("local")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("connect")
+                .help("Start a server in this process, to measure its frame time"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .required(false)
+                .takes_value(true)
+                .requires("local")
+                .help("Configuration of the local server. Its address, max_players and connection limit are replaced"),
+        )
+        .arg(
+            Arg::with_name("map")
+                .short("m")
+                .long("map")
+                .required(false)
+                .takes_value(true)
+                .requires("local")
+                .help("Level of the local server. None by default"),
+        )
+        .arg(
+            Arg::with_name("levels")
+                .long("levels")
+                .required(false)
+                .takes_value(true)
+                .default_value(".")
+                .help("Directory of the levels, for the clients and the local server"),
+        )
+        .arg(
+            Arg::with_name("clients")
+                .short("n")
+                .long("clients")
+                .required(false)
+                .takes_value(true)
+                .default_value("10")
+                .validator(is_positive)
+                .help("Number of simulated clients"),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .short("d")
+                .long("duration")
+                .required(false)
+                .takes_value(true)
+                .default_value("30")
+                .validator(is_u64)
+                .help("Seconds of test once all the clients joined"),
+        )
+        .arg(
+            Arg::with_name("fps")
+                .long("fps")
+                .required(false)
+                .takes_value(true)
+                .default_value("60")
+                .validator(is_positive)
+                .help("Frames per second of each client. One input is sent per frame"),
+        )
+        .arg(
+            Arg::with_name("behavior")
+                .short("b")
+                .long("behavior")
+                .required(false)
+                .takes_value(true)
+                .default_value("random")
+                .validator(is_behavior)
+                .help("What the players do: idle, square or random"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .required(false)
+                .takes_value(true)
+                .default_value("0")
+                .validator(is_u64)
+                .help("Seed of the random players"),
+        )
+        .arg(
+            Arg::with_name("connect_rate")
+                .long("connect-rate")
+                .required(false)
+                .takes_value(true)
+                .default_value("20")
+                .validator(is_positive)
+                .help("Clients started per second"),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .required(false)
+                .takes_value(true)
+                .default_value("5")
+                .validator(is_positive)
+                .help("Seconds between two reports"),
+        )
+        .get_matches();
+
+    // clap has already done the validation and default value.
+    let settings = LoadTestSettings {
+        nb_clients: matches.value_of("clients").unwrap().parse()?,
+        frame_rate: matches.value_of("fps").unwrap().parse()?,
+        behavior: matches.value_of("behavior").unwrap().parse()?,
+        seed: matches.value_of("seed").unwrap().parse()?,
+        connect_rate: matches.value_of("connect_rate").unwrap().parse()?,
+        levels_dir: PathBuf::from(matches.value_of("levels").unwrap()),
+    };
+    let duration = Duration::from_secs(matches.value_of("duration").unwrap().parse()?);
+    let report_interval = Duration::from_secs(matches.value_of("report").unwrap().parse()?);
+
+    let server = if matches.is_present("local") {
+        let mut config = match matches.value_of("config") {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+        config.bind_address = "127.0.0.1:0".parse()?;
+        config.max_players = settings.nb_clients;
+        config.connection_attempts_per_sec = 2 * settings.connect_rate + 1;
+        config.map = matches.value_of("map").unwrap_or("").to_string();
+        config.levels_dir = matches.value_of("levels").unwrap().to_string();
+        config.validate()?;
+        Some(LocalServer::start(&config))
+    } else {
+        None
+    };
+    let addr: SocketAddr = match server {
+        Some(ref server) => server.addr(),
+        None => matches
+            .value_of("connect")
+            .unwrap()
+            .to_socket_addrs()?
+            .next()
+            .ok_or("Server address did not resolve")?,
+    };
+
+    info!(
+        "Start {} clients against {} ({:?})",
+        settings.nb_clients, addr, settings.behavior
+    );
+    let mut test = LoadTest::new(addr, settings);
+    let report = |test: &LoadTest| {
+        let mut report = test.report();
+        report.server_frames = server.as_ref().map(LocalServer::frame_times);
+        report
+    };
+
+    // Joining is measured apart; it should not depend on the duration.
+    let join_timeout = Duration::from_secs(60);
+    if !test.wait_for_clients(join_timeout) {
+        println!("Some clients are still connecting after {:?}", join_timeout);
+    }
+    println!("{}", report(&test));
+
+    let start = Instant::now();
+    let mut last_report = Instant::now();
+    while start.elapsed() < duration {
+        test.step();
+        if last_report.elapsed() >= report_interval {
+            last_report = Instant::now();
+            println!("{}", report(&test));
+        }
+    }
+
+    let last = report(&test);
+    test.disconnect_all();
+    println!("Final report");
+    println!("{}", last);
+    for (i, client) in last.clients.iter().enumerate() {
+        println!("  client {}: {}", i, client);
+    }
+
+    if last.nb_in_game() == 0 {
+        return Err("No client is in game".into());
+    }
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use clap::{App, Arg, SubCommand};
+use log::info;
+use std::path::Path;
+use std::time::Duration;
+use twgraph::net::crypto::{self, ConnectToken};
+
+/// Validator for clap
+fn is_u64(v: String) -> Result<(), String> {
+    if let Err(_) = v.parse::<u64>() {
+        return Err("The value should represent an u64".to_string());
+    }
+
+    Ok(())
+}
+
+/// Stand-in for a real matchmaker: creates the key shared with the servers
+/// and signs connect tokens with it.
+fn main() -> Result<(), Box<std::error::Error>> {
+    env_logger::init();
+
+    let matches = App::new("Matchmaker")
+        .version("0.1")
+        .author("Benoit Eudier")
+        .arg(
+            Arg::with_name("key")
+                .short("k")
+                .long("key")
+                .required(false)
+                .takes_value(true)
+                .default_value("connect.key")
+                .help("Key shared with the servers (--connect-key of the server)"),
+        )
+        .subcommand(SubCommand::with_name("keygen").about("Create a new shared key"))
+        .subcommand(
+            SubCommand::with_name("token")
+                .about("Sign a connect token for a client")
+                .arg(
+                    Arg::with_name("client_id")
+                        .long("client-id")
+                        .required(true)
+                        .takes_value(true)
+                        .validator(is_u64)
+                        .help("Unique id of the player"),
+                )
+                .arg(
+                    Arg::with_name("lifetime")
+                        .long("lifetime")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("300")
+                        .validator(is_u64)
+                        .help("Seconds during which the token can be used to connect"),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .short("o")
+                        .long("out")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("token.json")
+                        .help("Where to write the token (--token of the client)"),
+                ),
+        )
+        .get_matches();
+
+    crypto::init();
+    let key_path = matches.value_of("key").unwrap();
+
+    // clap has already done the validation and default value.
+    match matches.subcommand() {
+        ("keygen", _) => {
+            if Path::new(key_path).exists() {
+                return Err(format!("{} already exists, remove it first", key_path).into());
+            }
+            crypto::save_key(key_path, &crypto::generate_key())?;
+            info!("Wrote new key to {}", key_path);
+        }
+        ("token", Some(args)) => {
+            let key = crypto::load_key(key_path)?;
+            let client_id = args.value_of("client_id").unwrap().parse()?;
+            let lifetime = Duration::from_secs(args.value_of("lifetime").unwrap().parse()?);
+            let out = args.value_of("out").unwrap();
+
+            ConnectToken::generate(client_id, lifetime, &key).save(out)?;
+            info!("Wrote connect token of client {} to {}", client_id, out);
+        }
+        _ => println!("{}", matches.usage()),
+    }
+
+    Ok(())
+}
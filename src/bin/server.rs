@@ -1,6 +1,12 @@
-use clap::{App, Arg};
-use log::{info, trace};
+use clap::{App, Arg, ArgMatches};
+use log::{error, info, trace, warn};
+use std::io::{self, BufRead};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
 use std::time::{Duration, Instant};
+use twgraph::config::ServerConfig;
 use twgraph::scene::{NetworkScene, Scene};
 
 /// Validator for clap
@@ -12,6 +18,125 @@ fn is_usize(v: String) -> Result<(), String> {
     Ok(())
 }
 
+/// Validator for clap
+fn is_socket_addr(v: String) -> Result<(), String> {
+    if let Err(_) = v.parse::<SocketAddr>() {
+        return Err("The value should be an address such as 0.0.0.0:8080 or [::]:8080".to_string());
+    }
+
+    Ok(())
+}
+
+/// Validator for clap
+fn is_probability(v: String) -> Result<(), String> {
+    match v.parse::<f32>() {
+        Ok(p) if p >= 0.0 && p <= 1.0 => Ok(()),
+        _ => Err("The value should be between 0 and 1".to_string()),
+    }
+}
+
+/// Lines typed on the standard input, read by another thread so that the
+/// game loop does not wait for them.
+fn read_console() -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("Cannot read the console: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Commands typed by the operator. Only `map <level>` for now.
+fn run_command(scene: &mut NetworkScene, line: &str) {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("map"), Some(name)) => {
+            if let Err(e) = scene.change_map(name) {
+                warn!("Cannot change map to {}: {}", name, e);
+            }
+        }
+        (None, _) => (),
+        _ => warn!("Unknown command {:?}. Commands: map <level>", line),
+    }
+}
+
+/// Start from the configuration file and apply the command-line flags.
+fn load_config(matches: &ArgMatches) -> Result<ServerConfig, Box<std::error::Error>> {
+    let path = matches.value_of("config").unwrap();
+    let mut config = if Path::new(path).exists() || matches.occurrences_of("config") > 0 {
+        info!("Load server configuration from {}", path);
+        ServerConfig::load(path)?
+    } else {
+        info!("No {}, use the default server configuration", path);
+        ServerConfig::default()
+    };
+
+    // clap has already done the validation.
+    if let Some(bind) = matches.value_of("bind") {
+        config.bind_address = bind.parse()?;
+    }
+    if let Some(port) = matches.value_of("port") {
+        config.bind_address.set_port(port.parse()?);
+    }
+    if let Some(nb) = matches.value_of("number") {
+        config.max_players = nb.parse()?;
+    }
+    if let Some(name) = matches.value_of("name") {
+        config.name = name.to_string();
+    }
+    if let Some(map) = matches.value_of("map") {
+        config.map = map.to_string();
+    }
+    if let Some(dir) = matches.value_of("levels") {
+        config.levels_dir = dir.to_string();
+    }
+    if let Some(tick_rate) = matches.value_of("tick_rate") {
+        config.tick_rate = tick_rate.parse()?;
+    }
+    if let Some(size) = matches.value_of("snapshots") {
+        config.snapshot_ring_size = size.parse()?;
+    }
+    if let Some(path) = matches.value_of("connect_key") {
+        config.connect_key_file = Some(path.to_string());
+    }
+    if let Some(timeout) = matches.value_of("timeout") {
+        config.timeout_ms = timeout.parse()?;
+    }
+    if let Some(latency) = matches.value_of("latency") {
+        config.link_conditions.latency_ms = latency.parse()?;
+    }
+    if let Some(jitter) = matches.value_of("jitter") {
+        config.link_conditions.jitter_ms = jitter.parse()?;
+    }
+    if let Some(loss) = matches.value_of("loss") {
+        config.link_conditions.loss = loss.parse()?;
+    }
+    if let Some(duplication) = matches.value_of("duplication") {
+        config.link_conditions.duplication = duplication.parse()?;
+    }
+    if let Some(reordering) = matches.value_of("reordering") {
+        config.link_conditions.reordering = reordering.parse()?;
+    }
+    if let Some(seed) = matches.value_of("link_seed") {
+        config.link_seed = seed.parse()?;
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
 fn main() -> Result<(), Box<std::error::Error>> {
     env_logger::init();
 
@@ -19,13 +144,30 @@ fn main() -> Result<(), Box<std::error::Error>> {
     let matches = App::new("Server")
         .version("0.1")
         .author("Benoit Eudier")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .required(false)
+                .takes_value(true)
+                .default_value("server.json")
+                .help("Server configuration file. Flags below override its values"),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .short("b")
+                .long("bind")
+                .required(false)
+                .takes_value(true)
+                .validator(is_socket_addr)
+                .help("Address to listen to, e.g. 0.0.0.0:8080 or [::]:8080"),
+        )
         .arg(
             Arg::with_name("port")
                 .short("p")
                 .long("port")
                 .required(false)
                 .takes_value(true)
-                .default_value("8080")
                 .validator(is_usize)
                 .help("Port of the server"),
         )
@@ -35,28 +177,136 @@ fn main() -> Result<(), Box<std::error::Error>> {
                 .long("number")
                 .required(false)
                 .takes_value(true)
-                .default_value("8")
                 .validator(is_usize)
                 .help("Number of players"),
         )
+        .arg(
+            Arg::with_name("name")
+                .long("name")
+                .required(false)
+                .takes_value(true)
+                .help("Name shown in the server browser"),
+        )
+        .arg(
+            Arg::with_name("map")
+                .short("m")
+                .long("map")
+                .required(false)
+                .takes_value(true)
+                .help("Level loaded at start, relative to the levels directory"),
+        )
+        .arg(
+            Arg::with_name("levels")
+                .long("levels")
+                .required(false)
+                .takes_value(true)
+                .help("Directory of the levels. The clients need the same files"),
+        )
+        .arg(
+            Arg::with_name("tick_rate")
+                .long("tick-rate")
+                .required(false)
+                .takes_value(true)
+                .validator(is_usize)
+                .help("Server frames per second"),
+        )
+        .arg(
+            Arg::with_name("snapshots")
+                .long("snapshots")
+                .required(false)
+                .takes_value(true)
+                .validator(is_usize)
+                .help("Number of past states kept to compute deltas (max 256)"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .required(false)
+                .takes_value(true)
+                .validator(is_usize)
+                .help("Milliseconds without news from a client before it is disconnected"),
+        )
+        .arg(
+            Arg::with_name("connect_key")
+                .long("connect-key")
+                .required(false)
+                .takes_value(true)
+                .help("Key shared with the matchmaker. Clients then need a connect token"),
+        )
+        .arg(
+            Arg::with_name("latency")
+                .long("latency")
+                .required(false)
+                .takes_value(true)
+                .validator(is_usize)
+                .help("Simulated latency in milliseconds, added to each packet in both directions"),
+        )
+        .arg(
+            Arg::with_name("jitter")
+                .long("jitter")
+                .required(false)
+                .takes_value(true)
+                .validator(is_usize)
+                .help("Simulated random delay in milliseconds, added to the latency"),
+        )
+        .arg(
+            Arg::with_name("loss")
+                .long("loss")
+                .required(false)
+                .takes_value(true)
+                .validator(is_probability)
+                .help("Probability that a packet is dropped, e.g. 0.05"),
+        )
+        .arg(
+            Arg::with_name("duplication")
+                .long("duplication")
+                .required(false)
+                .takes_value(true)
+                .validator(is_probability)
+                .help("Probability that a packet is delivered twice"),
+        )
+        .arg(
+            Arg::with_name("reordering")
+                .long("reordering")
+                .required(false)
+                .takes_value(true)
+                .validator(is_probability)
+                .help("Probability that a packet arrives after the next ones"),
+        )
+        .arg(
+            Arg::with_name("link_seed")
+                .long("link-seed")
+                .required(false)
+                .takes_value(true)
+                .validator(is_usize)
+                .help("Seed of the simulated packet loss, to replay the same run"),
+        )
         .get_matches();
 
-    // clap has already done the validation and default value.
-    let port = matches.value_of("port").unwrap().parse().unwrap();
-    let nb = matches.value_of("number").unwrap().parse().unwrap();
+    let config = load_config(&matches)?;
 
-    info!("Will connect on port {}, with {} players", port, nb);
+    info!(
+        "{}: will listen on {}, with {} players",
+        config.name, config.bind_address, config.max_players
+    );
 
-    let fixed_time_stamp = Duration::new(0, 16666667);
+    let fixed_time_stamp = config.tick_duration();
     let mut previous_clock = Instant::now();
     let mut accumulator = Duration::new(0, 0);
 
     // The scene will contains all the systems, including the network stack.
     // Here, no need for Scene stack or anything fancy.
-    let mut scene = NetworkScene::from_file(port, nb, "arena.json".to_string());
-    //let mut scene = NetworkScene::new(port, nb);
+    let mut scene = NetworkScene::from_file(&config);
+    //let mut scene = NetworkScene::new(&config);
+
+    // Type `map <level>` to change the map during the game.
+    let console = read_console();
 
     'game_loop: loop {
+        for line in console.try_iter() {
+            run_command(&mut scene, &line);
+        }
+
         while accumulator > fixed_time_stamp {
             accumulator -= fixed_time_stamp;
 
@@ -34,6 +34,10 @@ impl<T> RingBuffer<T> {
         self.get(self.head_index())
     }
 
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
     pub fn head_index(&self) -> usize {
         if self.next == 0 {
             self.size - 1
@@ -1,5 +1,19 @@
-use crate::renderer::AttachmentType;
+use serde_derive::{Deserialize, Serialize};
 use std::default::Default;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::error::{TwError, TwResult};
+use crate::net::conditioner::LinkConditions;
+use crate::net::fragment::{DEFAULT_MTU, MAX_MTU};
+use crate::net::level::is_valid_level_name;
+use crate::net::packing::Quantization;
+use crate::net::validation::InputLimits;
+use crate::net::TimeoutSettings;
+use crate::renderer::AttachmentType;
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct GameConfig {
@@ -28,3 +42,247 @@ impl Default for RenderOptions {
         }
     }
 }
+
+// -----------------------------------------
+
+/// Settings of the dedicated server. They are read from a JSON file and
+/// can be overridden from the command-line. Missing fields take the default
+/// value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Where to listen. `0.0.0.0:8080` for all IPv4 interfaces, `[::]:8080`
+    /// for IPv6.
+    pub bind_address: SocketAddr,
+
+    /// Name shown in the server browser of the clients.
+    pub name: String,
+
+    /// Level loaded at start, relative to `levels_dir`. The clients load
+    /// the same file from their own levels directory.
+    pub map: String,
+
+    /// Where the levels are. Maps can be changed during the game to any
+    /// level of the directory.
+    pub levels_dir: String,
+
+    pub max_players: usize,
+
+    /// Connection requests accepted per second from one IP address. The
+    /// others are ignored.
+    pub connection_attempts_per_sec: u32,
+
+    /// File with the key shared with the matchmaker, in hexadecimal. When
+    /// set, clients need a connect token and the packets are encrypted.
+    pub connect_key_file: Option<String>,
+
+    /// Server frames per second. A snapshot is sent at every frame.
+    pub tick_rate: u32,
+
+    /// Number of past states kept to compute the deltas. A client whose last
+    /// known state is older is disconnected. Each state is a copy of the
+    /// ECS, so at most 256.
+    pub snapshot_ring_size: usize,
+
+    /// Clients that did not send anything for that long are disconnected.
+    pub timeout_ms: u64,
+
+    /// Ping sent to clients that did not receive anything for that long.
+    pub heartbeat_ms: u64,
+
+    /// Entities further than that from a player are not sent to it, unless
+    /// their RelevancyComponent says otherwise. None sends everything.
+    pub relevancy_radius: Option<f32>,
+
+    /// Inputs accepted from each player. Players who send too many invalid
+    /// inputs are kicked.
+    pub input_limits: InputLimits,
+
+    /// Level bounds and precision of the positions in the snapshots.
+    /// Positions outside of the bounds are clamped.
+    pub quantization: Quantization,
+
+    /// Biggest datagram sent, in bytes. Bigger packets are fragmented and
+    /// big snapshots are split.
+    pub mtu: usize,
+
+    /// Simulated latency, jitter, loss, duplication and reordering, to
+    /// test the netcode locally. Perfect by default.
+    pub link_conditions: LinkConditions,
+
+    /// Seed of the link conditioner. The same seed drops the same packets.
+    pub link_seed: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let timeouts = TimeoutSettings::default();
+        ServerConfig {
+            bind_address: "0.0.0.0:8080".parse().unwrap(),
+            name: "twgraph server".to_string(),
+            map: "arena.json".to_string(),
+            levels_dir: ".".to_string(),
+            max_players: 8,
+            connection_attempts_per_sec: 5,
+            connect_key_file: None,
+            tick_rate: 60,
+            snapshot_ring_size: 60,
+            timeout_ms: timeouts.timeout.as_millis() as u64,
+            heartbeat_ms: timeouts.heartbeat.as_millis() as u64,
+            relevancy_radius: Some(100.0),
+            input_limits: InputLimits::default(),
+            quantization: Quantization::default(),
+            mtu: DEFAULT_MTU,
+            link_conditions: LinkConditions::default(),
+            link_seed: 0,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> TwResult<Self> {
+        let mut file = File::open(path)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let config: ServerConfig = serde_json::from_str(&content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the values that would make the server misbehave.
+    pub fn validate(&self) -> TwResult<()> {
+        if self.max_players == 0 {
+            return Err(TwError::InvalidConfig(
+                "max_players should be at least 1".to_string(),
+            ));
+        }
+        if self.name.trim().is_empty() {
+            return Err(TwError::InvalidConfig(
+                "name should not be empty".to_string(),
+            ));
+        }
+        if !is_valid_level_name(&self.map) {
+            return Err(TwError::InvalidConfig(format!(
+                "map should be a relative path inside levels_dir, got {:?}",
+                self.map
+            )));
+        }
+        if self.connection_attempts_per_sec == 0 {
+            return Err(TwError::InvalidConfig(
+                "connection_attempts_per_sec should be at least 1".to_string(),
+            ));
+        }
+        if self.tick_rate == 0 {
+            return Err(TwError::InvalidConfig(
+                "tick_rate should be at least 1".to_string(),
+            ));
+        }
+        if self.snapshot_ring_size < 2 || self.snapshot_ring_size > 256 {
+            return Err(TwError::InvalidConfig(format!(
+                "snapshot_ring_size should be between 2 and 256, got {}",
+                self.snapshot_ring_size
+            )));
+        }
+        if self.relevancy_radius.map_or(false, |r| !(r > 0.0)) {
+            return Err(TwError::InvalidConfig(
+                "relevancy_radius should be positive".to_string(),
+            ));
+        }
+        if let Err(e) = self.input_limits.validate() {
+            return Err(TwError::InvalidConfig(format!("input_limits: {}", e)));
+        }
+        if let Err(e) = self.quantization.validate() {
+            return Err(TwError::InvalidConfig(format!("quantization: {}", e)));
+        }
+        if self.mtu < 576 || self.mtu > MAX_MTU {
+            return Err(TwError::InvalidConfig(format!(
+                "mtu should be between 576 and {}, got {}",
+                MAX_MTU, self.mtu
+            )));
+        }
+        if let Err(e) = self.link_conditions.validate() {
+            return Err(TwError::InvalidConfig(format!("link_conditions: {}", e)));
+        }
+        if self.heartbeat_ms >= self.timeout_ms {
+            return Err(TwError::InvalidConfig(format!(
+                "heartbeat_ms ({}) should be smaller than timeout_ms ({})",
+                self.heartbeat_ms, self.timeout_ms
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Duration of one server frame.
+    pub fn tick_duration(&self) -> Duration {
+        Duration::from_nanos(1_000_000_000 / u64::from(self.tick_rate))
+    }
+
+    pub fn timeouts(&self) -> TimeoutSettings {
+        TimeoutSettings {
+            timeout: Duration::from_millis(self.timeout_ms),
+            heartbeat: Duration::from_millis(self.heartbeat_ms),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_server_config_test() {
+        let config: ServerConfig =
+            serde_json::from_str(r#"{"bind_address": "[::]:9000", "max_players": 4}"#).unwrap();
+        assert_eq!(9000, config.bind_address.port());
+        assert!(config.bind_address.is_ipv6());
+        assert_eq!(4, config.max_players);
+        assert_eq!("arena.json", config.map);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_server_config_test() {
+        let config = ServerConfig {
+            snapshot_ring_size: 300,
+            ..ServerConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = ServerConfig {
+            name: " ".to_string(),
+            ..ServerConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = ServerConfig {
+            map: "../arena.json".to_string(),
+            ..ServerConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = ServerConfig {
+            heartbeat_ms: 5000,
+            timeout_ms: 1000,
+            ..ServerConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config: ServerConfig =
+            serde_json::from_str(r#"{"quantization": {"bounds_max": [10.0, -300.0, 10.0]}}"#)
+                .unwrap();
+        assert_eq!(20, config.quantization.position_bits);
+        assert!(config.validate().is_err());
+
+        let config: ServerConfig =
+            serde_json::from_str(r#"{"link_conditions": {"latency_ms": 100, "loss": 2.0}}"#)
+                .unwrap();
+        assert_eq!(100, config.link_conditions.latency_ms);
+        assert!(config.validate().is_err());
+
+        let config: ServerConfig =
+            serde_json::from_str(r#"{"input_limits": {"max_time_ahead": 0.0}}"#).unwrap();
+        assert_eq!(32, config.input_limits.max_inputs_per_tick);
+        assert!(config.validate().is_err());
+    }
+}
@@ -1,6 +1,9 @@
+use crate::ecs::Entity;
 use crate::editor::Editor;
+use crate::net::replication::{FieldType, FieldValue};
+use crate::replicate;
 use crate::ser::VectorDef;
-use cgmath::Vector3;
+use cgmath::{Euler, Matrix4, Rad, Vector3};
 use imgui::{im_str, ImGuiCond, ImGuiSelectableFlags, ImVec2, Ui};
 use serde_derive::{Deserialize, Serialize};
 use std::default::Default;
@@ -18,6 +21,10 @@ impl NameComponent {
     }
 }
 
+replicate!(NameComponent, 5, {
+    name: Text,
+});
+
 /// This is a component that is going to be rendered
 /// by the render system.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -88,6 +95,11 @@ impl Default for ModelComponent {
     }
 }
 
+replicate!(ModelComponent, 1, {
+    mesh_name: Name,
+    texture_name: Name,
+});
+
 /// Position of the game object. No position = no rendering.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TransformComponent {
@@ -111,6 +123,27 @@ impl Default for TransformComponent {
     }
 }
 
+impl TransformComponent {
+    /// Model matrix of the game object. The rotation is made of euler angles
+    /// in radians, the same that the glTF loader extracts from the nodes.
+    pub fn model_matrix(&self) -> Matrix4<f32> {
+        let rotation = Euler::new(
+            Rad(self.rotation.x),
+            Rad(self.rotation.y),
+            Rad(self.rotation.z),
+        );
+        Matrix4::from_translation(self.position)
+            * Matrix4::from(rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+replicate!(TransformComponent, 0, {
+    position: Position,
+    rotation: Rotation,
+    scale: Vec3,
+});
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct DummyComponent {
     pub speed: f32,
@@ -131,6 +164,29 @@ pub enum LightType {
     Ambient,
 }
 
+impl FieldType for LightType {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Int(match *self {
+            LightType::Point => 0,
+            LightType::Directional => 1,
+            LightType::Ambient => 2,
+        })
+    }
+
+    fn from_value(value: FieldValue) -> Option<Self> {
+        match value {
+            FieldValue::Int(0) => Some(LightType::Point),
+            FieldValue::Int(1) => Some(LightType::Directional),
+            FieldValue::Int(2) => Some(LightType::Ambient),
+            _ => None,
+        }
+    }
+
+    fn differs(&self, old: &Self) -> bool {
+        self != old
+    }
+}
+
 // Emit light! Right now, only one is supported.
 // An entity with a light component will need a transform.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -153,6 +209,12 @@ impl Default for LightComponent {
     }
 }
 
+replicate!(LightComponent, 2, {
+    light_type: Varint,
+    color: Vec3,
+    cast_shadows: Bool,
+});
+
 impl TransformComponent {
     pub fn draw_ui(&mut self, ui: &Ui, editor: &mut Editor) {
         ui.tree_node(im_str!("position:"))
@@ -344,6 +406,10 @@ pub struct PlayerComponent {
     pub right: Vector3<f32>,
     #[serde(with = "VectorDef")]
     pub up: Vector3<f32>,
+
+    /// Players in the same team can chat privately.
+    #[serde(default)]
+    pub team: u8,
 }
 
 impl PlayerComponent {
@@ -358,6 +424,171 @@ impl Default for PlayerComponent {
             look_at: Vector3::new(0.0, 0.0, -1.0),
             right: Vector3::new(-1.0, 0.0, 0.0),
             up: Vector3::new(0.0, 1.0, 0.0),
+            team: 0,
         }
     }
 }
+
+// right and up are not sent, the client does not need them.
+replicate!(PlayerComponent, 4, {
+    look_at: Vec3,
+    team: Varint,
+});
+
+/// Who receives the entity over the network. Entities without this component
+/// are only sent to the players that are close enough. See net/relevancy.rs.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct RelevancyComponent {
+    /// Sent to every player whatever the distance, e.g. the level or the
+    /// game state.
+    #[serde(default)]
+    pub always_relevant: bool,
+
+    /// Player entity that owns this entity. The owner always receives it.
+    #[serde(default)]
+    pub owner: Option<Entity>,
+
+    /// Only the owner receives it.
+    #[serde(default)]
+    pub owner_only: bool,
+
+    /// Only the players of this team receive it.
+    #[serde(default)]
+    pub team: Option<u8>,
+}
+
+impl RelevancyComponent {
+    pub fn draw_ui(&mut self, ui: &Ui, editor: &mut Editor) {
+        if ui.checkbox(im_str!("Always relevant"), &mut self.always_relevant) {
+            editor.set_unsaved();
+        }
+        if ui.checkbox(im_str!("Owner only"), &mut self.owner_only) {
+            editor.set_unsaved();
+        }
+        match self.team {
+            Some(team) => ui.text(im_str!("Team: {}", team)),
+            None => ui.text(im_str!("Team: <All>")),
+        }
+    }
+}
+
+/// Animation state of a skinned model. Only names and timers are stored here
+/// so that the state can be sent to clients. Skeletons and clips are in the
+/// `AnimationManager`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnimatorComponent {
+    pub skeleton: String,
+
+    /// Clip currently playing. None means rest pose.
+    pub clip: Option<String>,
+    pub time: f32,
+    pub speed: f32,
+    pub looping: bool,
+
+    // Clip that was playing before `play` was called. Both clips are blended
+    // during `blend_duration` seconds.
+    pub previous_clip: Option<String>,
+    pub previous_time: f32,
+    pub previous_looping: bool,
+    pub blend_duration: f32,
+    pub blend_elapsed: f32,
+
+    /// Computed before rendering. Not saved or sent.
+    #[serde(skip)]
+    pub joint_matrices: Vec<[[f32; 4]; 4]>,
+}
+
+impl AnimatorComponent {
+    pub fn new(skeleton: String) -> Self {
+        AnimatorComponent {
+            skeleton,
+            ..AnimatorComponent::default()
+        }
+    }
+
+    /// Start playing a clip. If another clip was playing, it will fade out
+    /// during `blend_duration` seconds. Playing the current clip again does nothing.
+    pub fn play(&mut self, clip: &str, looping: bool, blend_duration: f32) {
+        if self.clip.as_ref().map(|c| c == clip).unwrap_or(false) {
+            self.looping = looping;
+            return;
+        }
+
+        self.previous_clip = self.clip.take();
+        self.previous_time = self.time;
+        self.previous_looping = self.looping;
+        self.blend_duration = blend_duration;
+        self.blend_elapsed = 0.0;
+
+        self.clip = Some(clip.to_string());
+        self.time = 0.0;
+        self.looping = looping;
+    }
+
+    pub fn stop(&mut self) {
+        self.clip = None;
+        self.previous_clip = None;
+        self.time = 0.0;
+    }
+
+    /// Advance timers by dt seconds.
+    pub fn advance(&mut self, dt: f32) {
+        let dt = dt * self.speed;
+        self.time += dt;
+
+        if self.previous_clip.is_some() {
+            self.previous_time += dt;
+            self.blend_elapsed += dt;
+            if self.blend_elapsed >= self.blend_duration {
+                self.previous_clip = None;
+            }
+        }
+    }
+
+    pub fn draw_ui(&mut self, ui: &Ui, editor: &mut Editor) {
+        ui.text(im_str!("Skeleton: {}", self.skeleton));
+        ui.text(im_str!(
+            "Clip: {}",
+            self.clip.as_ref().map(|c| c.as_str()).unwrap_or("<None>")
+        ));
+        if ui.input_float(im_str!("speed"), &mut self.speed).build() {
+            editor.set_unsaved();
+        }
+        if ui.checkbox(im_str!("Loop"), &mut self.looping) {
+            editor.set_unsaved();
+        }
+    }
+}
+
+impl Default for AnimatorComponent {
+    fn default() -> Self {
+        AnimatorComponent {
+            skeleton: String::new(),
+            clip: None,
+            time: 0.0,
+            speed: 1.0,
+            looping: true,
+            previous_clip: None,
+            previous_time: 0.0,
+            previous_looping: true,
+            blend_duration: 0.0,
+            blend_elapsed: 0.0,
+            joint_matrices: Vec::new(),
+        }
+    }
+}
+
+// The component is sent when the playback changes. Timers are not compared
+// as they advance on both sides.
+replicate!(AnimatorComponent, 3, {
+    skeleton: Name,
+    clip: OptionalName,
+    speed: F32,
+    looping: Bool,
+    previous_clip: OptionalName,
+    time: F32(WithOthers),
+    previous_time: F32(WithOthers),
+    previous_looping: Bool(WithOthers),
+    blend_duration: F32(WithOthers),
+    blend_elapsed: F32(WithOthers),
+});
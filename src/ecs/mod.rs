@@ -10,12 +10,15 @@ pub mod gen_index;
 pub mod systems;
 
 use self::components::{
-    DummyComponent, LightComponent, LightType, ModelComponent, NameComponent, PlayerComponent,
-    TransformComponent,
+    AnimatorComponent, DummyComponent, LightComponent, LightType, ModelComponent, NameComponent,
+    PlayerComponent, RelevancyComponent, TransformComponent,
 };
 use self::gen_index::{GenerationalIndex, GenerationalIndexAllocator, GenerationalIndexArray};
 use crate::camera::Camera;
 use crate::error::TwResult;
+use crate::net::replication::{
+    apply_component_delta, component_delta, ComponentDelta, ComponentId, FieldInfo, Replicated,
+};
 
 pub type Entity = GenerationalIndex;
 type EntityArray<T> = GenerationalIndexArray<T>;
@@ -222,12 +225,16 @@ impl ECS {
 
     pub fn load_and_replace<P: AsRef<std::path::Path>>(&mut self, path: P) -> TwResult<()> {
         let new_ecs = ECS::load(path)?;
-
-        self.components = new_ecs.components;
-        self.allocator = new_ecs.allocator;
+        self.replace(new_ecs);
 
         Ok(())
     }
+
+    /// Take the entities of another ECS. The camera stays.
+    pub fn replace(&mut self, other: ECS) {
+        self.components = other.components;
+        self.allocator = other.allocator;
+    }
 }
 
 /// Macro to set up the component arrays in the ECS. It should be used with
@@ -414,4 +421,79 @@ register_components!(
     [lights, LightComponent, "Light"],
     [names, NameComponent, "Name"],
     [players, PlayerComponent, "Player"],
+    [animators, AnimatorComponent, "Animator"],
+    [relevancies, RelevancyComponent, "Relevancy"],
+);
+
+/// Macro to list the components that are sent to the clients. They should
+/// implement `Replicated` (see net/replication.rs).
+macro_rules! replicated_components {
+    { $([$name:ident, $component:ty],)+ } =>
+    {
+        impl Components {
+            /// Changes of the replicated components of the entity since
+            /// `old`. If the client does not know the entity (`known` is
+            /// false), all the components are sent.
+            pub fn replicated_deltas(
+                &self,
+                old: &Components,
+                entity: &Entity,
+                known: bool,
+            ) -> Vec<ComponentDelta> {
+                let mut deltas = Vec::new();
+                $(
+                    if let Some(new) = self.$name.get(entity) {
+                        let old = if known { old.$name.get(entity) } else { None };
+                        if let Some(delta) = component_delta(old, new) {
+                            deltas.push(delta);
+                        }
+                    }
+                )+
+                deltas
+            }
+
+            /// Apply the changes of a component. It is created if the entity
+            /// does not have it. Returns false if the delta does not match
+            /// a replicated component.
+            pub fn apply_replicated(&mut self, entity: &Entity, delta: &ComponentDelta) -> bool {
+                $(
+                    if delta.component == <$component as Replicated>::ID {
+                        if self.$name.get(entity).is_none() {
+                            self.$name.set(entity, <$component>::default());
+                        }
+                        let component = self.$name.get_mut(entity).unwrap();
+                        return apply_component_delta(component, delta);
+                    }
+                )+
+                false
+            }
+
+            /// Remove the replicated components of an entity, e.g. the ones
+            /// left by a deleted entity with the same index.
+            pub fn clear_replicated(&mut self, entity: &Entity) {
+                $(
+                    self.$name.empty(entity);
+                )+
+            }
+        }
+
+        /// Fields of a replicated component, to decode the snapshots.
+        pub fn replicated_fields(id: ComponentId) -> Option<&'static [FieldInfo]> {
+            $(
+                if id == <$component as Replicated>::ID {
+                    return Some(<$component as Replicated>::FIELDS);
+                }
+            )+
+            None
+        }
+    }
+}
+
+replicated_components!(
+    [transforms, TransformComponent],
+    [models, ModelComponent],
+    [lights, LightComponent],
+    [animators, AnimatorComponent],
+    [players, PlayerComponent],
+    [names, NameComponent],
 );
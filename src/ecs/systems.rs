@@ -1,6 +1,6 @@
 use cgmath::{Angle, InnerSpace, Rad, Vector3};
 use imgui::{FontGlyphRange, ImFontConfig, ImGui};
-use log::debug;
+use log::{debug, warn};
 use vulkano::device::{Device, Queue};
 use vulkano::instance::Instance;
 use vulkano::swapchain::Surface;
@@ -10,18 +10,22 @@ use winit::{Window, WindowBuilder, WindowEvent};
 
 use log::{error, trace};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use super::components::{AnimatorComponent, ModelComponent, PlayerComponent, TransformComponent};
 use super::{Entity, ECS};
+use crate::animation::AnimationSystem;
 use crate::camera::CameraDirection;
 use crate::event::Event;
+use crate::net::prediction::PlayerInput;
+use crate::net::validation::{is_possible_move, InputLimits, InputValidator, Violation};
 use crate::renderer::Renderer;
 use crate::resource::Resources;
 use crate::scene::ClientCommand;
 use crate::time::dt_as_secs;
 use crate::ui::Gui;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 
 pub struct RenderingSystem<'a> {
     renderer: Renderer<'a>,
@@ -123,6 +127,9 @@ impl<'a> RenderingSystem<'a> {
             panic!("wuuuuut");
         }
 
+        // Skinned meshes need their joint matrices before drawing.
+        AnimationSystem::compute_joint_matrices(ecs, &resources.animations);
+
         // That's a lot of memory allocation here. FIXME
         let live_entities = ecs.nb_entities();
         let mut lights = Vec::new();
@@ -132,10 +139,11 @@ impl<'a> RenderingSystem<'a> {
             let maybe_l = ecs.components.lights.get(entity);
             let maybe_t = ecs.components.transforms.get(entity);
             let maybe_m = ecs.components.models.get(entity);
+            let maybe_a = ecs.components.animators.get(entity);
 
             match (maybe_l, maybe_m, maybe_t) {
                 (Some(l), _, Some(t)) => lights.push((l, t)),
-                (_, Some(m), Some(t)) => objs.push((m, t)),
+                (_, Some(m), Some(t)) => objs.push((m, t, maybe_a)),
                 _ => {}
             }
         }
@@ -214,32 +222,90 @@ impl DummySystem {
     }
 }
 
+/// Speed of the players, in units per second.
+pub const PLAYER_SPEED: f32 = 10.0;
+
+/// Longest input that is accepted. Prevents a client to move far away by
+/// sending a huge dt.
+pub const MAX_INPUT_DT: f32 = 0.1;
+
+/// Skinned model of the players, from `assets/models/player.gltf`. Only the
+/// clients load it; the server only uses the names.
+pub const PLAYER_MESH: &str = "player/Body.0";
+pub const PLAYER_SKELETON: &str = "player/armature";
+pub const IDLE_CLIP: &str = "player/Idle";
+pub const RUN_CLIP: &str = "player/Run";
+
+/// Seconds to fade from a clip of the players to the other.
+const ANIMATION_BLEND: f32 = 0.2;
+
+/// Players who did not move for that long, in seconds, go back to idle. The
+/// inputs do not come at every tick, so a tick without move is not a stop.
+const IDLE_DELAY: f32 = 0.15;
+
+/// Model and animation state of a new player.
+pub fn player_model() -> (ModelComponent, AnimatorComponent) {
+    let mut animator = AnimatorComponent::new(PLAYER_SKELETON.to_string());
+    animator.play(IDLE_CLIP, true, 0.0);
+    (
+        ModelComponent {
+            mesh_name: PLAYER_MESH.to_string(),
+            ..ModelComponent::default()
+        },
+        animator,
+    )
+}
+
 /// In charge of updating players positions and so on from the events (network + physics)
 pub struct PlayerSystem {
-    /// Store the commands that should be applied to players at each frame.
-    ///
-    /// This also play a bit the role of a frame limiter. For example if a player sends
-    /// too many move packets during one frame, we are going to use only one here.
-    commands_per_players: HashMap<Entity, HashSet<CameraDirection>>,
+    /// Store the inputs that should be applied to players at each frame, in
+    /// the order they were sent.
+    inputs_per_players: HashMap<Entity, Vec<PlayerInput>>,
+
+    /// Inputs come from the clients and are checked before they are applied.
+    limits: InputLimits,
+    validators: HashMap<Entity, InputValidator>,
 
-    world_up: Vector3<f32>,
+    /// Seconds since each player last moved. Removed once the player is
+    /// back to idle.
+    still_for: HashMap<Entity, f32>,
 }
 
 impl PlayerSystem {
-    pub fn new() -> Self {
+    pub fn new(limits: InputLimits) -> Self {
         PlayerSystem {
-            commands_per_players: HashMap::new(),
-            world_up: Vector3::new(0.0, 1.0, 0.0),
+            inputs_per_players: HashMap::new(),
+            limits,
+            validators: HashMap::new(),
+            still_for: HashMap::new(),
         }
     }
 
-    /// Update commands to apply each player. Also, update their look_at vector
-    pub fn handle_network_events(&mut self, ecs: &mut ECS, events: &Vec<(Entity, Event)>) {
-        for v in self.commands_per_players.values_mut() {
+    /// Update inputs to apply to each player. Invalid inputs are dropped.
+    /// Returns the players that sent too many of them; they should be
+    /// kicked.
+    pub fn handle_network_events(
+        &mut self,
+        ecs: &mut ECS,
+        events: &Vec<(Entity, Event)>,
+    ) -> Vec<Entity> {
+        let now = Instant::now();
+        for v in self.inputs_per_players.values_mut() {
             v.clear();
         }
+        for validator in self.validators.values_mut() {
+            validator.start_tick(now);
+        }
 
         for (entity, event) in events {
+            // The entity is already deleted.
+            if let Event::PlayerDisconnected(_) = event {
+                self.inputs_per_players.remove(entity);
+                self.validators.remove(entity);
+                self.still_for.remove(entity);
+                continue;
+            }
+
             if let None = ecs.components.players.get(&entity) {
                 debug!(
                     "Got an event {:?} for entity {:?} that is not a player",
@@ -248,66 +314,166 @@ impl PlayerSystem {
                 continue;
             }
 
-            match event {
-                // Look at update will just update the direction where the player
-                // is looking at.
-                Event::ClientEvent(ClientCommand::LookAt(direction)) => {
-                    let mut comp = ecs.components.players.get_mut(&entity).unwrap();
-                    comp.look_at =
-                        Vector3::new(direction[0], direction[1], direction[2]).normalize();
-                    comp.right = comp.look_at.cross(self.world_up).normalize();
-                    comp.up = comp.right.cross(comp.look_at).normalize();
+            // Inputs are processed in the update functions. Basically,
+            // handle_network_events should be called before the update function.
+            if let Event::ClientInput(input) = event {
+                let limits = self.limits;
+                let validator = self
+                    .validators
+                    .entry(*entity)
+                    .or_insert_with(|| InputValidator::new(limits, now));
+                if validator.should_kick() {
+                    continue;
                 }
 
-                // Move update will add an element to the commands_per_player
-                // hashset. These will be processed in the update functions.
-                // Basically, handle_network_events should be called before
-                // the update function.
-                Event::ClientEvent(ClientCommand::Move(direction)) => {
-                    if !self.commands_per_players.contains_key(&entity) {
-                        self.commands_per_players
-                            .insert(entity.clone(), HashSet::new());
-                    }
-
-                    self.commands_per_players
-                        .get_mut(&entity)
-                        .unwrap()
-                        .insert(*direction);
+                match validator.check(input) {
+                    Ok(()) => self
+                        .inputs_per_players
+                        .entry(*entity)
+                        .or_insert_with(Vec::new)
+                        .push(input.clone()),
+                    Err(violation) => warn!(
+                        "Dropped input {} of player {:?}: {}",
+                        input.seq_number, entity, violation
+                    ),
                 }
-                _ => (),
             }
         }
+
+        // Also the players whose moves were wrong during the last update.
+        let to_kick: Vec<Entity> = self
+            .validators
+            .iter()
+            .filter(|(_, validator)| validator.should_kick())
+            .map(|(entity, _)| *entity)
+            .collect();
+        for entity in to_kick.iter() {
+            warn!(
+                "Player {:?} sent too many invalid inputs ({} violation points)",
+                entity,
+                self.validators[entity].violations()
+            );
+            self.inputs_per_players.remove(entity);
+            self.validators.remove(entity);
+        }
+        to_kick
     }
 
-    pub fn update(&self, dt: Duration, ecs: &mut ECS) {
-        for (entity, events) in self.commands_per_players.iter() {
-            let transform = ecs
-                .components
+    /// The players got new entities when the level changed. Their pending
+    /// inputs were for the previous level and are dropped.
+    pub fn replace_entities(&mut self, moved: &[(Entity, Entity)]) {
+        self.inputs_per_players.clear();
+        let mut validators = HashMap::new();
+        for (previous, entity) in moved {
+            if let Some(validator) = self.validators.remove(previous) {
+                validators.insert(*entity, validator);
+            }
+        }
+        self.validators = validators;
+
+        let mut still_for = HashMap::new();
+        for (previous, entity) in moved {
+            if let Some(still) = self.still_for.remove(previous) {
+                still_for.insert(*entity, still);
+            }
+        }
+        self.still_for = still_for;
+    }
+
+    pub fn update(&mut self, dt: Duration, ecs: &mut ECS) {
+        let components = &mut ecs.components;
+        for (entity, inputs) in self.inputs_per_players.iter() {
+            let transform = components
                 .transforms
                 .get_mut(&entity)
                 .expect("Player does not have a transform, but it should...");
-            let player = ecs.components.players.get(&entity).unwrap();
-
-            let dt_as_secs = dt_as_secs(dt);
-            let proj_front = player.look_at - (player.look_at.dot(self.world_up)) * self.world_up;
-            let proj_right = player.right - (player.right.dot(self.world_up)) * self.world_up;
-            for event in events {
-                match *event {
-                    CameraDirection::Forward => {
-                        // TODO replace 10.0 by player speed
-                        transform.position += 10.0 * dt_as_secs as f32 * proj_front;
-                    }
-                    CameraDirection::Backward => {
-                        transform.position -= 10.0 * dt_as_secs as f32 * proj_front;
-                    }
-                    CameraDirection::Left => {
-                        transform.position -= 10.0 * dt_as_secs as f32 * proj_right;
-                    }
-                    CameraDirection::Right => {
-                        transform.position += 10.0 * dt_as_secs as f32 * proj_right;
+            let player = components.players.get_mut(&entity).unwrap();
+
+            let previous = (transform.position, player.clone());
+            let mut inputs_dt = 0.0;
+            for input in inputs {
+                PlayerSystem::apply_input(transform, player, input);
+                inputs_dt += input.dt.max(0.0).min(MAX_INPUT_DT);
+            }
+
+            // The inputs were checked, so this should not happen. If it
+            // does, the player does not move.
+            if !is_possible_move(previous.0, transform.position, inputs_dt) {
+                let distance = (transform.position - previous.0).magnitude();
+                warn!("Player {:?}: {}", entity, Violation::InvalidMove(distance));
+                transform.position = previous.0;
+                *player = previous.1;
+                if let Some(validator) = self.validators.get_mut(entity) {
+                    validator.count_violation();
+                }
+            }
+
+            if transform.position != previous.0 {
+                self.still_for.insert(*entity, 0.0);
+            }
+        }
+
+        // Run while moving, idle after.
+        let dt = dt_as_secs(dt) as f32;
+        self.still_for.retain(|entity, still_for| {
+            let moving = *still_for < IDLE_DELAY;
+            if let Some(animator) = components.animators.get_mut(entity) {
+                let clip = if moving { RUN_CLIP } else { IDLE_CLIP };
+                animator.play(clip, true, ANIMATION_BLEND);
+            }
+            *still_for += dt;
+            moving
+        });
+    }
+
+    /// Movement of the player for one input. The client runs the same code to
+    /// predict its movement, so it should only depend on the input.
+    pub fn apply_input(
+        transform: &mut TransformComponent,
+        player: &mut PlayerComponent,
+        input: &PlayerInput,
+    ) {
+        let world_up = Vector3::new(0.0, 1.0, 0.0);
+        let dt = input.dt.max(0.0).min(MAX_INPUT_DT);
+
+        // This also play a bit the role of a frame limiter. For example if a player sends
+        // the same move many times during one input, we are going to use only one here.
+        let mut moves = Vec::new();
+        for command in input.commands.iter() {
+            match *command {
+                // Look at update will just update the direction where the player
+                // is looking at.
+                ClientCommand::LookAt(direction) => {
+                    player.look_at =
+                        Vector3::new(direction[0], direction[1], direction[2]).normalize();
+                    player.right = player.look_at.cross(world_up).normalize();
+                    player.up = player.right.cross(player.look_at).normalize();
+                }
+                ClientCommand::Move(direction) => {
+                    if !moves.contains(&direction) {
+                        moves.push(direction);
                     }
                 }
             }
         }
+
+        let proj_front = player.look_at - (player.look_at.dot(world_up)) * world_up;
+        let proj_right = player.right - (player.right.dot(world_up)) * world_up;
+        for direction in moves {
+            match direction {
+                CameraDirection::Forward => {
+                    transform.position += PLAYER_SPEED * dt * proj_front;
+                }
+                CameraDirection::Backward => {
+                    transform.position -= PLAYER_SPEED * dt * proj_front;
+                }
+                CameraDirection::Left => {
+                    transform.position -= PLAYER_SPEED * dt * proj_right;
+                }
+                CameraDirection::Right => {
+                    transform.position += PLAYER_SPEED * dt * proj_right;
+                }
+            }
+        }
     }
 }
@@ -18,6 +18,7 @@ pub enum TwError {
     // Mine
     ModelLoading(String),
     RenderingSystemInitialization(String),
+    InvalidConfig(String),
 
     // Vulkano
     VkDeviceMemoryAlloc(DeviceMemoryAllocError),
@@ -38,6 +39,9 @@ pub enum TwError {
     // Saving/recovering scene.
     Io(std::io::Error),
     JsonSerde(serde_json::Error),
+
+    // Importing glTF files.
+    GltfLoading(gltf::Error),
 }
 
 impl fmt::Display for TwError {
@@ -45,6 +49,7 @@ impl fmt::Display for TwError {
         match *self {
             TwError::ModelLoading(ref x) => write!(f, "{}", x),
             TwError::RenderingSystemInitialization(ref x) => write!(f, "{}", x),
+            TwError::InvalidConfig(ref x) => write!(f, "Invalid configuration: {}", x),
             TwError::VkDeviceMemoryAlloc(ref x) => write!(f, "{}", x),
             TwError::VkCapabilities(ref x) => write!(f, "{}", x),
             TwError::VkSwapchainCreation(ref x) => write!(f, "{}", x),
@@ -55,6 +60,7 @@ impl fmt::Display for TwError {
             TwError::VkDrawIndexed(ref x) => write!(f, "{}", x),
             TwError::Io(ref x) => write!(f, "{}", x),
             TwError::JsonSerde(ref x) => write!(f, "{}", x),
+            TwError::GltfLoading(ref x) => write!(f, "{}", x),
         }
     }
 }
@@ -64,6 +70,7 @@ impl Error for TwError {
         match *self {
             TwError::ModelLoading(ref x) => x,
             TwError::RenderingSystemInitialization(ref x) => x,
+            TwError::InvalidConfig(ref x) => x,
             TwError::VkDeviceMemoryAlloc(ref x) => x.description(),
             TwError::VkCapabilities(ref x) => x.description(),
             TwError::VkSwapchainCreation(ref x) => x.description(),
@@ -74,6 +81,7 @@ impl Error for TwError {
             TwError::VkDrawIndexed(ref x) => x.description(),
             TwError::Io(ref x) => x.description(),
             TwError::JsonSerde(ref x) => x.description(),
+            TwError::GltfLoading(ref x) => x.description(),
         }
     }
 }
@@ -137,3 +145,9 @@ impl From<serde_json::Error> for TwError {
         TwError::JsonSerde(err)
     }
 }
+
+impl From<gltf::Error> for TwError {
+    fn from(err: gltf::Error) -> Self {
+        TwError::GltfLoading(err)
+    }
+}
@@ -1,6 +1,9 @@
 use crate::camera::CameraDirection;
 use crate::config::GameConfig;
-use crate::scene::ClientCommand;
+use crate::net::prediction::PlayerInput;
+use crate::net::protocol::DisconnectReason;
+use crate::net::reliable::{ChannelId, ReliableContent};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
@@ -8,7 +11,14 @@ pub enum Event {
     EditorEvent(EditorEvent),
     ResourceEvent(ResourceEvent),
     GameEvent(GameEvent),
-    ClientEvent(ClientCommand),
+    ClientInput(PlayerInput),
+
+    /// Reliable message received from the network, in order for its channel.
+    ReliableMessage(ChannelId, ReliableContent),
+
+    /// The player left or was removed by the server. Its entity is already
+    /// deleted.
+    PlayerDisconnected(DisconnectReason),
 }
 
 /// Stuff that happens only in Editor.
@@ -40,4 +50,7 @@ pub enum ResourceEvent {
 #[derive(Debug, Clone)]
 pub enum GameEvent {
     QuitGame,
+
+    /// Join the server picked in the server browser.
+    ConnectTo(SocketAddr),
 }
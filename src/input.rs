@@ -22,6 +22,7 @@ pub enum KeyType {
     Print,
     NextScene,
     PreviousScene,
+    NetworkStats,
 }
 
 /// Similar to winit modifier state. Didn't want to leak type from
@@ -214,6 +215,7 @@ impl Input {
         mapping.insert(VirtualKeyCode::Space, KeyType::Space);
         mapping.insert(VirtualKeyCode::Subtract, KeyType::PreviousScene);
         mapping.insert(VirtualKeyCode::Equals, KeyType::NextScene);
+        mapping.insert(VirtualKeyCode::F3, KeyType::NetworkStats);
 
         Input {
             back,
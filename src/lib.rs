@@ -2,6 +2,7 @@
 
 #[macro_use]
 pub mod time;
+pub mod animation;
 pub mod camera;
 pub mod ecs;
 pub mod error;
@@ -0,0 +1,288 @@
+// Chat between players.
+//
+// Clients send a ChatRequest to the server on the chat channel. The server
+// checks it, writes it in the log and sends a ChatLine to everybody (or only
+// to the team of the player). Everything goes through the reliable channels
+// so a message is never lost.
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::reliable::{ChannelId, ReliableContent};
+use super::NetworkSystem;
+use crate::ecs::{Entity, ECS};
+use crate::event::Event;
+
+/// Chat has its own channel so that it is not blocked by other messages.
+pub const CHAT_CHANNEL: ChannelId = 1;
+
+/// Max number of characters in a message.
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+/// A player can send at most MAX_CHAT_MESSAGES every CHAT_RATE_WINDOW.
+pub const MAX_CHAT_MESSAGES: usize = 5;
+pub const CHAT_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// From client to server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatRequest {
+    pub text: String,
+    pub team_only: bool,
+}
+
+/// From server to clients.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatLine {
+    /// None is for messages from the server itself.
+    pub from: Option<String>,
+    pub text: String,
+    pub team_only: bool,
+}
+
+/// Why a message was not sent to the other players.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChatRejection {
+    Empty,
+    TooLong,
+    TooFast,
+    Muted,
+    Filtered,
+}
+
+impl ChatRejection {
+    fn reason(&self) -> &'static str {
+        match *self {
+            ChatRejection::Empty => "Message is empty",
+            ChatRejection::TooLong => "Message is too long",
+            ChatRejection::TooFast => "You are sending messages too fast",
+            ChatRejection::Muted => "You are muted",
+            ChatRejection::Filtered => "Message was blocked",
+        }
+    }
+}
+
+/// Hook to check messages before they are sent to the players. Return
+/// None to drop the message, or the text to send (it can be modified).
+pub trait ChatFilter: Send {
+    fn filter(&mut self, from: &Entity, text: &str) -> Option<String>;
+}
+
+/// Server side of the chat.
+pub struct ChatSystem {
+    last_messages: HashMap<Entity, VecDeque<Instant>>,
+    muted: HashSet<Entity>,
+    filter: Option<Box<ChatFilter>>,
+}
+
+impl ChatSystem {
+    pub fn new() -> Self {
+        ChatSystem {
+            last_messages: HashMap::new(),
+            muted: HashSet::new(),
+            filter: None,
+        }
+    }
+
+    pub fn set_filter(&mut self, filter: Box<ChatFilter>) {
+        self.filter = Some(filter);
+    }
+
+    pub fn mute(&mut self, player: Entity) {
+        self.muted.insert(player);
+    }
+
+    pub fn unmute(&mut self, player: &Entity) {
+        self.muted.remove(player);
+    }
+
+    pub fn is_muted(&self, player: &Entity) -> bool {
+        self.muted.contains(player)
+    }
+
+    /// The players got new entities when the level changed. They stay muted.
+    pub fn replace_entities(&mut self, moved: &[(Entity, Entity)]) {
+        let mut last_messages = HashMap::new();
+        let mut muted = HashSet::new();
+        for (previous, entity) in moved {
+            if let Some(sent) = self.last_messages.remove(previous) {
+                last_messages.insert(*entity, sent);
+            }
+            if self.muted.contains(previous) {
+                muted.insert(*entity);
+            }
+        }
+        self.last_messages = last_messages;
+        self.muted = muted;
+    }
+
+    /// Look for chat requests in the network events and send them to the
+    /// other players.
+    pub fn handle_network_events(
+        &mut self,
+        ecs: &ECS,
+        events: &Vec<(Entity, Event)>,
+        network: &mut NetworkSystem,
+    ) {
+        let now = Instant::now();
+        for (entity, event) in events {
+            if let Event::PlayerDisconnected(_) = event {
+                self.last_messages.remove(entity);
+                self.muted.remove(entity);
+                continue;
+            }
+
+            if let Event::ReliableMessage(_, ReliableContent::ChatRequest(request)) = event {
+                let name = player_name(ecs, entity);
+                match self.check(entity, &request.text, now) {
+                    Ok(text) => {
+                        info!(
+                            "[chat]{} {}: {}",
+                            if request.team_only { "[team]" } else { "" },
+                            name,
+                            text
+                        );
+                        let line = ReliableContent::ChatLine(ChatLine {
+                            from: Some(name),
+                            text,
+                            team_only: request.team_only,
+                        });
+
+                        if request.team_only {
+                            for teammate in teammates(ecs, entity) {
+                                network.send_reliable(&teammate, CHAT_CHANNEL, line.clone());
+                            }
+                        } else {
+                            network.broadcast_reliable(CHAT_CHANNEL, line);
+                        }
+                    }
+                    Err(rejection) => {
+                        info!("[chat] Rejected message from {}: {:?}", name, rejection);
+                        network.send_reliable(
+                            entity,
+                            CHAT_CHANNEL,
+                            ReliableContent::ChatLine(ChatLine {
+                                from: None,
+                                text: rejection.reason().to_string(),
+                                team_only: false,
+                            }),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the text to send, or why it should not be sent.
+    fn check(&mut self, from: &Entity, text: &str, now: Instant) -> Result<String, ChatRejection> {
+        if self.muted.contains(from) {
+            return Err(ChatRejection::Muted);
+        }
+
+        let text: String = text.trim().chars().filter(|c| !c.is_control()).collect();
+        if text.is_empty() {
+            return Err(ChatRejection::Empty);
+        }
+        if text.chars().count() > MAX_CHAT_LENGTH {
+            return Err(ChatRejection::TooLong);
+        }
+
+        let last_messages = self
+            .last_messages
+            .entry(*from)
+            .or_insert_with(VecDeque::new);
+        while let Some(sent) = last_messages.front() {
+            if now.duration_since(*sent) >= CHAT_RATE_WINDOW {
+                last_messages.pop_front();
+            } else {
+                break;
+            }
+        }
+        if last_messages.len() >= MAX_CHAT_MESSAGES {
+            return Err(ChatRejection::TooFast);
+        }
+        last_messages.push_back(now);
+
+        match self.filter {
+            Some(ref mut filter) => filter.filter(from, &text).ok_or(ChatRejection::Filtered),
+            None => Ok(text),
+        }
+    }
+}
+
+fn player_name(ecs: &ECS, player: &Entity) -> String {
+    ecs.components
+        .names
+        .get(player)
+        .map(|n| n.name.clone())
+        .unwrap_or_else(|| format!("Player {}", player.index()))
+}
+
+/// All players in the same team, including the player itself.
+fn teammates(ecs: &ECS, player: &Entity) -> Vec<Entity> {
+    let team = match ecs.components.players.get(player) {
+        Some(p) => p.team,
+        None => return vec![*player],
+    };
+
+    ecs.nb_entities()
+        .into_iter()
+        .filter(|e| {
+            ecs.components
+                .players
+                .get(e)
+                .map(|p| p.team == team)
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoShouting;
+    impl ChatFilter for NoShouting {
+        fn filter(&mut self, _from: &Entity, text: &str) -> Option<String> {
+            Some(text.to_lowercase())
+        }
+    }
+
+    #[test]
+    fn check_message_test() {
+        let player = Entity::new(0, 0);
+        let mut chat = ChatSystem::new();
+        let now = Instant::now();
+
+        assert_eq!(Err(ChatRejection::Empty), chat.check(&player, "  \n", now));
+        let long: String = std::iter::repeat('a').take(MAX_CHAT_LENGTH + 1).collect();
+        assert_eq!(Err(ChatRejection::TooLong), chat.check(&player, &long, now));
+        assert_eq!(Ok("hello".to_string()), chat.check(&player, " hello ", now));
+
+        chat.set_filter(Box::new(NoShouting));
+        assert_eq!(Ok("hello".to_string()), chat.check(&player, "HELLO", now));
+
+        chat.mute(player);
+        assert_eq!(Err(ChatRejection::Muted), chat.check(&player, "hello", now));
+        chat.unmute(&player);
+        assert_eq!(Ok("hi".to_string()), chat.check(&player, "hi", now));
+    }
+
+    #[test]
+    fn rate_limit_test() {
+        let player = Entity::new(0, 0);
+        let mut chat = ChatSystem::new();
+        let now = Instant::now();
+
+        for _ in 0..MAX_CHAT_MESSAGES {
+            assert!(chat.check(&player, "spam", now).is_ok());
+        }
+        assert_eq!(
+            Err(ChatRejection::TooFast),
+            chat.check(&player, "spam", now)
+        );
+
+        // Can talk again after the window.
+        assert!(chat.check(&player, "spam", now + CHAT_RATE_WINDOW).is_ok());
+    }
+}
@@ -1,278 +1,876 @@
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use cgmath::Vector3;
-use futures::sync::mpsc as futmpsc;
 use log::{debug, error, info, trace, warn};
-use std::io;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::thread;
-use tokio::net::{UdpFramed, UdpSocket};
-use tokio::prelude::stream::{SplitSink, SplitStream};
-use tokio::prelude::*;
-use tokio_codec::BytesCodec;
-
+use std::time::Instant;
+
+use super::conditioner::{LinkConditioner, LinkConditions};
+use super::crypto::{self, ConnectToken, Session};
+use super::demo::{DemoError, DemoRecorder};
+use super::fragment::{DEFAULT_MTU, MAX_MTU};
+use super::interpolation::{InterpolationBuffer, InterpolationSettings};
+use super::level::{Level, LevelInfo};
+use super::packing::{decode_snapshot, AssetNames, Quantization};
+use super::prediction::Predictor;
 use super::protocol;
-use super::protocol::Packet;
+use super::protocol::{DisconnectReason, MessageKind, Packet, RefuseReason};
+use super::relevancy::DistanceRelevancy;
+use super::reliable::{ChannelId, ReliableChannels, ReliableContent, DEFAULT_CHANNEL};
+use super::sequence::is_newer;
+use super::stats::{NetworkStats, StatsTracker};
+use super::transport::{any_local_addr, Transport, UdpTransport};
 
-use std::sync::mpsc as stdmpsc;
 use std::time::Duration;
 
-use super::NetworkError;
-use crate::ecs::ECS;
-use crate::net::snapshot::{apply_delta, DeltaSnapshot};
+use super::{NetworkError, TimeoutSettings};
+use crate::ecs::{components::TransformComponent, Entity, ECS};
+use crate::event::Event;
+use crate::net::replication::FieldValue;
+use crate::net::snapshot::{apply_delta, compute_delta, DeltaSnapshot};
 use crate::scene::ClientCommand;
-use crate::sync::SharedDeque;
 
 const NB_TRY: u32 = 10;
 
-/// Connect to the remote server and returns interfaces to send and receive
-/// messages
-///
-/// As opposed to the server, we don't need to attach the SocketAddr that
-/// sent the message and we don't need to specify the SocketAddr when sending
-/// because this is known at start time.
-pub fn start_connecting(
-    server_addr: SocketAddr,
-) -> Result<(SharedDeque<Packet>, stdmpsc::Sender<Packet>), Box<std::error::Error>> {
-    info!("Start connecting to {}", server_addr);
-    // interfaces
-    let net_to_game = SharedDeque::new(1024);
-    let mut net_to_game_clone = net_to_game.clone();
-    let (int_tx, int_rx) = futmpsc::channel(1024);
-    let (tx, rx) = stdmpsc::channel();
-    let int_rx = int_rx.map_err(|_| panic!("Error not possible on rx"));
-
-    thread::spawn(move || read_channel(int_tx, rx));
-
-    let async_stuff = connect(server_addr, Box::new(int_rx))?;
-    thread::spawn(move || {
-        tokio::run(
-            async_stuff
-                .for_each(move |buf| {
-                    match protocol::deserialize(buf.into()) {
-                        Ok(unpacked) => {
-                            net_to_game_clone.push(unpacked);
-                        }
-                        Err(e) => {
-                            error!(
-                                "Received malformed message from {}, error = {:?}",
-                                server_addr, e
-                            );
-                        }
-                    }
-
-                    Ok(())
-                })
-                .map_err(|e| error!("{:?}", e)),
-        );
-    });
+/// A connection request or challenge response is sent again after that.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the answer of the server is checked when connecting.
+const CONNECTION_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Where the client is in the handshake.
+#[derive(Debug)]
+enum ConnectionState {
+    Connecting {
+        /// Private data of the connect token, sent in the requests.
+        private_token: Option<Vec<u8>>,
+        challenge: Option<u64>,
+        nb_try: u32,
+        last_try: Option<Instant>,
+    },
+    Connected,
+    Refused(RefuseReason),
+    /// The server accepted us with settings we cannot use.
+    InvalidServer(String),
+    TimedOut,
+}
 
-    Ok((net_to_game, tx))
+/// Where the client is with the level of the server.
+#[derive(Debug)]
+enum LevelState {
+    /// The server did not say which one yet.
+    Unknown,
+    /// Asked by the server, loaded at the next poll.
+    Loading(LevelInfo),
+    /// Loaded, and the server was told. `playing` is set once the first
+    /// snapshot of the level is applied.
+    Ready { info: LevelInfo, playing: bool },
 }
 
-/// Will create the futures that will run in tokio runtime.
-fn connect(
+/// The actual game system that will be running in the main loop
+pub struct ClientSystem {
+    /// Datagrams to and from the server. Those from other addresses are
+    /// dropped.
+    transport: Box<Transport>,
     server_addr: SocketAddr,
-    game_to_net: Box<Stream<Item = Bytes, Error = io::Error> + Send>,
-) -> Result<Box<Stream<Item = BytesMut, Error = io::Error> + Send>, Box<std::error::Error>> {
-    let addr = "127.0.0.1:0".parse()?;
-    let socket = UdpSocket::bind(&addr)?;
-
-    let (sink, stream) = UdpFramed::new(socket, BytesCodec::new()).split();
-
-    // All bytes from `game_to_net` will go to the `addr` specified in our
-    // argument list. Like with TCP this is spawned concurrently
-    let forward = game_to_net
-        .map(move |chunk| (chunk, server_addr))
-        .forward(sink)
-        .then(|result| {
-            if let Err(e) = result {
-                error!("failed to write to socket: {}", e)
-            }
-            Ok(())
-        });
 
-    // TODO Also filter packet out of order
-    let receive = stream.filter_map(move |(chunk, src)| {
-        if src == server_addr {
-            Some(chunk)
-        } else {
-            None
-        }
-    });
+    /// Simulated network conditions, between the game and the queues.
+    incoming_link: LinkConditioner<Bytes>,
+    outgoing_link: LinkConditioner<Bytes>,
 
-    let stream = Box::new(
-        future::lazy(|| {
-            tokio::spawn(forward);
-            future::ok(receive)
-        })
-        .flatten_stream(),
-    );
+    /// Keys of the connect token. None if the server does not need one.
+    session: Option<Session>,
 
-    Ok(stream)
-}
+    connection: ConnectionState,
 
-fn read_channel(mut tx: futmpsc::Sender<Bytes>, rx: stdmpsc::Receiver<Packet>) {
-    loop {
-        match rx.recv() {
-            Ok(d) => {
-                // if cannot serialize here, we have a problem...
-
-                let packed = protocol::serialize(d)
-                    .map_err(|e| {
-                        error!("Error when unpacking in `read_channel` = {:?}", e);
-                        e
-                    })
-                    .unwrap();
-
-                tx = match tx.send(packed).wait() {
-                    Ok(tx) => tx,
-                    Err(e) => {
-                        error!("Error in read_channel = {:?}", e);
-                        break;
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Error on read channel = {:?}", e);
-                break;
-            }
-        }
-    }
-}
+    last_sent_seq_number: u32,
+    last_rec_seq_number: u32,
+    last_known_state: Option<u32>,
 
-/// The actual game system that will be running in the main loop
-pub struct ClientSystem {
-    /// Messages incoming from the server.
-    from_server: SharedDeque<Packet>,
+    /// To decode the snapshots. Given by the server.
+    quantization: Quantization,
+    asset_names: AssetNames,
 
-    /// Queue to send to server
-    to_server: stdmpsc::Sender<Packet>,
+    /// Parts received of a delta that was split, for its new state.
+    delta_parts: Option<(u32, Vec<Option<DeltaSnapshot>>)>,
 
-    last_sent_seq_number: u32,
-    last_rec_seq_number: u32,
-    last_known_state: Option<u8>,
+    /// Reliable messages to and from the server.
+    reliable: ReliableChannels,
+
+    /// Predicted position of the player.
+    predictor: Predictor,
+
+    /// State as sent by the server. The ECS of the scene displays the remote
+    /// entities a bit in the past, interpolated from the last states.
+    server_ecs: ECS,
+    interpolation: InterpolationBuffer,
+
+    /// Entity of the player, known with the first delta.
+    player_entity: Option<Entity>,
+
+    /// The level the server asked for, read from `levels_dir`.
+    level: LevelState,
+    levels_dir: PathBuf,
+
+    stats: StatsTracker,
+
+    /// State and size of the parts of a snapshot received so far.
+    snapshot_bytes: (u32, usize),
+
+    /// Where the received deltas are written, if a demo is recorded.
+    demo: Option<DemoRecorder<BufWriter<File>>>,
+
+    timeouts: TimeoutSettings,
+    last_heard: Instant,
+    last_sent: Instant,
+
+    /// Set when the connection is over. Nothing is sent or received after
+    /// that.
+    disconnected: Option<DisconnectReason>,
 }
 
 impl ClientSystem {
-    pub fn connect(addr: SocketAddr) -> Result<Self, NetworkError> {
-        let (mut from_server, to_server) = start_connecting(addr).map_err(|e| {
-            // meh, need to find a better way to extract tokio errors
-            error!("{:?}", e);
-            NetworkError::CannotConnectToServer
-        })?;
-
-        let mut sent_seq_number = 0;
-        // Connection to server. Try to send message every seconds until it receives
-        // a connection accepted or a connection refused.
+    /// The connect token is needed if the server has a connect key. Then
+    /// all the packets are encrypted with its keys.
+    ///
+    /// Blocks until the server answers. See `start` to connect without
+    /// blocking.
+    pub fn connect(addr: SocketAddr, token: Option<ConnectToken>) -> Result<Self, NetworkError> {
+        let mut client = ClientSystem::start(addr, token)?;
         info!("Will connect to the game server");
-        let is_connected: bool = {
-            let mut try_nb = 0u32;
-            let mut res = false;
-            'connection: loop {
-                if try_nb >= NB_TRY {
-                    info!("Timed out during connection to server");
-                    break 'connection;
-                }
+        while !client.poll_connection()? {
+            thread::sleep(CONNECTION_POLL_INTERVAL);
+        }
+        Ok(client)
+    }
 
-                if let Err(e) = to_server.send(Packet {
-                    content: protocol::NetMessageContent::ConnectionRequest,
-                    seq_number: sent_seq_number,
-                    last_known_state: None,
-                }) {
-                    error!("{:?}", e);
+    /// Open a UDP socket on any port. The handshake is done by
+    /// `poll_connection`, and by `poll_events` until the client is
+    /// connected.
+    pub fn start(addr: SocketAddr, token: Option<ConnectToken>) -> Result<Self, NetworkError> {
+        info!("Start connecting to {}", addr);
+        // The MTU of the server is in its configuration.
+        let transport =
+            UdpTransport::bind(any_local_addr(addr), DEFAULT_MTU, MAX_MTU).map_err(|e| {
+                error!("{}", e);
+                NetworkError::CannotConnectToServer
+            })?;
+        Ok(ClientSystem::with_transport(
+            Box::new(transport),
+            addr,
+            token,
+        ))
+    }
+
+    /// Reach the server at `addr` through another transport, e.g. a
+    /// loopback to a server of the same process. Does not block, like
+    /// `start`.
+    pub fn with_transport(
+        transport: Box<Transport>,
+        addr: SocketAddr,
+        token: Option<ConnectToken>,
+    ) -> Self {
+        crypto::init();
+        let session = token.as_ref().map(ConnectToken::client_session);
+        let private_token = token.map(|t| t.private_data);
+
+        Self {
+            transport,
+            server_addr: addr,
+            incoming_link: LinkConditioner::default(),
+            outgoing_link: LinkConditioner::default(),
+            session,
+            connection: ConnectionState::Connecting {
+                private_token,
+                challenge: None,
+                nb_try: 0,
+                last_try: None,
+            },
+            last_sent_seq_number: 0,
+            last_rec_seq_number: 0,
+            last_known_state: None,
+            quantization: Quantization::default(),
+            asset_names: AssetNames::new(),
+            delta_parts: None,
+            reliable: ReliableChannels::default(),
+            predictor: Predictor::new(),
+            server_ecs: ECS::new(),
+            interpolation: InterpolationBuffer::new(InterpolationSettings::default()),
+            player_entity: None,
+            level: LevelState::Unknown,
+            levels_dir: PathBuf::from("."),
+            stats: StatsTracker::new(Instant::now()),
+            snapshot_bytes: (0, 0),
+            demo: None,
+            timeouts: TimeoutSettings::default(),
+            last_heard: Instant::now(),
+            last_sent: Instant::now(),
+            disconnected: None,
+        }
+    }
+
+    /// Go on with the handshake. Returns true once the server accepted us.
+    ///
+    /// A connection request or challenge response is sent every second
+    /// until the server accepts or refuses. The server first answers with a
+    /// challenge, that is sent back instead of the request.
+    pub fn poll_connection(&mut self) -> Result<bool, NetworkError> {
+        let now = Instant::now();
+        let (private_token, challenge, nb_try, last_try) = match self.connection {
+            ConnectionState::Connected => return Ok(true),
+            ConnectionState::Refused(reason) => {
+                return Err(NetworkError::ConnectionRefused(reason))
+            }
+            ConnectionState::InvalidServer(ref e) => {
+                return Err(NetworkError::InvalidServerInfo(e.clone()))
+            }
+            ConnectionState::TimedOut => return Err(NetworkError::CannotConnectToServer),
+            ConnectionState::Connecting {
+                ref private_token,
+                ref mut challenge,
+                ref mut nb_try,
+                ref mut last_try,
+            } => (private_token, challenge, nb_try, last_try),
+        };
+
+        // ok we might lose some packets here. It's alright, the server is
+        // sending state every loop and if message needs to be reliably sent,
+        // the server will resend it.
+        let session = &mut self.session;
+        let packets: Vec<_> = receive(&mut *self.transport, self.server_addr)
+            .into_iter()
+            .filter_map(|bytes| unpack(session, bytes))
+            .collect();
+        let mut answer_now = false;
+        for packet in packets {
+            match packet.content {
+                protocol::NetMessageContent::Challenge(token) if *challenge != Some(token) => {
+                    debug!("Received challenge from the server");
+                    *challenge = Some(token);
+                    answer_now = true;
                 }
-                sent_seq_number += 1;
-
-                thread::sleep(Duration::from_secs(1));
-                let evs = from_server.drain();
-                // ok we might lose some events here. It's alright, the server
-                // is sending state every loop and if message needs to be reliably sent,
-                // the server will resend it.
-                for ev in evs {
-                    match ev.content {
-                        protocol::NetMessageContent::ConnectionAccepted => {
-                            res = true;
-                            break 'connection;
-                        }
-                        protocol::NetMessageContent::ConnectionRefused => {
-                            info!("Received connection refused");
-                            break 'connection;
-                        }
-                        _ => error!("Received {:?} when connecting. That is strange", ev),
+                protocol::NetMessageContent::Challenge(_) => (),
+                protocol::NetMessageContent::ConnectionAccepted(info) => {
+                    // Bad bounds or bits would make NaN positions or
+                    // overflow when decoding the snapshots.
+                    if let Err(e) = info.quantization.validate() {
+                        error!("Server sent an invalid quantization: {}", e);
+                        // Free the slot the server gave us.
+                        self.send_to_server(protocol::NetMessageContent::Disconnect(
+                            DisconnectReason::Quit,
+                        ));
+                        self.connection = ConnectionState::InvalidServer(e.clone());
+                        return Err(NetworkError::InvalidServerInfo(e));
                     }
+                    info!("Connected to the game server");
+                    self.quantization = info.quantization;
+                    self.last_rec_seq_number = packet.seq_number;
+                    self.last_heard = now;
+                    self.connection = ConnectionState::Connected;
+                    return Ok(true);
+                }
+                protocol::NetMessageContent::ConnectionRefused(reason) => {
+                    info!("Received connection refused: {}", reason);
+                    self.connection = ConnectionState::Refused(reason);
+                    return Err(NetworkError::ConnectionRefused(reason));
                 }
+                _ => error!("Received {:?} when connecting. That is strange", packet),
+            }
+        }
 
-                try_nb += 1;
+        let retry = last_try.map_or(true, |t| now.duration_since(t) >= RETRY_INTERVAL);
+        if answer_now || retry {
+            if *nb_try >= NB_TRY {
+                info!("Timed out during connection to server");
+                self.connection = ConnectionState::TimedOut;
+                return Err(NetworkError::CannotConnectToServer);
             }
 
-            res
-        };
+            let content = match *challenge {
+                Some(token) => protocol::NetMessageContent::ChallengeResponse(token),
+                None => protocol::NetMessageContent::ConnectionRequest(private_token.clone()),
+            };
+            let packet = Packet::new(self.last_sent_seq_number, None, content);
+            if let Some(bytes) = pack(&mut self.session, packet) {
+                if let Err(e) = self.transport.send(bytes, self.server_addr) {
+                    error!("{:?}", e);
+                }
+            }
+            self.last_sent_seq_number = self.last_sent_seq_number.wrapping_add(1);
+            *nb_try += 1;
+            *last_try = Some(now);
+        }
 
-        if is_connected {
-            Ok(Self {
-                to_server,
-                from_server,
-                last_sent_seq_number: sent_seq_number,
-                last_rec_seq_number: 0,
-                last_known_state: None,
-            })
-        } else {
-            Err(NetworkError::CannotConnectToServer)
+        Ok(false)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match self.connection {
+            ConnectionState::Connected => true,
+            _ => false,
         }
     }
 
-    /// Will get the latest events that were sent from the server
-    pub fn poll_events(&mut self, ecs: &mut ECS) {
-        let events = self.from_server.drain();
+    /// Will get the latest events that were sent from the server. The state is
+    /// applied directly to the ECS. Reliable messages are returned as events.
+    pub fn poll_events(&mut self, ecs: &mut ECS) -> Vec<Event> {
+        if !self.is_connected() {
+            if let Err(e) = self.poll_connection() {
+                debug!("Not connected: {}", e);
+            }
+            return Vec::new();
+        }
+
+        // Asked during the last poll, so that a loading screen could be
+        // drawn in between.
+        if let (LevelState::Loading(_), None) = (&self.level, self.disconnected) {
+            self.load_level(ecs);
+        }
+
+        let now = Instant::now();
+        for datagram in receive(&mut *self.transport, self.server_addr) {
+            self.incoming_link.push(datagram, now);
+        }
+        let session = &mut self.session;
+        let stats = &mut self.stats;
+        let events: Vec<_> = self
+            .incoming_link
+            .pop_ready(now)
+            .into_iter()
+            .filter_map(|bytes| {
+                let size = bytes.len();
+                let packet = unpack(session, bytes)?;
+                stats.on_received(packet.seq_number, size, now);
+                Some(packet)
+            })
+            .collect();
+        self.send_ready(now);
+        self.stats.update(now);
+
+        let mut reliable_events = Vec::new();
+        if self.disconnected.is_some() {
+            return reliable_events;
+        }
 
         for ev in events {
-            if self.last_rec_seq_number >= ev.seq_number {
+            if !is_newer(ev.seq_number, self.last_rec_seq_number) {
+                self.stats.on_out_of_order();
                 error!(
                     "Received packet out of order: last_rec_seq_number {} > packet.seq_number {}",
                     self.last_rec_seq_number, ev.seq_number
                 );
             } else {
                 self.last_rec_seq_number = ev.seq_number;
+                self.last_heard = Instant::now();
+
+                for (channel, content) in self.reliable.receive(ev.reliable, &ev.acks) {
+                    match content {
+                        ReliableContent::AssetName { id, name } => {
+                            self.asset_names.insert(id, name)
+                        }
+                        ReliableContent::LoadLevel(info) => {
+                            info!("Server asks to load level {:?}", info.name);
+                            self.level = LevelState::Loading(info);
+                        }
+                        content => reliable_events.push(Event::ReliableMessage(channel, content)),
+                    }
+                }
+
+                if let protocol::NetMessageContent::Disconnect(reason) = ev.content {
+                    info!("Disconnected by the server: {}", reason);
+                    self.disconnected = Some(reason);
+                    return reliable_events;
+                }
 
                 if let protocol::NetMessageContent::Delta(snapshot) = ev.content {
+                    // Based on a level we do not have yet.
+                    if self.level().is_none() {
+                        continue;
+                    }
+
+                    // The server knows that state, so our acknowledgement
+                    // went through.
+                    if let Some(old_state) = snapshot.old_state {
+                        self.stats.probe_acked(old_state, now);
+                    }
                     if self.last_known_state == snapshot.old_state {
-                        debug!("Client received delta: {:?}", snapshot);
+                        let size = snapshot.delta.0.len();
+                        self.snapshot_bytes = match self.snapshot_bytes {
+                            (state, bytes) if state == snapshot.new_state => (state, bytes + size),
+                            _ => (snapshot.new_state, size),
+                        };
+
+                        // If it cannot be decoded, the state is not acknowledged
+                        // so the server will send it again.
+                        let delta = match decode_snapshot(
+                            &snapshot.delta,
+                            &self.quantization,
+                            &self.asset_names,
+                        ) {
+                            Ok(delta) => delta,
+                            Err(e) => {
+                                warn!("Cannot decode snapshot {}: {}", snapshot.new_state, e);
+                                continue;
+                            }
+                        };
+                        let delta = match self.add_delta_part(
+                            snapshot.new_state,
+                            snapshot.part,
+                            snapshot.nb_parts,
+                            delta,
+                        ) {
+                            Some(delta) => delta,
+                            None => continue,
+                        };
+
+                        debug!("Client received delta: {:?}", delta);
+                        self.stats.on_snapshot(self.snapshot_bytes.1);
                         self.last_known_state = Some(snapshot.new_state);
-                        move_camera(ecs, &snapshot.delta);
-                        apply_delta(ecs, snapshot.delta);
+                        let position = delta
+                            .player_delta
+                            .field::<TransformComponent>("position")
+                            .and_then(FieldValue::as_vec3);
+                        self.predictor.reconcile(position, snapshot.last_input);
+                        self.player_entity = Some(delta.player_delta.entity);
+                        self.record_delta(&delta);
+                        apply_delta(&mut self.server_ecs, delta.clone());
+                        apply_delta(ecs, delta);
+                        if let LevelState::Ready {
+                            ref mut playing, ..
+                        } = self.level
+                        {
+                            *playing = true;
+                        }
+                        self.interpolation.push(
+                            snapshot.server_time,
+                            server_transforms(&self.server_ecs),
+                            Instant::now(),
+                        );
                     }
                 }
             }
         }
+
+        // The deltas were applied on interpolated transforms, so replace
+        // them in any case. Our player is not in the past but predicted.
+        for (entity, transform) in self.interpolation.interpolated(Instant::now()) {
+            if Some(entity) == self.player_entity {
+                continue;
+            }
+            if let Some(t) = ecs.components.transforms.get_mut(&entity) {
+                *t = transform;
+            }
+        }
+        self.apply_prediction(ecs);
+
+        if self.last_heard.elapsed() > self.timeouts.timeout {
+            info!("Server did not answer for {:?}", self.timeouts.timeout);
+            self.disconnected = Some(DisconnectReason::Timeout);
+        } else if self.last_sent.elapsed() >= self.timeouts.heartbeat {
+            self.send_to_server(protocol::NetMessageContent::Ping);
+        }
+
+        reliable_events
     }
 
-    pub fn send_commands(&mut self, commands: &Vec<ClientCommand>) {
-        for cmd in commands.iter() {
-            self.send_to_server(protocol::NetMessageContent::Command(*cmd));
+    /// Load the level asked by the server, check that it is the same as the
+    /// server's and tell the server. The states of the previous level are
+    /// forgotten: the next snapshot starts from the level.
+    fn load_level(&mut self, ecs: &mut ECS) {
+        let info = match self.level {
+            LevelState::Loading(ref info) => info.clone(),
+            _ => return,
+        };
+        let loaded = Level::load(&self.levels_dir, &info.name)
+            .and_then(|level| level.check(&info).map(|_| level));
+        let level = match loaded {
+            Ok(level) => level,
+            Err(e) => {
+                error!("Cannot load level {:?}: {}", info.name, e);
+                self.leave(DisconnectReason::LevelMismatch);
+                return;
+            }
+        };
+        info!("Loaded level {:?} ({:016x})", info.name, level.hash);
+
+        // The demo goes on with the new level: everything is removed, then
+        // the entities of the level are added.
+        if self.demo.is_some() {
+            let any_entity = self.player_entity.unwrap_or_else(|| Entity::new(0, 0));
+            let mut delta = compute_delta(
+                &ECS::new(),
+                level.ecs(),
+                &any_entity,
+                &DistanceRelevancy::new(None),
+            );
+            delta.entities_to_delete = self.server_ecs.nb_entities();
+            self.record_delta(&delta);
         }
 
-        if commands.is_empty() {
+        self.server_ecs = level.new_ecs();
+        ecs.replace(level.new_ecs());
+        self.last_known_state = None;
+        self.delta_parts = None;
+        self.player_entity = None;
+        self.interpolation = InterpolationBuffer::new(*self.interpolation.settings());
+
+        self.reliable.send(
+            DEFAULT_CHANNEL,
+            ReliableContent::LevelReady {
+                id: info.id,
+                hash: level.hash,
+            },
+        );
+        self.level = LevelState::Ready {
+            info,
+            playing: false,
+        };
+    }
+
+    /// The parts of a split delta are kept until they are all there. Returns
+    /// the whole delta when it is complete.
+    fn add_delta_part(
+        &mut self,
+        new_state: u32,
+        part: u8,
+        nb_parts: u8,
+        delta: DeltaSnapshot,
+    ) -> Option<DeltaSnapshot> {
+        if nb_parts <= 1 {
+            return Some(delta);
+        }
+        if part >= nb_parts {
+            warn!("Received part {} of a delta in {} parts", part, nb_parts);
+            return None;
+        }
+
+        let mut parts = match self.delta_parts.take() {
+            Some((state, parts)) if state == new_state && parts.len() == nb_parts as usize => parts,
+            _ => vec![None; nb_parts as usize],
+        };
+        parts[part as usize] = Some(delta);
+
+        if parts.iter().all(Option::is_some) {
+            DeltaSnapshot::merge(parts.into_iter().map(Option::unwrap).collect())
+        } else {
+            self.delta_parts = Some((new_state, parts));
+            None
+        }
+    }
+
+    /// Write the deltas received from now on to a demo. It starts with the
+    /// state known so far, so it can be started at any time.
+    pub fn record_demo<P: AsRef<Path>>(&mut self, path: P) -> Result<(), DemoError> {
+        self.stop_demo();
+        let mut recorder = DemoRecorder::create(path, Instant::now())?;
+        if let Some(player) = self.player_entity {
+            let full_state = compute_delta(
+                &ECS::new(),
+                &self.server_ecs,
+                &player,
+                &DistanceRelevancy::new(None),
+            );
+            recorder.record(&full_state, Instant::now())?;
+        }
+        self.demo = Some(recorder);
+        Ok(())
+    }
+
+    pub fn stop_demo(&mut self) {
+        if let Some(recorder) = self.demo.take() {
+            let nb_frames = recorder.nb_frames();
+            match recorder.finish() {
+                Ok(_) => info!("Recorded demo of {} frames", nb_frames),
+                Err(e) => error!("Cannot finish demo: {}", e),
+            }
+        }
+    }
+
+    pub fn is_recording_demo(&self) -> bool {
+        self.demo.is_some()
+    }
+
+    fn record_delta(&mut self, delta: &DeltaSnapshot) {
+        let result = match self.demo {
+            Some(ref mut recorder) => recorder.record(delta, Instant::now()),
+            None => return,
+        };
+        if let Err(e) = result {
+            error!("Stop recording demo: {}", e);
+            self.demo = None;
+        }
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: TimeoutSettings) {
+        self.timeouts = timeouts;
+    }
+
+    /// Why the connection is over, if it is.
+    pub fn disconnected(&self) -> Option<DisconnectReason> {
+        self.disconnected
+    }
+
+    /// Tell the server we are leaving. Also done when the system is dropped.
+    pub fn disconnect(&mut self) {
+        self.leave(DisconnectReason::Quit);
+    }
+
+    fn leave(&mut self, reason: DisconnectReason) {
+        if self.disconnected.is_none() && self.is_connected() {
+            self.send_to_server(protocol::NetMessageContent::Disconnect(reason));
+            self.disconnected = Some(reason);
+
+            // Nobody will poll anymore, so do not wait for the simulated
+            // latency.
+            for datagram in self.outgoing_link.flush() {
+                if let Err(e) = self.transport.send(datagram, self.server_addr) {
+                    error!("{:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Simulate a bad network on the packets sent and received. Applied in
+    /// both directions. The seed makes the losses reproducible.
+    pub fn set_link_conditions(&mut self, conditions: LinkConditions, seed: u64) {
+        self.incoming_link = LinkConditioner::new(conditions, seed);
+        self.outgoing_link = LinkConditioner::new(conditions, seed.wrapping_add(1));
+    }
+
+    /// Change the simulated network without resetting the random numbers.
+    pub fn update_link_conditions(&mut self, conditions: LinkConditions) {
+        self.incoming_link.set_conditions(conditions);
+        self.outgoing_link.set_conditions(conditions);
+    }
+
+    pub fn link_conditions(&self) -> LinkConditions {
+        self.outgoing_link.conditions()
+    }
+
+    pub fn set_interpolation_settings(&mut self, settings: InterpolationSettings) {
+        self.interpolation.set_settings(settings);
+    }
+
+    /// Send a message that will arrive, in order with the other messages of
+    /// the channel. It is sent with the next packets to the server.
+    pub fn send_reliable(&mut self, channel: ChannelId, content: ReliableContent) {
+        self.reliable.send(channel, content);
+    }
+
+    /// Send the commands of this frame. They are applied locally right away
+    /// so the player does not wait for the server.
+    pub fn send_commands(&mut self, commands: &Vec<ClientCommand>, dt: Duration) {
+        if self.disconnected.is_some() || !self.is_connected() {
+            return;
+        }
+
+        // The player is not in the level yet. The packets still carry the
+        // reliable messages and the acknowledgements.
+        if self.is_loading() {
             self.send_to_server(protocol::NetMessageContent::Ping);
+            return;
+        }
+
+        if !commands.is_empty() {
+            self.predictor.add_input(dt, commands.clone());
+        }
+        self.predictor.update(dt);
+
+        if self.predictor.nb_pending() > 0 {
+            let inputs = self.predictor.inputs_to_send();
+            self.send_to_server(protocol::NetMessageContent::Input(inputs));
+        } else {
+            self.send_to_server(protocol::NetMessageContent::Ping);
+        }
+    }
+
+    /// Statistics of the connection with the server.
+    pub fn stats(&self) -> NetworkStats {
+        self.stats.stats()
+    }
+
+    /// Where the levels asked by the server are read. The current directory
+    /// by default.
+    pub fn set_levels_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.levels_dir = dir.into();
+    }
+
+    /// The level of the server, once it is loaded.
+    pub fn level(&self) -> Option<&LevelInfo> {
+        match self.level {
+            LevelState::Ready { ref info, .. } => Some(info),
+            _ => None,
+        }
+    }
+
+    /// True until the level of the server is loaded and its first snapshot
+    /// applied.
+    pub fn is_loading(&self) -> bool {
+        match self.level {
+            LevelState::Ready { playing, .. } => !playing,
+            _ => true,
+        }
+    }
+
+    /// Entity of the player, known once a snapshot was received.
+    pub fn player_entity(&self) -> Option<Entity> {
+        self.player_entity
+    }
+
+    /// Where the player is, with the inputs that the server has not
+    /// processed yet.
+    pub fn player_position(&self) -> Vector3<f32> {
+        self.predictor.position()
+    }
+
+    /// Move the entity of the player to the predicted position, so that it
+    /// is drawn where the camera is. Call it after `send_commands`.
+    pub fn apply_prediction(&self, ecs: &mut ECS) {
+        let transform = self
+            .player_entity
+            .and_then(|entity| ecs.components.transforms.get_mut(&entity));
+        if let Some(transform) = transform {
+            transform.position = self.predictor.position();
         }
     }
 
     fn send_to_server(&mut self, content: protocol::NetMessageContent) {
-        if let Err(e) = self.to_server.send(Packet {
+        let now = Instant::now();
+        let (reliable, acks) = self.reliable.outgoing(now);
+        let packet = Packet {
             content,
             seq_number: self.last_sent_seq_number,
             last_known_state: self.last_known_state,
-        }) {
-            error!("{:?}", e);
+            reliable,
+            acks,
+        };
+        if let Some(bytes) = pack(&mut self.session, packet) {
+            self.stats.on_sent(bytes.len(), now);
+            self.outgoing_link.push(bytes, now);
+        }
+        if let Some(state) = self.last_known_state {
+            self.stats.probe_sent(state, now);
+        }
+        self.send_ready(now);
+        self.last_sent_seq_number = self.last_sent_seq_number.wrapping_add(1);
+        self.last_sent = now;
+    }
+
+    /// Hand the datagrams that went through the link conditioner to the
+    /// transport.
+    fn send_ready(&mut self, now: Instant) {
+        for datagram in self.outgoing_link.pop_ready(now) {
+            if let Err(e) = self.transport.send(datagram, self.server_addr) {
+                error!("{:?}", e);
+            }
         }
-        self.last_sent_seq_number += 1;
     }
 }
 
-/// Camera is basically the player position :)
-fn move_camera(ecs: &mut ECS, delta: &DeltaSnapshot) {
-    // We have some state to apply yay!
-    if let Some(position_delta) = delta.player_delta.delta_transform.0 {
-        ecs.camera.state.transform.position +=
-            Vector3::new(position_delta[0], position_delta[1], position_delta[2]);
+/// Datagrams received from the server. Anybody can send to our port.
+fn receive(transport: &mut Transport, server_addr: SocketAddr) -> Vec<Bytes> {
+    transport
+        .receive()
+        .into_iter()
+        .filter_map(|(bytes, from)| {
+            if from == server_addr {
+                Some(bytes)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Serialize a packet for the server, and encrypt it if there is a session.
+fn pack(session: &mut Option<Session>, packet: Packet) -> Option<Bytes> {
+    match protocol::serialize(packet) {
+        Ok(bytes) => Some(match session {
+            Some(session) => session.encrypt_if_needed(bytes),
+            None => bytes,
+        }),
+        Err(e) => {
+            error!("Cannot serialize packet = {:?}", e);
+            None
+        }
+    }
+}
+
+/// Decrypt and deserialize a datagram of the server. With a session, only
+/// refusals can be in clear.
+fn unpack(session: &mut Option<Session>, bytes: Bytes) -> Option<Packet> {
+    let (version, kind) = match protocol::read_header(&bytes) {
+        Ok(header) => header,
+        Err(e) => {
+            error!(
+                "Received malformed message from the server, error = {:?}",
+                e
+            );
+            return None;
+        }
+    };
+
+    // The server cannot read us, and we cannot read the refusal. Only
+    // the header is known.
+    if version != protocol::PROTOCOL_VERSION {
+        if kind == MessageKind::ConnectionRefused {
+            return Some(Packet::new(
+                0,
+                None,
+                protocol::NetMessageContent::ConnectionRefused(RefuseReason::VersionMismatch {
+                    server: version,
+                    client: protocol::PROTOCOL_VERSION,
+                }),
+            ));
+        }
+        error!("Received {:?} with protocol version {}", kind, version);
+        return None;
     }
+
+    let bytes = match session {
+        Some(session) if !crypto::is_plaintext(kind) => match session.decrypt(&bytes) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Drop {:?} from the server: {}", kind, e);
+                return None;
+            }
+        },
+        _ => bytes,
+    };
+
+    match protocol::deserialize(bytes) {
+        Ok(packet) => Some(packet),
+        Err(e) => {
+            error!(
+                "Received malformed message from the server, error = {:?}",
+                e
+            );
+            None
+        }
+    }
+}
+
+impl Drop for ClientSystem {
+    fn drop(&mut self) {
+        self.disconnect();
+        self.stop_demo();
+    }
+}
+
+fn server_transforms(server_ecs: &ECS) -> HashMap<Entity, TransformComponent> {
+    server_ecs
+        .nb_entities()
+        .into_iter()
+        .filter_map(|e| {
+            server_ecs
+                .components
+                .transforms
+                .get(&e)
+                .map(|t| (e, t.clone()))
+        })
+        .collect()
 }
@@ -0,0 +1,292 @@
+// Link conditioner, to test the netcode with a bad network on one machine.
+//
+// Packets go through it between the game and the transport. Each packet can
+// be lost, duplicated, delayed or delivered after the packets that were sent
+// after it. The random numbers come from a seeded generator so that a run
+// can be replayed with the same losses.
+//
+// Packets are released when the game polls the network, so the delays are
+// rounded up to the next frame.
+use serde_derive::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// What the conditioner does to the packets. The default is a perfect link.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LinkConditions {
+    /// Delay added to every packet, in milliseconds.
+    pub latency_ms: u32,
+
+    /// Random delay between 0 and that added on top of the latency. Jitter
+    /// alone does not reorder the packets.
+    pub jitter_ms: u32,
+
+    /// Probability that a packet is dropped, between 0 and 1.
+    pub loss: f32,
+
+    /// Probability that a packet is delivered twice.
+    pub duplication: f32,
+
+    /// Probability that a packet is held back and delivered after the
+    /// following ones.
+    pub reordering: f32,
+}
+
+impl LinkConditions {
+    /// Nothing is changed on the packets.
+    pub fn is_perfect(&self) -> bool {
+        *self == LinkConditions::default()
+    }
+
+    /// Probabilities should be between 0 and 1.
+    pub fn validate(&self) -> Result<(), String> {
+        let probabilities = [
+            ("loss", self.loss),
+            ("duplication", self.duplication),
+            ("reordering", self.reordering),
+        ];
+        for (name, p) in probabilities.iter() {
+            if !(*p >= 0.0 && *p <= 1.0) {
+                return Err(format!("{} should be between 0 and 1, got {}", name, p));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How much later than its successors a reordered packet arrives.
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// Xorshift generator. Good enough to drop packets, and the same seed gives
+/// the same packets. The load test uses it for its random players too.
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        // Zero is a fixed point of xorshift.
+        Rng(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    /// Uniform between 0 and 1 (excluded).
+    pub(crate) fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub(crate) fn chance(&mut self, probability: f32) -> bool {
+        probability > 0.0 && self.next_f32() < probability
+    }
+
+    /// Uniform between 0 and max (included).
+    pub(crate) fn up_to(&mut self, max: u32) -> u32 {
+        (self.next_u64() % (u64::from(max) + 1)) as u32
+    }
+}
+
+/// Holds the packets of one direction until they are due.
+#[derive(Debug, Clone)]
+pub struct LinkConditioner<T> {
+    conditions: LinkConditions,
+    rng: Rng,
+
+    /// Packets with the time they are delivered, in the order they were
+    /// pushed.
+    queue: Vec<(Instant, T)>,
+
+    /// Delivery time of the last packet that was not reordered. The next
+    /// ones do not arrive before it.
+    last_due: Option<Instant>,
+}
+
+impl<T: Clone> LinkConditioner<T> {
+    pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+        LinkConditioner {
+            conditions,
+            rng: Rng::new(seed),
+            queue: Vec::new(),
+            last_due: None,
+        }
+    }
+
+    pub fn conditions(&self) -> LinkConditions {
+        self.conditions
+    }
+
+    /// The packets already in the conditioner keep their delivery time.
+    pub fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.conditions = conditions;
+    }
+
+    /// A packet is sent on the link.
+    pub fn push(&mut self, packet: T, now: Instant) {
+        if self.rng.chance(self.conditions.loss) {
+            return;
+        }
+
+        let copies = if self.rng.chance(self.conditions.duplication) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let delay = self.conditions.latency_ms + self.rng.up_to(self.conditions.jitter_ms);
+            let mut due = now + Duration::from_millis(u64::from(delay));
+
+            if self.rng.chance(self.conditions.reordering) {
+                due += REORDER_DELAY;
+            } else {
+                if let Some(last_due) = self.last_due {
+                    due = due.max(last_due);
+                }
+                self.last_due = Some(due);
+            }
+
+            self.queue.push((due, packet.clone()));
+        }
+    }
+
+    /// Packets that arrived at `now`, in the order they arrived.
+    pub fn pop_ready(&mut self, now: Instant) -> Vec<T> {
+        let (mut ready, waiting): (Vec<_>, Vec<_>) =
+            self.queue.drain(..).partition(|(due, _)| *due <= now);
+        self.queue = waiting;
+
+        // Stable, so packets due at the same time keep their order.
+        ready.sort_by_key(|(due, _)| *due);
+        ready.into_iter().map(|(_, packet)| packet).collect()
+    }
+
+    /// All the packets still in the conditioner, without waiting. Used
+    /// before closing the connection.
+    pub fn flush(&mut self) -> Vec<T> {
+        self.last_due = None;
+        self.queue.sort_by_key(|(due, _)| *due);
+        self.queue.drain(..).map(|(_, packet)| packet).collect()
+    }
+
+    /// Number of packets not delivered yet.
+    pub fn nb_pending(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+impl<T: Clone> Default for LinkConditioner<T> {
+    fn default() -> Self {
+        LinkConditioner::new(LinkConditions::default(), 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_all(conditioner: &mut LinkConditioner<u32>, nb: u32, now: Instant) {
+        for i in 0..nb {
+            conditioner.push(i, now + Duration::from_millis(u64::from(i)));
+        }
+    }
+
+    #[test]
+    fn perfect_link_test() {
+        let mut conditioner = LinkConditioner::default();
+        let now = Instant::now();
+        send_all(&mut conditioner, 3, now);
+        assert_eq!(
+            vec![0, 1, 2],
+            conditioner.pop_ready(now + Duration::from_millis(2))
+        );
+        assert_eq!(0, conditioner.nb_pending());
+    }
+
+    #[test]
+    fn latency_and_jitter_test() {
+        let conditions = LinkConditions {
+            latency_ms: 100,
+            jitter_ms: 30,
+            ..LinkConditions::default()
+        };
+        let mut conditioner = LinkConditioner::new(conditions, 1);
+        let now = Instant::now();
+        send_all(&mut conditioner, 50, now);
+
+        assert!(conditioner
+            .pop_ready(now + Duration::from_millis(99))
+            .is_empty());
+        let received = conditioner.pop_ready(now + Duration::from_millis(200));
+        assert_eq!((0..50).collect::<Vec<_>>(), received);
+    }
+
+    #[test]
+    fn loss_and_duplication_test() {
+        let conditions = LinkConditions {
+            loss: 0.25,
+            duplication: 0.1,
+            ..LinkConditions::default()
+        };
+        let mut conditioner = LinkConditioner::new(conditions, 2);
+        let now = Instant::now();
+        send_all(&mut conditioner, 1000, now);
+        let received = conditioner.pop_ready(now + Duration::from_secs(2));
+
+        let mut unique = received.clone();
+        unique.dedup();
+        assert!(unique.len() > 650 && unique.len() < 850);
+        assert!(received.len() - unique.len() > 30);
+    }
+
+    #[test]
+    fn reordering_test() {
+        let conditions = LinkConditions {
+            reordering: 0.2,
+            ..LinkConditions::default()
+        };
+        let mut conditioner = LinkConditioner::new(conditions, 3);
+        let now = Instant::now();
+        send_all(&mut conditioner, 100, now);
+        let mut received = conditioner.pop_ready(now + Duration::from_secs(1));
+
+        assert_eq!(100, received.len());
+        assert!(received.windows(2).any(|w| w[0] > w[1]));
+        received.sort();
+        assert_eq!((0..100).collect::<Vec<_>>(), received);
+    }
+
+    #[test]
+    fn same_seed_test() {
+        let conditions = LinkConditions {
+            latency_ms: 20,
+            jitter_ms: 40,
+            loss: 0.3,
+            duplication: 0.2,
+            reordering: 0.2,
+        };
+        let now = Instant::now();
+        let run = |seed| {
+            let mut conditioner = LinkConditioner::new(conditions, seed);
+            send_all(&mut conditioner, 200, now);
+            conditioner.pop_ready(now + Duration::from_secs(1))
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn validate_test() {
+        assert!(LinkConditions::default().validate().is_ok());
+        let conditions = LinkConditions {
+            loss: 1.5,
+            ..LinkConditions::default()
+        };
+        assert!(conditions.validate().is_err());
+    }
+}
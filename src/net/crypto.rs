@@ -0,0 +1,579 @@
+// Authentication and encryption of the packets.
+//
+// A matchmaker (the `matchmaker` binary for now) shares a key with the
+// servers. It gives each player a connect token with two fresh session keys,
+// one per direction, and a private part sealed with the shared key. The
+// client sends the private part in its ConnectionRequest. Only a server with
+// the shared key can open it, so it learns the session keys without them
+// ever travelling in clear.
+//
+// Then every packet body is sealed with ChaCha20-Poly1305 and the session key
+// of its direction:
+//
+// protocol header | nonce (u64) | encrypted body and tag
+//
+// The header and the nonce are authenticated too. The nonce is a counter, and
+// the receiver remembers the last ones, so a packet cannot be replayed.
+// ConnectionRequest and ConnectionRefused are never encrypted: the first one
+// carries the token, the second one answers a token that cannot be opened.
+// The LAN discovery messages are not encrypted either: they come from
+// players who are not connected.
+use bytes::Bytes;
+use serde_derive::{Deserialize, Serialize};
+use sodiumoxide::crypto::aead::chacha20poly1305_ietf as aead;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::protocol::{read_header, MessageKind, HEADER_SIZE, PROTOCOL_VERSION};
+
+pub const KEY_SIZE: usize = aead::KEYBYTES;
+pub type Key = [u8; KEY_SIZE];
+
+/// Header + nonce + authentication tag.
+pub const ENCRYPTION_OVERHEAD: usize = 8 + aead::TAGBYTES;
+
+/// Packets older than that many packets are rejected as replays.
+const REPLAY_WINDOW: usize = 256;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    /// Too short to be an encrypted packet.
+    PacketTooShort,
+    /// The packet was not sealed with the session key, or was modified.
+    Tampered,
+    /// A packet with this nonce was already received.
+    Replayed(u64),
+    /// The connect token was not sealed with our key.
+    InvalidToken,
+    ExpiredToken,
+    /// The connect token already started a session.
+    UsedToken,
+    InvalidKey,
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CryptoError::Replayed(nonce) => write!(f, "Packet {} was already received", nonce),
+            CryptoError::Io(ref e) => write!(f, "{}", e),
+            CryptoError::Json(ref e) => write!(f, "{}", e),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for CryptoError {
+    fn description(&self) -> &str {
+        match *self {
+            CryptoError::PacketTooShort => "Packet is too short to be encrypted",
+            CryptoError::Tampered => "Packet cannot be authenticated",
+            CryptoError::Replayed(_) => "Packet was already received",
+            CryptoError::InvalidToken => "Connect token cannot be opened",
+            CryptoError::ExpiredToken => "Connect token has expired",
+            CryptoError::UsedToken => "Connect token was already used",
+            CryptoError::InvalidKey => "Key should be 64 hexadecimal characters",
+            CryptoError::Io(_) => "Cannot read or write key",
+            CryptoError::Json(_) => "Cannot read or write connect token",
+        }
+    }
+}
+
+impl From<io::Error> for CryptoError {
+    fn from(e: io::Error) -> Self {
+        CryptoError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for CryptoError {
+    fn from(e: serde_json::Error) -> Self {
+        CryptoError::Json(e)
+    }
+}
+
+/// Should be called before anything else in this module. Can be called
+/// several times.
+pub fn init() {
+    sodiumoxide::init().expect("Cannot initialize libsodium");
+}
+
+pub fn generate_key() -> Key {
+    aead::gen_key().0
+}
+
+pub fn key_to_hex(key: &Key) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn key_from_hex(s: &str) -> Result<Key, CryptoError> {
+    let s = s.trim();
+    if !s.is_ascii() || s.len() != 2 * KEY_SIZE {
+        return Err(CryptoError::InvalidKey);
+    }
+
+    let mut key = [0; KEY_SIZE];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| CryptoError::InvalidKey)?;
+    }
+    Ok(key)
+}
+
+/// The key is stored as hexadecimal text.
+pub fn load_key<P: AsRef<Path>>(path: P) -> Result<Key, CryptoError> {
+    key_from_hex(&fs::read_to_string(path)?)
+}
+
+pub fn save_key<P: AsRef<Path>>(path: P, key: &Key) -> Result<(), CryptoError> {
+    fs::write(path, key_to_hex(key))?;
+    Ok(())
+}
+
+/// Seconds since the epoch. Tokens are checked by another machine than the
+/// one that made them, so Instant cannot be used.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// What the server learns when it opens a connect token.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenContent {
+    pub client_id: u64,
+    /// Unix time after which the token cannot be used to connect.
+    pub expires_at: u64,
+    pub client_to_server_key: Key,
+    pub server_to_client_key: Key,
+}
+
+/// Given to the client by the matchmaker. The client uses the keys and
+/// sends `private_data` to the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectToken {
+    pub client_id: u64,
+    pub expires_at: u64,
+    pub client_to_server_key: Key,
+    pub server_to_client_key: Key,
+
+    /// Nonce, then the TokenContent sealed with the key shared by the
+    /// matchmaker and the servers.
+    pub private_data: Vec<u8>,
+}
+
+impl ConnectToken {
+    pub fn generate(client_id: u64, lifetime: Duration, shared_key: &Key) -> Self {
+        let content = TokenContent {
+            client_id,
+            expires_at: unix_time() + lifetime.as_secs(),
+            client_to_server_key: generate_key(),
+            server_to_client_key: generate_key(),
+        };
+
+        let nonce = aead::gen_nonce();
+        let plain = rmp_serde::to_vec(&content).expect("Cannot serialize connect token");
+        let mut private_data = nonce.0.to_vec();
+        private_data.extend(aead::seal(
+            &plain,
+            Some(&PROTOCOL_VERSION.to_be_bytes()[..]),
+            &nonce,
+            &aead::Key(*shared_key),
+        ));
+
+        ConnectToken {
+            client_id,
+            expires_at: content.expires_at,
+            client_to_server_key: content.client_to_server_key,
+            server_to_client_key: content.server_to_client_key,
+            private_data,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CryptoError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), CryptoError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Session of the client side.
+    pub fn client_session(&self) -> Session {
+        Session::new(
+            self.client_id,
+            &self.client_to_server_key,
+            &self.server_to_client_key,
+        )
+    }
+}
+
+/// Done by the server with the private data sent by the client.
+pub fn open_connect_token(
+    private_data: &[u8],
+    shared_key: &Key,
+    now: u64,
+) -> Result<TokenContent, CryptoError> {
+    if private_data.len() < aead::NONCEBYTES {
+        return Err(CryptoError::InvalidToken);
+    }
+
+    let (nonce, sealed) = private_data.split_at(aead::NONCEBYTES);
+    let nonce = aead::Nonce::from_slice(nonce).ok_or(CryptoError::InvalidToken)?;
+    let plain = aead::open(
+        sealed,
+        Some(&PROTOCOL_VERSION.to_be_bytes()[..]),
+        &nonce,
+        &aead::Key(*shared_key),
+    )
+    .map_err(|_| CryptoError::InvalidToken)?;
+    let content: TokenContent =
+        rmp_serde::from_slice(&plain).map_err(|_| CryptoError::InvalidToken)?;
+
+    if content.expires_at < now {
+        return Err(CryptoError::ExpiredToken);
+    }
+    Ok(content)
+}
+
+/// Connect tokens that started a session, until they expire. The session of
+/// a token used again would have the same keys and start its nonces at 0
+/// again, so the packets of the first session could be replayed.
+pub struct UsedTokens {
+    /// Client id and nonce of the private data, with the expiry time.
+    tokens: HashMap<(u64, [u8; aead::NONCEBYTES]), u64>,
+}
+
+impl UsedTokens {
+    pub fn new() -> Self {
+        UsedTokens {
+            tokens: HashMap::new(),
+        }
+    }
+
+    /// Remember a token that was opened with `open_connect_token`. Fails if
+    /// it was already used.
+    pub fn insert(
+        &mut self,
+        private_data: &[u8],
+        content: &TokenContent,
+        now: u64,
+    ) -> Result<(), CryptoError> {
+        // Expired tokens cannot be opened anymore.
+        self.tokens.retain(|_, expires_at| *expires_at >= now);
+
+        if private_data.len() < aead::NONCEBYTES {
+            return Err(CryptoError::InvalidToken);
+        }
+        let mut nonce = [0; aead::NONCEBYTES];
+        nonce.copy_from_slice(&private_data[..aead::NONCEBYTES]);
+        match self
+            .tokens
+            .insert((content.client_id, nonce), content.expires_at)
+        {
+            Some(_) => Err(CryptoError::UsedToken),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for UsedTokens {
+    fn default() -> Self {
+        UsedTokens::new()
+    }
+}
+
+/// Those are sent in clear, see the top of the file.
+pub fn is_plaintext(kind: MessageKind) -> bool {
+    match kind {
+        MessageKind::ConnectionRequest
+        | MessageKind::ConnectionRefused
+        | MessageKind::DiscoveryRequest
+        | MessageKind::DiscoveryResponse => true,
+        _ => false,
+    }
+}
+
+/// Nonces received recently.
+struct ReplayWindow {
+    most_recent: u64,
+    /// Indexed by nonce modulo the window size.
+    received: Vec<u64>,
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        ReplayWindow {
+            most_recent: 0,
+            received: vec![u64::max_value(); REPLAY_WINDOW],
+        }
+    }
+
+    fn is_replayed(&self, nonce: u64) -> bool {
+        nonce.saturating_add(REPLAY_WINDOW as u64) <= self.most_recent
+            || self.received[nonce as usize % REPLAY_WINDOW] == nonce
+    }
+
+    fn mark(&mut self, nonce: u64) {
+        self.most_recent = self.most_recent.max(nonce);
+        self.received[nonce as usize % REPLAY_WINDOW] = nonce;
+    }
+}
+
+/// Keys and counters of one connection, on either side.
+pub struct Session {
+    client_id: u64,
+    send_key: aead::Key,
+    receive_key: aead::Key,
+    next_nonce: u64,
+    replay: ReplayWindow,
+}
+
+impl Session {
+    pub fn new(client_id: u64, send_key: &Key, receive_key: &Key) -> Self {
+        Session {
+            client_id,
+            send_key: aead::Key(*send_key),
+            receive_key: aead::Key(*receive_key),
+            next_nonce: 0,
+            replay: ReplayWindow::new(),
+        }
+    }
+
+    /// Session of the server side.
+    pub fn from_token(token: &TokenContent) -> Self {
+        Session::new(
+            token.client_id,
+            &token.server_to_client_key,
+            &token.client_to_server_key,
+        )
+    }
+
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    /// Is it the server session of this token?
+    pub fn is_from_token(&self, token: &TokenContent) -> bool {
+        self.receive_key.0 == token.client_to_server_key
+            && self.send_key.0 == token.server_to_client_key
+    }
+
+    /// Seal a serialized packet. The header stays readable.
+    pub fn encrypt(&mut self, packet: &[u8]) -> Bytes {
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+
+        let mut b = Vec::with_capacity(packet.len() + ENCRYPTION_OVERHEAD);
+        b.extend_from_slice(&packet[..HEADER_SIZE]);
+        b.extend_from_slice(&nonce.to_be_bytes());
+        let sealed = aead::seal(
+            &packet[HEADER_SIZE..],
+            Some(&b[..]),
+            &aead_nonce(nonce),
+            &self.send_key,
+        );
+        b.extend(sealed);
+        b.into()
+    }
+
+    /// Check and open a packet sealed by the remote. Returns the serialized
+    /// packet.
+    pub fn decrypt(&mut self, packet: &[u8]) -> Result<Bytes, CryptoError> {
+        if packet.len() < HEADER_SIZE + ENCRYPTION_OVERHEAD {
+            return Err(CryptoError::PacketTooShort);
+        }
+
+        let (authenticated, sealed) = packet.split_at(HEADER_SIZE + 8);
+        let mut nonce = [0; 8];
+        nonce.copy_from_slice(&authenticated[HEADER_SIZE..]);
+        let nonce = u64::from_be_bytes(nonce);
+        if self.replay.is_replayed(nonce) {
+            return Err(CryptoError::Replayed(nonce));
+        }
+
+        let body = aead::open(
+            sealed,
+            Some(authenticated),
+            &aead_nonce(nonce),
+            &self.receive_key,
+        )
+        .map_err(|_| CryptoError::Tampered)?;
+        // Only once it is authenticated, so that forged packets cannot
+        // fill the window.
+        self.replay.mark(nonce);
+
+        let mut b = Vec::with_capacity(HEADER_SIZE + body.len());
+        b.extend_from_slice(&packet[..HEADER_SIZE]);
+        b.extend(body);
+        Ok(b.into())
+    }
+
+    /// Seal the packet unless its kind is sent in clear.
+    pub fn encrypt_if_needed(&mut self, packet: Bytes) -> Bytes {
+        match read_header(&packet) {
+            Ok((_, kind)) if !is_plaintext(kind) => self.encrypt(&packet),
+            _ => packet,
+        }
+    }
+}
+
+fn aead_nonce(nonce: u64) -> aead::Nonce {
+    let mut n = [0; aead::NONCEBYTES];
+    n[aead::NONCEBYTES - 8..].copy_from_slice(&nonce.to_be_bytes());
+    aead::Nonce(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::protocol::{serialize, NetMessageContent, Packet};
+
+    fn sessions() -> (Session, Session) {
+        init();
+        let token = ConnectToken::generate(7, Duration::from_secs(60), &generate_key());
+        let content = TokenContent {
+            client_id: token.client_id,
+            expires_at: token.expires_at,
+            client_to_server_key: token.client_to_server_key,
+            server_to_client_key: token.server_to_client_key,
+        };
+        (token.client_session(), Session::from_token(&content))
+    }
+
+    fn ping() -> Bytes {
+        serialize(Packet::new(3, None, NetMessageContent::Ping)).unwrap()
+    }
+
+    #[test]
+    fn connect_token_test() {
+        init();
+        let key = generate_key();
+        let token = ConnectToken::generate(42, Duration::from_secs(60), &key);
+
+        let content = open_connect_token(&token.private_data, &key, unix_time()).unwrap();
+        assert_eq!(42, content.client_id);
+        assert_eq!(token.client_to_server_key, content.client_to_server_key);
+        assert_eq!(token.server_to_client_key, content.server_to_client_key);
+
+        match open_connect_token(&token.private_data, &generate_key(), unix_time()) {
+            Err(CryptoError::InvalidToken) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let mut tampered = token.private_data.clone();
+        tampered[20] ^= 1;
+        assert!(open_connect_token(&tampered, &key, unix_time()).is_err());
+
+        match open_connect_token(&token.private_data, &key, token.expires_at + 1) {
+            Err(CryptoError::ExpiredToken) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn used_token_test() {
+        init();
+        let key = generate_key();
+        let token = ConnectToken::generate(42, Duration::from_secs(60), &key);
+        let content = open_connect_token(&token.private_data, &key, unix_time()).unwrap();
+
+        let mut used = UsedTokens::new();
+        assert!(used
+            .insert(&token.private_data, &content, unix_time())
+            .is_ok());
+        match used.insert(&token.private_data, &content, unix_time()) {
+            Err(CryptoError::UsedToken) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Another token of the same client is fine.
+        let other = ConnectToken::generate(42, Duration::from_secs(60), &key);
+        let other_content = open_connect_token(&other.private_data, &key, unix_time()).unwrap();
+        assert!(used
+            .insert(&other.private_data, &other_content, unix_time())
+            .is_ok());
+
+        // Forgotten once expired, as they cannot be opened anymore.
+        let later = other.expires_at.max(token.expires_at) + 1;
+        assert!(used.insert(&token.private_data, &content, later).is_ok());
+        assert_eq!(1, used.tokens.len());
+    }
+
+    #[test]
+    fn encrypt_test() {
+        let (mut client, mut server) = sessions();
+        let packet = ping();
+        let encrypted = client.encrypt(&packet);
+        assert_eq!(packet.len() + ENCRYPTION_OVERHEAD, encrypted.len());
+        assert_eq!(&packet[..HEADER_SIZE], &encrypted[..HEADER_SIZE]);
+        assert_eq!(packet, server.decrypt(&encrypted).unwrap());
+
+        // Each direction has its own key.
+        let encrypted = client.encrypt(&packet);
+        assert!(client.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn tampered_test() {
+        let (mut client, mut server) = sessions();
+        let encrypted = client.encrypt(&ping());
+        for i in 0..encrypted.len() {
+            let mut tampered = encrypted.to_vec();
+            tampered[i] ^= 0x80;
+            assert!(server.decrypt(&tampered).is_err());
+        }
+        assert!(server.decrypt(&encrypted).is_ok());
+    }
+
+    #[test]
+    fn replay_test() {
+        let (mut client, mut server) = sessions();
+        let first = client.encrypt(&ping());
+        let packets: Vec<_> = (0..REPLAY_WINDOW)
+            .map(|_| client.encrypt(&ping()))
+            .collect();
+
+        // Out of order is fine, twice is not.
+        assert!(server.decrypt(&packets[1]).is_ok());
+        assert!(server.decrypt(&packets[0]).is_ok());
+        match server.decrypt(&packets[0]) {
+            Err(CryptoError::Replayed(1)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Too old to know if it was received.
+        assert!(server.decrypt(&packets[REPLAY_WINDOW - 1]).is_ok());
+        assert!(server.decrypt(&first).is_err());
+    }
+
+    #[test]
+    fn plaintext_test() {
+        let (mut client, _) = sessions();
+        let request = serialize(Packet::new(
+            0,
+            None,
+            NetMessageContent::ConnectionRequest(None),
+        ))
+        .unwrap();
+        assert_eq!(request, client.encrypt_if_needed(request.clone()));
+        assert_ne!(ping(), client.encrypt_if_needed(ping()));
+    }
+
+    #[test]
+    fn hex_key_test() {
+        let key = [0xab; KEY_SIZE];
+        assert_eq!(
+            key,
+            key_from_hex(&format!("{}\n", key_to_hex(&key))).unwrap()
+        );
+        assert!(key_from_hex("abcd").is_err());
+        assert!(key_from_hex(&"zz".repeat(KEY_SIZE)).is_err());
+    }
+}
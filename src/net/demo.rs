@@ -0,0 +1,414 @@
+// Demos: recordings of a game as the client received it.
+//
+// The client writes every delta it applies, with the time it was received.
+// A demo can then be watched without a server by applying the deltas again
+// on an empty ECS. The first frame has the whole state of the game and the
+// next ones only have what changed.
+//
+// Deltas cannot be undone, so seeking backward starts again from the first
+// frame. Seeking forward applies the frames in between.
+//
+// File: magic (4 bytes) + demo version (2 bytes, big endian) + protocol
+// version (2 bytes, big endian), then the frames in MessagePack, one after
+// the other. The deltas change with the protocol, so demos of another
+// protocol version are refused.
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use super::protocol::PROTOCOL_VERSION;
+use super::snapshot::DeltaSnapshot;
+use crate::time::dt_as_secs;
+
+/// First bytes of every demo.
+pub const DEMO_MAGIC: [u8; 4] = *b"TWDM";
+
+/// Bump this when the layout of the file changes.
+pub const DEMO_VERSION: u16 = 1;
+
+const DEMO_HEADER_SIZE: usize = 8;
+
+#[derive(Debug)]
+pub enum DemoError {
+    Io(io::Error),
+    /// The file does not start with DEMO_MAGIC.
+    NotADemo,
+    /// The demo was recorded by another build.
+    VersionMismatch {
+        demo: u16,
+        protocol: u16,
+    },
+    /// There is no frame after the header.
+    Empty,
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+}
+
+impl fmt::Display for DemoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DemoError::Io(ref e) => write!(f, "Cannot access demo: {}", e),
+            DemoError::VersionMismatch { demo, protocol } => write!(
+                f,
+                "Demo was recorded with demo version {} and protocol version {} (ours are {} and {})",
+                demo, protocol, DEMO_VERSION, PROTOCOL_VERSION
+            ),
+            DemoError::Encode(ref e) => write!(f, "Cannot encode demo frame: {}", e),
+            DemoError::Decode(ref e) => write!(f, "Cannot decode demo frame: {}", e),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for DemoError {
+    fn description(&self) -> &str {
+        match *self {
+            DemoError::Io(_) => "Cannot access demo",
+            DemoError::NotADemo => "File is not a demo",
+            DemoError::VersionMismatch { .. } => "Demo was recorded by another version",
+            DemoError::Empty => "Demo does not contain any frame",
+            DemoError::Encode(_) => "Cannot encode demo frame",
+            DemoError::Decode(_) => "Cannot decode demo frame",
+        }
+    }
+}
+
+impl From<io::Error> for DemoError {
+    fn from(e: io::Error) -> Self {
+        DemoError::Io(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for DemoError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        DemoError::Encode(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for DemoError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        DemoError::Decode(e)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DemoFrame {
+    /// Seconds since the beginning of the recording.
+    pub time: f32,
+    pub delta: DeltaSnapshot,
+}
+
+/// Writes the frames as they are recorded, so a crash does not lose the
+/// whole demo.
+pub struct DemoRecorder<W: Write> {
+    writer: W,
+    start: Instant,
+    nb_frames: usize,
+}
+
+impl DemoRecorder<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, now: Instant) -> Result<Self, DemoError> {
+        DemoRecorder::new(BufWriter::new(File::create(path)?), now)
+    }
+}
+
+impl<W: Write> DemoRecorder<W> {
+    /// Write the header. Frames are timed from `now`.
+    pub fn new(mut writer: W, now: Instant) -> Result<Self, DemoError> {
+        writer.write_all(&DEMO_MAGIC)?;
+        writer.write_all(&DEMO_VERSION.to_be_bytes())?;
+        writer.write_all(&PROTOCOL_VERSION.to_be_bytes())?;
+
+        Ok(DemoRecorder {
+            writer,
+            start: now,
+            nb_frames: 0,
+        })
+    }
+
+    /// The first delta should contain the whole state.
+    pub fn record(&mut self, delta: &DeltaSnapshot, now: Instant) -> Result<(), DemoError> {
+        let frame = DemoFrame {
+            time: dt_as_secs(now.duration_since(self.start)) as f32,
+            delta: delta.clone(),
+        };
+        rmp_serde::encode::write(&mut self.writer, &frame)?;
+        self.nb_frames += 1;
+        Ok(())
+    }
+
+    pub fn nb_frames(&self) -> usize {
+        self.nb_frames
+    }
+
+    /// Flush what is left and give back the writer.
+    pub fn finish(mut self) -> Result<W, DemoError> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// A demo loaded in memory.
+#[derive(Debug, Clone)]
+pub struct Demo {
+    frames: Vec<DemoFrame>,
+}
+
+impl Demo {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, DemoError> {
+        Demo::from_bytes(&fs::read(path)?)
+    }
+
+    /// A demo whose end is cut, because the game crashed for example, is
+    /// read until the last complete frame.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DemoError> {
+        if bytes.len() < DEMO_HEADER_SIZE || bytes[0..4] != DEMO_MAGIC {
+            return Err(DemoError::NotADemo);
+        }
+
+        let demo = u16::from_be_bytes([bytes[4], bytes[5]]);
+        let protocol = u16::from_be_bytes([bytes[6], bytes[7]]);
+        if demo != DEMO_VERSION || protocol != PROTOCOL_VERSION {
+            return Err(DemoError::VersionMismatch { demo, protocol });
+        }
+
+        let mut frames = Vec::new();
+        let mut rest = &bytes[DEMO_HEADER_SIZE..];
+        while !rest.is_empty() {
+            match rmp_serde::decode::from_read::<_, DemoFrame>(&mut rest) {
+                Ok(frame) => frames.push(frame),
+                Err(e) if !frames.is_empty() => {
+                    warn!("Demo is cut after {} frames: {}", frames.len(), e);
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if frames.is_empty() {
+            return Err(DemoError::Empty);
+        }
+        Ok(Demo { frames })
+    }
+
+    pub fn frames(&self) -> &[DemoFrame] {
+        &self.frames
+    }
+
+    /// Time of the last frame, in seconds.
+    pub fn duration(&self) -> f32 {
+        self.frames.last().map(|f| f.time).unwrap_or(0.0)
+    }
+}
+
+/// Frames to apply after the player moved in the demo.
+#[derive(Debug)]
+pub enum Playback<'a> {
+    /// Apply these frames on the current state.
+    Apply(&'a [DemoFrame]),
+    /// Start again from an empty state and apply these frames.
+    Restart(&'a [DemoFrame]),
+}
+
+/// Where we are in a demo. It does not touch the ECS: the scene applies the
+/// frames it returns.
+pub struct DemoPlayer {
+    demo: Demo,
+    time: f32,
+
+    /// First frame that was not applied yet.
+    next_frame: usize,
+
+    speed: f32,
+    paused: bool,
+}
+
+impl DemoPlayer {
+    pub fn new(demo: Demo) -> Self {
+        DemoPlayer {
+            demo,
+            time: 0.0,
+            next_frame: 0,
+            speed: 1.0,
+            paused: false,
+        }
+    }
+
+    /// Move forward by `dt` seconds of real time, unless paused.
+    pub fn update(&mut self, dt: f32) -> Playback {
+        if !self.paused {
+            self.time = (self.time + dt * self.speed).min(self.duration());
+        }
+        self.advance()
+    }
+
+    /// Go to `time` seconds in the demo.
+    pub fn seek(&mut self, time: f32) -> Playback {
+        let time = time.max(0.0).min(self.duration());
+        if time < self.time {
+            self.time = time;
+            self.next_frame = self.frames_until(0, time);
+            Playback::Restart(&self.demo.frames[..self.next_frame])
+        } else {
+            self.time = time;
+            self.advance()
+        }
+    }
+
+    fn advance(&mut self) -> Playback {
+        let start = self.next_frame;
+        self.next_frame = self.frames_until(start, self.time);
+        Playback::Apply(&self.demo.frames[start..self.next_frame])
+    }
+
+    /// Index after the last frame at or before `time`, searching from `start`.
+    fn frames_until(&self, start: usize, time: f32) -> usize {
+        start
+            + self.demo.frames[start..]
+                .iter()
+                .take_while(|f| f.time <= time)
+                .count()
+    }
+
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.demo.duration()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next_frame == self.demo.frames.len()
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.max(0.0);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::Entity;
+    use crate::net::snapshot::DeltaEntity;
+    use std::time::Duration;
+
+    fn delta(index: usize) -> DeltaSnapshot {
+        let entity = Entity::new(index, 0);
+        DeltaSnapshot {
+            player_delta: DeltaEntity::empty(entity),
+            deltas: Vec::new(),
+            entities_to_delete: Vec::new(),
+        }
+    }
+
+    /// One frame every 100 ms.
+    fn record(nb_frames: usize) -> Vec<u8> {
+        let start = Instant::now();
+        let mut recorder = DemoRecorder::new(Vec::new(), start).unwrap();
+        for i in 0..nb_frames {
+            let now = start + Duration::from_millis(100 * i as u64);
+            recorder.record(&delta(i), now).unwrap();
+        }
+        assert_eq!(nb_frames, recorder.nb_frames());
+        recorder.finish().unwrap()
+    }
+
+    fn indices(frames: &[DemoFrame]) -> Vec<usize> {
+        frames
+            .iter()
+            .map(|f| f.delta.player_delta.entity.index())
+            .collect()
+    }
+
+    #[test]
+    fn roundtrip_test() {
+        let demo = Demo::from_bytes(&record(5)).unwrap();
+        assert_eq!(vec![0, 1, 2, 3, 4], indices(demo.frames()));
+        assert!((demo.duration() - 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cut_demo_test() {
+        let bytes = record(5);
+        let demo = Demo::from_bytes(&bytes[..bytes.len() - 2]).unwrap();
+        assert_eq!(vec![0, 1, 2, 3], indices(demo.frames()));
+
+        match Demo::from_bytes(&bytes[..DEMO_HEADER_SIZE]) {
+            Err(DemoError::Empty) => (),
+            other => panic!("Expected an empty demo, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn old_demo_test() {
+        let mut bytes = record(2);
+        bytes[6..8].copy_from_slice(&(PROTOCOL_VERSION - 1).to_be_bytes());
+        match Demo::from_bytes(&bytes) {
+            Err(DemoError::VersionMismatch { protocol, .. }) => {
+                assert_eq!(PROTOCOL_VERSION - 1, protocol)
+            }
+            other => panic!("Old demo was accepted: {:?}", other),
+        }
+
+        match Demo::from_bytes(b"TWNP\0\x0c\0\x0c") {
+            Err(DemoError::NotADemo) => (),
+            other => panic!("Expected not a demo, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn playback_test() {
+        let mut player = DemoPlayer::new(Demo::from_bytes(&record(10)).unwrap());
+        match player.update(0.15) {
+            Playback::Apply(frames) => assert_eq!(vec![0, 1], indices(frames)),
+            other => panic!("{:?}", other),
+        }
+
+        // Paused, time does not move.
+        player.set_paused(true);
+        match player.update(1.0) {
+            Playback::Apply(frames) => assert!(frames.is_empty()),
+            other => panic!("{:?}", other),
+        }
+        player.set_paused(false);
+
+        player.set_speed(2.0);
+        match player.update(0.1) {
+            Playback::Apply(frames) => assert_eq!(vec![2, 3], indices(frames)),
+            other => panic!("{:?}", other),
+        }
+
+        match player.seek(0.65) {
+            Playback::Apply(frames) => assert_eq!(vec![4, 5, 6], indices(frames)),
+            other => panic!("{:?}", other),
+        }
+        match player.seek(0.25) {
+            Playback::Restart(frames) => assert_eq!(vec![0, 1, 2], indices(frames)),
+            other => panic!("{:?}", other),
+        }
+
+        // Stops at the end.
+        player.update(100.0);
+        assert!(player.is_finished());
+        assert!((player.time() - player.duration()).abs() < 1e-6);
+    }
+}
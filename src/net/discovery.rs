@@ -0,0 +1,312 @@
+// Finding the servers of the local network.
+//
+// The server browser sends a DiscoveryRequest to the usual game ports, on
+// the broadcast address of the local network. Servers answer on their game
+// socket with a ServerAnnouncement: name, map and number of players. The
+// browser lists the answers and forgets the servers that stop answering.
+//
+// A request is padded with zeros so that it is bigger than the answer.
+// Otherwise a spoofed request would make the server send more bytes than it
+// received to the victim. Requests are also rate-limited per address like
+// connection requests.
+//
+// In loopback mode, the requests only go to 127.0.0.1. It finds the servers
+// of this machine without touching the network, which is what tests need.
+use bytes::Bytes;
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use super::protocol::{self, NetMessageContent, Packet};
+
+/// Ports where the servers are looked for. The server listens on 8080 by
+/// default; more servers on the same machine take the next ports.
+pub const FIRST_DISCOVERY_PORT: u16 = 8080;
+pub const NB_DISCOVERY_PORTS: u16 = 8;
+
+/// Zeros at the end of a request. Servers ignore shorter requests.
+pub const DISCOVERY_PADDING: usize = 256;
+
+/// Longer names and maps are cut, so that the answer stays smaller than
+/// the request.
+pub const MAX_ANNOUNCED_NAME: usize = 64;
+
+/// How often the browser asks again.
+pub const QUERY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Servers that did not answer for that long are removed from the list.
+pub const SERVER_EXPIRY: Duration = Duration::from_secs(5);
+
+/// What a server tells the browsers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerAnnouncement {
+    pub name: String,
+    pub map: String,
+    pub players: u32,
+    pub max_players: u32,
+}
+
+impl ServerAnnouncement {
+    /// Name and map are cut at MAX_ANNOUNCED_NAME bytes.
+    pub fn new(name: &str, map: &str, players: usize, max_players: usize) -> Self {
+        ServerAnnouncement {
+            name: truncate(name, MAX_ANNOUNCED_NAME).to_string(),
+            map: truncate(map, MAX_ANNOUNCED_NAME).to_string(),
+            players: players as u32,
+            max_players: max_players as u32,
+        }
+    }
+}
+
+/// Cut a string at `max` bytes, without splitting a character.
+fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Where the browser sends its requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiscoveryMode {
+    /// Everybody on the local network.
+    Broadcast,
+    /// Only this machine.
+    Loopback,
+}
+
+impl DiscoveryMode {
+    fn ip(self) -> Ipv4Addr {
+        match self {
+            DiscoveryMode::Broadcast => Ipv4Addr::BROADCAST,
+            DiscoveryMode::Loopback => Ipv4Addr::LOCALHOST,
+        }
+    }
+}
+
+/// A server that answered recently.
+#[derive(Debug, Clone)]
+pub struct DiscoveredServer {
+    /// Address of the game socket, to connect to.
+    pub addr: SocketAddr,
+    pub announcement: ServerAnnouncement,
+    pub last_seen: Instant,
+}
+
+/// Servers that answered, in the order they first did.
+#[derive(Debug, Clone, Default)]
+pub struct ServerList {
+    servers: Vec<DiscoveredServer>,
+}
+
+impl ServerList {
+    pub fn new() -> Self {
+        ServerList::default()
+    }
+
+    /// A server answered. Update it, or add it at the end.
+    pub fn insert(&mut self, addr: SocketAddr, announcement: ServerAnnouncement, now: Instant) {
+        match self.servers.iter_mut().find(|s| s.addr == addr) {
+            Some(server) => {
+                server.announcement = announcement;
+                server.last_seen = now;
+            }
+            None => self.servers.push(DiscoveredServer {
+                addr,
+                announcement,
+                last_seen: now,
+            }),
+        }
+    }
+
+    /// Forget the servers that stopped answering.
+    pub fn expire(&mut self, now: Instant) {
+        self.servers
+            .retain(|s| now.duration_since(s.last_seen) < SERVER_EXPIRY);
+    }
+
+    pub fn servers(&self) -> &[DiscoveredServer] {
+        &self.servers
+    }
+}
+
+/// Datagram sent by the browser.
+pub fn discovery_request() -> Bytes {
+    let request = NetMessageContent::DiscoveryRequest(vec![0; DISCOVERY_PADDING]);
+    protocol::serialize(Packet::new(0, None, request)).expect("Cannot serialize discovery request")
+}
+
+/// Sends the requests and collects the answers. The socket does not block,
+/// so it can be polled at every frame.
+pub struct ServerBrowser {
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+    list: ServerList,
+    last_query: Option<Instant>,
+}
+
+impl ServerBrowser {
+    /// Look for servers on the usual ports.
+    pub fn new(mode: DiscoveryMode) -> io::Result<Self> {
+        let ports: Vec<u16> =
+            (FIRST_DISCOVERY_PORT..FIRST_DISCOVERY_PORT + NB_DISCOVERY_PORTS).collect();
+        ServerBrowser::with_ports(mode, &ports)
+    }
+
+    pub fn with_ports(mode: DiscoveryMode, ports: &[u16]) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(mode == DiscoveryMode::Broadcast)?;
+        socket.set_nonblocking(true)?;
+
+        let ip = mode.ip();
+        Ok(ServerBrowser {
+            socket,
+            targets: ports.iter().map(|p| SocketAddr::from((ip, *p))).collect(),
+            list: ServerList::new(),
+            last_query: None,
+        })
+    }
+
+    /// Send the requests again if it is time, and read the answers.
+    pub fn update(&mut self, now: Instant) {
+        let due = self
+            .last_query
+            .map_or(true, |last| now.duration_since(last) >= QUERY_INTERVAL);
+        if due {
+            self.query(now);
+        }
+        self.poll(now);
+    }
+
+    /// Send a request to every target now.
+    pub fn query(&mut self, now: Instant) {
+        let request = discovery_request();
+        for target in self.targets.iter() {
+            if let Err(e) = self.socket.send_to(&request, target) {
+                debug!("Cannot send discovery request to {}: {}", target, e);
+            }
+        }
+        self.last_query = Some(now);
+    }
+
+    /// Read the answers received so far.
+    pub fn poll(&mut self, now: Instant) {
+        let mut buf = [0; 2048];
+        loop {
+            let (size, addr) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Some systems report the ports where nobody listens.
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    warn!("Cannot receive discovery answers: {}", e);
+                    break;
+                }
+            };
+
+            match protocol::deserialize(Bytes::from(&buf[..size])) {
+                Ok(Packet {
+                    content: NetMessageContent::DiscoveryResponse(announcement),
+                    ..
+                }) => self.list.insert(addr, announcement, now),
+                Ok(packet) => debug!("Unexpected {:?} from {}", packet.content.kind(), addr),
+                // Servers of another version are not listed.
+                Err(e) => debug!("Ignore answer of {}: {}", addr, e),
+            }
+        }
+        self.list.expire(now);
+    }
+
+    pub fn servers(&self) -> &[DiscoveredServer] {
+        self.list.servers()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn announcement(name: &str, players: usize) -> ServerAnnouncement {
+        ServerAnnouncement::new(name, "arena.json", players, 8)
+    }
+
+    #[test]
+    fn no_amplification_test() {
+        let long_name: String = std::iter::repeat('é').take(100).collect();
+        let announcement = ServerAnnouncement::new(&long_name, &long_name, 1000, 1000);
+        assert_eq!(MAX_ANNOUNCED_NAME, announcement.name.len());
+
+        let response = NetMessageContent::DiscoveryResponse(announcement);
+        let response = protocol::serialize(Packet::new(u32::max_value(), None, response)).unwrap();
+        assert!(response.len() < discovery_request().len());
+    }
+
+    #[test]
+    fn server_list_test() {
+        let mut list = ServerList::new();
+        let now = Instant::now();
+        list.insert(addr("10.0.0.1:8080"), announcement("first", 1), now);
+        list.insert(addr("10.0.0.2:8080"), announcement("second", 0), now);
+
+        let later = now + SERVER_EXPIRY / 2;
+        list.insert(addr("10.0.0.1:8080"), announcement("first", 2), later);
+        assert_eq!(2, list.servers().len());
+        assert_eq!(2, list.servers()[0].announcement.players);
+
+        // The second one stopped answering.
+        list.expire(now + SERVER_EXPIRY);
+        assert_eq!(1, list.servers().len());
+        assert_eq!("first", list.servers()[0].announcement.name);
+    }
+
+    #[test]
+    fn loopback_discovery_test() {
+        // Stands for the game socket of a server.
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        let mut browser = ServerBrowser::with_ports(DiscoveryMode::Loopback, &[port]).unwrap();
+        browser.query(Instant::now());
+
+        let mut buf = [0; 2048];
+        let (size, from) = server.recv_from(&mut buf).unwrap();
+        match protocol::deserialize(Bytes::from(&buf[..size]))
+            .unwrap()
+            .content
+        {
+            NetMessageContent::DiscoveryRequest(padding) => {
+                assert_eq!(DISCOVERY_PADDING, padding.len())
+            }
+            other => panic!("Expected a discovery request, got {:?}", other),
+        }
+
+        let response = NetMessageContent::DiscoveryResponse(announcement("local", 3));
+        let response = protocol::serialize(Packet::new(0, None, response)).unwrap();
+        server.send_to(&response, from).unwrap();
+
+        // Wait a bit for the answer.
+        for _ in 0..100 {
+            browser.poll(Instant::now());
+            if !browser.servers().is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(1, browser.servers().len());
+        assert_eq!(port, browser.servers()[0].addr.port());
+        assert_eq!(announcement("local", 3), browser.servers()[0].announcement);
+    }
+}
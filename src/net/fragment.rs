@@ -0,0 +1,392 @@
+// Fragmentation of the packets that do not fit in the MTU.
+//
+// UDP datagrams bigger than the path MTU are split by IP, and the whole
+// datagram is lost as soon as one of its pieces is. Some routers just drop
+// them. Big packets (the first snapshot of a large level for example) are
+// split here instead. Each fragment is a datagram with its own header:
+//
+// protocol header (kind Fragment) | packet id (u16) | index (u8) | count (u8) | data
+//
+// The receiver keeps the fragments until it has the whole packet, then
+// handles it as if it came in one piece. Incomplete packets are dropped after
+// a while. Nothing is resent: snapshots are sent every frame anyway and
+// reliable messages are resent by their channel.
+//
+// This happens before the handshake, so fragments bigger than the MTU of the
+// sender are refused and the memory used by all the remotes together is
+// capped.
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::protocol::{read_header, write_header, MessageKind, HEADER_SIZE};
+
+/// Safe UDP payload size on most networks.
+pub const DEFAULT_MTU: usize = 1200;
+
+/// Biggest UDP payload over IPv4.
+pub const MAX_MTU: usize = 65507;
+
+/// Protocol header + packet id + index + count.
+pub const FRAGMENT_HEADER_SIZE: usize = HEADER_SIZE + 4;
+
+/// The count is a u8.
+pub const MAX_FRAGMENTS: usize = 255;
+
+/// Incomplete packets are dropped after that.
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Packets being reassembled for one remote. A new one replaces the oldest.
+const MAX_PENDING_PACKETS: usize = 8;
+
+/// Packets being reassembled for all the remotes together, and the bytes
+/// they hold. A new one replaces the oldest.
+const MAX_TOTAL_PENDING_PACKETS: usize = 256;
+const MAX_PENDING_BYTES: usize = 4 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum FragmentError {
+    /// Would need more than MAX_FRAGMENTS fragments.
+    PacketTooBig(usize),
+    /// Received fragment is bigger than the MTU of the sender.
+    FragmentTooBig(usize),
+    MalformedFragment,
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FragmentError::PacketTooBig(size) => {
+                write!(f, "Packet of {} bytes is too big to be fragmented", size)
+            }
+            FragmentError::FragmentTooBig(size) => {
+                write!(f, "Fragment of {} bytes is bigger than the MTU", size)
+            }
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for FragmentError {
+    fn description(&self) -> &str {
+        match *self {
+            FragmentError::PacketTooBig(_) => "Packet is too big to be fragmented",
+            FragmentError::FragmentTooBig(_) => "Fragment is bigger than the MTU",
+            FragmentError::MalformedFragment => "Fragment header is invalid",
+        }
+    }
+}
+
+/// Split the packet in datagrams of at most `mtu` bytes. A packet that fits
+/// is returned as it is.
+pub fn fragment(packet: Bytes, packet_id: u16, mtu: usize) -> Result<Vec<Bytes>, FragmentError> {
+    if packet.len() <= mtu {
+        return Ok(vec![packet]);
+    }
+
+    let chunk_size = mtu - FRAGMENT_HEADER_SIZE;
+    let count = (packet.len() + chunk_size - 1) / chunk_size;
+    if count > MAX_FRAGMENTS {
+        return Err(FragmentError::PacketTooBig(packet.len()));
+    }
+
+    Ok(packet
+        .chunks(chunk_size)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut b = Vec::with_capacity(FRAGMENT_HEADER_SIZE + chunk.len());
+            write_header(&mut b, MessageKind::Fragment);
+            b.extend_from_slice(&packet_id.to_be_bytes());
+            b.push(index as u8);
+            b.push(count as u8);
+            b.extend_from_slice(chunk);
+            b.into()
+        })
+        .collect())
+}
+
+#[derive(Debug)]
+struct PendingPacket {
+    fragments: Vec<Option<Bytes>>,
+    received: usize,
+    // Bytes of the fragments received so far.
+    size: usize,
+    first_received: Instant,
+}
+
+/// Put the fragments back together. Each remote has its own packet ids.
+#[derive(Debug)]
+pub struct Reassembler {
+    // MTU of the remotes. Their fragments are not bigger.
+    mtu: usize,
+    timeout: Duration,
+    pending: HashMap<(SocketAddr, u16), PendingPacket>,
+    pending_bytes: usize,
+}
+
+impl Reassembler {
+    pub fn new(mtu: usize, timeout: Duration) -> Self {
+        Reassembler {
+            mtu,
+            timeout,
+            pending: HashMap::new(),
+            pending_bytes: 0,
+        }
+    }
+
+    /// Returns the packet when it is complete. Datagrams that are not
+    /// fragments are complete packets, returned as they are.
+    pub fn receive(
+        &mut self,
+        from: SocketAddr,
+        bytes: Bytes,
+        now: Instant,
+    ) -> Result<Option<Bytes>, FragmentError> {
+        match read_header(&bytes) {
+            Ok((_, MessageKind::Fragment)) => (),
+            _ => return Ok(Some(bytes)),
+        }
+
+        let timeout = self.timeout;
+        self.pending
+            .retain(|_, p| now.duration_since(p.first_received) <= timeout);
+        self.pending_bytes = self.pending.values().map(|p| p.size).sum();
+
+        if bytes.len() < FRAGMENT_HEADER_SIZE {
+            return Err(FragmentError::MalformedFragment);
+        }
+        if bytes.len() > self.mtu {
+            return Err(FragmentError::FragmentTooBig(bytes.len()));
+        }
+        let packet_id = u16::from_be_bytes([bytes[HEADER_SIZE], bytes[HEADER_SIZE + 1]]);
+        let index = bytes[HEADER_SIZE + 2] as usize;
+        let count = bytes[HEADER_SIZE + 3] as usize;
+        if index >= count {
+            return Err(FragmentError::MalformedFragment);
+        }
+
+        let key = (from, packet_id);
+        if !self.pending.contains_key(&key) {
+            self.drop_oldest_if_full(from);
+            self.pending.insert(
+                key,
+                PendingPacket {
+                    fragments: vec![None; count],
+                    received: 0,
+                    size: 0,
+                    first_received: now,
+                },
+            );
+        }
+
+        if self.pending[&key].fragments.len() != count {
+            self.remove(&key);
+            return Err(FragmentError::MalformedFragment);
+        }
+
+        // Duplicated fragments are ignored.
+        let data = bytes.slice_from(FRAGMENT_HEADER_SIZE);
+        if self.pending[&key].fragments[index].is_some() {
+            return Ok(None);
+        }
+        let size = data.len();
+        self.make_room(&key, size);
+
+        let pending = self.pending.get_mut(&key).unwrap();
+        pending.fragments[index] = Some(data);
+        pending.received += 1;
+        pending.size += size;
+        self.pending_bytes += size;
+
+        if pending.received < count {
+            return Ok(None);
+        }
+
+        let pending = self.remove(&key).unwrap();
+        let mut packet = Vec::new();
+        for fragment in pending.fragments {
+            packet.extend_from_slice(&fragment.unwrap());
+        }
+        Ok(Some(packet.into()))
+    }
+
+    /// Number of packets waiting for fragments.
+    pub fn nb_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Bytes held by the packets waiting for fragments.
+    pub fn pending_bytes(&self) -> usize {
+        self.pending_bytes
+    }
+
+    fn remove(&mut self, key: &(SocketAddr, u16)) -> Option<PendingPacket> {
+        let removed = self.pending.remove(key);
+        if let Some(ref p) = removed {
+            self.pending_bytes -= p.size;
+        }
+        removed
+    }
+
+    fn drop_oldest_if_full(&mut self, from: SocketAddr) {
+        let from_remote: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|((addr, _), _)| *addr == from)
+            .map(|(key, p)| (*key, p.first_received))
+            .collect();
+
+        if from_remote.len() >= MAX_PENDING_PACKETS {
+            if let Some((key, _)) = from_remote.iter().min_by_key(|(_, t)| *t) {
+                self.remove(key);
+            }
+        }
+
+        if self.pending.len() >= MAX_TOTAL_PENDING_PACKETS {
+            if let Some(key) = self.oldest(None) {
+                self.remove(&key);
+            }
+        }
+    }
+
+    /// Drop the oldest packets of any remote until `size` more bytes fit.
+    /// The packet being received is kept.
+    fn make_room(&mut self, receiving: &(SocketAddr, u16), size: usize) {
+        while self.pending_bytes + size > MAX_PENDING_BYTES {
+            match self.oldest(Some(receiving)) {
+                Some(key) => {
+                    self.remove(&key);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn oldest(&self, except: Option<&(SocketAddr, u16)>) -> Option<(SocketAddr, u16)> {
+        self.pending
+            .iter()
+            .filter(|(key, _)| Some(*key) != except)
+            .min_by_key(|(_, p)| p.first_received)
+            .map(|(key, _)| *key)
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler::new(DEFAULT_MTU, FRAGMENT_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:8080".parse().unwrap()
+    }
+
+    fn big_packet(size: usize) -> Bytes {
+        let mut b = Vec::new();
+        write_header(&mut b, MessageKind::Delta);
+        b.extend((0..size).map(|i| i as u8));
+        b.into()
+    }
+
+    #[test]
+    fn small_packet_test() {
+        let packet = big_packet(100);
+        let fragments = fragment(packet.clone(), 0, DEFAULT_MTU).unwrap();
+        assert_eq!(vec![packet.clone()], fragments);
+
+        let mut reassembler = Reassembler::default();
+        let received = reassembler.receive(addr(), packet.clone(), Instant::now());
+        assert_eq!(Some(packet), received.unwrap());
+    }
+
+    #[test]
+    fn reassemble_test() {
+        let packet = big_packet(5000);
+        let mut fragments = fragment(packet.clone(), 3, DEFAULT_MTU).unwrap();
+        assert_eq!(5, fragments.len());
+        assert!(fragments.iter().all(|f| f.len() <= DEFAULT_MTU));
+
+        // Out of order, with a duplicate.
+        fragments.reverse();
+        fragments.insert(2, fragments[1].clone());
+        let last = fragments.pop().unwrap();
+
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        for f in fragments {
+            assert_eq!(None, reassembler.receive(addr(), f, now).unwrap());
+        }
+        assert_eq!(1, reassembler.nb_pending());
+        assert_eq!(
+            Some(packet),
+            reassembler.receive(addr(), last, now).unwrap()
+        );
+        assert_eq!(0, reassembler.nb_pending());
+    }
+
+    #[test]
+    fn timeout_test() {
+        let fragments = fragment(big_packet(3000), 1, DEFAULT_MTU).unwrap();
+        let mut reassembler = Reassembler::new(DEFAULT_MTU, Duration::from_millis(100));
+        let start = Instant::now();
+        reassembler
+            .receive(addr(), fragments[0].clone(), start)
+            .unwrap();
+        reassembler
+            .receive(addr(), fragments[1].clone(), start)
+            .unwrap();
+
+        // The first fragments are gone, the packet cannot be complete.
+        let later = start + Duration::from_millis(200);
+        let received = reassembler.receive(addr(), fragments[2].clone(), later);
+        assert_eq!(None, received.unwrap());
+        assert_eq!(1, reassembler.nb_pending());
+    }
+
+    #[test]
+    fn fragment_bigger_than_mtu_test() {
+        let fragments = fragment(big_packet(3000), 0, 1400).unwrap();
+        let mut reassembler = Reassembler::default();
+        match reassembler.receive(addr(), fragments[0].clone(), Instant::now()) {
+            Err(FragmentError::FragmentTooBig(1400)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert_eq!(0, reassembler.nb_pending());
+    }
+
+    #[test]
+    fn many_remotes_test() {
+        let fragments = fragment(big_packet(100_000), 0, DEFAULT_MTU).unwrap();
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        for port in 0..1000 {
+            let from = SocketAddr::new(addr().ip(), port);
+            for f in &fragments[..fragments.len() - 1] {
+                reassembler.receive(from, f.clone(), now).unwrap();
+            }
+            assert!(reassembler.nb_pending() <= MAX_TOTAL_PENDING_PACKETS);
+            assert!(reassembler.pending_bytes() <= MAX_PENDING_BYTES);
+        }
+
+        // The last remote can still finish its packet.
+        let last = SocketAddr::new(addr().ip(), 999);
+        let received = reassembler.receive(last, fragments[fragments.len() - 1].clone(), now);
+        assert!(received.unwrap().is_some());
+    }
+
+    #[test]
+    fn too_big_test() {
+        let packet = big_packet(MAX_FRAGMENTS * DEFAULT_MTU);
+        match fragment(packet, 0, DEFAULT_MTU) {
+            Err(FragmentError::PacketTooBig(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+}
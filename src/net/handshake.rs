@@ -0,0 +1,177 @@
+// Connection handshake.
+//
+// A ConnectionRequest is one UDP packet, and the source address of a UDP
+// packet can be anything. So the server does not give a slot for it. It
+// answers with a challenge token instead, and only a client that echoes the
+// token back in a ChallengeResponse gets a slot and a player entity. To echo
+// it, the client has to receive packets at its address.
+//
+// The server keeps nothing per challenge: the token is a hash of the client
+// address, the current time window and a secret picked at start.
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// A token is valid during its time window and the next one.
+pub const CHALLENGE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Connection attempts are counted per address over that period.
+const RATE_LIMIT_PERIOD: Duration = Duration::from_secs(1);
+
+/// Above that many addresses, the ones that did not try recently are
+/// forgotten.
+const RATE_LIMIT_CLEANUP: usize = 1024;
+
+/// Gives and checks the challenge tokens.
+#[derive(Debug, Clone)]
+pub struct Challenges {
+    /// Random keys of the hash. New at each start of the server.
+    secret: RandomState,
+    start: Instant,
+}
+
+impl Challenges {
+    pub fn new() -> Self {
+        Challenges {
+            secret: RandomState::new(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Token to send to the client at `addr`.
+    pub fn token(&self, addr: SocketAddr, now: Instant) -> u64 {
+        self.token_for_window(addr, self.window(now))
+    }
+
+    /// Is it the token we gave to `addr` recently?
+    pub fn verify(&self, addr: SocketAddr, token: u64, now: Instant) -> bool {
+        let window = self.window(now);
+        token == self.token_for_window(addr, window)
+            || (window > 0 && token == self.token_for_window(addr, window - 1))
+    }
+
+    fn window(&self, now: Instant) -> u64 {
+        now.duration_since(self.start).as_secs() / CHALLENGE_WINDOW.as_secs()
+    }
+
+    fn token_for_window(&self, addr: SocketAddr, window: u64) -> u64 {
+        let mut hasher = self.secret.build_hasher();
+        addr.hash(&mut hasher);
+        window.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Default for Challenges {
+    fn default() -> Self {
+        Challenges::new()
+    }
+}
+
+/// Limits the connection attempts of each IP address. Requests and
+/// responses over the limit are dropped without answer.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimiter {
+    max_attempts: u32,
+
+    /// Start of the period and attempts during it.
+    attempts: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl ConnectionLimiter {
+    /// At most `max_attempts` per second for each address.
+    pub fn new(max_attempts: u32) -> Self {
+        ConnectionLimiter {
+            max_attempts,
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// Count an attempt. Returns false if the address tried too often.
+    pub fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.attempts.len() >= RATE_LIMIT_CLEANUP {
+            self.attempts
+                .retain(|_, (start, _)| now.duration_since(*start) < RATE_LIMIT_PERIOD);
+        }
+
+        let entry = self.attempts.entry(ip).or_insert((now, 0));
+        if now.duration_since(entry.0) >= RATE_LIMIT_PERIOD {
+            *entry = (now, 0);
+        }
+
+        if entry.1 >= self.max_attempts {
+            false
+        } else {
+            entry.1 += 1;
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn challenge_test() {
+        let challenges = Challenges::new();
+        let client = addr("10.0.0.1:5000");
+        let now = Instant::now();
+        let token = challenges.token(client, now);
+
+        assert!(challenges.verify(client, token, now));
+        assert!(!challenges.verify(client, token.wrapping_add(1), now));
+        // Same IP, another port.
+        assert!(!challenges.verify(addr("10.0.0.1:5001"), token, now));
+        assert!(!challenges.verify(addr("[::1]:5000"), token, now));
+
+        // Another server does not accept it.
+        assert!(!Challenges::new().verify(client, token, now));
+    }
+
+    #[test]
+    fn challenge_expiry_test() {
+        let challenges = Challenges::new();
+        let client = addr("10.0.0.1:5000");
+        let now = Instant::now();
+        let token = challenges.token(client, now);
+
+        assert!(challenges.verify(client, token, now + CHALLENGE_WINDOW));
+        assert!(!challenges.verify(client, token, now + CHALLENGE_WINDOW * 2));
+    }
+
+    #[test]
+    fn rate_limit_test() {
+        let mut limiter = ConnectionLimiter::new(3);
+        let ip = addr("10.0.0.1:5000").ip();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.allow(ip, now));
+        }
+        assert!(!limiter.allow(ip, now + Duration::from_millis(500)));
+        // Other addresses are not limited.
+        assert!(limiter.allow(addr("10.0.0.2:5000").ip(), now));
+
+        assert!(limiter.allow(ip, now + RATE_LIMIT_PERIOD));
+    }
+
+    #[test]
+    fn rate_limit_cleanup_test() {
+        let mut limiter = ConnectionLimiter::new(1);
+        let now = Instant::now();
+        for i in 0..RATE_LIMIT_CLEANUP as u32 {
+            assert!(limiter.allow(IpAddr::from([10, 0, (i >> 8) as u8, i as u8]), now));
+        }
+
+        let later = now + RATE_LIMIT_PERIOD;
+        assert!(limiter.allow(IpAddr::from([10, 1, 0, 0]), later));
+        assert_eq!(1, limiter.attempts.len());
+    }
+}
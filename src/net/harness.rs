@@ -0,0 +1,204 @@
+// A server and headless clients in one process, for the integration tests.
+//
+// The harness owns a NetworkScene and the clients connected to it, all on
+// the same loopback network: no socket is opened. A step runs one frame of
+// every client, then one frame of the server, always with the same dt. The
+// datagrams are delivered at once, but the timeouts, the statistics and the
+// link conditions follow the clock, so each side still gets a little real
+// time and tests wait for a condition with `run_until` instead of counting
+// steps.
+//
+// Game time goes faster than real time. The inputs are allowed to go ahead
+// of the clock, otherwise the server would drop them as a speed hack.
+//
+// The clients read the levels from the same directory as the server.
+use log::debug;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use super::crypto::ConnectToken;
+use super::headless::HeadlessClient;
+use super::loopback::LoopbackNetwork;
+use super::stats::NetworkStats;
+use crate::config::ServerConfig;
+use crate::ecs::{Entity, ECS};
+use crate::scene::{ClientCommand, NetworkScene, Scene};
+
+/// Duration of a frame, for the server and the clients.
+pub const HARNESS_DT: Duration = Duration::from_millis(16);
+
+/// Real time given to each side at every step.
+const SETTLE_TIME: Duration = Duration::from_millis(2);
+
+/// Server on a free port of 127.0.0.1, without connect key. Only the port
+/// of the loopback network is taken, not one of the machine.
+pub fn harness_config() -> ServerConfig {
+    let mut config = ServerConfig::default();
+    config.bind_address = (Ipv4Addr::LOCALHOST, 0).into();
+    config.input_limits.max_time_ahead = 3600.0;
+    config
+}
+
+pub struct TestHarness {
+    network: LoopbackNetwork,
+    server: NetworkScene,
+    clients: Vec<HeadlessClient>,
+
+    /// Sent by each client at every step.
+    commands: Vec<Vec<ClientCommand>>,
+
+    nb_steps: usize,
+
+    levels_dir: PathBuf,
+}
+
+impl TestHarness {
+    pub fn new(nb_clients: usize) -> Self {
+        TestHarness::with_config(&harness_config(), nb_clients)
+    }
+
+    pub fn with_config(config: &ServerConfig, nb_clients: usize) -> Self {
+        let network = LoopbackNetwork::new();
+        let transport = network
+            .bind(config.bind_address)
+            .expect("Cannot bind the server");
+        let mut harness = TestHarness {
+            server: NetworkScene::with_transport(config, Box::new(transport)),
+            network,
+            clients: Vec::with_capacity(nb_clients),
+            commands: Vec::with_capacity(nb_clients),
+            nb_steps: 0,
+            levels_dir: PathBuf::from(&config.levels_dir),
+        };
+        for _ in 0..nb_clients {
+            harness.add_client();
+        }
+        harness
+    }
+
+    /// Where the clients connect.
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server.local_addr()
+    }
+
+    /// The network of the server and the clients.
+    pub fn loopback(&self) -> &LoopbackNetwork {
+        &self.network
+    }
+
+    /// Start one more client. It connects during the next steps. Returns its
+    /// index.
+    pub fn add_client(&mut self) -> usize {
+        self.add_client_with_token(None)
+    }
+
+    /// Like `add_client`, for a server with a connect key.
+    pub fn add_client_with_token(&mut self, token: Option<ConnectToken>) -> usize {
+        let transport = self
+            .network
+            .bind((Ipv4Addr::LOCALHOST, 0).into())
+            .expect("Cannot bind headless client");
+        let mut client =
+            HeadlessClient::with_transport(Box::new(transport), self.server_addr(), token);
+        client.backend_mut().set_levels_dir(&self.levels_dir);
+        self.clients.push(client);
+        self.commands.push(Vec::new());
+        self.clients.len() - 1
+    }
+
+    /// The indices of the clients after it go down by one.
+    pub fn remove_client(&mut self, client: usize) -> HeadlessClient {
+        self.commands.remove(client);
+        self.clients.remove(client)
+    }
+
+    /// One frame of the clients, then one of the server.
+    pub fn step(&mut self) {
+        for (client, commands) in self.clients.iter_mut().zip(self.commands.iter()) {
+            if let Err(e) = client.update() {
+                debug!("Headless client is not connected: {}", e);
+            }
+            client.send_commands(commands, HARNESS_DT);
+        }
+        thread::sleep(SETTLE_TIME);
+
+        self.server.update(HARNESS_DT);
+        thread::sleep(SETTLE_TIME);
+        self.nb_steps += 1;
+    }
+
+    pub fn run(&mut self, nb_steps: usize) {
+        for _ in 0..nb_steps {
+            self.step();
+        }
+    }
+
+    /// Step until the condition holds. Returns false if it still does not
+    /// after `max_steps`.
+    pub fn run_until<F>(&mut self, max_steps: usize, mut condition: F) -> bool
+    where
+        F: FnMut(&TestHarness) -> bool,
+    {
+        for _ in 0..max_steps {
+            if condition(self) {
+                return true;
+            }
+            self.step();
+        }
+        condition(self)
+    }
+
+    /// Wait until every client is connected and knows its entity.
+    pub fn connect_all(&mut self, max_steps: usize) -> bool {
+        self.run_until(max_steps, |h| {
+            h.clients
+                .iter()
+                .all(|c| c.is_connected() && c.player_entity().is_some())
+        })
+    }
+
+    /// Commands sent by a client at every step, until they are changed.
+    pub fn set_commands(&mut self, client: usize, commands: Vec<ClientCommand>) {
+        self.commands[client] = commands;
+    }
+
+    pub fn client(&self, client: usize) -> &HeadlessClient {
+        &self.clients[client]
+    }
+
+    pub fn client_mut(&mut self, client: usize) -> &mut HeadlessClient {
+        &mut self.clients[client]
+    }
+
+    pub fn nb_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn server(&mut self) -> &mut NetworkScene {
+        &mut self.server
+    }
+
+    pub fn server_ecs(&self) -> &ECS {
+        self.server.get_ecs()
+    }
+
+    /// Player entities on the server.
+    pub fn server_players(&self) -> Vec<Entity> {
+        let ecs = self.server_ecs();
+        ecs.nb_entities()
+            .into_iter()
+            .filter(|e| ecs.components.players.get(e).is_some())
+            .collect()
+    }
+
+    /// Statistics of a player, as measured by the server.
+    pub fn server_stats(&self, player: &Entity) -> Option<NetworkStats> {
+        self.server.network().stats(player)
+    }
+
+    pub fn nb_steps(&self) -> usize {
+        self.nb_steps
+    }
+}
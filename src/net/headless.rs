@@ -0,0 +1,121 @@
+// A client without window, renderer or input.
+//
+// It does what the client scene does with the network: the handshake, the
+// snapshots applied to an ECS and the commands of the player. Tests and
+// tools use it to play against a server from code.
+use cgmath::Vector3;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::client::ClientSystem;
+use super::crypto::ConnectToken;
+use super::level::LevelInfo;
+use super::protocol::DisconnectReason;
+use super::reliable::{ChannelId, ReliableContent};
+use super::stats::NetworkStats;
+use super::transport::Transport;
+use super::NetworkError;
+use crate::ecs::{Entity, ECS};
+use crate::event::Event;
+use crate::scene::ClientCommand;
+
+pub struct HeadlessClient {
+    /// What the server sent, as the client scene would draw it.
+    ecs: ECS,
+    backend: ClientSystem,
+}
+
+impl HeadlessClient {
+    /// Blocks until the server accepts or refuses.
+    pub fn connect(addr: SocketAddr, token: Option<ConnectToken>) -> Result<Self, NetworkError> {
+        Ok(HeadlessClient {
+            ecs: ECS::new(),
+            backend: ClientSystem::connect(addr, token)?,
+        })
+    }
+
+    /// Does not block. The handshake goes on in `update`, which is what a
+    /// client sharing its thread with the server needs.
+    pub fn start(addr: SocketAddr, token: Option<ConnectToken>) -> Result<Self, NetworkError> {
+        Ok(HeadlessClient {
+            ecs: ECS::new(),
+            backend: ClientSystem::start(addr, token)?,
+        })
+    }
+
+    /// Like `start`, through another transport than UDP.
+    pub fn with_transport(
+        transport: Box<Transport>,
+        addr: SocketAddr,
+        token: Option<ConnectToken>,
+    ) -> Self {
+        HeadlessClient {
+            ecs: ECS::new(),
+            backend: ClientSystem::with_transport(transport, addr, token),
+        }
+    }
+
+    /// Read what the server sent. Returns the reliable messages, or why the
+    /// server could not be joined.
+    pub fn update(&mut self) -> Result<Vec<Event>, NetworkError> {
+        if !self.backend.poll_connection()? {
+            return Ok(Vec::new());
+        }
+        Ok(self.backend.poll_events(&mut self.ecs))
+    }
+
+    /// Commands of one frame. Nothing is sent before the client is
+    /// connected.
+    pub fn send_commands(&mut self, commands: &Vec<ClientCommand>, dt: Duration) {
+        self.backend.send_commands(commands, dt);
+        self.backend.apply_prediction(&mut self.ecs);
+    }
+
+    pub fn send_reliable(&mut self, channel: ChannelId, content: ReliableContent) {
+        self.backend.send_reliable(channel, content);
+    }
+
+    pub fn disconnect(&mut self) {
+        self.backend.disconnect();
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.backend.is_connected()
+    }
+
+    /// Why the connection is over, if it is.
+    pub fn disconnected(&self) -> Option<DisconnectReason> {
+        self.backend.disconnected()
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.backend.stats()
+    }
+
+    /// The level of the server, once it is loaded.
+    pub fn level(&self) -> Option<&LevelInfo> {
+        self.backend.level()
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.backend.is_loading()
+    }
+
+    pub fn player_entity(&self) -> Option<Entity> {
+        self.backend.player_entity()
+    }
+
+    /// Predicted position, as used for the camera of the client scene.
+    pub fn player_position(&self) -> Vector3<f32> {
+        self.backend.player_position()
+    }
+
+    pub fn ecs(&self) -> &ECS {
+        &self.ecs
+    }
+
+    /// For the settings: timeouts, simulated link, levels directory...
+    pub fn backend_mut(&mut self) -> &mut ClientSystem {
+        &mut self.backend
+    }
+}
@@ -0,0 +1,266 @@
+// Interpolation of the remote entities on the client.
+//
+// Snapshots do not arrive at a regular pace, and some of them are lost. If
+// they were applied directly, entities would jump around. Instead, the
+// client keeps the last server states and displays the entities a bit in
+// the past (`delay`), between two known states. If there is no state after
+// the render time (packets were lost), the movement is extrapolated for a
+// short while.
+use cgmath::{Euler, InnerSpace, Quaternion, Rad, Vector3};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::ecs::components::TransformComponent;
+use crate::ecs::Entity;
+use crate::time::dt_as_secs;
+
+/// How fast the estimation of the server clock follows the new samples.
+const CLOCK_SMOOTHING: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterpolationSettings {
+    /// Entities are displayed at server time - delay. Should be a few times
+    /// the interval between two snapshots.
+    pub delay: Duration,
+
+    /// Number of server states to keep.
+    pub buffer_size: usize,
+
+    /// How long to extrapolate when there is no new state.
+    pub max_extrapolation: Duration,
+}
+
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        InterpolationSettings {
+            delay: Duration::from_millis(100),
+            buffer_size: 32,
+            max_extrapolation: Duration::from_millis(250),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ServerState {
+    /// Server time of the snapshot, in seconds.
+    time: f64,
+    transforms: HashMap<Entity, TransformComponent>,
+}
+
+pub struct InterpolationBuffer {
+    settings: InterpolationSettings,
+    states: VecDeque<ServerState>,
+
+    /// Local time - server time, in seconds.
+    clock_offset: Option<f64>,
+    start: Instant,
+}
+
+impl InterpolationBuffer {
+    pub fn new(settings: InterpolationSettings) -> Self {
+        InterpolationBuffer {
+            states: VecDeque::with_capacity(settings.buffer_size),
+            settings,
+            clock_offset: None,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn settings(&self) -> &InterpolationSettings {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: InterpolationSettings) {
+        self.settings = settings;
+        while self.states.len() > self.settings.buffer_size {
+            self.states.pop_front();
+        }
+    }
+
+    /// Add a server state. `now` is when it was received.
+    pub fn push(
+        &mut self,
+        server_time: f64,
+        transforms: HashMap<Entity, TransformComponent>,
+        now: Instant,
+    ) {
+        // Packets that arrive quickly give the best estimation of the offset.
+        // Slow ones only move it a bit.
+        let sample = self.local_time(now) - server_time;
+        self.clock_offset = Some(match self.clock_offset {
+            Some(offset) if sample > offset => offset + (sample - offset) * CLOCK_SMOOTHING,
+            _ => sample,
+        });
+
+        if let Some(last) = self.states.back() {
+            if server_time <= last.time {
+                return;
+            }
+        }
+
+        self.states.push_back(ServerState {
+            time: server_time,
+            transforms,
+        });
+        while self.states.len() > self.settings.buffer_size {
+            self.states.pop_front();
+        }
+    }
+
+    /// Transforms of the entities at render time (now - delay).
+    pub fn interpolated(&self, now: Instant) -> Vec<(Entity, TransformComponent)> {
+        let time = match self.render_time(now) {
+            Some(time) => time,
+            None => return Vec::new(),
+        };
+
+        match self.states.back() {
+            Some(newest) => newest
+                .transforms
+                .keys()
+                .filter_map(|entity| self.transform_at(entity, time).map(|t| (*entity, t)))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn local_time(&self, now: Instant) -> f64 {
+        dt_as_secs(now.duration_since(self.start))
+    }
+
+    /// Server time that should be displayed.
+    fn render_time(&self, now: Instant) -> Option<f64> {
+        self.clock_offset
+            .map(|offset| self.local_time(now) - offset - dt_as_secs(self.settings.delay))
+    }
+
+    fn transform_at(&self, entity: &Entity, time: f64) -> Option<TransformComponent> {
+        let next = self.states.iter().position(|s| s.time > time);
+        match next {
+            // Before the oldest state. Nothing better than the oldest one.
+            Some(0) => self.states[0].transforms.get(entity).cloned(),
+            Some(i) => {
+                let (before, after) = (&self.states[i - 1], &self.states[i]);
+                match (before.transforms.get(entity), after.transforms.get(entity)) {
+                    (Some(a), Some(b)) => {
+                        let alpha = ((time - before.time) / (after.time - before.time)) as f32;
+                        Some(lerp(a, b, alpha))
+                    }
+                    (None, b) => b.cloned(),
+                    (a, None) => a.cloned(),
+                }
+            }
+            // After the newest state. Continue the movement for a while.
+            None => {
+                let newest = self.states.back()?;
+                let last = newest.transforms.get(entity)?;
+                let previous = self
+                    .states
+                    .iter()
+                    .rev()
+                    .skip(1)
+                    .find_map(|s| s.transforms.get(entity).map(|t| (s.time, t)));
+
+                match previous {
+                    Some((previous_time, previous)) => {
+                        let max_extrapolation = dt_as_secs(self.settings.max_extrapolation);
+                        let extra = (time - newest.time).min(max_extrapolation);
+                        let alpha = 1.0 + (extra / (newest.time - previous_time)) as f32;
+                        Some(lerp(previous, last, alpha))
+                    }
+                    None => Some(last.clone()),
+                }
+            }
+        }
+    }
+}
+
+/// alpha = 0 is a, alpha = 1 is b. Above 1 is extrapolation.
+fn lerp(a: &TransformComponent, b: &TransformComponent, alpha: f32) -> TransformComponent {
+    let mix = |a: Vector3<f32>, b: Vector3<f32>| a + (b - a) * alpha;
+    TransformComponent {
+        position: mix(a.position, b.position),
+        rotation: lerp_rotation(a.rotation, b.rotation, alpha),
+        scale: mix(a.scale, b.scale),
+    }
+}
+
+/// Euler angles cannot be mixed directly: the same rotation has several
+/// representations, and the snapshots do not keep the one of the server.
+fn lerp_rotation(a: Vector3<f32>, b: Vector3<f32>, alpha: f32) -> Vector3<f32> {
+    let to_quaternion =
+        |r: Vector3<f32>| Quaternion::from(Euler::new(Rad(r.x), Rad(r.y), Rad(r.z)));
+    let from = to_quaternion(a);
+    let to = to_quaternion(b);
+    let to = if from.dot(to) < 0.0 { -to } else { to };
+
+    let rotation = Euler::from((from * (1.0 - alpha) + to * alpha).normalize());
+    Vector3::new(rotation.x.0, rotation.y.0, rotation.z.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> HashMap<Entity, TransformComponent> {
+        let mut transforms = HashMap::new();
+        transforms.insert(
+            Entity::new(0, 0),
+            TransformComponent {
+                position: Vector3::new(x, 0.0, 0.0),
+                ..TransformComponent::default()
+            },
+        );
+        transforms
+    }
+
+    fn x_at(buffer: &InterpolationBuffer, now: Instant) -> f32 {
+        buffer.interpolated(now)[0].1.position.x
+    }
+
+    #[test]
+    fn interpolate_between_states_test() {
+        let mut buffer = InterpolationBuffer::new(InterpolationSettings {
+            delay: Duration::from_millis(100),
+            ..InterpolationSettings::default()
+        });
+        let start = buffer.start;
+
+        // Server sends a state every 100ms, received without latency.
+        for i in 0..3 {
+            let t = 0.1 * i as f64;
+            buffer.push(t, at(i as f32), start + Duration::from_millis(100 * i));
+        }
+
+        // At local 250ms, display the server at 150ms.
+        let x = x_at(&buffer, start + Duration::from_millis(250));
+        assert!((x - 1.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn extrapolate_for_a_while_test() {
+        let mut buffer = InterpolationBuffer::new(InterpolationSettings {
+            delay: Duration::from_millis(0),
+            buffer_size: 2,
+            max_extrapolation: Duration::from_millis(200),
+        });
+        let start = buffer.start;
+
+        for i in 0..3 {
+            buffer.push(
+                0.1 * i as f64,
+                at(i as f32),
+                start + Duration::from_millis(100 * i),
+            );
+        }
+        assert_eq!(2, buffer.states.len());
+
+        // 100ms after the last state, keeps the speed.
+        let x = x_at(&buffer, start + Duration::from_millis(300));
+        assert!((x - 3.0).abs() < 1e-3);
+
+        // Then stops.
+        let x = x_at(&buffer, start + Duration::from_millis(1000));
+        assert!((x - 4.0).abs() < 1e-3);
+    }
+}
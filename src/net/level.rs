@@ -0,0 +1,209 @@
+// Levels: the static part of the world.
+//
+// A level is a scene file, an ECS saved by the editor. The server tells the
+// clients which level to load and they read it from their own disk. Both
+// sides start from the level, so the snapshots only carry what changed since
+// it was loaded. The server can change the level during the game: the
+// clients load the new one and confirm, and only then receive snapshots
+// again.
+//
+// Both sides need the same file. The server sends a hash of its copy with
+// the name, and the client compares it with the hash of its own. The hash is
+// FNV-1a over the bytes of the file. It tells versions apart; it is not meant
+// to resist somebody looking for a collision.
+//
+// Names are paths relative to the levels directory. The client refuses the
+// names that would read outside of it. The empty name is the empty level,
+// for servers without map.
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path};
+
+use crate::ecs::ECS;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// What the server tells the clients to load.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LevelInfo {
+    /// Incremented at each change, so that a late confirmation for the
+    /// previous level is not mistaken for the current one.
+    pub id: u32,
+    pub name: String,
+    pub hash: u64,
+}
+
+#[derive(Debug)]
+pub enum LevelError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    InvalidName(String),
+    Mismatch { expected: u64, found: u64 },
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LevelError::Io(ref e) => write!(f, "Cannot read level: {}", e),
+            LevelError::Parse(ref e) => write!(f, "Cannot parse level: {}", e),
+            LevelError::InvalidName(ref name) => write!(f, "Invalid level name {:?}", name),
+            LevelError::Mismatch { expected, found } => write!(
+                f,
+                "Level is different from the server's: hash {:016x}, expected {:016x}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl Error for LevelError {
+    fn description(&self) -> &str {
+        match *self {
+            LevelError::Io(_) => "Cannot read level",
+            LevelError::Parse(_) => "Cannot parse level",
+            LevelError::InvalidName(_) => "Invalid level name",
+            LevelError::Mismatch { .. } => "Level is different from the server's",
+        }
+    }
+}
+
+impl From<io::Error> for LevelError {
+    fn from(e: io::Error) -> Self {
+        LevelError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for LevelError {
+    fn from(e: serde_json::Error) -> Self {
+        LevelError::Parse(e)
+    }
+}
+
+/// FNV-1a, 64 bits.
+pub fn level_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// A relative path that stays in the levels directory.
+pub fn is_valid_level_name(name: &str) -> bool {
+    Path::new(name).components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    })
+}
+
+/// A level read from the disk.
+pub struct Level {
+    pub name: String,
+    pub hash: u64,
+    ecs: ECS,
+}
+
+impl Level {
+    pub fn empty() -> Self {
+        Level {
+            name: String::new(),
+            hash: level_hash(&[]),
+            ecs: ECS::new(),
+        }
+    }
+
+    /// Read `name` in the directory `dir`.
+    pub fn load<P: AsRef<Path>>(dir: P, name: &str) -> Result<Self, LevelError> {
+        if name.is_empty() {
+            return Ok(Level::empty());
+        }
+        if !is_valid_level_name(name) {
+            return Err(LevelError::InvalidName(name.to_string()));
+        }
+
+        let bytes = fs::read(dir.as_ref().join(name))?;
+        let ecs = serde_json::from_slice(&bytes)?;
+        Ok(Level {
+            name: name.to_string(),
+            hash: level_hash(&bytes),
+            ecs,
+        })
+    }
+
+    pub fn info(&self, id: u32) -> LevelInfo {
+        LevelInfo {
+            id,
+            name: self.name.clone(),
+            hash: self.hash,
+        }
+    }
+
+    /// Is it the level the server has?
+    pub fn check(&self, info: &LevelInfo) -> Result<(), LevelError> {
+        if self.hash == info.hash {
+            Ok(())
+        } else {
+            Err(LevelError::Mismatch {
+                expected: info.hash,
+                found: self.hash,
+            })
+        }
+    }
+
+    pub fn ecs(&self) -> &ECS {
+        &self.ecs
+    }
+
+    /// A copy of the world of the level, to play in.
+    pub fn new_ecs(&self) -> ECS {
+        ECS::new_from_existing(&self.ecs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_hash_test() {
+        // Reference values of FNV-1a.
+        assert_eq!(0xcbf2_9ce4_8422_2325, level_hash(b""));
+        assert_eq!(0xaf63_dc4c_8601_ec8c, level_hash(b"a"));
+        assert_eq!(0x8594_4171_f739_67e8, level_hash(b"foobar"));
+    }
+
+    #[test]
+    fn level_name_test() {
+        assert!(is_valid_level_name("arena.json"));
+        assert!(is_valid_level_name("maps/arena.json"));
+        assert!(!is_valid_level_name("../arena.json"));
+        assert!(!is_valid_level_name("maps/../../arena.json"));
+        assert!(!is_valid_level_name("/etc/passwd"));
+
+        match Level::load(".", "../arena.json") {
+            Err(LevelError::InvalidName(_)) => (),
+            _ => panic!("Level outside of the directory was loaded"),
+        }
+    }
+
+    #[test]
+    fn check_test() {
+        let level = Level::empty();
+        assert!(level.check(&level.info(3)).is_ok());
+
+        let other = LevelInfo {
+            id: 3,
+            name: String::new(),
+            hash: level.hash + 1,
+        };
+        match level.check(&other) {
+            Err(LevelError::Mismatch { expected, found }) => {
+                assert_eq!(other.hash, expected);
+                assert_eq!(level.hash, found);
+            }
+            _ => panic!("Different levels were not detected"),
+        }
+    }
+}
@@ -0,0 +1,606 @@
+// Load test: many simulated players in one process against a server.
+//
+// Each simulated client is a headless client: it does the handshake, sends
+// one input per frame at the frame rate of a real client and applies the
+// snapshots to its own ECS, so the server sees the traffic of real players.
+// Nothing is drawn, so it runs on machines without GPU.
+//
+// The game side of all the clients runs in one thread, and their sockets on
+// one UdpRuntime. When the game thread cannot keep up, the client frame rate
+// of the report goes down, and the numbers then say more about the load
+// generator than about the server.
+//
+// Latency and bandwidth come from the statistics of each client (see
+// stats.rs). The server frame time is only known for a server started in the
+// same process with LocalServer.
+use log::{debug, info};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::conditioner::Rng;
+use super::fragment::{DEFAULT_MTU, MAX_MTU};
+use super::headless::HeadlessClient;
+use super::protocol::DisconnectReason;
+use super::stats::NetworkStats;
+use super::transport::{any_local_addr, UdpRuntime, UdpTransport};
+use crate::camera::CameraDirection;
+use crate::config::ServerConfig;
+use crate::scene::{ClientCommand, NetworkScene, Scene};
+use crate::time::dt_as_secs;
+
+/// Each move of the square lasts that long.
+const SQUARE_SIDE: Duration = Duration::from_secs(1);
+
+/// Random players keep their commands between these durations, in seconds.
+const MIN_RANDOM_HOLD: f32 = 0.2;
+const MAX_RANDOM_HOLD: f32 = 1.0;
+
+const DIRECTIONS: [CameraDirection; 4] = [
+    CameraDirection::Forward,
+    CameraDirection::Right,
+    CameraDirection::Backward,
+    CameraDirection::Left,
+];
+
+/// What the simulated players do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Behavior {
+    /// Nothing. Only snapshots and heartbeats go through.
+    Idle,
+    /// Forward, right, backward and left, one second each, so the players
+    /// stay around the spawn.
+    Square,
+    /// Random moves and directions, changed a few times per second.
+    Random,
+}
+
+impl FromStr for Behavior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "idle" => Ok(Behavior::Idle),
+            "square" => Ok(Behavior::Square),
+            "random" => Ok(Behavior::Random),
+            _ => Err(format!(
+                "Unknown behavior {:?}, expected idle, square or random",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadTestSettings {
+    pub nb_clients: usize,
+
+    /// Frames per second of each client. One input is sent per frame.
+    pub frame_rate: u32,
+
+    pub behavior: Behavior,
+
+    /// Seed of the random players. The same seed sends the same commands.
+    pub seed: u64,
+
+    /// Clients started per second. The server limits the connection
+    /// attempts per IP address, and all the clients share one.
+    pub connect_rate: u32,
+
+    /// Where the clients read the levels asked by the server.
+    pub levels_dir: PathBuf,
+}
+
+impl Default for LoadTestSettings {
+    fn default() -> Self {
+        LoadTestSettings {
+            nb_clients: 10,
+            frame_rate: 60,
+            behavior: Behavior::Random,
+            seed: 0,
+            connect_rate: 20,
+            levels_dir: PathBuf::from("."),
+        }
+    }
+}
+
+/// One simulated player.
+struct SimulatedClient {
+    client: HeadlessClient,
+    started: Instant,
+
+    /// Time from the start to the first snapshot.
+    join_time: Option<Duration>,
+
+    /// Sent at every frame, until `next_change`.
+    commands: Vec<ClientCommand>,
+    next_change: Instant,
+    square_side: usize,
+}
+
+impl SimulatedClient {
+    fn update_commands(&mut self, behavior: Behavior, rng: &mut Rng, now: Instant) {
+        if now < self.next_change {
+            return;
+        }
+
+        match behavior {
+            Behavior::Idle => (),
+            Behavior::Square => {
+                self.square_side = (self.square_side + 1) % DIRECTIONS.len();
+                self.commands = vec![ClientCommand::Move(DIRECTIONS[self.square_side])];
+                self.next_change = now + SQUARE_SIDE;
+            }
+            Behavior::Random => {
+                self.commands = random_commands(rng);
+                let hold = MIN_RANDOM_HOLD + rng.next_f32() * (MAX_RANDOM_HOLD - MIN_RANDOM_HOLD);
+                self.next_change = now + Duration::from_millis((hold * 1000.0) as u64);
+            }
+        }
+    }
+}
+
+/// A horizontal direction to look at, and up to two moves.
+fn random_commands(rng: &mut Rng) -> Vec<ClientCommand> {
+    let angle = rng.next_f32() * 2.0 * std::f32::consts::PI;
+    let mut commands = vec![ClientCommand::LookAt([angle.cos(), 0.0, angle.sin()])];
+    for _ in 0..rng.up_to(2) {
+        let direction = DIRECTIONS[rng.up_to(DIRECTIONS.len() as u32 - 1) as usize];
+        commands.push(ClientCommand::Move(direction));
+    }
+    commands
+}
+
+pub struct LoadTest {
+    settings: LoadTestSettings,
+    server_addr: SocketAddr,
+    clients: Vec<SimulatedClient>,
+
+    /// Runs the sockets of all the clients.
+    runtime: UdpRuntime,
+
+    /// Clients whose socket could not be opened.
+    failed: usize,
+
+    rng: Rng,
+    frame: Duration,
+    start: Instant,
+    next_frame: Instant,
+    nb_frames: u64,
+}
+
+impl LoadTest {
+    /// The clients are started during the first steps, at the connect rate.
+    pub fn new(server_addr: SocketAddr, settings: LoadTestSettings) -> Self {
+        let now = Instant::now();
+        LoadTest {
+            server_addr,
+            clients: Vec::with_capacity(settings.nb_clients),
+            runtime: UdpRuntime::new(),
+            failed: 0,
+            rng: Rng::new(settings.seed),
+            frame: Duration::from_nanos(1_000_000_000 / u64::from(settings.frame_rate.max(1))),
+            start: now,
+            next_frame: now,
+            nb_frames: 0,
+            settings,
+        }
+    }
+
+    /// One frame of every client. Waits until it is time for it.
+    pub fn step(&mut self) {
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        }
+        // Late frames are not caught up; the frame rate shows it.
+        self.next_frame = (self.next_frame + self.frame).max(Instant::now());
+
+        self.start_clients();
+
+        let now = Instant::now();
+        for simulated in self.clients.iter_mut() {
+            if let Err(e) = simulated.client.update() {
+                debug!("Simulated client is not connected: {}", e);
+            }
+            if simulated.join_time.is_none() && simulated.client.player_entity().is_some() {
+                simulated.join_time = Some(now.duration_since(simulated.started));
+            }
+
+            simulated.update_commands(self.settings.behavior, &mut self.rng, now);
+            simulated
+                .client
+                .send_commands(&simulated.commands, self.frame);
+        }
+        self.nb_frames += 1;
+    }
+
+    fn start_clients(&mut self) {
+        let elapsed = dt_as_secs(self.start.elapsed());
+        let due = (elapsed * f64::from(self.settings.connect_rate)) as usize + 1;
+        while self.clients.len() + self.failed < due.min(self.settings.nb_clients) {
+            let local_addr = any_local_addr(self.server_addr);
+            match UdpTransport::bind_on(&self.runtime, local_addr, DEFAULT_MTU, MAX_MTU) {
+                Ok(transport) => {
+                    let mut client =
+                        HeadlessClient::with_transport(Box::new(transport), self.server_addr, None);
+                    client
+                        .backend_mut()
+                        .set_levels_dir(&self.settings.levels_dir);
+                    let now = Instant::now();
+                    self.clients.push(SimulatedClient {
+                        client,
+                        started: now,
+                        join_time: None,
+                        commands: Vec::new(),
+                        next_change: now,
+                        square_side: 0,
+                    });
+                }
+                Err(e) => {
+                    info!("Cannot start simulated client: {}", e);
+                    self.failed += 1;
+                }
+            }
+        }
+    }
+
+    /// Run until all the clients joined or left. Returns false if some are
+    /// still connecting after `timeout`.
+    pub fn wait_for_clients(&mut self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            let started = self.clients.len() + self.failed == self.settings.nb_clients;
+            let settled = self
+                .clients
+                .iter()
+                .all(|c| c.join_time.is_some() || c.client.disconnected().is_some());
+            if started && settled {
+                return true;
+            }
+            self.step();
+        }
+        false
+    }
+
+    /// Tell the server that all the clients leave.
+    pub fn disconnect_all(&mut self) {
+        for simulated in self.clients.iter_mut() {
+            simulated.client.disconnect();
+        }
+    }
+
+    pub fn report(&self) -> LoadReport {
+        let elapsed = self.start.elapsed();
+        LoadReport {
+            elapsed,
+            frame_rate: self.nb_frames as f32 / dt_as_secs(elapsed).max(1e-3) as f32,
+            failed: self.failed,
+            clients: self
+                .clients
+                .iter()
+                .map(|c| ClientReport {
+                    join_time: c.join_time,
+                    stats: c.client.stats(),
+                    disconnected: c.client.disconnected(),
+                })
+                .collect(),
+            server_frames: None,
+        }
+    }
+}
+
+/// Frame times of a server.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameTimes {
+    pub nb_frames: u64,
+    pub total: Duration,
+    pub max: Duration,
+    /// Frames that took longer than the tick duration.
+    pub late: u64,
+}
+
+impl FrameTimes {
+    fn add(&mut self, duration: Duration, budget: Duration) {
+        self.nb_frames += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+        if duration > budget {
+            self.late += 1;
+        }
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.nb_frames == 0 {
+            Duration::from_secs(0)
+        } else {
+            self.total / self.nb_frames as u32
+        }
+    }
+}
+
+/// A server in its own thread of this process, with the loop of the server
+/// binary. Its frames are timed.
+pub struct LocalServer {
+    addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    frames: Arc<Mutex<FrameTimes>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LocalServer {
+    /// Panics if the server cannot start, e.g. if its map cannot be loaded.
+    pub fn start(config: &ServerConfig) -> Self {
+        let config = config.clone();
+        let running = Arc::new(AtomicBool::new(true));
+        let frames = Arc::new(Mutex::new(FrameTimes::default()));
+        let (addr_tx, addr_rx) = mpsc::channel();
+
+        let thread = {
+            let running = running.clone();
+            let frames = frames.clone();
+            thread::spawn(move || {
+                let mut scene = NetworkScene::from_file(&config);
+                let _ = addr_tx.send(scene.local_addr());
+
+                let tick = config.tick_duration();
+                let mut next_tick = Instant::now();
+                while running.load(Ordering::Relaxed) {
+                    let start = Instant::now();
+                    scene.update(tick);
+                    frames.lock().unwrap().add(start.elapsed(), tick);
+
+                    next_tick = (next_tick + tick).max(Instant::now());
+                    let now = Instant::now();
+                    if next_tick > now {
+                        thread::sleep(next_tick - now);
+                    }
+                }
+            })
+        };
+
+        let mut addr = addr_rx.recv().expect("Local server did not start");
+        if addr.ip().is_unspecified() {
+            addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        LocalServer {
+            addr,
+            running,
+            frames,
+            thread: Some(thread),
+        }
+    }
+
+    /// Where the clients connect.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn frame_times(&self) -> FrameTimes {
+        *self.frames.lock().unwrap()
+    }
+}
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// What one simulated client measured.
+#[derive(Debug, Clone)]
+pub struct ClientReport {
+    /// From the first connection request to the first snapshot.
+    pub join_time: Option<Duration>,
+    pub stats: NetworkStats,
+    pub disconnected: Option<DisconnectReason>,
+}
+
+impl ClientReport {
+    pub fn in_game(&self) -> bool {
+        self.join_time.is_some() && self.disconnected.is_none()
+    }
+}
+
+impl fmt::Display for ClientReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.join_time, self.disconnected) {
+            (_, Some(reason)) => write!(f, "disconnected: {}", reason),
+            (None, None) => write!(f, "connecting"),
+            (Some(join_time), None) => {
+                write!(f, "joined in {} ms, {}", join_time.as_millis(), self.stats)
+            }
+        }
+    }
+}
+
+/// Smallest, mean and biggest of some values.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Spread {
+    pub min: f32,
+    pub mean: f32,
+    pub max: f32,
+}
+
+impl Spread {
+    pub fn of<I: IntoIterator<Item = f32>>(values: I) -> Option<Self> {
+        let mut nb = 0;
+        let mut spread = Spread {
+            min: std::f32::INFINITY,
+            mean: 0.0,
+            max: std::f32::NEG_INFINITY,
+        };
+        for value in values {
+            nb += 1;
+            spread.min = spread.min.min(value);
+            spread.max = spread.max.max(value);
+            spread.mean += value;
+        }
+        if nb == 0 {
+            return None;
+        }
+        spread.mean /= nb as f32;
+        Some(spread)
+    }
+}
+
+impl fmt::Display for Spread {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "min {:.1}, mean {:.1}, max {:.1}",
+            self.min, self.mean, self.max
+        )
+    }
+}
+
+/// State of a load test at some point.
+#[derive(Debug, Clone)]
+pub struct LoadReport {
+    pub elapsed: Duration,
+    /// Frames per second the clients actually ran at.
+    pub frame_rate: f32,
+    pub failed: usize,
+    pub clients: Vec<ClientReport>,
+    /// Only known for a LocalServer.
+    pub server_frames: Option<FrameTimes>,
+}
+
+impl LoadReport {
+    pub fn nb_in_game(&self) -> usize {
+        self.clients.iter().filter(|c| c.in_game()).count()
+    }
+
+    fn in_game_stats(&self) -> impl Iterator<Item = &NetworkStats> {
+        self.clients
+            .iter()
+            .filter(|c| c.in_game())
+            .map(|c| &c.stats)
+    }
+
+    /// Round-trip times of the clients in game, in milliseconds.
+    pub fn rtt(&self) -> Option<Spread> {
+        Spread::of(self.in_game_stats().map(|s| s.rtt_ms))
+    }
+
+    /// Bytes per second received by the clients in game.
+    pub fn bytes_in(&self) -> Option<Spread> {
+        Spread::of(self.in_game_stats().map(|s| s.bytes_in_per_sec))
+    }
+
+    /// Bytes per second sent by the clients in game.
+    pub fn bytes_out(&self) -> Option<Spread> {
+        Spread::of(self.in_game_stats().map(|s| s.bytes_out_per_sec))
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let disconnected = self
+            .clients
+            .iter()
+            .filter(|c| c.disconnected.is_some())
+            .count();
+        writeln!(
+            f,
+            "{:.1} s: {} clients in game, {} connecting, {} disconnected, {} failed to start; client frames {:.1}/s",
+            dt_as_secs(self.elapsed),
+            self.nb_in_game(),
+            self.clients.len() - self.nb_in_game() - disconnected,
+            disconnected,
+            self.failed,
+            self.frame_rate
+        )?;
+
+        let join_times = Spread::of(
+            self.clients
+                .iter()
+                .filter_map(|c| c.join_time)
+                .map(|t| dt_as_secs(t) as f32 * 1000.0),
+        );
+        if let Some(join_times) = join_times {
+            writeln!(f, "  join time (ms): {}", join_times)?;
+        }
+        if let Some(rtt) = self.rtt() {
+            writeln!(f, "  rtt (ms): {}", rtt)?;
+        }
+        if let (Some(bytes_in), Some(bytes_out)) = (self.bytes_in(), self.bytes_out()) {
+            let nb = self.nb_in_game() as f32;
+            writeln!(
+                f,
+                "  server to clients (B/s): {}, total {:.0}",
+                bytes_in,
+                bytes_in.mean * nb
+            )?;
+            writeln!(
+                f,
+                "  clients to server (B/s): {}, total {:.0}",
+                bytes_out,
+                bytes_out.mean * nb
+            )?;
+        }
+        if let Some(frames) = self.server_frames {
+            writeln!(
+                f,
+                "  server frame: mean {:.2} ms, max {:.2} ms, {} of {} over the tick duration",
+                dt_as_secs(frames.mean()) * 1000.0,
+                dt_as_secs(frames.max) * 1000.0,
+                frames.late,
+                frames.nb_frames
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::validation::is_valid_look_at;
+
+    #[test]
+    fn behavior_test() {
+        assert_eq!(Ok(Behavior::Square), "square".parse());
+        assert!("run".parse::<Behavior>().is_err());
+
+        let mut rng = Rng::new(3);
+        for _ in 0..100 {
+            let commands = random_commands(&mut rng);
+            assert!(commands.len() <= 3);
+            match commands[0] {
+                ClientCommand::LookAt(direction) => assert!(is_valid_look_at(direction)),
+                _ => panic!("Random commands do not start with a direction"),
+            }
+        }
+    }
+
+    #[test]
+    fn spread_test() {
+        assert_eq!(None, Spread::of(Vec::new()));
+        let spread = Spread::of(vec![3.0, 1.0, 2.0]).unwrap();
+        assert_eq!(1.0, spread.min);
+        assert_eq!(2.0, spread.mean);
+        assert_eq!(3.0, spread.max);
+    }
+
+    #[test]
+    fn frame_times_test() {
+        let budget = Duration::from_millis(16);
+        let mut frames = FrameTimes::default();
+        assert_eq!(Duration::from_secs(0), frames.mean());
+
+        frames.add(Duration::from_millis(10), budget);
+        frames.add(Duration::from_millis(20), budget);
+        assert_eq!(Duration::from_millis(15), frames.mean());
+        assert_eq!(Duration::from_millis(20), frames.max);
+        assert_eq!(1, frames.late);
+    }
+}
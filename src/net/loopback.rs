@@ -0,0 +1,224 @@
+// Transports of the same process, without socket.
+//
+// A LoopbackNetwork is a set of transports, each bound to an address. A
+// datagram sent to an address goes straight to the queue of the transport
+// bound to it, and is received at its next poll: nothing is lost, delayed or
+// reordered. The link conditioner of the systems can still simulate a bad
+// network on top. Like with UDP, datagrams sent to an address nobody is
+// bound to are dropped.
+//
+// The addresses are only names, nothing is bound on the machine. Single
+// player, the tests or a listen server use it to run the server and its
+// clients in one process.
+use bytes::Bytes;
+use log::trace;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use super::transport::{Transport, TransportError};
+use crate::sync::SharedDeque;
+
+/// Datagrams waiting for the game, per transport. The oldest are dropped
+/// after that.
+const RECEIVE_QUEUE_SIZE: usize = 1024;
+
+/// Port 0 is replaced by the first free port from there.
+const FIRST_PORT: u16 = 1024;
+
+struct Bindings {
+    queues: HashMap<SocketAddr, SharedDeque<(Bytes, SocketAddr)>>,
+    next_port: u16,
+}
+
+/// Cloned to give it to each side.
+#[derive(Clone)]
+pub struct LoopbackNetwork {
+    bindings: Arc<Mutex<Bindings>>,
+}
+
+impl Default for LoopbackNetwork {
+    fn default() -> Self {
+        LoopbackNetwork {
+            bindings: Arc::new(Mutex::new(Bindings {
+                queues: HashMap::new(),
+                next_port: FIRST_PORT,
+            })),
+        }
+    }
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> Self {
+        LoopbackNetwork::default()
+    }
+
+    /// Like a socket: port 0 picks a free port, and an unspecified IP is
+    /// replaced by localhost so that the remotes see a usable address.
+    pub fn bind(&self, addr: SocketAddr) -> Result<LoopbackTransport, TransportError> {
+        let mut bindings = self.bindings.lock().unwrap();
+        let mut addr = addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        if addr.port() == 0 {
+            addr.set_port(bindings.free_port(addr.ip())?);
+        }
+        if bindings.queues.contains_key(&addr) {
+            return Err(TransportError::Io(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already bound", addr),
+            )));
+        }
+
+        let received = SharedDeque::new(RECEIVE_QUEUE_SIZE);
+        bindings.queues.insert(addr, received.clone());
+        Ok(LoopbackTransport {
+            network: self.clone(),
+            addr,
+            received,
+        })
+    }
+
+    /// Number of transports bound.
+    pub fn nb_bound(&self) -> usize {
+        self.bindings.lock().unwrap().queues.len()
+    }
+}
+
+impl Bindings {
+    fn free_port(&mut self, ip: IpAddr) -> Result<u16, TransportError> {
+        for _ in FIRST_PORT..=u16::max_value() {
+            let port = self.next_port;
+            self.next_port = if port == u16::max_value() {
+                FIRST_PORT
+            } else {
+                port + 1
+            };
+            if !self.queues.contains_key(&SocketAddr::new(ip, port)) {
+                return Ok(port);
+            }
+        }
+        Err(TransportError::Io(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "No free port",
+        )))
+    }
+}
+
+/// Unbound when dropped.
+pub struct LoopbackTransport {
+    network: LoopbackNetwork,
+    addr: SocketAddr,
+    received: SharedDeque<(Bytes, SocketAddr)>,
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, bytes: Bytes, addr: SocketAddr) -> Result<(), TransportError> {
+        let mut bindings = self.network.bindings.lock().unwrap();
+        match bindings.queues.get_mut(&addr) {
+            Some(queue) => queue.push((bytes, self.addr)),
+            None => trace!("Nobody is bound to {}, datagram dropped", addr),
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Vec<(Bytes, SocketAddr)> {
+        self.received.drain()
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        self.network
+            .bindings
+            .lock()
+            .unwrap()
+            .queues
+            .remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn any_addr() -> SocketAddr {
+        "0.0.0.0:0".parse().unwrap()
+    }
+
+    #[test]
+    fn send_receive_test() {
+        let network = LoopbackNetwork::new();
+        let mut server = network.bind("127.0.0.1:7000".parse().unwrap()).unwrap();
+        let mut client = network.bind(any_addr()).unwrap();
+        assert!(client.local_addr().ip().is_loopback());
+        assert_ne!(0, client.local_addr().port());
+
+        client
+            .send(Bytes::from(&b"hello"[..]), server.local_addr())
+            .unwrap();
+        client
+            .send(Bytes::from(&b"world"[..]), server.local_addr())
+            .unwrap();
+        assert_eq!(
+            vec![
+                (Bytes::from(&b"hello"[..]), client.local_addr()),
+                (Bytes::from(&b"world"[..]), client.local_addr()),
+            ],
+            server.receive()
+        );
+        assert!(server.receive().is_empty());
+
+        server
+            .send(Bytes::from(&b"back"[..]), client.local_addr())
+            .unwrap();
+        assert_eq!(
+            vec![(Bytes::from(&b"back"[..]), server.local_addr())],
+            client.receive()
+        );
+    }
+
+    #[test]
+    fn bind_test() {
+        let network = LoopbackNetwork::new();
+        let first = network.bind(any_addr()).unwrap();
+        let second = network.bind(any_addr()).unwrap();
+        assert_ne!(first.local_addr(), second.local_addr());
+
+        match network.bind(first.local_addr()) {
+            Err(TransportError::Io(ref e)) if e.kind() == io::ErrorKind::AddrInUse => (),
+            _ => panic!("Address was bound twice"),
+        }
+
+        // Dropping unbinds.
+        let addr = first.local_addr();
+        drop(first);
+        assert_eq!(1, network.nb_bound());
+        assert!(network.bind(addr).is_ok());
+    }
+
+    #[test]
+    fn unbound_address_test() {
+        let network = LoopbackNetwork::new();
+        let mut client = network.bind(any_addr()).unwrap();
+        let nobody = "127.0.0.1:9".parse().unwrap();
+        assert!(client.send(Bytes::from(&b"lost"[..]), nobody).is_ok());
+
+        // Another network does not see it.
+        let other = LoopbackNetwork::new();
+        let mut server = other.bind(nobody).unwrap();
+        client
+            .send(Bytes::from(&b"lost"[..]), server.local_addr())
+            .unwrap();
+        assert!(server.receive().is_empty());
+    }
+}
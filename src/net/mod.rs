@@ -5,24 +5,77 @@ use std::error::Error;
 use std::fmt;
 use std::net::SocketAddr;
 use std::thread;
+use std::time::Duration;
 use tokio::prelude::*;
 
+pub mod chat;
 mod client;
+pub mod conditioner;
+pub mod crypto;
+pub mod demo;
+pub mod discovery;
+pub mod fragment;
+pub mod handshake;
+pub mod harness;
+pub mod headless;
+pub mod interpolation;
+pub mod level;
+pub mod loadtest;
+pub mod loopback;
+pub mod packing;
+pub mod prediction;
 pub mod protocol;
+pub mod relevancy;
+pub mod reliable;
+pub mod replication;
+pub mod sequence;
 mod server;
 pub mod snapshot;
+pub mod stats;
+pub mod transport;
+pub mod validation;
 
 use crate::sync::SharedDeque;
 
+/// How long to wait for the remote before giving up on the connection.
+/// Used by the server for each client and by the client for the server.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeoutSettings {
+    /// The remote is disconnected if nothing was received for that long.
+    pub timeout: Duration,
+
+    /// Send a Ping if nothing else was sent for that long, so that the
+    /// remote does not time out.
+    pub heartbeat: Duration,
+}
+
+impl Default for TimeoutSettings {
+    fn default() -> Self {
+        TimeoutSettings {
+            timeout: Duration::from_secs(5),
+            heartbeat: Duration::from_secs(1),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum NetworkError {
     CannotConnectToServer,
+    ConnectionRefused(protocol::RefuseReason),
+    /// The server accepted us with settings we cannot use.
+    InvalidServerInfo(String),
 }
 
 impl fmt::Display for NetworkError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             NetworkError::CannotConnectToServer => write!(f, "Cannot connect to game server"),
+            NetworkError::ConnectionRefused(ref reason) => {
+                write!(f, "Connection refused by game server: {}", reason)
+            }
+            NetworkError::InvalidServerInfo(ref e) => {
+                write!(f, "Game server sent invalid settings: {}", e)
+            }
         }
     }
 }
@@ -31,9 +84,12 @@ impl Error for NetworkError {
     fn description(&self) -> &str {
         match *self {
             NetworkError::CannotConnectToServer => "Cannot connect to game server",
+            NetworkError::ConnectionRefused(_) => "Connection refused by game server",
+            NetworkError::InvalidServerInfo(_) => "Game server sent invalid settings",
         }
     }
 }
 
+pub use chat::ChatSystem;
 pub use client::ClientSystem;
 pub use server::NetworkSystem;
@@ -0,0 +1,901 @@
+// Compact encoding of the snapshots.
+//
+// DeltaSnapshot is what the server computes and what the client applies. On
+// the wire it is bit-packed instead of going through MessagePack:
+// - each entity has the ids of the components that changed, each followed by
+//   a bitmask of the fields that changed;
+// - the fields are encoded as their component declares (see
+//   replication.rs). Positions are quantized inside the level bounds and
+//   rotations are sent as quaternions with the smallest-three encoding;
+// - asset names are interned. The server sends each name once on the
+//   reliable channel and snapshots only contain its id.
+//
+// Integers such as entity indices use a variable number of bits. Scale,
+// colors and animation timers are rare enough to stay f32.
+use cgmath::{Euler, InnerSpace, Quaternion, Rad};
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+use super::replication::{ComponentDelta, FieldKind, FieldValue};
+use super::snapshot::{DeltaEntity, DeltaSnapshot, SnapshotError};
+use crate::ecs::{replicated_fields, Entity};
+
+/// Bits for each of the three smallest components of a rotation.
+const ROTATION_BITS: u32 = 10;
+
+/// How positions are quantized. Sent to the client when it connects.
+/// Positions outside of the bounds are clamped.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quantization {
+    pub bounds_min: [f32; 3],
+    pub bounds_max: [f32; 3],
+
+    /// Bits per coordinate. The precision is (max - min) / 2^bits.
+    pub position_bits: u8,
+}
+
+impl Default for Quantization {
+    fn default() -> Self {
+        // About 2mm horizontally and 0.5mm vertically.
+        Quantization {
+            bounds_min: [-1024.0, -256.0, -1024.0],
+            bounds_max: [1024.0, 256.0, 1024.0],
+            position_bits: 20,
+        }
+    }
+}
+
+impl Quantization {
+    /// f32 have 24 bits of precision so more bits would not help.
+    pub const MAX_POSITION_BITS: u8 = 24;
+
+    /// Checked by the server for its configuration and by the client for
+    /// what the server sends.
+    pub fn validate(&self) -> Result<(), String> {
+        let (min, max) = (self.bounds_min, self.bounds_max);
+        if (0..3).any(|i| min[i] >= max[i] || !min[i].is_finite() || !max[i].is_finite()) {
+            return Err(format!(
+                "bounds_min {:?} should be smaller than bounds_max {:?}",
+                min, max
+            ));
+        }
+        if self.position_bits < 8 || self.position_bits > Quantization::MAX_POSITION_BITS {
+            return Err(format!(
+                "position_bits should be between 8 and {}, got {}",
+                Quantization::MAX_POSITION_BITS,
+                self.position_bits
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn max_value(&self) -> f32 {
+        ((1u64 << self.position_bits) - 1) as f32
+    }
+
+    pub fn quantize_position(&self, position: [f32; 3]) -> [u64; 3] {
+        let mut quantized = [0; 3];
+        for i in 0..3 {
+            let t = (position[i] - self.bounds_min[i]) / (self.bounds_max[i] - self.bounds_min[i]);
+            quantized[i] = (t.max(0.0).min(1.0) * self.max_value()).round() as u64;
+        }
+        quantized
+    }
+
+    pub fn dequantize_position(&self, quantized: [u64; 3]) -> [f32; 3] {
+        let mut position = [0.0; 3];
+        for i in 0..3 {
+            let t = quantized[i] as f32 / self.max_value();
+            position[i] = self.bounds_min[i] + t * (self.bounds_max[i] - self.bounds_min[i]);
+        }
+        position
+    }
+}
+
+/// Names of the assets used in the snapshots.
+#[derive(Debug, Clone, Default)]
+pub struct AssetNames {
+    ids: HashMap<String, u32>,
+    names: HashMap<u32, String>,
+
+    /// Names interned since the last call to `take_new`.
+    new_names: Vec<(u32, String)>,
+}
+
+impl AssetNames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Server side. Id of the name, a new one if the name was never seen.
+    pub fn intern(&mut self, name: &str) -> u32 {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+
+        let id = self.ids.len() as u32;
+        self.ids.insert(name.to_string(), id);
+        self.names.insert(id, name.to_string());
+        self.new_names.push((id, name.to_string()));
+        id
+    }
+
+    /// Names that the clients do not know yet.
+    pub fn take_new(&mut self) -> Vec<(u32, String)> {
+        std::mem::replace(&mut self.new_names, Vec::new())
+    }
+
+    /// All the names, for a client that just connected.
+    pub fn all(&self) -> Vec<(u32, String)> {
+        let mut all: Vec<_> = self
+            .names
+            .iter()
+            .map(|(id, name)| (*id, name.clone()))
+            .collect();
+        all.sort();
+        all
+    }
+
+    /// Client side. Name sent by the server.
+    pub fn insert(&mut self, id: u32, name: String) {
+        self.ids.insert(name.clone(), id);
+        self.names.insert(id, name);
+    }
+
+    pub fn name(&self, id: u32) -> Option<&str> {
+        self.names.get(&id).map(|n| n.as_str())
+    }
+}
+
+/// Snapshot as it is sent. Serialized as MessagePack binary so that the
+/// bytes are not encoded one by one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackedSnapshot(pub Vec<u8>);
+
+impl Serialize for PackedSnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for PackedSnapshot {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = PackedSnapshot;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a byte array")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                Ok(PackedSnapshot(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(PackedSnapshot(v))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut bytes = Vec::new();
+                while let Some(b) = seq.next_element()? {
+                    bytes.push(b);
+                }
+                Ok(PackedSnapshot(bytes))
+            }
+        }
+
+        deserializer.deserialize_bytes(BytesVisitor)
+    }
+}
+
+/// Write values with an arbitrary number of bits, least significant bit
+/// first.
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    // Bits already used in the last byte.
+    used: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_bits(&mut self, value: u64, nb_bits: u32) {
+        for i in 0..nb_bits {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            if (value >> i) & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 1 << self.used;
+            }
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u64, 1);
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_bits(u64::from(value.to_bits()), 32);
+    }
+
+    /// Groups of 7 bits followed by a bit that tells if there is more.
+    /// Small values only take one byte.
+    pub fn write_varint(&mut self, mut value: u64) {
+        loop {
+            self.write_bits(value & 0x7f, 7);
+            value >>= 7;
+            self.write_bool(value != 0);
+            if value == 0 {
+                break;
+            }
+        }
+    }
+
+    pub fn len_bits(&self) -> usize {
+        match self.used {
+            0 => self.bytes.len() * 8,
+            used => (self.bytes.len() - 1) * 8 + used as usize,
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    // Position in bits.
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    pub fn read_bits(&mut self, nb_bits: u32) -> Result<u64, SnapshotError> {
+        if self.position + nb_bits as usize > self.bytes.len() * 8 {
+            return Err(SnapshotError::MalformedSnapshot);
+        }
+
+        let mut value = 0;
+        for i in 0..nb_bits {
+            let byte = self.bytes[self.position / 8];
+            if (byte >> (self.position % 8)) & 1 == 1 {
+                value |= 1 << i;
+            }
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SnapshotError> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, SnapshotError> {
+        Ok(f32::from_bits(self.read_bits(32)? as u32))
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64, SnapshotError> {
+        let mut value = 0;
+        // 10 groups of 7 bits are enough for an u64.
+        for group in 0..10 {
+            value |= self.read_bits(7)? << (7 * group);
+            if !self.read_bool()? {
+                return Ok(value);
+            }
+        }
+        Err(SnapshotError::MalformedSnapshot)
+    }
+}
+
+pub fn encode_snapshot(
+    delta: &DeltaSnapshot,
+    quantization: &Quantization,
+    names: &mut AssetNames,
+) -> PackedSnapshot {
+    let mut writer = BitWriter::new();
+
+    write_entity_delta(&mut writer, &delta.player_delta, quantization, names);
+
+    writer.write_varint(delta.deltas.len() as u64);
+    for entity_delta in &delta.deltas {
+        write_entity_delta(&mut writer, entity_delta, quantization, names);
+    }
+
+    writer.write_varint(delta.entities_to_delete.len() as u64);
+    for entity in &delta.entities_to_delete {
+        write_entity(&mut writer, entity);
+    }
+
+    PackedSnapshot(writer.into_bytes())
+}
+
+/// Encode the snapshot in parts of at most `max_bytes` if it is too big for
+/// one packet. The deltas are split by entity. The player delta and the
+/// entities to delete are in the first part. An entity bigger than
+/// `max_bytes` gets a part of its own.
+pub fn encode_snapshot_parts(
+    delta: &DeltaSnapshot,
+    quantization: &Quantization,
+    names: &mut AssetNames,
+    max_bytes: usize,
+) -> Vec<PackedSnapshot> {
+    let packed = encode_snapshot(delta, quantization, names);
+    if packed.0.len() <= max_bytes {
+        return vec![packed];
+    }
+
+    let entity_bits = |entity_delta: &DeltaEntity, names: &mut AssetNames| {
+        let mut writer = BitWriter::new();
+        write_entity_delta(&mut writer, entity_delta, quantization, names);
+        writer.len_bits()
+    };
+    // Leave room for the number of deltas and of entities to delete.
+    let max_bits = max_bytes.saturating_sub(8) * 8;
+
+    let mut deletes = BitWriter::new();
+    for entity in &delta.entities_to_delete {
+        write_entity(&mut deletes, entity);
+    }
+
+    let mut parts = Vec::new();
+    let mut current = DeltaSnapshot {
+        player_delta: delta.player_delta.clone(),
+        deltas: Vec::new(),
+        entities_to_delete: delta.entities_to_delete.clone(),
+    };
+    let mut bits = entity_bits(&delta.player_delta, names) + deletes.len_bits();
+    let empty_player = DeltaEntity::empty(delta.player_delta.entity);
+
+    for entity_delta in &delta.deltas {
+        let entity_size = entity_bits(entity_delta, names);
+        if !current.deltas.is_empty() && bits + entity_size > max_bits {
+            let next = DeltaSnapshot {
+                player_delta: empty_player.clone(),
+                deltas: Vec::new(),
+                entities_to_delete: Vec::new(),
+            };
+            parts.push(std::mem::replace(&mut current, next));
+            bits = entity_bits(&empty_player, names);
+        }
+        current.deltas.push(entity_delta.clone());
+        bits += entity_size;
+    }
+    parts.push(current);
+
+    parts
+        .iter()
+        .map(|part| encode_snapshot(part, quantization, names))
+        .collect()
+}
+
+/// Fails if the snapshot is malformed or uses an asset name that has not
+/// been received yet.
+pub fn decode_snapshot(
+    packed: &PackedSnapshot,
+    quantization: &Quantization,
+    names: &AssetNames,
+) -> Result<DeltaSnapshot, SnapshotError> {
+    let mut reader = BitReader::new(&packed.0);
+
+    let player_delta = read_entity_delta(&mut reader, quantization, names)?;
+
+    let nb_deltas = reader.read_varint()?;
+    let mut deltas = Vec::new();
+    for _ in 0..nb_deltas {
+        deltas.push(read_entity_delta(&mut reader, quantization, names)?);
+    }
+
+    let nb_to_delete = reader.read_varint()?;
+    let mut entities_to_delete = Vec::new();
+    for _ in 0..nb_to_delete {
+        entities_to_delete.push(read_entity(&mut reader)?);
+    }
+
+    Ok(DeltaSnapshot {
+        player_delta,
+        deltas,
+        entities_to_delete,
+    })
+}
+
+fn write_entity(writer: &mut BitWriter, entity: &Entity) {
+    writer.write_varint(entity.index() as u64);
+    writer.write_varint(entity.generation());
+}
+
+fn read_entity(reader: &mut BitReader) -> Result<Entity, SnapshotError> {
+    let index = reader.read_varint()? as usize;
+    let generation = reader.read_varint()?;
+    Ok(Entity::new(index, generation))
+}
+
+fn write_entity_delta(
+    writer: &mut BitWriter,
+    delta: &DeltaEntity,
+    quantization: &Quantization,
+    names: &mut AssetNames,
+) {
+    write_entity(writer, &delta.entity);
+    writer.write_varint(delta.components.len() as u64);
+    for component in &delta.components {
+        let fields = replicated_fields(component.component)
+            .unwrap_or_else(|| panic!("Component {} is not replicated", component.component));
+        let mask = component
+            .fields
+            .iter()
+            .fold(0, |mask, (index, _)| mask | (1 << index));
+
+        writer.write_varint(u64::from(component.component));
+        writer.write_bits(mask, fields.len() as u32);
+        for (index, value) in &component.fields {
+            let kind = fields[*index as usize].kind;
+            write_field(writer, kind, value, quantization, names);
+        }
+    }
+}
+
+fn read_entity_delta(
+    reader: &mut BitReader,
+    quantization: &Quantization,
+    names: &AssetNames,
+) -> Result<DeltaEntity, SnapshotError> {
+    let entity = read_entity(reader)?;
+    let mut delta = DeltaEntity::empty(entity);
+
+    let nb_components = reader.read_varint()?;
+    for _ in 0..nb_components {
+        let id = reader.read_varint()?;
+        let fields = if id <= 0xff {
+            replicated_fields(id as u8).ok_or(SnapshotError::MalformedSnapshot)?
+        } else {
+            return Err(SnapshotError::MalformedSnapshot);
+        };
+        let mask = reader.read_bits(fields.len() as u32)?;
+
+        let mut component = ComponentDelta {
+            component: id as u8,
+            fields: Vec::new(),
+        };
+        for (index, field) in fields.iter().enumerate() {
+            if mask & (1 << index) != 0 {
+                let value = read_field(reader, field.kind, quantization, names)?;
+                component.fields.push((index as u8, value));
+            }
+        }
+        delta.components.push(component);
+    }
+
+    Ok(delta)
+}
+
+fn write_field(
+    writer: &mut BitWriter,
+    kind: FieldKind,
+    value: &FieldValue,
+    quantization: &Quantization,
+    names: &mut AssetNames,
+) {
+    match (kind, value) {
+        (FieldKind::Position, FieldValue::Vec3(position)) => {
+            for q in quantization.quantize_position(*position).iter() {
+                writer.write_bits(*q, u32::from(quantization.position_bits));
+            }
+        }
+        (FieldKind::Rotation, FieldValue::Vec3(rotation)) => write_rotation(writer, *rotation),
+        (FieldKind::Vec3, FieldValue::Vec3(v)) => write_vec3(writer, v),
+        (FieldKind::F32, FieldValue::F32(v)) => writer.write_f32(*v),
+        (FieldKind::Bool, FieldValue::Bool(v)) => writer.write_bool(*v),
+        (FieldKind::Varint, FieldValue::Int(v)) => writer.write_varint(*v),
+        (FieldKind::Name, FieldValue::Str(name)) => {
+            writer.write_varint(u64::from(names.intern(name)))
+        }
+        (FieldKind::OptionalName, FieldValue::OptionalStr(name)) => {
+            write_optional_name(writer, name, names)
+        }
+        (FieldKind::Text, FieldValue::Str(text)) => {
+            writer.write_varint(text.len() as u64);
+            for b in text.bytes() {
+                writer.write_bits(u64::from(b), 8);
+            }
+        }
+        _ => panic!("{:?} cannot be encoded as {:?}", value, kind),
+    }
+}
+
+fn read_field(
+    reader: &mut BitReader,
+    kind: FieldKind,
+    quantization: &Quantization,
+    names: &AssetNames,
+) -> Result<FieldValue, SnapshotError> {
+    Ok(match kind {
+        FieldKind::Position => {
+            let bits = u32::from(quantization.position_bits);
+            let quantized = [
+                reader.read_bits(bits)?,
+                reader.read_bits(bits)?,
+                reader.read_bits(bits)?,
+            ];
+            FieldValue::Vec3(quantization.dequantize_position(quantized))
+        }
+        FieldKind::Rotation => FieldValue::Vec3(read_rotation(reader)?),
+        FieldKind::Vec3 => FieldValue::Vec3(read_vec3(reader)?),
+        FieldKind::F32 => FieldValue::F32(reader.read_f32()?),
+        FieldKind::Bool => FieldValue::Bool(reader.read_bool()?),
+        FieldKind::Varint => FieldValue::Int(reader.read_varint()?),
+        FieldKind::Name => FieldValue::Str(read_name(reader, names)?),
+        FieldKind::OptionalName => FieldValue::OptionalStr(read_optional_name(reader, names)?),
+        FieldKind::Text => {
+            let len = reader.read_varint()?;
+            let mut bytes = Vec::new();
+            for _ in 0..len {
+                bytes.push(reader.read_bits(8)? as u8);
+            }
+            FieldValue::Str(String::from_utf8(bytes).map_err(|_| SnapshotError::MalformedSnapshot)?)
+        }
+    })
+}
+
+fn write_vec3(writer: &mut BitWriter, v: &[f32; 3]) {
+    for x in v.iter() {
+        writer.write_f32(*x);
+    }
+}
+
+fn read_vec3(reader: &mut BitReader) -> Result<[f32; 3], SnapshotError> {
+    Ok([reader.read_f32()?, reader.read_f32()?, reader.read_f32()?])
+}
+
+fn read_name(reader: &mut BitReader, names: &AssetNames) -> Result<String, SnapshotError> {
+    let id = reader.read_varint()? as u32;
+    names
+        .name(id)
+        .map(|n| n.to_string())
+        .ok_or(SnapshotError::UnknownAsset)
+}
+
+fn write_optional_name(writer: &mut BitWriter, name: &Option<String>, names: &mut AssetNames) {
+    writer.write_bool(name.is_some());
+    if let Some(name) = name {
+        writer.write_varint(u64::from(names.intern(name)));
+    }
+}
+
+fn read_optional_name(
+    reader: &mut BitReader,
+    names: &AssetNames,
+) -> Result<Option<String>, SnapshotError> {
+    if reader.read_bool()? {
+        Ok(Some(read_name(reader, names)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Rotations are euler angles in the ECS. They are sent as a unit
+/// quaternion: the biggest component is dropped as it can be computed from
+/// the three others, which are all smaller than 1/sqrt(2).
+fn write_rotation(writer: &mut BitWriter, rotation: [f32; 3]) {
+    let q: Quaternion<f32> =
+        Euler::new(Rad(rotation[0]), Rad(rotation[1]), Rad(rotation[2])).into();
+    let q = q.normalize();
+    let components = [q.v.x, q.v.y, q.v.z, q.s];
+
+    let mut largest = 0;
+    for i in 1..4 {
+        if components[i].abs() > components[largest].abs() {
+            largest = i;
+        }
+    }
+
+    // q and -q are the same rotation. Make the dropped component positive.
+    let sign = if components[largest] < 0.0 { -1.0 } else { 1.0 };
+    writer.write_bits(largest as u64, 2);
+    for (i, c) in components.iter().enumerate() {
+        if i != largest {
+            let unit = c * sign * std::f32::consts::SQRT_2;
+            writer.write_bits(quantize_unit(unit, ROTATION_BITS), ROTATION_BITS);
+        }
+    }
+}
+
+fn read_rotation(reader: &mut BitReader) -> Result<[f32; 3], SnapshotError> {
+    let largest = reader.read_bits(2)? as usize;
+    let mut components = [0.0; 4];
+    let mut sum = 0.0;
+    for i in 0..4 {
+        if i != largest {
+            let unit = dequantize_unit(reader.read_bits(ROTATION_BITS)?, ROTATION_BITS);
+            components[i] = unit / std::f32::consts::SQRT_2;
+            sum += components[i] * components[i];
+        }
+    }
+    components[largest] = (1.0 - sum).max(0.0).sqrt();
+
+    let q = Quaternion::new(components[3], components[0], components[1], components[2]).normalize();
+    let euler = Euler::from(q);
+    Ok([euler.x.0, euler.y.0, euler.z.0])
+}
+
+/// [-1, 1] to [0, 2^bits - 1]
+fn quantize_unit(v: f32, bits: u32) -> u64 {
+    let max = ((1u64 << bits) - 1) as f32;
+    ((v.max(-1.0).min(1.0) + 1.0) / 2.0 * max).round() as u64
+}
+
+fn dequantize_unit(q: u64, bits: u32) -> f32 {
+    let max = ((1u64 << bits) - 1) as f32;
+    q as f32 / max * 2.0 - 1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::components::{
+        AnimatorComponent, LightComponent, LightType, ModelComponent, NameComponent,
+        TransformComponent,
+    };
+    use crate::net::replication::{component_delta, Replicated};
+
+    fn moving_entity(i: usize) -> DeltaEntity {
+        DeltaEntity {
+            entity: Entity::new(i, 1),
+            components: vec![ComponentDelta {
+                component: TransformComponent::ID,
+                fields: vec![
+                    (
+                        0,
+                        FieldValue::Vec3([i as f32 * 1.5, 1.0, -(i as f32) * 3.25]),
+                    ),
+                    (1, FieldValue::Vec3([0.0, 0.1 * i as f32, 0.0])),
+                ],
+            }],
+        }
+    }
+
+    #[test]
+    fn validate_quantization_test() {
+        assert!(Quantization::default().validate().is_ok());
+        let bad = [
+            Quantization {
+                position_bits: 64,
+                ..Quantization::default()
+            },
+            Quantization {
+                position_bits: 0,
+                ..Quantization::default()
+            },
+            Quantization {
+                bounds_min: [0.0; 3],
+                bounds_max: [0.0; 3],
+                ..Quantization::default()
+            },
+            Quantization {
+                bounds_max: [std::f32::INFINITY; 3],
+                ..Quantization::default()
+            },
+        ];
+        for q in &bad {
+            assert!(q.validate().is_err(), "{:?} is valid", q);
+        }
+    }
+
+    fn vec3(delta: &DeltaEntity, name: &str) -> [f32; 3] {
+        delta
+            .field::<TransformComponent>(name)
+            .and_then(FieldValue::as_vec3)
+            .unwrap()
+    }
+
+    fn assert_close(expected: [f32; 3], actual: [f32; 3], tolerance: f32) {
+        for i in 0..3 {
+            assert!(
+                (expected[i] - actual[i]).abs() <= tolerance,
+                "{:?} != {:?}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    fn assert_same_rotation(expected: [f32; 3], actual: [f32; 3]) {
+        let to_quaternion =
+            |r: [f32; 3]| Quaternion::from(Euler::new(Rad(r[0]), Rad(r[1]), Rad(r[2])));
+        let dot = to_quaternion(expected).dot(to_quaternion(actual)).abs();
+        assert!(dot > 0.9999, "{:?} != {:?}", expected, actual);
+    }
+
+    #[test]
+    fn bits_test() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(5, 3);
+        writer.write_bool(true);
+        writer.write_varint(300);
+        writer.write_f32(-1.5);
+        writer.write_bits(0x3ff, 10);
+        let bytes = writer.into_bytes();
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(5, reader.read_bits(3).unwrap());
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(300, reader.read_varint().unwrap());
+        assert_eq!(-1.5, reader.read_f32().unwrap());
+        assert_eq!(0x3ff, reader.read_bits(10).unwrap());
+        assert!(reader.read_bits(8).is_err());
+    }
+
+    #[test]
+    fn rotation_test() {
+        for rotation in &[[0.0, 0.0, 0.0], [0.3, -1.2, 0.7], [0.0, 3.0, 0.0]] {
+            let mut writer = BitWriter::new();
+            write_rotation(&mut writer, *rotation);
+            let bytes = writer.into_bytes();
+            assert_eq!(4, bytes.len());
+
+            let decoded = read_rotation(&mut BitReader::new(&bytes)).unwrap();
+            assert_same_rotation(*rotation, decoded);
+        }
+    }
+
+    #[test]
+    fn roundtrip_test() {
+        let quantization = Quantization::default();
+        let mut server_names = AssetNames::new();
+
+        let mut player_delta = moving_entity(0);
+        player_delta.components.extend(component_delta(
+            None,
+            &ModelComponent {
+                mesh_name: "player".to_string(),
+                texture_name: "player_texture".to_string(),
+            },
+        ));
+        player_delta.components.extend(component_delta(
+            None,
+            &NameComponent {
+                name: "Joueur Ü".to_string(),
+            },
+        ));
+        let mut animated = moving_entity(3);
+        animated.components[0]
+            .fields
+            .push((2, FieldValue::Vec3([2.0, 2.0, 2.0])));
+        animated.components.extend(component_delta(
+            None,
+            &LightComponent {
+                color: [1.0, 0.5, 0.2],
+                light_type: LightType::Ambient,
+                cast_shadows: true,
+            },
+        ));
+        animated.components.extend(component_delta(
+            None,
+            &AnimatorComponent::new("player/armature".to_string()),
+        ));
+
+        let delta = DeltaSnapshot {
+            player_delta,
+            deltas: vec![moving_entity(1), animated],
+            entities_to_delete: vec![Entity::new(7, 3)],
+        };
+
+        let packed = encode_snapshot(&delta, &quantization, &mut server_names);
+
+        // Names have not been received yet.
+        let mut client_names = AssetNames::new();
+        match decode_snapshot(&packed, &quantization, &client_names) {
+            Err(SnapshotError::UnknownAsset) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        for (id, name) in server_names.take_new() {
+            client_names.insert(id, name);
+        }
+        let decoded = decode_snapshot(&packed, &quantization, &client_names).unwrap();
+
+        assert_eq!(delta.entities_to_delete, decoded.entities_to_delete);
+        assert_eq!(
+            delta.player_delta.components[1..],
+            decoded.player_delta.components[1..]
+        );
+        for (expected, actual) in delta.deltas.iter().zip(decoded.deltas.iter()) {
+            assert_eq!(expected.entity, actual.entity);
+            assert_close(vec3(expected, "position"), vec3(actual, "position"), 0.002);
+            assert_same_rotation(vec3(expected, "rotation"), vec3(actual, "rotation"));
+            assert_eq!(
+                expected.field::<TransformComponent>("scale"),
+                actual.field::<TransformComponent>("scale")
+            );
+            // Everything else is not quantized.
+            assert_eq!(expected.components[1..], actual.components[1..]);
+        }
+    }
+
+    #[test]
+    fn unknown_component_test() {
+        let mut writer = BitWriter::new();
+        write_entity(&mut writer, &Entity::new(1, 1));
+        writer.write_varint(1);
+        writer.write_varint(200);
+        writer.write_bits(0, 8);
+        let bytes = writer.into_bytes();
+
+        match read_entity_delta(
+            &mut BitReader::new(&bytes),
+            &Quantization::default(),
+            &AssetNames::new(),
+        ) {
+            Err(SnapshotError::MalformedSnapshot) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn split_test() {
+        let quantization = Quantization::default();
+        let mut names = AssetNames::new();
+        let delta = DeltaSnapshot {
+            player_delta: moving_entity(0),
+            deltas: (1..=200).map(moving_entity).collect(),
+            entities_to_delete: vec![Entity::new(300, 0)],
+        };
+
+        let parts = encode_snapshot_parts(&delta, &quantization, &mut names, 500);
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|p| p.0.len() <= 500));
+
+        let parts: Vec<_> = parts
+            .iter()
+            .map(|p| decode_snapshot(p, &quantization, &names).unwrap())
+            .collect();
+        assert!(parts[0]
+            .player_delta
+            .field::<TransformComponent>("position")
+            .is_some());
+        assert_eq!(delta.entities_to_delete, parts[0].entities_to_delete);
+        let entities: Vec<_> = parts
+            .iter()
+            .flat_map(|p| p.deltas.iter().map(|d| d.entity))
+            .collect();
+        let expected: Vec<_> = delta.deltas.iter().map(|d| d.entity).collect();
+        assert_eq!(expected, entities);
+
+        // Small enough, not split.
+        let parts = encode_snapshot_parts(&delta, &quantization, &mut names, 10000);
+        assert_eq!(1, parts.len());
+    }
+
+    /// Compare with the MessagePack encoding of the same snapshot.
+    #[test]
+    fn bytes_per_entity_test() {
+        let nb_entities = 32;
+        let delta = DeltaSnapshot {
+            player_delta: moving_entity(0),
+            deltas: (1..=nb_entities).map(moving_entity).collect(),
+            entities_to_delete: vec![],
+        };
+
+        let mut msgpack = Vec::new();
+        rmp_serde::encode::write(&mut msgpack, &delta).unwrap();
+        let packed = encode_snapshot(&delta, &Quantization::default(), &mut AssetNames::new());
+
+        let before = msgpack.len() as f32 / nb_entities as f32;
+        let after = packed.0.len() as f32 / nb_entities as f32;
+        assert!(after * 3.0 < before);
+    }
+}
@@ -0,0 +1,243 @@
+// Client-side prediction.
+//
+// The client does not wait for the server to move the player. Each frame,
+// the commands are packed in a PlayerInput with a sequence number and the
+// frame duration. The input is applied locally with the same code as the
+// server (PlayerSystem::apply_input) and kept until the server tells us
+// it has processed it.
+//
+// When a snapshot arrives, the authoritative position is the one of the
+// server after the last processed input. The inputs that the server has not
+// seen yet are applied again on top of it. If the result is different from
+// what was displayed, the difference is removed over a few frames instead of
+// teleporting the player.
+use cgmath::{InnerSpace, Vector3, Zero};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use super::sequence::is_newer;
+use crate::ecs::components::{PlayerComponent, TransformComponent};
+use crate::ecs::systems::PlayerSystem;
+use crate::scene::ClientCommand;
+use crate::time::dt_as_secs;
+
+/// Unacked inputs are sent again in the next packets in case they were
+/// lost. This is the max number of inputs in a packet.
+pub const MAX_INPUTS_PER_PACKET: usize = 8;
+
+/// How fast a misprediction is corrected (per second).
+const CORRECTION_SPEED: f32 = 10.0;
+
+/// Above that distance, the player is moved directly to the corrected
+/// position.
+const SNAP_DISTANCE: f32 = 2.0;
+
+/// Commands of one client frame.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerInput {
+    pub seq_number: u32,
+    /// Duration of the frame, in seconds.
+    pub dt: f32,
+    pub commands: Vec<ClientCommand>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Predictor {
+    next_seq_number: u32,
+
+    /// Inputs that the server has not processed yet.
+    pending: VecDeque<PlayerInput>,
+
+    /// State after the last input processed by the server.
+    server_transform: TransformComponent,
+    server_player: PlayerComponent,
+
+    /// State after all the inputs.
+    predicted_transform: TransformComponent,
+    predicted_player: PlayerComponent,
+
+    /// Offset between displayed and predicted position. Goes to zero with
+    /// time.
+    correction: Vector3<f32>,
+}
+
+impl Predictor {
+    pub fn new() -> Self {
+        // Position will be set by the first snapshot.
+        let transform = TransformComponent::default();
+        let player = PlayerComponent::default();
+
+        Predictor {
+            next_seq_number: 0,
+            pending: VecDeque::new(),
+            server_transform: transform.clone(),
+            server_player: player.clone(),
+            predicted_transform: transform,
+            predicted_player: player,
+            correction: Vector3::zero(),
+        }
+    }
+
+    /// Apply the commands locally. The input is kept until the server
+    /// acknowledges it.
+    pub fn add_input(&mut self, dt: Duration, commands: Vec<ClientCommand>) {
+        let input = PlayerInput {
+            seq_number: self.next_seq_number,
+            dt: dt_as_secs(dt) as f32,
+            commands,
+        };
+        self.next_seq_number = self.next_seq_number.wrapping_add(1);
+
+        PlayerSystem::apply_input(
+            &mut self.predicted_transform,
+            &mut self.predicted_player,
+            &input,
+        );
+        self.pending.push_back(input);
+    }
+
+    /// Most recent inputs that have not been acknowledged yet.
+    pub fn inputs_to_send(&self) -> Vec<PlayerInput> {
+        let skip = self.pending.len().saturating_sub(MAX_INPUTS_PER_PACKET);
+        self.pending.iter().skip(skip).cloned().collect()
+    }
+
+    pub fn nb_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// A new snapshot arrived. `position` is the player's position in the
+    /// snapshot if it changed, and `last_input` the last input that the
+    /// server has processed before taking the snapshot.
+    pub fn reconcile(&mut self, position: Option<[f32; 3]>, last_input: Option<u32>) {
+        let displayed = self.position();
+
+        if let Some(position) = position {
+            self.server_transform.position = Vector3::new(position[0], position[1], position[2]);
+        }
+
+        // Drop what the server already knows. Only the orientation is needed
+        // from these inputs, the position is in the snapshot.
+        if let Some(last_input) = last_input {
+            while self
+                .pending
+                .front()
+                .map(|input| !is_newer(input.seq_number, last_input))
+                .unwrap_or(false)
+            {
+                let input = self.pending.pop_front().unwrap();
+                let mut unused = self.server_transform.clone();
+                PlayerSystem::apply_input(&mut unused, &mut self.server_player, &input);
+            }
+        }
+
+        // Replay.
+        self.predicted_transform = self.server_transform.clone();
+        self.predicted_player = self.server_player.clone();
+        for input in self.pending.iter() {
+            PlayerSystem::apply_input(
+                &mut self.predicted_transform,
+                &mut self.predicted_player,
+                input,
+            );
+        }
+
+        let error = displayed - self.predicted_transform.position;
+        self.correction = if error.magnitude() > SNAP_DISTANCE {
+            Vector3::zero()
+        } else {
+            error
+        };
+    }
+
+    /// Smooth the corrections.
+    pub fn update(&mut self, dt: Duration) {
+        let dt = dt_as_secs(dt) as f32;
+        self.correction *= (-CORRECTION_SPEED * dt).exp();
+    }
+
+    /// Position where the player should be displayed.
+    pub fn position(&self) -> Vector3<f32> {
+        self.predicted_transform.position + self.correction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::CameraDirection;
+
+    const FRAME: Duration = Duration::from_millis(50);
+
+    fn forward() -> Vec<ClientCommand> {
+        vec![ClientCommand::Move(CameraDirection::Forward)]
+    }
+
+    #[test]
+    fn predict_instantly_test() {
+        let mut predictor = Predictor::new();
+        predictor.add_input(FRAME, forward());
+
+        // 10 units/s * 0.05 s towards -z.
+        assert!((predictor.position() - Vector3::new(0.0, 0.0, -0.5)).magnitude() < 1e-5);
+        assert_eq!(1, predictor.inputs_to_send().len());
+    }
+
+    #[test]
+    fn replay_unacked_inputs_test() {
+        let mut predictor = Predictor::new();
+        for _ in 0..4 {
+            predictor.add_input(FRAME, forward());
+        }
+
+        // Server processed the first two inputs.
+        predictor.reconcile(Some([0.0, 0.0, -1.0]), Some(1));
+        assert_eq!(2, predictor.nb_pending());
+        assert!((predictor.position() - Vector3::new(0.0, 0.0, -2.0)).magnitude() < 1e-5);
+        assert_eq!(
+            vec![2, 3],
+            predictor
+                .inputs_to_send()
+                .iter()
+                .map(|i| i.seq_number)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn wraparound_test() {
+        let mut predictor = Predictor::new();
+        predictor.next_seq_number = u32::max_value();
+        for _ in 0..3 {
+            predictor.add_input(FRAME, forward());
+        }
+
+        // Inputs u32::MAX and 0 are processed.
+        predictor.reconcile(Some([0.0, 0.0, -1.0]), Some(0));
+        assert_eq!(
+            vec![1],
+            predictor
+                .inputs_to_send()
+                .iter()
+                .map(|i| i.seq_number)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn smooth_correction_test() {
+        let mut predictor = Predictor::new();
+        predictor.add_input(FRAME, forward());
+
+        // Server did not move us (blocked by something). Displayed position
+        // does not jump.
+        predictor.reconcile(None, Some(0));
+        assert!((predictor.position() - Vector3::new(0.0, 0.0, -0.5)).magnitude() < 1e-5);
+
+        for _ in 0..60 {
+            predictor.update(Duration::from_millis(16));
+        }
+        assert!(predictor.position().magnitude() < 1e-3);
+    }
+}
@@ -1,12 +1,80 @@
-use super::snapshot::DeltaSnapshot;
-use crate::camera::CameraDirection;
-use crate::scene::ClientCommand;
-use bytes::{Bytes, BytesMut};
-use rmp_serde::Serializer;
-use serde::{Deserialize, Serialize};
+use super::discovery::ServerAnnouncement;
+use super::packing::{PackedSnapshot, Quantization};
+use super::prediction::PlayerInput;
+use super::reliable::{Ack, ReliableMessage};
+use bytes::Bytes;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 use std::net::SocketAddr;
 
+/// First bytes of every packet. Anything else is not for us.
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"TWNP";
+
+/// Bump this every time a message or a serialized component changes. Client
+/// and server need the same version to talk to each other.
+pub const PROTOCOL_VERSION: u16 = 15;
+
+/// magic (4 bytes) + version (2 bytes, big endian) + message kind (1 byte).
+/// The body is MessagePack.
+pub const HEADER_SIZE: usize = 7;
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// Packet is smaller than the header.
+    PacketTooShort,
+    /// The packet does not start with PROTOCOL_MAGIC.
+    WrongMagic,
+    /// The header is valid but the remote runs another version of the
+    /// protocol. The body cannot be trusted, only the kind of message.
+    VersionMismatch {
+        version: u16,
+        kind: MessageKind,
+    },
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolError::VersionMismatch { version, kind } => write!(
+                f,
+                "Received {:?} with protocol version {} (ours is {})",
+                kind, version, PROTOCOL_VERSION
+            ),
+            ProtocolError::Encode(ref e) => write!(f, "Cannot encode packet: {}", e),
+            ProtocolError::Decode(ref e) => write!(f, "Cannot decode packet: {}", e),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for ProtocolError {
+    fn description(&self) -> &str {
+        match *self {
+            ProtocolError::PacketTooShort => "Packet is smaller than the protocol header",
+            ProtocolError::WrongMagic => "Packet does not start with the protocol magic",
+            ProtocolError::VersionMismatch { .. } => "Protocol version mismatch",
+            ProtocolError::Encode(_) => "Cannot encode packet",
+            ProtocolError::Decode(_) => "Cannot decode packet",
+        }
+    }
+}
+
+impl From<rmp_serde::encode::Error> for ProtocolError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        ProtocolError::Encode(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for ProtocolError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        ProtocolError::Decode(e)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NetMessage {
     pub target: SocketAddr,
@@ -15,11 +83,11 @@ pub struct NetMessage {
 
 impl NetMessage {
     /// return the message ready to be sent. Consume the object.
-    pub fn pack(self) -> Result<(Bytes, SocketAddr), serde_json::error::Error> {
+    pub fn pack(self) -> Result<(Bytes, SocketAddr), ProtocolError> {
         Ok((serialize(self.content)?, self.target))
     }
 
-    pub fn unpack(buf: Bytes, target: SocketAddr) -> Result<NetMessage, serde_json::error::Error> {
+    pub fn unpack(buf: Bytes, target: SocketAddr) -> Result<NetMessage, ProtocolError> {
         Ok(NetMessage {
             content: deserialize(buf)?,
             target,
@@ -32,8 +100,26 @@ pub struct Packet {
     pub seq_number: u32,
     // Only matter on client>erver side. Should we remove from here and put in NetMessageContent
     // instead?
-    pub last_known_state: Option<u8>,
+    pub last_known_state: Option<u32>,
     pub content: NetMessageContent,
+
+    // Reliable messages and acks of the remote's reliable messages. They can
+    // be attached to any packet.
+    pub reliable: Vec<ReliableMessage>,
+    pub acks: Vec<Ack>,
+}
+
+impl Packet {
+    /// Packet without reliable messages or acks.
+    pub fn new(seq_number: u32, last_known_state: Option<u32>, content: NetMessageContent) -> Self {
+        Packet {
+            seq_number,
+            last_known_state,
+            content,
+            reliable: Vec::new(),
+            acks: Vec::new(),
+        }
+    }
 }
 
 // Here we define all the messages that travel around client and servers.
@@ -43,23 +129,39 @@ pub enum NetMessageContent {
     // -----------------------------------
     // NETWORK LOGIC LEVEL
     // -----------------------------------
-    // Client sends that to the server.
-    ConnectionRequest,
+    // Client sends that to the server, with the private data of its connect
+    // token if the server needs one. See crypto.rs
+    ConnectionRequest(Option<Vec<u8>>),
+
+    // Server answers with a token, and the client sends it back to prove
+    // that it receives packets at its address. See handshake.rs
+    Challenge(u64),
+    ChallengeResponse(u64),
 
     // Server answers by accept or refuse
-    ConnectionAccepted,
-    ConnectionRefused,
+    ConnectionAccepted(ServerInfo),
+    ConnectionRefused(RefuseReason),
 
     Ping,
 
+    // LAN discovery, outside of any connection. The request is padded with
+    // zeros to be bigger than the answer. See discovery.rs
+    DiscoveryRequest(Vec<u8>),
+    DiscoveryResponse(ServerAnnouncement),
+
+    // Either side leaves. Not reliable: if it is lost, the remote will
+    // time out.
+    Disconnect(DisconnectReason),
+
     // ----------------------------------
     // GAME LOGIC LEVEL
     // ----------------------------------
     // contain the server state.
     Delta(DeltaSnapshotInfo),
 
-    // Command from the client.
-    Command(ClientCommand),
+    // Inputs from the client. The last unacked inputs are sent again in case
+    // a packet is lost.
+    Input(Vec<PlayerInput>),
 
     // ----------------------------------
     // FOR DEBUGGING
@@ -67,20 +169,388 @@ pub enum NetMessageContent {
     Text(String),
 }
 
+impl NetMessageContent {
+    pub fn kind(&self) -> MessageKind {
+        match *self {
+            NetMessageContent::ConnectionRequest(_) => MessageKind::ConnectionRequest,
+            NetMessageContent::Challenge(_) => MessageKind::Challenge,
+            NetMessageContent::ChallengeResponse(_) => MessageKind::ChallengeResponse,
+            NetMessageContent::ConnectionAccepted(_) => MessageKind::ConnectionAccepted,
+            NetMessageContent::ConnectionRefused(_) => MessageKind::ConnectionRefused,
+            NetMessageContent::Ping => MessageKind::Ping,
+            NetMessageContent::DiscoveryRequest(_) => MessageKind::DiscoveryRequest,
+            NetMessageContent::DiscoveryResponse(_) => MessageKind::DiscoveryResponse,
+            NetMessageContent::Disconnect(_) => MessageKind::Disconnect,
+            NetMessageContent::Delta(_) => MessageKind::Delta,
+            NetMessageContent::Input(_) => MessageKind::Input,
+            NetMessageContent::Text(_) => MessageKind::Text,
+        }
+    }
+}
+
+/// Kind of message, written in the header so that it can be read even
+/// when the body cannot. Values of ConnectionRequest and ConnectionRefused
+/// should never change, otherwise different versions cannot tell each other
+/// they are different.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MessageKind {
+    ConnectionRequest = 0,
+    ConnectionAccepted = 1,
+    ConnectionRefused = 2,
+    Ping = 3,
+    Delta = 4,
+    Input = 5,
+    Text = 6,
+    Disconnect = 7,
+    // Piece of a packet too big for the MTU, see fragment.rs
+    Fragment = 8,
+    Challenge = 9,
+    ChallengeResponse = 10,
+    DiscoveryRequest = 11,
+    DiscoveryResponse = 12,
+    Unknown = 255,
+}
+
+impl MessageKind {
+    pub fn from_u8(b: u8) -> MessageKind {
+        match b {
+            0 => MessageKind::ConnectionRequest,
+            1 => MessageKind::ConnectionAccepted,
+            2 => MessageKind::ConnectionRefused,
+            3 => MessageKind::Ping,
+            4 => MessageKind::Delta,
+            5 => MessageKind::Input,
+            6 => MessageKind::Text,
+            7 => MessageKind::Disconnect,
+            8 => MessageKind::Fragment,
+            9 => MessageKind::Challenge,
+            10 => MessageKind::ChallengeResponse,
+            11 => MessageKind::DiscoveryRequest,
+            12 => MessageKind::DiscoveryResponse,
+            _ => MessageKind::Unknown,
+        }
+    }
+}
+
+/// Why the server did not let the client in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RefuseReason {
+    ServerFull,
+    VersionMismatch {
+        server: u16,
+        client: u16,
+    },
+    /// The server needs a connect token signed by the matchmaker.
+    InvalidConnectToken,
+}
+
+impl fmt::Display for RefuseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RefuseReason::ServerFull => write!(f, "Server is full"),
+            RefuseReason::VersionMismatch { server, client } => write!(
+                f,
+                "Server runs protocol version {} but client runs version {}",
+                server, client
+            ),
+            RefuseReason::InvalidConnectToken => {
+                write!(f, "Connect token is missing, invalid or expired")
+            }
+        }
+    }
+}
+
+/// Why a client left the game.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    /// The player quit the game.
+    Quit,
+    Kicked,
+    ServerShutdown,
+    /// Nothing was received for too long.
+    Timeout,
+    /// The last state known by the client is not in the server history
+    /// anymore.
+    OutOfSync,
+    /// The client does not have the level of the server, or a different
+    /// version of it.
+    LevelMismatch,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisconnectReason::Quit => write!(f, "Player left the game"),
+            DisconnectReason::Kicked => write!(f, "Kicked by the server"),
+            DisconnectReason::ServerShutdown => write!(f, "Server was shut down"),
+            DisconnectReason::Timeout => write!(f, "Connection timed out"),
+            DisconnectReason::OutOfSync => write!(f, "Client is too far behind the server"),
+            DisconnectReason::LevelMismatch => {
+                write!(f, "Level is missing or different from the server's")
+            }
+        }
+    }
+}
+
+/// What the client needs to know about the server to play.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// To decode the positions in the snapshots.
+    pub quantization: Quantization,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeltaSnapshotInfo {
-    pub old_state: Option<u8>,
-    pub new_state: u8,
-    pub delta: DeltaSnapshot,
+    /// Ticks of the states, see Snapshotter.
+    pub old_state: Option<u32>,
+    pub new_state: u32,
+    pub delta: PackedSnapshot,
+
+    /// A delta too big for one packet is split by entity. The client
+    /// applies it when it has all the parts.
+    pub part: u8,
+    pub nb_parts: u8,
+
+    /// Last input of the client that was applied before the snapshot.
+    pub last_input: Option<u32>,
+
+    /// When the snapshot was taken, in seconds since the server started.
+    pub server_time: f64,
+}
+
+/// Read the header only. Returns the version and the kind of message.
+pub fn read_header(bytes: &[u8]) -> Result<(u16, MessageKind), ProtocolError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(ProtocolError::PacketTooShort);
+    }
+
+    if bytes[0..4] != PROTOCOL_MAGIC {
+        return Err(ProtocolError::WrongMagic);
+    }
+
+    let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+    Ok((version, MessageKind::from_u8(bytes[6])))
 }
 
-pub fn deserialize(bytes: Bytes) -> Result<Packet, serde_json::error::Error> {
-    serde_json::from_slice::<Packet>(&bytes.to_vec())
+pub fn deserialize(bytes: Bytes) -> Result<Packet, ProtocolError> {
+    let (version, kind) = read_header(&bytes)?;
+    if version != PROTOCOL_VERSION {
+        return Err(ProtocolError::VersionMismatch { version, kind });
+    }
+
+    Ok(rmp_serde::from_slice::<Packet>(&bytes[HEADER_SIZE..])?)
 }
 
-pub fn serialize(msg: Packet) -> Result<Bytes, serde_json::error::Error> {
-    //let mut b = Vec::new();
-    let b = serde_json::to_vec(&msg)?;
-    //msg.serialize(&mut Serializer::new(&mut b))?;
+pub fn write_header(b: &mut Vec<u8>, kind: MessageKind) {
+    b.extend_from_slice(&PROTOCOL_MAGIC);
+    b.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    b.push(kind as u8);
+}
+
+pub fn serialize(msg: Packet) -> Result<Bytes, ProtocolError> {
+    let mut b = Vec::with_capacity(64);
+    write_header(&mut b, msg.content.kind());
+    rmp_serde::encode::write(&mut b, &msg)?;
     Ok(b.into())
 }
+
+/// Keep track of the size of the packets that go through a socket, per kind
+/// of message.
+#[derive(Debug, Default)]
+pub struct PacketSizes {
+    // count, total bytes and biggest packet.
+    sizes: HashMap<MessageKind, (usize, usize, usize)>,
+}
+
+impl PacketSizes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, kind: MessageKind, size: usize) {
+        let entry = self.sizes.entry(kind).or_insert((0, 0, 0));
+        entry.0 += 1;
+        entry.1 += size;
+        entry.2 = entry.2.max(size);
+    }
+
+    /// Record a packet that is already serialized.
+    pub fn record_bytes(&mut self, bytes: &[u8]) {
+        if let Ok((_, kind)) = read_header(bytes) {
+            self.record(kind, bytes.len());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.sizes.clear();
+    }
+
+    /// One line per message kind: count, average and max size in bytes.
+    pub fn report(&self) -> String {
+        let mut kinds: Vec<_> = self.sizes.iter().collect();
+        kinds.sort_by_key(|(kind, _)| **kind);
+
+        kinds
+            .iter()
+            .map(|(kind, (count, total, max))| {
+                format!(
+                    "{:?}: {} packets, avg {} bytes, max {} bytes",
+                    kind,
+                    count,
+                    total / count,
+                    max
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::CameraDirection;
+    use crate::net::reliable::ReliableContent;
+    use crate::scene::ClientCommand;
+
+    fn input_packet() -> Packet {
+        Packet::new(
+            42,
+            Some(3),
+            NetMessageContent::Input(vec![PlayerInput {
+                seq_number: 12,
+                dt: 0.016,
+                commands: vec![ClientCommand::Move(CameraDirection::Forward)],
+            }]),
+        )
+    }
+
+    #[test]
+    fn roundtrip_test() {
+        let mut packet = input_packet();
+        packet.reliable.push(ReliableMessage {
+            channel: 1,
+            seq_number: 7,
+            content: ReliableContent::Text("hi".to_string()),
+        });
+        packet.acks.push((2, 3));
+        let bytes = serialize(packet).unwrap();
+        assert_eq!(&PROTOCOL_MAGIC, &bytes[0..4]);
+        assert_eq!(MessageKind::Input as u8, bytes[6]);
+
+        let packet = deserialize(bytes).unwrap();
+        assert_eq!(42, packet.seq_number);
+        assert_eq!(Some(3), packet.last_known_state);
+        assert_eq!(7, packet.reliable[0].seq_number);
+        assert_eq!(vec![(2, 3)], packet.acks);
+        match packet.content {
+            NetMessageContent::Input(ref inputs) => {
+                assert_eq!(12, inputs[0].seq_number);
+                assert_eq!(
+                    vec![ClientCommand::Move(CameraDirection::Forward)],
+                    inputs[0].commands
+                );
+            }
+            c => panic!("Unexpected content {:?}", c),
+        }
+    }
+
+    #[test]
+    fn smaller_than_json_test() {
+        let packet = input_packet();
+        let json = serde_json::to_vec(&packet).unwrap();
+        let binary = serialize(packet).unwrap();
+        assert!(binary.len() < json.len());
+    }
+
+    #[test]
+    fn wrong_magic_test() {
+        let mut bytes = serialize(input_packet()).unwrap().to_vec();
+        bytes[0] = b'X';
+        match deserialize(bytes.into()) {
+            Err(ProtocolError::WrongMagic) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        match deserialize(Bytes::from(&b"TWN"[..])) {
+            Err(ProtocolError::PacketTooShort) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn version_mismatch_test() {
+        let mut bytes = serialize(Packet::new(
+            0,
+            None,
+            NetMessageContent::ConnectionRequest(None),
+        ))
+        .unwrap()
+        .to_vec();
+        bytes[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+
+        match deserialize(bytes.into()) {
+            Err(ProtocolError::VersionMismatch { version, kind }) => {
+                assert_eq!(PROTOCOL_VERSION + 1, version);
+                assert_eq!(MessageKind::ConnectionRequest, kind);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn disconnect_test() {
+        let packet = Packet::new(
+            3,
+            None,
+            NetMessageContent::Disconnect(DisconnectReason::Kicked),
+        );
+        let bytes = serialize(packet).unwrap();
+        assert_eq!(MessageKind::Disconnect as u8, bytes[6]);
+
+        match deserialize(bytes).unwrap().content {
+            NetMessageContent::Disconnect(reason) => assert_eq!(DisconnectReason::Kicked, reason),
+            c => panic!("Unexpected content {:?}", c),
+        }
+    }
+
+    #[test]
+    fn packed_delta_test() {
+        let packed = PackedSnapshot(vec![0, 1, 2, 255]);
+        let packet = Packet::new(
+            1,
+            None,
+            NetMessageContent::Delta(DeltaSnapshotInfo {
+                old_state: None,
+                new_state: 4,
+                delta: packed.clone(),
+                part: 0,
+                nb_parts: 1,
+                last_input: None,
+                server_time: 0.5,
+            }),
+        );
+        let bytes = serialize(packet).unwrap();
+
+        match deserialize(bytes).unwrap().content {
+            NetMessageContent::Delta(info) => assert_eq!(packed, info.delta),
+            c => panic!("Unexpected content {:?}", c),
+        }
+    }
+
+    #[test]
+    fn packet_sizes_test() {
+        let mut sizes = PacketSizes::new();
+        sizes.record(MessageKind::Ping, 10);
+        sizes.record(MessageKind::Ping, 20);
+        sizes.record_bytes(&serialize(input_packet()).unwrap());
+
+        let report = sizes.report();
+        assert!(report.contains("Ping: 2 packets, avg 15 bytes, max 20 bytes"));
+        assert!(report.contains("Input: 1 packets"));
+    }
+}
@@ -0,0 +1,183 @@
+// Interest management: which entities are sent to which player.
+//
+// Sending the whole world to everybody does not scale with the size of the
+// map. Each snapshot only contains the entities that are relevant to the
+// player. When an entity stops being relevant, the client is told to delete
+// it; when it becomes relevant again, it is sent in full like a new entity.
+//
+// The rules are evaluated on the states kept by the Snapshotter, for the old
+// and the new state, so they should only depend on the ECS they are given.
+use std::collections::HashSet;
+
+use cgmath::InnerSpace;
+
+use crate::ecs::{Entity, ECS};
+
+/// Decides what a player receives. Game modes can provide their own rules
+/// with `NetworkSystem::set_relevancy_rules`.
+pub trait RelevancyRules: Send {
+    /// Should `entity` be sent to the player `viewer` in this state of the
+    /// world?
+    fn is_relevant(&self, ecs: &ECS, viewer: &Entity, entity: &Entity) -> bool;
+}
+
+/// Default rules. The RelevancyComponent of the entity is checked first:
+/// - owned entities are always sent to their owner, and only to it if
+///   `owner_only` is set;
+/// - team entities are only sent to the players of that team;
+/// - `always_relevant` entities are sent whatever the distance.
+///
+/// Other entities are sent if they are closer than `radius` to the player.
+/// Entities without a position cannot be filtered so they are always sent.
+#[derive(Debug, Clone, Copy)]
+pub struct DistanceRelevancy {
+    /// None to send everything.
+    pub radius: Option<f32>,
+}
+
+impl DistanceRelevancy {
+    pub fn new(radius: Option<f32>) -> Self {
+        DistanceRelevancy { radius }
+    }
+}
+
+impl RelevancyRules for DistanceRelevancy {
+    fn is_relevant(&self, ecs: &ECS, viewer: &Entity, entity: &Entity) -> bool {
+        if entity == viewer {
+            return true;
+        }
+
+        if let Some(relevancy) = ecs.components.relevancies.get(entity) {
+            let owned = relevancy.owner.as_ref() == Some(viewer);
+            if owned {
+                return true;
+            }
+            if relevancy.owner_only {
+                return false;
+            }
+
+            if let Some(team) = relevancy.team {
+                let viewer_team = ecs.components.players.get(viewer).map(|p| p.team);
+                if viewer_team != Some(team) {
+                    return false;
+                }
+            }
+
+            if relevancy.always_relevant {
+                return true;
+            }
+        }
+
+        match self.radius {
+            Some(radius) => {
+                let transforms = &ecs.components.transforms;
+                match (transforms.get(viewer), transforms.get(entity)) {
+                    (Some(v), Some(e)) => (e.position - v.position).magnitude2() <= radius * radius,
+                    _ => true,
+                }
+            }
+            None => true,
+        }
+    }
+}
+
+/// Live entities of the ECS that should be sent to the player.
+pub fn relevant_entities(rules: &RelevancyRules, ecs: &ECS, viewer: &Entity) -> HashSet<Entity> {
+    ecs.nb_entities()
+        .into_iter()
+        .filter(|e| rules.is_relevant(ecs, viewer, e))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::components::{PlayerComponent, RelevancyComponent, TransformComponent};
+    use cgmath::Vector3;
+
+    fn entity_at(ecs: &mut ECS, x: f32) -> Entity {
+        let entity = ecs.new_entity();
+        ecs.components.transforms.set(
+            &entity,
+            TransformComponent {
+                position: Vector3::new(x, 0.0, 0.0),
+                ..TransformComponent::default()
+            },
+        );
+        entity
+    }
+
+    #[test]
+    fn radius_test() {
+        let mut ecs = ECS::new();
+        let player = entity_at(&mut ecs, 0.0);
+        let close = entity_at(&mut ecs, 5.0);
+        let far = entity_at(&mut ecs, 50.0);
+        let no_position = ecs.new_entity();
+
+        let relevant = relevant_entities(&DistanceRelevancy::new(Some(10.0)), &ecs, &player);
+        assert!(relevant.contains(&player));
+        assert!(relevant.contains(&close));
+        assert!(!relevant.contains(&far));
+        assert!(relevant.contains(&no_position));
+
+        let relevant = relevant_entities(&DistanceRelevancy::new(None), &ecs, &player);
+        assert!(relevant.contains(&far));
+    }
+
+    #[test]
+    fn relevancy_component_test() {
+        let mut ecs = ECS::new();
+        let player = entity_at(&mut ecs, 0.0);
+        ecs.components.players.set(
+            &player,
+            PlayerComponent {
+                team: 1,
+                ..PlayerComponent::default()
+            },
+        );
+        let other_player = entity_at(&mut ecs, 1.0);
+
+        let far_but_global = entity_at(&mut ecs, 50.0);
+        ecs.components.relevancies.set(
+            &far_but_global,
+            RelevancyComponent {
+                always_relevant: true,
+                ..RelevancyComponent::default()
+            },
+        );
+
+        let far_but_mine = entity_at(&mut ecs, 50.0);
+        let private = entity_at(&mut ecs, 1.0);
+        for e in &[far_but_mine, private] {
+            ecs.components.relevancies.set(
+                e,
+                RelevancyComponent {
+                    owner: Some(player),
+                    owner_only: *e == private,
+                    ..RelevancyComponent::default()
+                },
+            );
+        }
+
+        let other_team = entity_at(&mut ecs, 1.0);
+        ecs.components.relevancies.set(
+            &other_team,
+            RelevancyComponent {
+                team: Some(0),
+                ..RelevancyComponent::default()
+            },
+        );
+
+        let rules = DistanceRelevancy::new(Some(10.0));
+        let relevant = relevant_entities(&rules, &ecs, &player);
+        assert!(relevant.contains(&far_but_global));
+        assert!(relevant.contains(&far_but_mine));
+        assert!(relevant.contains(&private));
+        assert!(!relevant.contains(&other_team));
+
+        let relevant = relevant_entities(&rules, &ecs, &other_player);
+        assert!(!relevant.contains(&far_but_mine));
+        assert!(!relevant.contains(&private));
+    }
+}
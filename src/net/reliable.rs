@@ -0,0 +1,381 @@
+// Reliable messages on top of the unreliable packets.
+//
+// Each reliable message has a channel and a sequence number in that channel.
+// The messages are attached to the next outgoing packets until the remote
+// acknowledges them. Acks are attached to outgoing packets as well, so there
+// is no packet just for reliability.
+//
+// On the receiving side, messages are delivered in order for each channel.
+// A message that arrives too early is kept until the missing ones are there.
+// Messages too far ahead, or that would not fit in the buffers, are dropped
+// without ack: the sender sends them again later.
+use super::chat::{ChatLine, ChatRequest};
+use super::level::LevelInfo;
+use super::sequence::sequence_diff;
+use log::{debug, error, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+pub type ChannelId = u8;
+
+/// Channel to use when the order with other messages does not matter.
+pub const DEFAULT_CHANNEL: ChannelId = 0;
+
+/// When an unacked message is sent again.
+pub const RESEND_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// To keep the packets small. Other messages will wait for the next packet.
+pub const MAX_RELIABLE_PER_PACKET: usize = 16;
+
+/// Messages further ahead than that in their channel are dropped. Also the
+/// number of early messages kept for each channel.
+pub const RECEIVE_WINDOW: usize = 256;
+
+/// Messages sent and not acked yet. A remote that does not ack would make
+/// them pile up, so new messages are dropped past that and the channels are
+/// marked as overflowed.
+pub const MAX_PENDING_MESSAGES: usize = 1024;
+
+/// Acks waiting for the next outgoing packet. Messages received when it is
+/// full are dropped.
+pub const MAX_PENDING_ACKS: usize = 256;
+
+/// Content of the reliable messages. Gameplay messages that must arrive
+/// go there.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ReliableContent {
+    Text(String),
+
+    // Chat, see chat.rs
+    ChatRequest(ChatRequest),
+    ChatLine(ChatLine),
+
+    // Asset name used in the snapshots, see packing.rs
+    AssetName { id: u32, name: String },
+
+    // Level changes, see level.rs. The server says what to load, the client
+    // answers with the hash of its copy once it is loaded.
+    LoadLevel(LevelInfo),
+    LevelReady { id: u32, hash: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReliableMessage {
+    pub channel: ChannelId,
+    pub seq_number: u32,
+    pub content: ReliableContent,
+}
+
+/// Identifies a reliable message: channel and sequence number.
+pub type Ack = (ChannelId, u32);
+
+#[derive(Debug, Clone)]
+struct PendingMessage {
+    message: ReliableMessage,
+    last_sent: Option<Instant>,
+}
+
+/// Reliable state for one remote (the server for a client, each client for
+/// the server).
+#[derive(Debug, Clone)]
+pub struct ReliableChannels {
+    resend_timeout: Duration,
+
+    // Sending side.
+    next_seq_numbers: HashMap<ChannelId, u32>,
+    pending: Vec<PendingMessage>,
+    overflowed: bool,
+
+    // Receiving side.
+    next_expected: HashMap<ChannelId, u32>,
+    early: HashMap<ChannelId, BTreeMap<u32, ReliableContent>>,
+    to_ack: Vec<Ack>,
+}
+
+impl Default for ReliableChannels {
+    fn default() -> Self {
+        ReliableChannels::new(RESEND_TIMEOUT)
+    }
+}
+
+impl ReliableChannels {
+    pub fn new(resend_timeout: Duration) -> Self {
+        Self {
+            resend_timeout,
+            next_seq_numbers: HashMap::new(),
+            pending: Vec::new(),
+            overflowed: false,
+            next_expected: HashMap::new(),
+            early: HashMap::new(),
+            to_ack: Vec::new(),
+        }
+    }
+
+    /// Queue a message. It will be attached to the next outgoing packets
+    /// until it is acked. Dropped if MAX_PENDING_MESSAGES are already
+    /// waiting, see `is_overflowed`.
+    pub fn send(&mut self, channel: ChannelId, content: ReliableContent) {
+        if self.pending.len() >= MAX_PENDING_MESSAGES {
+            error!(
+                "{} reliable messages are not acked, drop message on channel {}",
+                self.pending.len(),
+                channel
+            );
+            self.overflowed = true;
+            return;
+        }
+
+        let seq_number = self.next_seq_numbers.entry(channel).or_insert(0);
+        self.pending.push(PendingMessage {
+            message: ReliableMessage {
+                channel,
+                seq_number: *seq_number,
+                content,
+            },
+            last_sent: None,
+        });
+        *seq_number = seq_number.wrapping_add(1);
+    }
+
+    /// Number of messages that have not been acked yet.
+    pub fn nb_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Was a message dropped because the remote does not ack? The order of
+    /// the messages is broken, so the connection should be closed.
+    pub fn is_overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// Messages and acks to attach to the packet that is about to be sent.
+    /// Messages that were sent less than `resend_timeout` ago are not sent
+    /// again.
+    pub fn outgoing(&mut self, now: Instant) -> (Vec<ReliableMessage>, Vec<Ack>) {
+        let resend_timeout = self.resend_timeout;
+        let messages = self
+            .pending
+            .iter_mut()
+            .filter(|p| match p.last_sent {
+                Some(last_sent) => now.duration_since(last_sent) >= resend_timeout,
+                None => true,
+            })
+            .take(MAX_RELIABLE_PER_PACKET)
+            .map(|p| {
+                if p.last_sent.is_some() {
+                    debug!(
+                        "Resend reliable message {}/{}",
+                        p.message.channel, p.message.seq_number
+                    );
+                }
+                p.last_sent = Some(now);
+                p.message.clone()
+            })
+            .collect();
+
+        let acks = self.to_ack.drain(..).collect();
+        (messages, acks)
+    }
+
+    /// Process the reliable part of an incoming packet. Returns the messages
+    /// that can be delivered, in order for each channel.
+    pub fn receive(
+        &mut self,
+        messages: Vec<ReliableMessage>,
+        acks: &[Ack],
+    ) -> Vec<(ChannelId, ReliableContent)> {
+        self.pending
+            .retain(|p| !acks.contains(&(p.message.channel, p.message.seq_number)));
+
+        let mut delivered = Vec::new();
+        for message in messages {
+            let channel = message.channel;
+            if self.to_ack.len() >= MAX_PENDING_ACKS {
+                debug!(
+                    "Too many acks to send, drop message {}/{}",
+                    channel, message.seq_number
+                );
+                continue;
+            }
+
+            let next_expected = self.next_expected.entry(channel).or_insert(0);
+            let diff = sequence_diff(message.seq_number, *next_expected);
+            if diff >= RECEIVE_WINDOW as i32 {
+                debug!(
+                    "Message {}/{} is too far ahead of {}, drop it",
+                    channel, message.seq_number, next_expected
+                );
+                continue;
+            }
+
+            // Always ack, even duplicates. The previous ack might have been lost.
+            self.to_ack.push((channel, message.seq_number));
+            if diff < 0 {
+                continue;
+            }
+
+            // In the window, so there is room for it.
+            let early = self.early.entry(channel).or_insert_with(BTreeMap::new);
+            early.insert(message.seq_number, message.content);
+
+            while let Some(content) = early.remove(next_expected) {
+                delivered.push((channel, content));
+                *next_expected = next_expected.wrapping_add(1);
+            }
+
+            if !early.is_empty() {
+                warn!(
+                    "Channel {} waits for message {} ({} early messages)",
+                    channel,
+                    next_expected,
+                    early.len()
+                );
+            }
+        }
+
+        delivered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> ReliableContent {
+        ReliableContent::Text(s.to_string())
+    }
+
+    #[test]
+    fn resend_until_ack_test() {
+        let mut sender = ReliableChannels::new(Duration::from_millis(100));
+        let now = Instant::now();
+        sender.send(DEFAULT_CHANNEL, text("hello"));
+
+        let (messages, _) = sender.outgoing(now);
+        assert_eq!(1, messages.len());
+
+        // Not timed out yet.
+        let (messages, _) = sender.outgoing(now + Duration::from_millis(50));
+        assert!(messages.is_empty());
+
+        // First one is lost. Send it again.
+        let (messages, _) = sender.outgoing(now + Duration::from_millis(100));
+        assert_eq!(1, messages.len());
+
+        sender.receive(vec![], &[(DEFAULT_CHANNEL, 0)]);
+        assert_eq!(0, sender.nb_pending());
+        let (messages, _) = sender.outgoing(now + Duration::from_millis(500));
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn ordered_delivery_test() {
+        let mut sender = ReliableChannels::default();
+        let mut receiver = ReliableChannels::default();
+        sender.send(1, text("a"));
+        sender.send(1, text("b"));
+        sender.send(2, text("c"));
+
+        let (mut messages, _) = sender.outgoing(Instant::now());
+        let first = messages.remove(0);
+
+        // b and c arrive before a. c is on another channel so it does not wait.
+        let delivered = receiver.receive(messages.clone(), &[]);
+        assert_eq!(vec![(2, text("c"))], delivered);
+
+        let delivered = receiver.receive(vec![first.clone()], &[]);
+        assert_eq!(vec![(1, text("a")), (1, text("b"))], delivered);
+
+        // Duplicates are not delivered again but acked again.
+        let delivered = receiver.receive(vec![first], &[]);
+        assert!(delivered.is_empty());
+        let (_, acks) = receiver.outgoing(Instant::now());
+        assert_eq!(vec![(1, 1), (2, 0), (1, 0), (1, 0)], acks);
+
+        sender.receive(vec![], &acks);
+        assert_eq!(0, sender.nb_pending());
+    }
+
+    #[test]
+    fn overflow_test() {
+        let mut sender = ReliableChannels::default();
+        for _ in 0..MAX_PENDING_MESSAGES {
+            sender.send(DEFAULT_CHANNEL, text("hello"));
+        }
+        assert!(!sender.is_overflowed());
+
+        // Never acked.
+        sender.send(DEFAULT_CHANNEL, text("dropped"));
+        assert!(sender.is_overflowed());
+        assert_eq!(MAX_PENDING_MESSAGES, sender.nb_pending());
+    }
+
+    #[test]
+    fn receive_window_test() {
+        let mut receiver = ReliableChannels::default();
+        let message = |channel, seq_number| ReliableMessage {
+            channel,
+            seq_number,
+            content: text("far"),
+        };
+
+        // Too far ahead: neither kept nor acked.
+        let far = RECEIVE_WINDOW as u32;
+        assert!(receiver.receive(vec![message(1, far)], &[]).is_empty());
+        assert!(receiver.early.get(&1).map_or(true, |e| e.is_empty()));
+        let (_, acks) = receiver.outgoing(Instant::now());
+        assert!(acks.is_empty());
+
+        // The whole window can wait for the first message.
+        let early: Vec<_> = (1..far).map(|s| message(1, s)).collect();
+        assert!(receiver.receive(early, &[]).is_empty());
+        assert_eq!(RECEIVE_WINDOW - 1, receiver.early[&1].len());
+        receiver.outgoing(Instant::now());
+
+        // Acks are not kept forever if no packet is sent.
+        for channel in 2..10 {
+            let messages: Vec<_> = (0..100).map(|s| message(channel, s)).collect();
+            receiver.receive(messages, &[]);
+        }
+        let (_, acks) = receiver.outgoing(Instant::now());
+        assert_eq!(MAX_PENDING_ACKS, acks.len());
+
+        let delivered = receiver.receive(vec![message(1, 0)], &[]);
+        assert_eq!(RECEIVE_WINDOW, delivered.len());
+        assert!(receiver.early[&1].is_empty());
+    }
+
+    #[test]
+    fn wraparound_test() {
+        let mut sender = ReliableChannels::default();
+        let mut receiver = ReliableChannels::default();
+        let start = u32::max_value() - 1;
+        sender.next_seq_numbers.insert(1, start);
+        receiver.next_expected.insert(1, start);
+
+        for s in &["a", "b", "c", "d"] {
+            sender.send(1, text(s));
+        }
+        let (messages, _) = sender.outgoing(Instant::now());
+        assert_eq!(
+            vec![start, start + 1, 0, 1],
+            messages.iter().map(|m| m.seq_number).collect::<Vec<_>>()
+        );
+
+        // Delivered in order across the wrap, old ones are still duplicates.
+        let delivered = receiver.receive(vec![messages[2].clone(), messages[3].clone()], &[]);
+        assert!(delivered.is_empty());
+        let delivered = receiver.receive(messages.clone(), &[]);
+        assert_eq!(
+            vec![
+                (1, text("a")),
+                (1, text("b")),
+                (1, text("c")),
+                (1, text("d"))
+            ],
+            delivered
+        );
+        assert!(receiver.receive(messages, &[]).is_empty());
+    }
+}
@@ -0,0 +1,484 @@
+// Replication of the components to the clients.
+//
+// A component is sent to the clients when it implements `Replicated`. The
+// `replicate!` macro implements it from the list of fields to send, with how
+// each field is encoded in the snapshots:
+//
+//     replicate!(TransformComponent, 0, {
+//         position: Position,
+//         rotation: Rotation,
+//         scale: Vec3,
+//     });
+//
+// The number is the id of the component in the snapshots, so it should not
+// change. Fields are sent when they changed. Fields marked `(WithOthers)`
+// are never compared; they are sent with the other fields of the component,
+// e.g. the animation timers that advance on both sides.
+//
+// The component also has to be in the `replicated_components!` list of
+// ecs/mod.rs. The snapshots then diff and apply it with the other ones.
+use cgmath::{InnerSpace, Vector3};
+use serde_derive::{Deserialize, Serialize};
+
+/// Id of a replicated component in the snapshots.
+pub type ComponentId = u8;
+
+/// Vectors closer than that (squared distance) are considered equal.
+const EPSILON: f32 = 0.00001;
+
+/// How a field is encoded in the snapshots. See packing.rs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    /// Quantized inside the level bounds.
+    Position,
+    /// Euler angles, sent as a quaternion.
+    Rotation,
+    Vec3,
+    F32,
+    Bool,
+    /// Small integers and enums.
+    Varint,
+    /// Asset name. The clients receive each name once.
+    Name,
+    OptionalName,
+    /// Any string.
+    Text,
+}
+
+impl FieldKind {
+    /// Can a value of this kind be encoded?
+    pub fn accepts(self, value: &FieldValue) -> bool {
+        match (self, value) {
+            (FieldKind::Position, FieldValue::Vec3(_))
+            | (FieldKind::Rotation, FieldValue::Vec3(_))
+            | (FieldKind::Vec3, FieldValue::Vec3(_))
+            | (FieldKind::F32, FieldValue::F32(_))
+            | (FieldKind::Bool, FieldValue::Bool(_))
+            | (FieldKind::Varint, FieldValue::Int(_))
+            | (FieldKind::Name, FieldValue::Str(_))
+            | (FieldKind::Text, FieldValue::Str(_))
+            | (FieldKind::OptionalName, FieldValue::OptionalStr(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// When a field is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Diff {
+    /// When it changed.
+    Changed,
+    /// When another field of the component is sent.
+    WithOthers,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FieldInfo {
+    pub name: &'static str,
+    pub kind: FieldKind,
+    pub diff: Diff,
+}
+
+/// Value of a replicated field.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FieldValue {
+    Vec3([f32; 3]),
+    F32(f32),
+    Bool(bool),
+    Int(u64),
+    Str(String),
+    OptionalStr(Option<String>),
+}
+
+impl FieldValue {
+    pub fn as_vec3(&self) -> Option<[f32; 3]> {
+        match *self {
+            FieldValue::Vec3(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Types of the replicated fields.
+pub trait FieldType: Sized {
+    fn to_value(&self) -> FieldValue;
+
+    /// None if the value has another type.
+    fn from_value(value: FieldValue) -> Option<Self>;
+
+    /// Should the new value be sent?
+    fn differs(&self, old: &Self) -> bool;
+}
+
+impl FieldType for Vector3<f32> {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Vec3((*self).into())
+    }
+
+    fn from_value(value: FieldValue) -> Option<Self> {
+        match value {
+            FieldValue::Vec3(v) => Some(v.into()),
+            _ => None,
+        }
+    }
+
+    fn differs(&self, old: &Self) -> bool {
+        (self - old).magnitude2() > EPSILON
+    }
+}
+
+impl FieldType for [f32; 3] {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Vec3(*self)
+    }
+
+    fn from_value(value: FieldValue) -> Option<Self> {
+        match value {
+            FieldValue::Vec3(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn differs(&self, old: &Self) -> bool {
+        Vector3::from(*self).differs(&Vector3::from(*old))
+    }
+}
+
+impl FieldType for f32 {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::F32(*self)
+    }
+
+    fn from_value(value: FieldValue) -> Option<Self> {
+        match value {
+            FieldValue::F32(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn differs(&self, old: &Self) -> bool {
+        self != old
+    }
+}
+
+impl FieldType for bool {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Bool(*self)
+    }
+
+    fn from_value(value: FieldValue) -> Option<Self> {
+        match value {
+            FieldValue::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn differs(&self, old: &Self) -> bool {
+        self != old
+    }
+}
+
+impl FieldType for u8 {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Int(u64::from(*self))
+    }
+
+    fn from_value(value: FieldValue) -> Option<Self> {
+        match value {
+            FieldValue::Int(v) if v <= u64::from(u8::max_value()) => Some(v as u8),
+            _ => None,
+        }
+    }
+
+    fn differs(&self, old: &Self) -> bool {
+        self != old
+    }
+}
+
+impl FieldType for u32 {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Int(u64::from(*self))
+    }
+
+    fn from_value(value: FieldValue) -> Option<Self> {
+        match value {
+            FieldValue::Int(v) if v <= u64::from(u32::max_value()) => Some(v as u32),
+            _ => None,
+        }
+    }
+
+    fn differs(&self, old: &Self) -> bool {
+        self != old
+    }
+}
+
+impl FieldType for String {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Str(self.clone())
+    }
+
+    fn from_value(value: FieldValue) -> Option<Self> {
+        match value {
+            FieldValue::Str(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn differs(&self, old: &Self) -> bool {
+        self != old
+    }
+}
+
+impl FieldType for Option<String> {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::OptionalStr(self.clone())
+    }
+
+    fn from_value(value: FieldValue) -> Option<Self> {
+        match value {
+            FieldValue::OptionalStr(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn differs(&self, old: &Self) -> bool {
+        self != old
+    }
+}
+
+/// A component that is sent to the clients. Implemented by `replicate!`.
+pub trait Replicated: Default {
+    const ID: ComponentId;
+
+    /// Replicated fields. At most 64.
+    const FIELDS: &'static [FieldInfo];
+
+    fn field(&self, index: usize) -> FieldValue;
+
+    /// False if there is no such field or the value has the wrong type.
+    fn set_field(&mut self, index: usize, value: FieldValue) -> bool;
+
+    /// Compare a field with the one of `old`.
+    fn field_differs(&self, old: &Self, index: usize) -> bool;
+}
+
+/// Changes of one component of an entity.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ComponentDelta {
+    pub component: ComponentId,
+
+    /// Index of the field in `Replicated::FIELDS` and its new value, by
+    /// increasing index.
+    pub fields: Vec<(u8, FieldValue)>,
+}
+
+impl ComponentDelta {
+    pub fn field(&self, index: usize) -> Option<&FieldValue> {
+        self.fields
+            .iter()
+            .find(|(i, _)| *i as usize == index)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Fields of `new` to send to a client that has `old`. All of them if the
+/// client does not have the component. None if nothing changed.
+pub fn component_delta<T: Replicated>(old: Option<&T>, new: &T) -> Option<ComponentDelta> {
+    let mut send: Vec<bool> = (0..T::FIELDS.len())
+        .map(|i| match (old, T::FIELDS[i].diff) {
+            (None, _) => true,
+            (Some(old), Diff::Changed) => new.field_differs(old, i),
+            (Some(_), Diff::WithOthers) => false,
+        })
+        .collect();
+
+    if !send.iter().any(|s| *s) {
+        return None;
+    }
+    for (i, field) in T::FIELDS.iter().enumerate() {
+        if field.diff == Diff::WithOthers {
+            send[i] = true;
+        }
+    }
+
+    Some(ComponentDelta {
+        component: T::ID,
+        fields: (0..T::FIELDS.len())
+            .filter(|i| send[*i])
+            .map(|i| (i as u8, new.field(i)))
+            .collect(),
+    })
+}
+
+/// Set the fields of the delta. Returns false if a field is unknown or has
+/// the wrong type; the other fields are still applied.
+pub fn apply_component_delta<T: Replicated>(component: &mut T, delta: &ComponentDelta) -> bool {
+    let mut valid = true;
+    for (index, value) in delta.fields.iter() {
+        valid &= component.set_field(*index as usize, value.clone());
+    }
+    valid
+}
+
+/// Implement `Replicated` for a component. See the top of the module.
+#[macro_export]
+macro_rules! replicate {
+    (@diff) => { $crate::net::replication::Diff::Changed };
+    (@diff $diff:ident) => { $crate::net::replication::Diff::$diff };
+    ($component:ty, $id:expr, { $($field:ident: $kind:ident $(($diff:ident))*,)+ }) => {
+        impl $crate::net::replication::Replicated for $component {
+            const ID: $crate::net::replication::ComponentId = $id;
+
+            const FIELDS: &'static [$crate::net::replication::FieldInfo] = &[
+                $(
+                    $crate::net::replication::FieldInfo {
+                        name: stringify!($field),
+                        kind: $crate::net::replication::FieldKind::$kind,
+                        diff: $crate::replicate!(@diff $($diff)*),
+                    },
+                )+
+            ];
+
+            #[allow(unused_assignments)]
+            fn field(&self, index: usize) -> $crate::net::replication::FieldValue {
+                let mut i = 0;
+                $(
+                    if i == index {
+                        return $crate::net::replication::FieldType::to_value(&self.$field);
+                    }
+                    i += 1;
+                )+
+                panic!("{} has no replicated field {}", stringify!($component), index)
+            }
+
+            #[allow(unused_assignments)]
+            fn set_field(
+                &mut self,
+                index: usize,
+                value: $crate::net::replication::FieldValue,
+            ) -> bool {
+                let mut i = 0;
+                $(
+                    if i == index {
+                        return match $crate::net::replication::FieldType::from_value(value) {
+                            Some(v) => {
+                                self.$field = v;
+                                true
+                            }
+                            None => false,
+                        };
+                    }
+                    i += 1;
+                )+
+                false
+            }
+
+            #[allow(unused_assignments)]
+            fn field_differs(&self, old: &Self, index: usize) -> bool {
+                let mut i = 0;
+                $(
+                    if i == index {
+                        return $crate::net::replication::FieldType::differs(
+                            &self.$field,
+                            &old.$field,
+                        );
+                    }
+                    i += 1;
+                )+
+                false
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Default, PartialEq)]
+    struct TestComponent {
+        position: [f32; 3],
+        name: String,
+        timer: f32,
+        local: u32,
+    }
+
+    replicate!(TestComponent, 42, {
+        position: Position,
+        name: Name,
+        timer: F32(WithOthers),
+    });
+
+    fn component() -> TestComponent {
+        TestComponent {
+            position: [1.0, 2.0, 3.0],
+            name: "cube".to_string(),
+            timer: 0.5,
+            local: 7,
+        }
+    }
+
+    #[test]
+    fn fields_test() {
+        assert_eq!(42, TestComponent::ID);
+        let names: Vec<_> = TestComponent::FIELDS.iter().map(|f| f.name).collect();
+        assert_eq!(vec!["position", "name", "timer"], names);
+        assert_eq!(Diff::WithOthers, TestComponent::FIELDS[2].diff);
+        for (i, field) in TestComponent::FIELDS.iter().enumerate() {
+            assert!(field.kind.accepts(&component().field(i)));
+        }
+    }
+
+    #[test]
+    fn full_delta_test() {
+        let delta = component_delta(None, &component()).unwrap();
+        assert_eq!(3, delta.fields.len());
+
+        let mut client = TestComponent::default();
+        assert!(apply_component_delta(&mut client, &delta));
+        assert_eq!(
+            TestComponent {
+                local: 0,
+                ..component()
+            },
+            client
+        );
+    }
+
+    #[test]
+    fn changed_fields_test() {
+        let old = component();
+        let mut new = component();
+
+        // Timers are not compared.
+        new.timer = 2.0;
+        new.position[0] += 0.0001;
+        assert_eq!(None, component_delta(Some(&old), &new));
+
+        new.name = "sphere".to_string();
+        let delta = component_delta(Some(&old), &new).unwrap();
+        assert_eq!(
+            vec![
+                (1, FieldValue::Str("sphere".to_string())),
+                (2, FieldValue::F32(2.0))
+            ],
+            delta.fields
+        );
+        assert_eq!(Some(&FieldValue::F32(2.0)), delta.field(2));
+    }
+
+    #[test]
+    fn wrong_field_test() {
+        let mut client = TestComponent::default();
+        let delta = ComponentDelta {
+            component: 42,
+            fields: vec![
+                (0, FieldValue::Bool(true)),
+                (1, FieldValue::Str("a".to_string())),
+                (9, FieldValue::F32(1.0)),
+            ],
+        };
+        assert!(!apply_component_delta(&mut client, &delta));
+        assert_eq!("a", client.name);
+    }
+}
@@ -0,0 +1,42 @@
+// Sequence numbers that wrap around.
+//
+// Packets, inputs, reliable messages and snapshots are numbered with u32 that
+// wrap after u32::MAX. Comparing them with `>` breaks at the wrap: 1 comes
+// after u32::MAX. Here a number is newer than another one if it is less than
+// half of the range ahead of it.
+
+/// Signed distance from `b` to `a`. Positive if `a` is newer than `b`.
+pub fn sequence_diff(a: u32, b: u32) -> i32 {
+    a.wrapping_sub(b) as i32
+}
+
+/// Is `a` strictly newer than `b`?
+pub fn is_newer(a: u32, b: u32) -> bool {
+    sequence_diff(a, b) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_newer_test() {
+        assert!(is_newer(2, 1));
+        assert!(!is_newer(1, 2));
+        assert!(!is_newer(1, 1));
+        assert_eq!(-3, sequence_diff(7, 10));
+    }
+
+    #[test]
+    fn wraparound_test() {
+        let max = u32::max_value();
+        assert!(is_newer(0, max));
+        assert!(is_newer(5, max - 5));
+        assert!(!is_newer(max, 0));
+        assert_eq!(11, sequence_diff(5, max - 5));
+
+        // Too far ahead is considered as behind.
+        assert!(is_newer(1 << 30, 0));
+        assert!(!is_newer((1 << 31) + 1, 0));
+    }
+}
@@ -1,132 +1,47 @@
-use bytes::{Bytes, BytesMut};
-use futures::sync::mpsc as futmpsc;
+use bytes::Bytes;
 use log::{debug, error, info, trace, warn};
-use std::io;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use tokio::net::{UdpFramed, UdpSocket};
-use tokio::prelude::stream::{SplitSink, SplitStream};
-use tokio::prelude::*;
-use tokio_codec::BytesCodec;
+use std::time::{Duration, Instant};
 
+use super::conditioner::{LinkConditioner, LinkConditions};
+use super::crypto::{self, open_connect_token, unix_time, Session, UsedTokens};
+use super::discovery::{ServerAnnouncement, DISCOVERY_PADDING};
+use super::fragment::DEFAULT_MTU;
+use super::handshake::CHALLENGE_WINDOW;
+use super::handshake::{Challenges, ConnectionLimiter};
+use super::level::{Level, LevelInfo};
+use super::packing::{encode_snapshot_parts, AssetNames, Quantization};
 use super::protocol;
-use super::protocol::{DeltaSnapshotInfo, Packet};
-use std::thread;
-
-use std::sync::mpsc as stdmpsc;
+use super::protocol::{
+    DeltaSnapshotInfo, DisconnectReason, MessageKind, Packet, RefuseReason, ServerInfo,
+};
+use super::relevancy::{DistanceRelevancy, RelevancyRules};
+use super::reliable::{ChannelId, ReliableChannels, ReliableContent, DEFAULT_CHANNEL};
+use super::sequence::is_newer;
+use super::stats::{NetworkStats, StatsTracker};
+use super::transport::{Transport, UdpTransport};
 
-use super::NetworkError;
+use super::{NetworkError, TimeoutSettings};
 use crate::camera::CameraDirection;
 use crate::collections::OptionArray;
+use crate::config::ServerConfig;
 use crate::ecs::{
-    components::{ModelComponent, PlayerComponent, TransformComponent},
+    components::{PlayerComponent, TransformComponent},
+    systems::player_model,
     Entity, ECS,
 };
 use crate::event::{Event, GameEvent};
-use crate::net::snapshot::{DeltaSnapshot, SnapshotError, Snapshotter};
-use crate::sync::SharedDeque;
+use crate::net::snapshot::{SnapshotError, Snapshotter};
+use crate::time::dt_as_secs;
 use cgmath::Vector3;
 
-pub fn start_serving(
-    port: usize,
-) -> Result<
-    (
-        SharedDeque<protocol::NetMessage>,
-        stdmpsc::Sender<protocol::NetMessage>,
-    ),
-    Box<std::error::Error>,
-> {
-    info!("Start serving on {}", port);
-    // interfaces
-    let net_to_game = SharedDeque::new(1024);
-    let mut net_to_game_clone = net_to_game.clone();
-    let (int_tx, int_rx) = futmpsc::channel(1024);
-    let (tx, rx) = stdmpsc::channel();
-    let int_rx = int_rx.map_err(|_| panic!("Error not possible on rx"));
-
-    thread::spawn(move || read_channel(int_tx, rx));
-
-    let async_stuff = connect(port, Box::new(int_rx))?;
-    thread::spawn(move || {
-        tokio::run(
-            async_stuff
-                .for_each(move |(buf, client)| {
-                    match protocol::NetMessage::unpack(buf.into(), client) {
-                        Ok(unpacked) => net_to_game_clone.push(unpacked),
-                        Err(e) => {
-                            error!(
-                                "Received malformed message from {}, error = {:?}",
-                                client, e
-                            );
-                        }
-                    }
+/// How often the statistics of each client are written to the log.
+const STATS_REPORT_INTERVAL: Duration = Duration::from_secs(10);
 
-                    Ok(())
-                })
-                .map_err(|e| error!("{:?}", e)),
-        );
-    });
-
-    Ok((net_to_game, tx))
-}
-
-/// Will create the futures that will run in tokio runtime.
-fn connect(
-    port: usize,
-    game_to_net: Box<Stream<Item = (Bytes, SocketAddr), Error = io::Error> + Send>,
-) -> Result<
-    Box<Stream<Item = (BytesMut, SocketAddr), Error = io::Error> + Send>,
-    Box<std::error::Error>,
-> {
-    let addr = format!("127.0.0.1:{}", port).parse()?;
-    let socket = UdpSocket::bind(&addr)?;
-
-    let (sink, stream) = UdpFramed::new(socket, BytesCodec::new()).split();
-
-    // All bytes from `game_to_net` will go to the `addr` specified in our
-    // argument list. Like with TCP this is spawned concurrently
-    let forward = game_to_net.forward(sink).then(|result| {
-        if let Err(e) = result {
-            error!("failed to write to socket: {}", e)
-        }
-        Ok(())
-    });
-
-    let all_futs = Box::new(
-        future::lazy(|| {
-            tokio::spawn(forward);
-            future::ok(stream)
-        })
-        .flatten_stream(),
-    );
-
-    Ok(all_futs)
-}
-
-fn read_channel(
-    mut tx: futmpsc::Sender<(Bytes, SocketAddr)>,
-    rx: stdmpsc::Receiver<protocol::NetMessage>,
-) {
-    loop {
-        let d = rx.recv().unwrap();
-
-        // if cannot serialize here, we have a problem...
-        let packed = d
-            .pack()
-            .map_err(|e| {
-                error!("Error when unpacking in `read_channel` = {:?}", e);
-                e
-            })
-            .unwrap();
-
-        tx = match tx.send(packed).wait() {
-            Ok(tx) => tx,
-            Err(e) => {
-                error!("Error in read_channel = {:?}", e);
-                break;
-            }
-        }
-    }
-}
+/// Room left in a packet for the reliable messages when a snapshot is
+/// split.
+const SNAPSHOT_PART_MARGIN: usize = 200;
 
 // State of each clients
 #[derive(Debug, Clone)]
@@ -134,75 +49,336 @@ struct Client {
     // IP/Port
     addr: SocketAddr,
 
-    // Index in the snapshot circular buffer
+    // Tick of the last state received by the client
     // None is hasn't received information yet
-    last_state: Option<u8>,
+    last_state: Option<u32>,
 
-    // Incremented nb that is sent in the packet
+    // Incremented nb that is sent in the packet. They wrap around.
     last_rec_seq_number: u32,
     last_sent_seq_number: u32,
 
+    // Set when something else than a ChallengeResponse is received.
+    established: bool,
+
     // The entity in the server ECS associated to this client
     entity: Option<Entity>,
+
+    // Reliable messages to and from this client.
+    reliable: ReliableChannels,
+
+    // Sequence number of the last input applied to the player.
+    last_input: Option<u32>,
+
+    // Last time a valid packet was received from/sent to this client. Used
+    // for timeouts and heartbeats.
+    last_heard: Instant,
+    last_sent: Instant,
+
+    // Set when the client confirmed it loaded the current level. Snapshots
+    // and inputs wait for it.
+    level_ready: bool,
+
+    stats: StatsTracker,
+}
+
+impl Client {
+    fn new(addr: SocketAddr, request_seq_number: u32) -> Self {
+        Client {
+            addr,
+            last_rec_seq_number: request_seq_number,
+            last_sent_seq_number: 0,
+            established: false,
+            last_state: None,
+            entity: None,
+            reliable: ReliableChannels::default(),
+            last_input: None,
+            last_heard: Instant::now(),
+            last_sent: Instant::now(),
+            level_ready: false,
+            stats: StatsTracker::new(Instant::now()),
+        }
+    }
+
+    /// Names that are used in the snapshots, for a client that starts.
+    fn send_asset_names(&mut self, names: &AssetNames) {
+        for (id, name) in names.all() {
+            self.reliable
+                .send(DEFAULT_CHANNEL, ReliableContent::AssetName { id, name });
+        }
+    }
+
+    /// Tell the client to load a level. It gets no snapshot until it is done.
+    fn send_level(&mut self, level: &LevelInfo) {
+        self.level_ready = false;
+        self.reliable
+            .send(DEFAULT_CHANNEL, ReliableContent::LoadLevel(level.clone()));
+    }
+}
+
+/// Add the entity of a new player to the world.
+fn spawn_player(ecs: &mut ECS, i: usize) -> Entity {
+    let entity = ecs.new_entity();
+    ecs.components.transforms.set(
+        &entity,
+        TransformComponent {
+            position: Vector3::new(0.0, 1.0, 0.0),
+            rotation: Vector3::new(0.0, 0.0, 0.0),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        },
+    );
+    let (model, animator) = player_model();
+    ecs.components.models.set(&entity, model);
+    ecs.components.animators.set(&entity, animator);
+    // Two teams for now.
+    ecs.components.players.set(
+        &entity,
+        PlayerComponent {
+            team: (i % 2) as u8,
+            ..PlayerComponent::default()
+        },
+    );
+    entity
 }
 
 /// The network system is the ECS system that will be called in the main loop.
 /// it should provide events and allow to send messages.
 pub struct NetworkSystem {
-    from_clients: SharedDeque<protocol::NetMessage>,
-    to_clients: std::sync::mpsc::Sender<protocol::NetMessage>,
+    // Datagrams to and from the clients. Those of the clients that do not
+    // speak our protocol version are dropped when received.
+    transport: Box<Transport>,
+
+    // Simulated network conditions, between the game and the queues.
+    incoming_link: LinkConditioner<(Bytes, SocketAddr)>,
+    outgoing_link: LinkConditioner<(Bytes, SocketAddr)>,
+
+    // Key shared with the matchmaker. Without it, anybody can connect and
+    // the packets are not encrypted.
+    connect_key: Option<crypto::Key>,
+
+    // Keys of each address that sent a valid connect token, with when the
+    // token was received. Those that do not become clients are dropped.
+    sessions: HashMap<SocketAddr, (Session, Instant)>,
+
+    // Tokens that started a session. They are refused until they expire,
+    // even once their session is gone.
+    used_tokens: UsedTokens,
 
     my_clients: OptionArray<Client>,
 
+    // New clients have to echo a challenge before they get a slot. Attempts
+    // are limited per address.
+    challenges: Challenges,
+    connection_limiter: ConnectionLimiter,
+
     snapshotter: Snapshotter,
+
+    // Which entities are sent to each player.
+    relevancy: Box<RelevancyRules>,
+
+    // How the snapshots are encoded. Asset names are sent once, then only
+    // their id is used.
+    quantization: Quantization,
+    asset_names: AssetNames,
+
+    // Snapshots bigger than that are split by entity.
+    max_snapshot_size: usize,
+
+    // Told to the server browsers of the LAN.
+    name: String,
+
+    // Level the clients must load. States older than `level_tick` were taken
+    // in the previous level.
+    level: LevelInfo,
+    level_tick: u32,
+
+    // Snapshots are timestamped from there.
+    start: Instant,
+
+    last_stats_report: Instant,
+
+    timeouts: TimeoutSettings,
+
+    // Players removed since the last poll_events. They are returned as
+    // events so that other systems can clean up.
+    disconnected: Vec<(Entity, DisconnectReason)>,
 }
 
 impl NetworkSystem {
-    pub fn new(port: usize, max_clients: usize) -> Self {
-        let (from_clients, to_clients) = start_serving(port).unwrap();
+    /// Serve on UDP, at the address of the configuration.
+    pub fn new(config: &ServerConfig) -> Self {
+        // The clients send with the default MTU.
+        let transport = UdpTransport::bind(config.bind_address, config.mtu, DEFAULT_MTU).unwrap();
+        NetworkSystem::with_transport(config, Box::new(transport))
+    }
 
-        let my_clients = OptionArray::new(max_clients);
+    /// Serve on another transport, e.g. a loopback for the clients of the
+    /// same process. The bind address and MTU of the configuration are not
+    /// used.
+    pub fn with_transport(config: &ServerConfig, transport: Box<Transport>) -> Self {
+        info!("Start serving on {}", transport.local_addr());
+        let my_clients = OptionArray::new(config.max_players);
+        if !config.link_conditions.is_perfect() {
+            warn!("Simulate network conditions: {:?}", config.link_conditions);
+        }
+
+        crypto::init();
+        let connect_key = config.connect_key_file.as_ref().map(|path| {
+            info!("Clients need a connect token signed with {}", path);
+            crypto::load_key(path).expect("Cannot read the connect key")
+        });
+        if connect_key.is_none() {
+            warn!("No connect key: anybody can connect and packets are not encrypted");
+        }
 
         Self {
             //server,
-            to_clients,
-            from_clients,
+            transport,
+            incoming_link: LinkConditioner::new(config.link_conditions, config.link_seed),
+            outgoing_link: LinkConditioner::new(
+                config.link_conditions,
+                config.link_seed.wrapping_add(1),
+            ),
+            connect_key,
+            sessions: HashMap::new(),
+            used_tokens: UsedTokens::new(),
             my_clients,
-            snapshotter: Snapshotter::new(60),
+            challenges: Challenges::new(),
+            connection_limiter: ConnectionLimiter::new(config.connection_attempts_per_sec),
+            snapshotter: Snapshotter::new(config.snapshot_ring_size),
+            relevancy: Box::new(DistanceRelevancy::new(config.relevancy_radius)),
+            quantization: config.quantization,
+            asset_names: AssetNames::new(),
+            max_snapshot_size: config.mtu - SNAPSHOT_PART_MARGIN,
+            name: config.name.clone(),
+            level: Level::empty().info(0),
+            level_tick: u32::max_value(),
+            start: Instant::now(),
+            last_stats_report: Instant::now(),
+            timeouts: config.timeouts(),
+            disconnected: Vec::new(),
         }
     }
 
+    /// Address of the transport. The actual port when the server was bound
+    /// to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.transport.local_addr()
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: TimeoutSettings) {
+        self.timeouts = timeouts;
+    }
+
+    /// Change the simulated network, in both directions.
+    pub fn set_link_conditions(&mut self, conditions: LinkConditions) {
+        self.incoming_link.set_conditions(conditions);
+        self.outgoing_link.set_conditions(conditions);
+    }
+
+    /// Replace the rules that decide which entities are sent to each player,
+    /// e.g. for a game mode. The default rules only send the entities around
+    /// the player.
+    pub fn set_relevancy_rules(&mut self, rules: Box<RelevancyRules>) {
+        self.relevancy = rules;
+    }
+
     /// Will get the latest events that were sent to the server
     /// For example, player commands and so on.
     ///
     /// Returns a list of events generated by a player
     pub fn poll_events(&mut self, ecs: &mut ECS) -> Vec<(Entity, Event)> {
-        let events = self.from_clients.drain();
+        let now = Instant::now();
+        for (bytes, addr) in self.transport.receive() {
+            if self.check_version(&bytes, addr) {
+                self.incoming_link.push((bytes, addr), now);
+            }
+        }
+        let datagrams = self.incoming_link.pop_ready(now);
+        self.send_ready(now);
 
         let mut game_events = vec![];
 
-        for ev in events {
+        for (bytes, addr) in datagrams {
+            let size = bytes.len();
+            let ev = match self.unpack(bytes, addr) {
+                Some(ev) => ev,
+                None => continue,
+            };
             trace!("Network system received {:?}", ev);
-            if let protocol::NetMessageContent::ConnectionRequest = ev.content.content {
-                self.handle_connection_request(ev.target, ecs);
+            if let protocol::NetMessageContent::ConnectionRequest(token) = ev.content.content {
+                self.handle_connection_request(ev.target, token);
+            } else if let protocol::NetMessageContent::ChallengeResponse(token) = ev.content.content
+            {
+                self.handle_challenge_response(ev.target, ev.content.seq_number, token, ecs);
+            } else if let protocol::NetMessageContent::DiscoveryRequest(padding) =
+                ev.content.content
+            {
+                self.handle_discovery_request(ev.target, padding.len());
             } else {
                 // if the client is known, send OK, else send connection refused. Update
                 // the last known state so that we send the correct thing in snapshots.
                 if let Some(index) = self.get_client_id(ev.target) {
                     let client = self.my_clients.get_mut(index).unwrap();
+                    client.stats.on_received(ev.content.seq_number, size, now);
 
                     // Discard out of order.
-                    if client.last_rec_seq_number >= ev.content.seq_number {
+                    if !is_newer(ev.content.seq_number, client.last_rec_seq_number) {
+                        client.stats.on_out_of_order();
                         error!("Receive packet out of order for {}: last_rec_seq_number {} >= packet.seq_number {}", ev.target, client.last_rec_seq_number, ev.content.seq_number);
                     } else {
-                        client.last_state = ev.content.last_known_state;
-                        client.last_rec_seq_number = ev.content.seq_number;
+                        let mut packet = ev.content;
+                        if let Some(state) = packet.last_known_state {
+                            client.stats.probe_acked(state, now);
+                        }
+                        // The states of the previous level are gone from
+                        // the snapshotter.
+                        let level_tick = self.level_tick;
+                        client.last_state = packet
+                            .last_known_state
+                            .filter(|state| is_newer(*state, level_tick));
+                        client.last_rec_seq_number = packet.seq_number;
+                        client.established = true;
+                        client.last_heard = Instant::now();
+                        let entity = client.entity.unwrap().clone();
+
+                        let mut level_mismatch = false;
+                        let reliable = std::mem::replace(&mut packet.reliable, Vec::new());
+                        for (channel, content) in client.reliable.receive(reliable, &packet.acks) {
+                            match content {
+                                ReliableContent::LevelReady { id, hash } => {
+                                    if id != self.level.id {
+                                        debug!("Player {} loaded a previous level", index);
+                                    } else if hash != self.level.hash {
+                                        warn!(
+                                            "Player {} has another version of {}: hash {:016x}",
+                                            index, self.level.name, hash
+                                        );
+                                        level_mismatch = true;
+                                    } else {
+                                        info!("Player {} loaded {}", index, self.level.name);
+                                        client.level_ready = true;
+                                    }
+                                }
+                                content => game_events
+                                    .push((entity, Event::ReliableMessage(channel, content))),
+                            }
+                        }
 
-                        // Now convert the message as an event that will be processed by the
-                        // engine (physics,... and so on).
-                        if let Some(ev) = NetworkSystem::handle_client_message(&client, ev.content)
+                        if level_mismatch {
+                            self.disconnect(index, DisconnectReason::LevelMismatch, ecs);
+                        } else if let protocol::NetMessageContent::Disconnect(reason) =
+                            packet.content
                         {
-                            game_events.push((client.entity.unwrap().clone(), ev));
+                            // No need to answer, the client is gone.
+                            self.remove_client(index, reason, ecs);
+                        } else if client.level_ready {
+                            // Inputs sent while loading would move a player
+                            // the client does not see yet.
+                            // Now convert the message as an event that will be processed by the
+                            // engine (physics,... and so on).
+                            for ev in NetworkSystem::handle_client_message(client, packet) {
+                                game_events.push((entity, ev));
+                            }
                         }
                     }
                 } else {
@@ -211,6 +387,51 @@ impl NetworkSystem {
             }
         }
 
+        // Drop the clients we have not heard from for a while.
+        let timeout = self.timeouts.timeout;
+        let timed_out: Vec<usize> = self
+            .my_clients
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| {
+                c.as_ref()
+                    .map_or(false, |c| c.last_heard.elapsed() > timeout)
+            })
+            .map(|(i, _)| i)
+            .collect();
+        for i in timed_out {
+            self.disconnect(i, DisconnectReason::Timeout, ecs);
+        }
+
+        // Those that do not ack the reliable messages lost some of them.
+        let overflowed: Vec<usize> = self
+            .my_clients
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.as_ref().map_or(false, |c| c.reliable.is_overflowed()))
+            .map(|(i, _)| i)
+            .collect();
+        for i in overflowed {
+            warn!("Player {} does not ack its reliable messages", i);
+            self.disconnect(i, DisconnectReason::OutOfSync, ecs);
+        }
+
+        // Forget the sessions of the tokens that were never used to finish
+        // the handshake.
+        let clients = &self.my_clients;
+        self.sessions.retain(|addr, (_, since)| {
+            since.elapsed() < CHALLENGE_WINDOW * 2
+                || clients.iter().flatten().any(|c| c.addr == *addr)
+        });
+
+        for (entity, reason) in self.disconnected.drain(..) {
+            game_events.push((entity, Event::PlayerDisconnected(reason)));
+        }
+
+        for client in self.my_clients.iter_mut().flatten() {
+            client.stats.update(now);
+        }
+
         game_events
     }
 
@@ -218,27 +439,73 @@ impl NetworkSystem {
     pub fn send_state(&mut self, ecs: &mut ECS) {
         // First take a snapshot.
         self.snapshotter.set_current(ecs);
+        let server_time = dt_as_secs(self.start.elapsed());
+        let now = Instant::now();
 
         let mut to_disconnect = Vec::new();
+        let mut snapshots = Vec::new();
         for i in 0..self.my_clients.len() {
             if let Some(client) = self.my_clients.get_mut(i) {
+                // The client could not apply it before it loads the level.
+                if !client.level_ready {
+                    continue;
+                }
                 let player_entity = client.entity.as_ref().unwrap();
-                let delta_res = if let Some(idx) = client.last_state {
-                    self.snapshotter.get_delta(idx as usize, player_entity)
+                let delta_res = if let Some(tick) = client.last_state {
+                    self.snapshotter
+                        .get_delta(tick, player_entity, &*self.relevancy)
                 } else {
-                    self.snapshotter.get_full_snapshot(player_entity)
+                    self.snapshotter
+                        .get_full_snapshot(player_entity, &*self.relevancy)
                 };
 
                 match delta_res {
                     Ok(delta) => {
                         debug!("STATE: to player {:?} = {:?}", i, delta);
-                        let msg = protocol::NetMessageContent::Delta(DeltaSnapshotInfo {
-                            delta,
-                            old_state: client.last_state,
-                            // Don't worry it is ok for now :D
-                            new_state: self.snapshotter.get_current_index() as u8,
-                        });
-                        self.send_to_client(i, msg);
+                        let parts = encode_snapshot_parts(
+                            &delta,
+                            &self.quantization,
+                            &mut self.asset_names,
+                            self.max_snapshot_size,
+                        );
+                        // Whole, it would be too big to be fragmented. Part
+                        // of it would be acknowledged as the whole state.
+                        // The client keeps its state; if it stays behind
+                        // for too long, it is out of sync.
+                        if parts.len() > usize::from(u8::max_value()) {
+                            error!(
+                                "Snapshot to player {} needs {} parts, do not send it",
+                                i,
+                                parts.len()
+                            );
+                            continue;
+                        }
+                        let size = parts.iter().map(|p| p.0.len()).sum::<usize>();
+                        trace!(
+                            "Snapshot to player {}: {} entities in {} parts, {} bytes",
+                            i,
+                            delta.deltas.len(),
+                            parts.len(),
+                            size
+                        );
+                        client.stats.on_snapshot(size);
+                        client
+                            .stats
+                            .probe_sent(self.snapshotter.get_current_tick(), now);
+
+                        let nb_parts = parts.len() as u8;
+                        for (part, packed) in parts.into_iter().enumerate() {
+                            let msg = protocol::NetMessageContent::Delta(DeltaSnapshotInfo {
+                                delta: packed,
+                                part: part as u8,
+                                nb_parts,
+                                old_state: client.last_state,
+                                new_state: self.snapshotter.get_current_tick(),
+                                last_input: client.last_input,
+                                server_time,
+                            });
+                            snapshots.push((i, msg));
+                        }
                     }
                     Err(SnapshotError::ClientCaughtUp) => {
                         info!("To disconnect!");
@@ -249,80 +516,304 @@ impl NetworkSystem {
             }
         }
 
+        // The names used for the first time must go with the snapshots that
+        // use them.
+        for (id, name) in self.asset_names.take_new() {
+            self.broadcast_reliable(DEFAULT_CHANNEL, ReliableContent::AssetName { id, name });
+        }
+        for (i, msg) in snapshots {
+            self.send_to_client(i, msg);
+        }
+
         for i in to_disconnect {
-            info!("Will disconnect player {}", i);
-            if let Some(c) = self.my_clients.remove(i) {
-                debug!("Will remove player {}", i);
-                if let Some(entity) = c.entity {
-                    ecs.delete_entity(&entity);
+            self.disconnect(i, DisconnectReason::OutOfSync, ecs);
+        }
+
+        // Clients that did not receive a snapshot still need to know the
+        // server is alive.
+        let heartbeat = self.timeouts.heartbeat;
+        for i in 0..self.my_clients.len() {
+            let idle = self
+                .my_clients
+                .get(i)
+                .map_or(false, |c| c.last_sent.elapsed() >= heartbeat);
+            if idle {
+                self.send_to_client(i, protocol::NetMessageContent::Ping);
+            }
+        }
+
+        if self.last_stats_report.elapsed() >= STATS_REPORT_INTERVAL {
+            self.last_stats_report = now;
+            for (i, client) in self.my_clients.iter().enumerate() {
+                if let Some(client) = client {
+                    info!("Player {} ({}): {}", i, client.addr, client.stats.stats());
                 }
-            } else {
-                error!("Could not remove player {}", i);
             }
         }
     }
 
-    fn handle_client_message(_client: &Client, packet: Packet) -> Option<Event> {
+    /// The level the clients are told to load.
+    pub fn level(&self) -> &LevelInfo {
+        &self.level
+    }
+
+    /// Switch to another level during the game. `ecs` is the world of the
+    /// new level, already loaded: each player gets a new entity in it. The
+    /// clients load the level and receive snapshots again once they confirm.
+    /// Returns the previous and the new entity of each player.
+    pub fn change_level(&mut self, level: &Level, ecs: &mut ECS) -> Vec<(Entity, Entity)> {
+        info!("Change level to {} ({:016x})", level.name, level.hash);
+        self.level = level.info(self.level.id.wrapping_add(1));
+        self.level_tick = self.snapshotter.get_current_tick();
+        self.snapshotter.reset(ECS::new_from_existing(ecs));
+
+        let mut moved = Vec::new();
+        for i in 0..self.my_clients.len() {
+            if let Some(client) = self.my_clients.get_mut(i) {
+                let entity = spawn_player(ecs, i);
+                if let Some(previous) = client.entity.replace(entity) {
+                    moved.push((previous, entity));
+                }
+                client.last_state = None;
+                client.send_level(&self.level);
+            }
+        }
+        moved
+    }
+
+    /// Statistics of the connection of a player.
+    pub fn stats(&self, player: &Entity) -> Option<NetworkStats> {
+        self.get_client_id_by_entity(player)
+            .and_then(|i| self.my_clients.get(i))
+            .map(|c| c.stats.stats())
+    }
+
+    /// Statistics of all the connected players.
+    pub fn all_stats(&self) -> Vec<(Entity, NetworkStats)> {
+        self.my_clients
+            .iter()
+            .flatten()
+            .filter_map(|c| c.entity.map(|entity| (entity, c.stats.stats())))
+            .collect()
+    }
+
+    /// Disconnect a player. The client is told it was kicked.
+    pub fn kick(&mut self, player: &Entity, ecs: &mut ECS) {
+        match self.get_client_id_by_entity(player) {
+            Some(i) => self.disconnect(i, DisconnectReason::Kicked, ecs),
+            None => warn!("Cannot kick {:?}: not a player", player),
+        }
+    }
+
+    /// Disconnect all the players. Should be called before stopping the
+    /// server so that the clients do not wait for the timeout.
+    pub fn shutdown(&mut self, ecs: &mut ECS) {
+        for i in 0..self.my_clients.len() {
+            if self.my_clients.get(i).is_some() {
+                self.disconnect(i, DisconnectReason::ServerShutdown, ecs);
+            }
+        }
+
+        // Do not wait for the simulated latency, the server is stopping.
+        for (bytes, addr) in self.outgoing_link.flush() {
+            if let Err(e) = self.transport.send(bytes, addr) {
+                error!("Error in shutdown = {:?}", e);
+            }
+        }
+    }
+
+    /// Tell the client why it is disconnected, then remove it. The message is
+    /// not reliable; if it is lost, the client will time out.
+    fn disconnect(&mut self, client_id: usize, reason: DisconnectReason, ecs: &mut ECS) {
+        self.send_to_client(client_id, protocol::NetMessageContent::Disconnect(reason));
+        self.remove_client(client_id, reason, ecs);
+    }
+
+    /// Forget about the client and delete its player entity.
+    fn remove_client(&mut self, client_id: usize, reason: DisconnectReason, ecs: &mut ECS) {
+        if let Some(c) = self.my_clients.remove(client_id) {
+            info!(
+                "Player {} ({}) is disconnected: {}",
+                client_id, c.addr, reason
+            );
+            if let Some(entity) = c.entity {
+                ecs.delete_entity(&entity);
+                self.disconnected.push((entity, reason));
+            }
+            // The Disconnect message is already encrypted.
+            self.sessions.remove(&c.addr);
+        } else {
+            error!("Could not remove player {}", client_id);
+        }
+    }
+
+    fn handle_client_message(client: &mut Client, packet: Packet) -> Vec<Event> {
         match packet.content {
-            protocol::NetMessageContent::Command(cmd) => Some(Event::ClientEvent(cmd)),
-            _ => None,
+            // Inputs can be received several times. Only keep the new ones.
+            protocol::NetMessageContent::Input(inputs) => {
+                let mut events = Vec::new();
+                for input in inputs {
+                    if client
+                        .last_input
+                        .map_or(true, |last| is_newer(input.seq_number, last))
+                    {
+                        client.last_input = Some(input.seq_number);
+                        events.push(Event::ClientInput(input));
+                    }
+                }
+                events
+            }
+            _ => Vec::new(),
         }
     }
 
-    /// This is called when a ConnectionRequest message is received
-    /// It will reply with either connection accepted or connection refused
-    /// and add the client to our map of clients.
+    /// This is called when a ConnectionRequest message is received. It
+    /// replies with a challenge and does not remember anything, so spoofed
+    /// requests cannot take the slots.
+    ///
+    /// With a connect key, the request has to carry a valid connect token.
+    /// The session keys it contains are used from now on for this address,
+    /// so the challenge is encrypted.
+    fn handle_connection_request(&mut self, addr: SocketAddr, token: Option<Vec<u8>>) {
+        if !self.connection_limiter.allow(addr.ip(), Instant::now()) {
+            debug!("Too many connection attempts from {}", addr);
+            return;
+        }
+
+        if let Some(key) = self.connect_key {
+            let token = match token {
+                Some(token) => token,
+                None => {
+                    info!("Refuse connection from {}: no connect token", addr);
+                    self.refuse(addr, RefuseReason::InvalidConnectToken);
+                    return;
+                }
+            };
+            let content = match open_connect_token(&token, &key, unix_time()) {
+                Ok(content) => content,
+                Err(e) => {
+                    info!("Refuse connection from {}: {}", addr, e);
+                    self.refuse(addr, RefuseReason::InvalidConnectToken);
+                    return;
+                }
+            };
+
+            // A token is for one address only.
+            let used_elsewhere = self
+                .sessions
+                .iter()
+                .any(|(a, (s, _))| *a != addr && s.client_id() == content.client_id);
+            if used_elsewhere {
+                warn!(
+                    "Connect token of client {} used by {}",
+                    content.client_id, addr
+                );
+                return;
+            }
+
+            // Requests are sent again until the challenge arrives. Keep the
+            // session so that its replay protection is not reset. A client
+            // that restarts needs a new token.
+            let same_token = self
+                .sessions
+                .get(&addr)
+                .map_or(false, |(s, _)| s.is_from_token(&content));
+            if !same_token {
+                // Its first session may be gone: disconnected, timed out or
+                // never finished. A new one would reuse the nonces.
+                if let Err(e) = self.used_tokens.insert(&token, &content, unix_time()) {
+                    info!("Refuse connection from {}: {}", addr, e);
+                    self.refuse(addr, RefuseReason::InvalidConnectToken);
+                    return;
+                }
+                self.sessions
+                    .insert(addr, (Session::from_token(&content), Instant::now()));
+            }
+        }
+
+        debug!("Send challenge to {}", addr);
+        let token = self.challenges.token(addr, Instant::now());
+        self.transmit(protocol::NetMessage {
+            target: addr,
+            content: Packet::new(0, None, protocol::NetMessageContent::Challenge(token)),
+        });
+    }
+
+    /// This is called when a client echoes its challenge. It will reply with
+    /// either connection accepted or connection refused and add the client to
+    /// our map of clients.
     ///
     /// If a client is already in the map, it should reply connection
     /// accepted. The reason is that the connection acception message
     /// might have been lost so the client thinks it is still trying to connect
-    fn handle_connection_request(&mut self, addr: SocketAddr, ecs: &mut ECS) {
+    ///
+    /// If the client already sent other messages, it is a new session from
+    /// the same address (the client restarted) so its sequence numbers start
+    /// again. The player entity is kept.
+    fn handle_challenge_response(
+        &mut self,
+        addr: SocketAddr,
+        seq_number: u32,
+        token: u64,
+        ecs: &mut ECS,
+    ) {
+        let now = Instant::now();
+        if !self.connection_limiter.allow(addr.ip(), now) {
+            debug!("Too many connection attempts from {}", addr);
+            return;
+        }
+        if !self.challenges.verify(addr, token, now) {
+            warn!("Invalid challenge response from {}", addr);
+            return;
+        }
+
         info!("Handle new connection request from {}", addr);
 
         let (to_send, client_id) = {
             if let Some(id) = self.get_client_id(addr) {
-                info!("Client was already connected, resend ConnectionAccepted");
-                (protocol::NetMessageContent::ConnectionAccepted, Some(id))
+                let client = self.my_clients.get_mut(id).unwrap();
+                if client.established {
+                    info!("Client {} reconnected, start a new session", addr);
+                    *client = Client {
+                        entity: client.entity,
+                        last_sent_seq_number: client.last_sent_seq_number,
+                        ..Client::new(addr, seq_number)
+                    };
+                    client.send_asset_names(&self.asset_names);
+                    client.send_level(&self.level);
+                } else {
+                    info!("Client was already connected, resend ConnectionAccepted");
+                }
+                (self.connection_accepted(), Some(id))
             } else {
                 // in that case we need to find an empty slot. If available,
                 // return connection accepted.
 
-                match self.my_clients.add(Client {
-                    addr,
-                    last_rec_seq_number: 0,
-                    last_sent_seq_number: 0,
-                    last_state: None,
-                    entity: None,
-                }) {
+                match self.my_clients.add(Client::new(addr, seq_number)) {
                     Some(i) => {
                         info!("New player connected: Player {}!", i);
 
                         // Now we have a new client, let's create a new player entity
                         // from the player template.
-                        let entity = ecs.new_entity();
-                        ecs.components.transforms.set(
-                            &entity,
-                            TransformComponent {
-                                position: Vector3::new(0.0, 1.0, 0.0),
-                                rotation: Vector3::new(0.0, 0.0, 0.0),
-                                scale: Vector3::new(1.0, 1.0, 1.0),
-                            },
-                        );
-                        ecs.components
-                            .models
-                            .set(&entity, ModelComponent::default());
-                        ecs.components
-                            .players
-                            .set(&entity, PlayerComponent::default());
+                        let entity = spawn_player(ecs, i);
                         debug!("Player {} entity is {:?}", i, entity);
 
-                        self.my_clients.get_mut(i).unwrap().entity = Some(entity);
-                        (protocol::NetMessageContent::ConnectionAccepted, Some(i))
+                        let client = self.my_clients.get_mut(i).unwrap();
+                        client.entity = Some(entity);
+                        // The names already known by the other clients.
+                        client.send_asset_names(&self.asset_names);
+                        client.send_level(&self.level);
+                        (self.connection_accepted(), Some(i))
                     }
 
                     None => {
                         info!("Too many clients connected, send ConnectionRefused");
-                        (protocol::NetMessageContent::ConnectionRefused, None)
+                        (
+                            protocol::NetMessageContent::ConnectionRefused(
+                                RefuseReason::ServerFull,
+                            ),
+                            None,
+                        )
                     }
                 }
             }
@@ -333,38 +824,219 @@ impl NetworkSystem {
             self.send_to_client(id, to_send);
         } else {
             // ConnectionRefused is sent to parties that are not client yet.
-            if let Err(e) = self.to_clients.send(protocol::NetMessage {
+            self.transmit(protocol::NetMessage {
                 target: addr,
-                content: Packet {
-                    content: to_send,
-                    seq_number: 0,
-                    last_known_state: None,
-                },
-            }) {
-                error!("Error when sending ConnectionRefused = {:?}", e);
-            };
+                content: Packet::new(0, None, to_send),
+            });
         }
     }
 
+    /// A server browser is looking for servers. Short requests could be
+    /// used to amplify an attack, so they are ignored.
+    fn handle_discovery_request(&mut self, addr: SocketAddr, padding: usize) {
+        if padding < DISCOVERY_PADDING {
+            debug!("Ignore discovery request of {}: not padded", addr);
+            return;
+        }
+        if !self.connection_limiter.allow(addr.ip(), Instant::now()) {
+            debug!("Too many discovery requests from {}", addr);
+            return;
+        }
+
+        let announcement = ServerAnnouncement::new(
+            &self.name,
+            &self.level.name,
+            self.my_clients.iter().flatten().count(),
+            self.my_clients.len(),
+        );
+        self.transmit(protocol::NetMessage {
+            target: addr,
+            content: Packet::new(
+                0,
+                None,
+                protocol::NetMessageContent::DiscoveryResponse(announcement),
+            ),
+        });
+    }
+
+    fn refuse(&mut self, addr: SocketAddr, reason: RefuseReason) {
+        self.transmit(protocol::NetMessage {
+            target: addr,
+            content: Packet::new(
+                0,
+                None,
+                protocol::NetMessageContent::ConnectionRefused(reason),
+            ),
+        });
+    }
+
+    fn connection_accepted(&self) -> protocol::NetMessageContent {
+        protocol::NetMessageContent::ConnectionAccepted(ServerInfo {
+            quantization: self.quantization,
+        })
+    }
+
     /// Should be used to send a message to a client. Will increase a sequence number.
     fn send_to_client(&mut self, client_id: usize, msg: protocol::NetMessageContent) {
         let client = self
             .my_clients
             .get_mut(client_id)
             .expect("Something wrong happend here");
+        let (reliable, acks) = client.reliable.outgoing(Instant::now());
         let to_send = protocol::NetMessage {
             target: client.addr,
             content: Packet {
                 content: msg,
                 seq_number: client.last_sent_seq_number,
                 last_known_state: None, // doesn't matter on server->client
+                reliable,
+                acks,
             },
         };
 
-        if let Err(e) = self.to_clients.send(to_send) {
-            error!("Error in send_to_client = {:?}", e);
+        client.last_sent_seq_number = client.last_sent_seq_number.wrapping_add(1);
+        client.last_sent = Instant::now();
+        let size = self.transmit(to_send);
+        if let Some(client) = self.my_clients.get_mut(client_id) {
+            client.stats.on_sent(size, Instant::now());
+        }
+    }
+
+    /// Serialize, encrypt with the session of the target if there is one,
+    /// and send through the link conditioner. Returns the size of the
+    /// datagram.
+    fn transmit(&mut self, msg: protocol::NetMessage) -> usize {
+        let (bytes, addr) = match msg.pack() {
+            Ok(packed) => packed,
+            Err(e) => {
+                error!("Cannot pack message = {:?}", e);
+                return 0;
+            }
+        };
+        let bytes = match self.sessions.get_mut(&addr) {
+            Some((session, _)) => session.encrypt_if_needed(bytes),
+            None => bytes,
+        };
+
+        let size = bytes.len();
+        let now = Instant::now();
+        self.outgoing_link.push((bytes, addr), now);
+        self.send_ready(now);
+        size
+    }
+
+    /// Hand the datagrams that went through the link conditioner to the
+    /// transport.
+    fn send_ready(&mut self, now: Instant) {
+        for (bytes, addr) in self.outgoing_link.pop_ready(now) {
+            if let Err(e) = self.transport.send(bytes, addr) {
+                error!("Error in send_to_client = {:?}", e);
+            }
+        }
+    }
+
+    /// Only the datagrams of our protocol version go to the game. The
+    /// clients of another version are told why they cannot connect.
+    fn check_version(&mut self, bytes: &Bytes, addr: SocketAddr) -> bool {
+        match protocol::read_header(bytes) {
+            Ok((protocol::PROTOCOL_VERSION, _)) => return true,
+            Ok((version, MessageKind::ConnectionRequest)) => {
+                info!(
+                    "Refuse connection from {}: protocol version {} (ours is {})",
+                    addr,
+                    version,
+                    protocol::PROTOCOL_VERSION
+                );
+                let refused = protocol::NetMessage {
+                    target: addr,
+                    content: Packet::new(
+                        0,
+                        None,
+                        protocol::NetMessageContent::ConnectionRefused(
+                            RefuseReason::VersionMismatch {
+                                server: protocol::PROTOCOL_VERSION,
+                                client: version,
+                            },
+                        ),
+                    ),
+                };
+                match refused.pack() {
+                    Ok((refused, addr)) => {
+                        if let Err(e) = self.transport.send(refused, addr) {
+                            error!("Error when sending ConnectionRefused = {:?}", e);
+                        }
+                    }
+                    Err(e) => error!("Cannot pack ConnectionRefused = {:?}", e),
+                }
+            }
+            Ok((version, kind)) => {
+                debug!(
+                    "Received {:?} with protocol version {} from {}",
+                    kind, version, addr
+                );
+            }
+            Err(e) => {
+                error!("Received malformed message from {}, error = {:?}", addr, e);
+            }
+        }
+        false
+    }
+
+    /// Decrypt and deserialize a datagram. With a connect key, only
+    /// connection requests are accepted in clear, and only from the
+    /// addresses that sent a valid token otherwise.
+    fn unpack(&mut self, bytes: Bytes, addr: SocketAddr) -> Option<protocol::NetMessage> {
+        let kind = match protocol::read_header(&bytes) {
+            Ok((_, kind)) => kind,
+            Err(e) => {
+                error!("Received malformed message from {}, error = {:?}", addr, e);
+                return None;
+            }
+        };
+
+        let bytes = match self.sessions.get_mut(&addr) {
+            _ if crypto::is_plaintext(kind) => bytes,
+            Some((session, _)) => match session.decrypt(&bytes) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    warn!("Drop {:?} from {}: {}", kind, addr, e);
+                    return None;
+                }
+            },
+            None if self.connect_key.is_some() => {
+                debug!("Drop {:?} from {}: no session", kind, addr);
+                return None;
+            }
+            None => bytes,
+        };
+
+        match protocol::NetMessage::unpack(bytes, addr) {
+            Ok(msg) => Some(msg),
+            Err(e) => {
+                error!("Received malformed message from {}, error = {:?}", addr, e);
+                None
+            }
+        }
+    }
+
+    /// Send a message that will arrive, in order with the other messages
+    /// of the channel. It is sent with the next packets to the player.
+    pub fn send_reliable(&mut self, player: &Entity, channel: ChannelId, content: ReliableContent) {
+        let client = self
+            .get_client_id_by_entity(player)
+            .and_then(|i| self.my_clients.get_mut(i));
+
+        if let Some(client) = client {
+            client.reliable.send(channel, content);
         } else {
-            client.last_sent_seq_number += 1;
+            warn!("Cannot send reliable message to {:?}: not a player", player);
+        }
+    }
+
+    /// Send a reliable message to all connected players.
+    pub fn broadcast_reliable(&mut self, channel: ChannelId, content: ReliableContent) {
+        for client in self.my_clients.iter_mut().filter_map(|c| c.as_mut()) {
+            client.reliable.send(channel, content.clone());
         }
     }
 
@@ -375,4 +1047,11 @@ impl NetworkSystem {
             .find(|(_, client)| client.is_some() && client.as_ref().unwrap().addr == addr)
             .map(|t| t.0)
     }
+
+    fn get_client_id_by_entity(&self, player: &Entity) -> Option<usize> {
+        self.my_clients.iter().position(|c| {
+            c.as_ref()
+                .map_or(false, |c| c.entity.as_ref() == Some(player))
+        })
+    }
 }
@@ -2,26 +2,28 @@
 // ECS.
 //
 // Fortunately, we do not send everything over the network
-// At the moment, only position and render state will be
-// target for the delta.
+// Only the replicated components are target for the delta (see
+// replication.rs).
 //
-// For example, if the object has moved a bit, send the delta. If the mesh has morphed, send it as
-// well.
+// For example, if the object has moved a bit, send the new position. If the mesh has morphed, send
+// it as well. Only the fields that changed are sent, with their new value.
+//
+// See packing.rs for how the deltas are encoded on the wire.
+use super::relevancy::{relevant_entities, RelevancyRules};
+use super::replication::{ComponentDelta, FieldValue, Replicated};
+use super::sequence::sequence_diff;
 use crate::collections::RingBuffer;
-use crate::ecs::{
-    components::{LightComponent, LightType, ModelComponent, TransformComponent},
-    Entity, ECS,
-};
-use cgmath::{InnerSpace, Vector3};
-use log::{debug, error};
+use crate::ecs::{Entity, ECS};
+use log::{debug, error, warn};
 use serde_derive::{Deserialize, Serialize};
-const EPSILON: f32 = 0.00001;
 
 #[derive(Debug)]
 pub enum SnapshotError {
     RingBufferEmpty,
     ClientCaughtUp,
     InvalidStateIndex,
+    MalformedSnapshot,
+    UnknownAsset,
 }
 
 use std::error::Error;
@@ -38,7 +40,9 @@ impl Error for SnapshotError {
         match *self {
             SnapshotError::RingBufferEmpty => "The ringbuffer is currently empty",
             SnapshotError::ClientCaughtUp => "The client's known state is too old",
-            SnapshotError::InvalidStateIndex => "Provided state index is out of bound",
+            SnapshotError::InvalidStateIndex => "Provided state is not in the ring buffer",
+            SnapshotError::MalformedSnapshot => "The snapshot cannot be decoded",
+            SnapshotError::UnknownAsset => "The snapshot uses an asset name not received yet",
         }
     }
 }
@@ -53,62 +57,89 @@ impl Error for SnapshotError {
 /// a full round, the client will be considered disconnected. Timeout to disconnection
 /// can be calculated from buffer size and frame duration. (60 fps -> 1 sec timeout =
 /// buffer of size 60).
+///
+/// States are identified by a tick number that is incremented for each state. It
+/// wraps around much later than the ring buffer so a client cannot mistake an old
+/// state for a new one.
+///
+/// A client that knows no state yet gets the difference with the baseline:
+/// the level, that it loads from its disk (see level.rs).
 pub struct Snapshotter {
-    state_buf: RingBuffer<ECS>,
-    empty_ecs: ECS,
+    state_buf: RingBuffer<(u32, ECS)>,
+    baseline: ECS,
+
+    // Tick of the head of the ring buffer.
+    current_tick: u32,
 }
 
 impl Snapshotter {
     pub fn new(ring_size: usize) -> Self {
         let state_buf = RingBuffer::new(ring_size);
-        let empty_ecs = ECS::new();
+        let baseline = ECS::new();
 
         Snapshotter {
             state_buf,
-            empty_ecs,
+            baseline,
+            // The first state is tick 0.
+            current_tick: u32::max_value(),
         }
     }
 
     /// Update ring buffer with current state.
     pub fn set_current(&mut self, ecs: &ECS) {
+        self.current_tick = self.current_tick.wrapping_add(1);
         // it's making a copy.
-        self.state_buf.push(ECS::new_from_existing(ecs));
+        self.state_buf
+            .push((self.current_tick, ECS::new_from_existing(ecs)));
     }
 
-    pub fn get_current_index(&self) -> usize {
-        self.state_buf.head_index()
+    /// A new level was loaded. The states of the previous one are forgotten;
+    /// ticks go on, so they are not mistaken for new states.
+    pub fn reset(&mut self, baseline: ECS) {
+        self.state_buf = RingBuffer::new(self.state_buf.size());
+        self.baseline = baseline;
+    }
+
+    pub fn get_current_tick(&self) -> u32 {
+        self.current_tick
     }
 
     /// Compute snapshot between current and last known state.
-    /// If return value is None. it means, we cannot compute because the
+    /// If return value is ClientCaughtUp. it means, we cannot compute because the
     /// last known state has been replaced by now. -> disconnect client.
     pub fn get_delta(
         &self,
-        known_state: usize,
+        known_tick: u32,
         player_entity: &Entity,
+        rules: &RelevancyRules,
     ) -> Result<DeltaSnapshot, SnapshotError> {
-        if known_state == self.state_buf.head_index() {
+        let age = sequence_diff(self.current_tick, known_tick);
+        if age <= 0 {
+            return Err(SnapshotError::InvalidStateIndex);
+        }
+        if age as usize >= self.state_buf.size() {
             return Err(SnapshotError::ClientCaughtUp);
         }
 
-        if let Some(old_ecs) = self.state_buf.get(known_state) {
-            if let Some(new_ecs) = self.state_buf.head() {
-                Ok(compute_delta(old_ecs, new_ecs, player_entity))
-            } else {
-                Err(SnapshotError::RingBufferEmpty)
+        let size = self.state_buf.size();
+        let index = (self.state_buf.head_index() + size - age as usize) % size;
+        match (self.state_buf.get(index), self.state_buf.head()) {
+            (Some((tick, old_ecs)), Some((_, new_ecs))) if *tick == known_tick => {
+                Ok(compute_delta(old_ecs, new_ecs, player_entity, rules))
             }
-        } else {
-            Err(SnapshotError::InvalidStateIndex)
+            (_, None) => Err(SnapshotError::RingBufferEmpty),
+            _ => Err(SnapshotError::InvalidStateIndex),
         }
     }
 
-    /// From client that havn't received anything yet.
+    /// From client that havn't received anything yet. It has the baseline.
     pub fn get_full_snapshot(
         &self,
         player_entity: &Entity,
+        rules: &RelevancyRules,
     ) -> Result<DeltaSnapshot, SnapshotError> {
-        if let Some(new_ecs) = self.state_buf.head() {
-            Ok(compute_delta(&self.empty_ecs, new_ecs, player_entity))
+        if let Some((_, new_ecs)) = self.state_buf.head() {
+            Ok(compute_delta(&self.baseline, new_ecs, player_entity, rules))
         } else {
             debug!("RingBuffer is empty? {}", self.state_buf.head_index());
             Err(SnapshotError::RingBufferEmpty)
@@ -123,44 +154,49 @@ pub struct DeltaSnapshot {
     pub entities_to_delete: Vec<Entity>,
 }
 
+impl DeltaSnapshot {
+    /// Put back together a delta that was split by entity. The player delta
+    /// is in the first part.
+    pub fn merge(parts: Vec<DeltaSnapshot>) -> Option<DeltaSnapshot> {
+        let mut parts = parts.into_iter();
+        let mut merged = parts.next()?;
+        for part in parts {
+            merged.deltas.extend(part.deltas);
+            merged.entities_to_delete.extend(part.entities_to_delete);
+        }
+        Some(merged)
+    }
+}
+
 // That is the change for an entity.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeltaEntity {
     pub entity: Entity,
-    //TODO is using 0 instead of option better? (smaller packet size)
-    pub delta_transform: (Option<[f32; 3]>, Option<[f32; 3]>, Option<[f32; 3]>),
-    pub delta_model: (Option<String>, Option<String>),
-    pub delta_light: (Option<LightType>, Option<[f32; 3]>, Option<bool>),
+
+    /// Replicated components that changed, by increasing id.
+    pub components: Vec<ComponentDelta>,
 }
 
 impl DeltaEntity {
     fn is_empty(&self) -> bool {
-        match self.delta_transform {
-            (None, None, None) => (),
-            _ => return false,
-        }
-
-        match self.delta_model {
-            (None, None) => (),
-            _ => return false,
-        }
-
-        match self.delta_light {
-            (None, None, None) => (),
-            _ => return false,
-        }
-
-        true
+        self.components.is_empty()
     }
 
-    fn empty(entity: Entity) -> DeltaEntity {
+    pub(crate) fn empty(entity: Entity) -> DeltaEntity {
         Self {
             entity,
-            delta_transform: (None, None, None),
-            delta_model: (None, None),
-            delta_light: (None, None, None),
+            components: Vec::new(),
         }
     }
+
+    /// New value of a field of a component, if it changed.
+    pub fn field<T: Replicated>(&self, name: &str) -> Option<&FieldValue> {
+        let index = T::FIELDS.iter().position(|f| f.name == name)?;
+        self.components
+            .iter()
+            .find(|c| c.component == T::ID)
+            .and_then(|c| c.field(index))
+    }
 }
 
 // Compute change between two ECS
@@ -168,55 +204,28 @@ impl DeltaEntity {
 // What kind of action:
 // - UPDATE entity (if update non-existing, should create it)
 // - DEALLOCATE entity
-pub fn compute_delta(old: &ECS, current: &ECS, player_entity: &Entity) -> DeltaSnapshot {
+//
+// Only the entities that are relevant to the player are in the delta. An
+// entity that was not relevant in the old state is sent in full, and one that
+// is not relevant anymore is deleted on the client.
+pub fn compute_delta(
+    old: &ECS,
+    current: &ECS,
+    player_entity: &Entity,
+    rules: &RelevancyRules,
+) -> DeltaSnapshot {
+    let old_relevant = relevant_entities(rules, old, player_entity);
+    let current_relevant = relevant_entities(rules, current, player_entity);
+
     // Did the player move? change orientation or whatever?
     let player_delta = {
         if current.is_entity_alive(player_entity) {
-            let delta_transform = {
-                match (
-                    current.components.transforms.get(&player_entity),
-                    old.components.transforms.get(&player_entity),
-                ) {
-                    (Some(new_transform), Some(old_transform))
-                        if old.is_entity_alive(&player_entity) =>
-                    {
-                        compute_transform_delta(old_transform, new_transform)
-                    }
-                    (Some(new_transform), _) => compute_transform_delta_empty(new_transform),
-                    (None, _) => (None, None, None),
-                }
-            };
-
-            let delta_model = {
-                match (
-                    current.components.models.get(&player_entity),
-                    old.components.models.get(&player_entity),
-                ) {
-                    (Some(new_model), Some(old_model)) if old.is_entity_alive(&player_entity) => {
-                        compute_model_delta(old_model, new_model)
-                    }
-                    (Some(new_model), _) => compute_model_delta_empty(new_model),
-                    (None, _) => (None, None),
-                }
-            };
-
-            let delta_light = {
-                match (
-                    current.components.lights.get(&player_entity),
-                    old.components.lights.get(&player_entity),
-                ) {
-                    (Some(new_light), Some(old_light)) => compute_light_delta(old_light, new_light),
-                    (Some(new_light), None) => compute_light_delta_empty(new_light),
-                    (None, _) => (None, None, None),
-                }
-            };
-
-            DeltaEntity {
-                entity: (*player_entity).clone(),
-                delta_transform,
-                delta_model,
-                delta_light,
-            }
+            compute_entity_delta(
+                old,
+                current,
+                player_entity,
+                old.is_entity_alive(player_entity),
+            )
         } else {
             DeltaEntity::empty(player_entity.clone())
         }
@@ -224,62 +233,24 @@ pub fn compute_delta(old: &ECS, current: &ECS, player_entity: &Entity) -> DeltaS
 
     // Deallocating should be done first on client side to remove
     // outdated entities.
-    // Find entities to delete, i.e. alive before but dead now.
+    // Find entities to delete, i.e. known by the client before but dead or
+    // not relevant now.
     let entities_to_delete: Vec<_> = old
         .nb_entities()
-        .iter()
-        .filter(|entity| !current.is_entity_alive(&entity))
-        .map(|e| *e)
+        .into_iter()
+        .filter(|entity| old_relevant.contains(entity) && !current_relevant.contains(entity))
         .collect();
 
-    // Get all live entities in current
+    // Get all relevant live entities in current
     let mut deltas = Vec::new();
     for entity in current.nb_entities() {
-        // If can find same entity in old, compute the difference.
-        let delta_transform = {
-            match (
-                current.components.transforms.get(&entity),
-                old.components.transforms.get(&entity),
-            ) {
-                (Some(new_transform), Some(old_transform)) if old.is_entity_alive(&entity) => {
-                    compute_transform_delta(old_transform, new_transform)
-                }
-                (Some(new_transform), _) => compute_transform_delta_empty(new_transform),
-                (None, _) => (None, None, None),
-            }
-        };
-
-        let delta_model = {
-            match (
-                current.components.models.get(&entity),
-                old.components.models.get(&entity),
-            ) {
-                (Some(new_model), Some(old_model)) if old.is_entity_alive(&entity) => {
-                    compute_model_delta(old_model, new_model)
-                }
-                (Some(new_model), _) => compute_model_delta_empty(new_model),
-                (None, _) => (None, None),
-            }
-        };
-
-        let delta_light = {
-            match (
-                current.components.lights.get(&entity),
-                old.components.lights.get(&entity),
-            ) {
-                (Some(new_light), Some(old_light)) => compute_light_delta(old_light, new_light),
-                (Some(new_light), None) => compute_light_delta_empty(new_light),
-                (None, _) => (None, None, None),
-            }
-        };
-
-        let delta_entity = DeltaEntity {
-            entity,
-            delta_transform,
-            delta_model,
-            delta_light,
-        };
+        if !current_relevant.contains(&entity) {
+            continue;
+        }
 
+        // If the client knows the entity, compute the difference.
+        let delta_entity =
+            compute_entity_delta(old, current, &entity, old_relevant.contains(&entity));
         if !delta_entity.is_empty() {
             deltas.push(delta_entity);
         }
@@ -292,6 +263,17 @@ pub fn compute_delta(old: &ECS, current: &ECS, player_entity: &Entity) -> DeltaS
     }
 }
 
+/// Delta of one entity. If the client does not know the entity (`known` is
+/// false), all the components are sent.
+fn compute_entity_delta(old: &ECS, current: &ECS, entity: &Entity, known: bool) -> DeltaEntity {
+    DeltaEntity {
+        entity: *entity,
+        components: current
+            .components
+            .replicated_deltas(&old.components, entity, known),
+    }
+}
+
 pub fn apply_delta(ecs: &mut ECS, delta_snapshot: DeltaSnapshot) {
     // First delete the entities that have to be deleted.
     for entity in &delta_snapshot.entities_to_delete {
@@ -305,213 +287,29 @@ pub fn apply_delta(ecs: &mut ECS, delta_snapshot: DeltaSnapshot) {
         if !ecs.is_entity_alive(&delta.entity) {
             ecs.overwrite(&delta.entity);
 
-            // Maybe need to create some components.
-            match &delta.delta_transform {
-                (None, None, None) => (),
-                _ => {
-                    ecs.components
-                        .transforms
-                        .set(&delta.entity, TransformComponent::default());
-                }
-            }
-
-            match &delta.delta_model {
-                (None, None) => (),
-                _ => {
-                    ecs.components
-                        .models
-                        .set(&delta.entity, ModelComponent::default());
-                }
-            }
-
-            match &delta.delta_light {
-                (None, None, None) => (),
-                _ => {
-                    ecs.components
-                        .lights
-                        .set(&delta.entity, LightComponent::default());
-                }
-            }
-        }
-
-        if let Some(transform) = ecs.components.transforms.get_mut(&delta.entity) {
-            apply_transform_delta(transform, &delta.delta_transform);
-        }
-
-        if let Some(model) = ecs.components.models.get_mut(&delta.entity) {
-            apply_model_delta(model, &delta.delta_model);
-        }
-
-        if let Some(light) = ecs.components.lights.get_mut(&delta.entity) {
-            apply_light_delta(light, &delta.delta_light);
+            // The components are all in the delta.
+            ecs.components.clear_replicated(&delta.entity);
         }
-    }
-}
-
-/* ----------------------------------------------------------------------------------
- * Components delta. Maybe should implement that as macro or in the component.rs file...
- *----------------------------------------------------------------------------------*/
-
-/// Compute delta between 2 transforms. There is a tolerance parameter
-/// because floating point operation.
-fn compute_transform_delta(
-    old_transform: &TransformComponent,
-    new_transform: &TransformComponent,
-) -> (Option<[f32; 3]>, Option<[f32; 3]>, Option<[f32; 3]>) {
-    let delta_pos = new_transform.position - old_transform.position;
-    let delta_rot = new_transform.rotation - old_transform.rotation;
-    let delta_scale = new_transform.scale - old_transform.scale;
-
-    let delta_pos = if delta_pos.magnitude2() > EPSILON {
-        Some(delta_pos.into())
-    } else {
-        None
-    };
-
-    let delta_rot = if delta_rot.magnitude2() > EPSILON {
-        Some(delta_rot.into())
-    } else {
-        None
-    };
-    let delta_scale = if delta_scale.magnitude2() > EPSILON {
-        Some(delta_scale.into())
-    } else {
-        None
-    };
 
-    (delta_pos, delta_rot, delta_scale)
-}
-
-/// Same as above with empty old_transform :D
-fn compute_transform_delta_empty(
-    new_transform: &TransformComponent,
-) -> (Option<[f32; 3]>, Option<[f32; 3]>, Option<[f32; 3]>) {
-    (
-        Some(new_transform.position.into()),
-        Some(new_transform.rotation.into()),
-        Some(new_transform.scale.into()),
-    )
-}
-
-fn apply_transform_delta(
-    transform: &mut TransformComponent,
-    delta: &(Option<[f32; 3]>, Option<[f32; 3]>, Option<[f32; 3]>),
-) {
-    if let Some(ref dpos) = delta.0.as_ref() {
-        transform.position += Vector3::new(dpos[0], dpos[1], dpos[2]);
-    }
-
-    if let Some(ref drot) = delta.1.as_ref() {
-        transform.rotation += Vector3::new(drot[0], drot[1], drot[2]);
-    }
-
-    if let Some(ref dscale) = delta.2.as_ref() {
-        transform.scale += Vector3::new(dscale[0], dscale[1], dscale[2]);
-    }
-}
-
-/// Delta between two model components is easy as the two elements are strings.
-fn compute_model_delta(
-    old_model: &ModelComponent,
-    new_model: &ModelComponent,
-) -> (Option<String>, Option<String>) {
-    let delta_mesh = if new_model.mesh_name != old_model.mesh_name {
-        Some(new_model.mesh_name.clone())
-    } else {
-        None
-    };
-    let delta_texture = if new_model.texture_name != old_model.texture_name {
-        Some(new_model.texture_name.clone())
-    } else {
-        None
-    };
-
-    (delta_mesh, delta_texture)
-}
-
-fn compute_model_delta_empty(new_model: &ModelComponent) -> (Option<String>, Option<String>) {
-    (
-        Some(new_model.mesh_name.clone()),
-        Some(new_model.texture_name.clone()),
-    )
-}
-
-fn apply_model_delta(model: &mut ModelComponent, delta: &(Option<String>, Option<String>)) {
-    if let Some(ref model_name) = delta.0.as_ref() {
-        model.mesh_name = (*model_name).clone();
-    }
-    if let Some(ref texture_name) = delta.1.as_ref() {
-        model.texture_name = (*texture_name).clone();
-    }
-}
-
-fn compute_light_delta(
-    old_light: &LightComponent,
-    new_light: &LightComponent,
-) -> (Option<LightType>, Option<[f32; 3]>, Option<bool>) {
-    let lt = if old_light.light_type == new_light.light_type {
-        None
-    } else {
-        Some(new_light.light_type)
-    };
-
-    let cast_shadows = {
-        if new_light.cast_shadows == old_light.cast_shadows {
-            None
-        } else {
-            Some(new_light.cast_shadows)
+        for component in &delta.components {
+            if !ecs.components.apply_replicated(&delta.entity, component) {
+                warn!(
+                    "Invalid delta of component {} for {:?}",
+                    component.component, delta.entity
+                );
+            }
         }
-    };
-
-    let color_diff = [
-        new_light.color[0] - old_light.color[0],
-        new_light.color[1] - old_light.color[1],
-        new_light.color[2] - old_light.color[2],
-    ];
-
-    let color = if color_diff[0] * color_diff[0]
-        + color_diff[0] * color_diff[0]
-        + color_diff[0] * color_diff[0]
-        < EPSILON
-    {
-        None
-    } else {
-        Some(color_diff)
-    };
-
-    (lt, color, cast_shadows)
-}
-
-fn apply_light_delta(
-    light: &mut LightComponent,
-    delta: &(Option<LightType>, Option<[f32; 3]>, Option<bool>),
-) {
-    if let Some(light_type) = delta.0.as_ref() {
-        light.light_type = *light_type;
-    }
-
-    if let Some(color) = delta.1.as_ref() {
-        light.color = *color;
-    }
-
-    if let Some(cast_shadows) = delta.2.as_ref() {
-        light.cast_shadows = *cast_shadows;
     }
 }
 
-fn compute_light_delta_empty(
-    new_light: &LightComponent,
-) -> (Option<LightType>, Option<[f32; 3]>, Option<bool>) {
-    (
-        Some(new_light.light_type),
-        Some(new_light.color),
-        Some(new_light.cast_shadows),
-    )
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ecs::components::{
+        AnimatorComponent, ModelComponent, NameComponent, PlayerComponent, TransformComponent,
+    };
+    use crate::net::relevancy::DistanceRelevancy;
+    use crate::net::replication::{apply_component_delta, component_delta};
     use cgmath::Vector3;
 
     #[test]
@@ -527,12 +325,16 @@ mod tests {
             scale: Vector3::new(1.0, 1.0, 1.0),
         };
 
-        let (mpos, mrot, mscale) = compute_transform_delta(&old, &current);
-        assert_eq!(Some([2.3, 0.0, 2.0]), mpos);
-        assert_eq!(None, mrot);
-        assert_eq!(Some([-1.0, -1.0, -1.0]), mscale);
+        let delta = component_delta(Some(&old), &current).unwrap();
+        assert_eq!(
+            vec![
+                (0, FieldValue::Vec3([2.3, -12.0, 2.0])),
+                (2, FieldValue::Vec3([0.0, 0.0, 0.0]))
+            ],
+            delta.fields
+        );
 
-        apply_transform_delta(&mut old, &(mpos, mrot, mscale));
+        assert!(apply_component_delta(&mut old, &delta));
         assert_eq!(current.position, old.position);
         assert_eq!(current.rotation, old.rotation);
         assert_eq!(current.scale, old.scale);
@@ -550,8 +352,167 @@ mod tests {
             texture_name: "old".to_string(),
         };
 
-        let (mmesh, mtext) = compute_model_delta(&old, &current);
-        assert_eq!(Some("current".to_string()), mmesh);
-        assert_eq!(None, mtext);
+        let delta = component_delta(Some(&old), &current).unwrap();
+        assert_eq!(
+            vec![(0, FieldValue::Str("current".to_string()))],
+            delta.fields
+        );
+        assert!(component_delta(Some(&current), &current).is_none());
+    }
+
+    #[test]
+    fn relevancy_delta_test() {
+        let rules = DistanceRelevancy::new(Some(10.0));
+        let mut ecs = ECS::new();
+        let player = ecs.new_entity();
+        ecs.components
+            .transforms
+            .set(&player, TransformComponent::default());
+        let other = ecs.new_entity();
+        ecs.components.transforms.set(
+            &other,
+            TransformComponent {
+                position: Vector3::new(5.0, 0.0, 0.0),
+                ..TransformComponent::default()
+            },
+        );
+        let before = ECS::new_from_existing(&ecs);
+
+        // Goes away. The client deletes it.
+        ecs.components
+            .transforms
+            .get_mut(&other)
+            .unwrap()
+            .position
+            .x = 50.0;
+        let away = ECS::new_from_existing(&ecs);
+        let delta = compute_delta(&before, &away, &player, &rules);
+        assert_eq!(vec![other], delta.entities_to_delete);
+        assert!(delta.deltas.iter().all(|d| d.entity != other));
+
+        // Comes back. Sent in full as the client does not have it anymore.
+        ecs.components
+            .transforms
+            .get_mut(&other)
+            .unwrap()
+            .position
+            .x = 6.0;
+        let delta = compute_delta(&away, &ecs, &player, &rules);
+        assert!(delta.entities_to_delete.is_empty());
+        let other_delta = delta.deltas.iter().find(|d| d.entity == other).unwrap();
+        assert_eq!(
+            Some(&FieldValue::Vec3([6.0, 0.0, 0.0])),
+            other_delta.field::<TransformComponent>("position")
+        );
+        assert_eq!(
+            Some(&FieldValue::Vec3([1.0, 1.0, 1.0])),
+            other_delta.field::<TransformComponent>("scale")
+        );
+
+        let mut client = ECS::new();
+        apply_delta(&mut client, delta);
+        let transform = client.components.transforms.get(&other).unwrap();
+        assert_eq!(Vector3::new(6.0, 0.0, 0.0), transform.position);
+        assert_eq!(Vector3::new(1.0, 1.0, 1.0), transform.scale);
+    }
+
+    #[test]
+    fn tick_wraparound_test() {
+        let rules = DistanceRelevancy::new(None);
+        let mut ecs = ECS::new();
+        let player = ecs.new_entity();
+
+        let mut snapshotter = Snapshotter::new(4);
+        snapshotter.current_tick = u32::max_value() - 2;
+        for _ in 0..3 {
+            snapshotter.set_current(&ecs);
+        }
+        assert_eq!(0, snapshotter.get_current_tick());
+
+        let max = u32::max_value();
+        assert!(snapshotter.get_delta(max - 1, &player, &rules).is_ok());
+        assert!(snapshotter.get_delta(max, &player, &rules).is_ok());
+        match snapshotter.get_delta(0, &player, &rules) {
+            Err(SnapshotError::InvalidStateIndex) => (),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+        match snapshotter.get_delta(5, &player, &rules) {
+            Err(SnapshotError::InvalidStateIndex) => (),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+
+        // Replaced in the ring buffer.
+        for _ in 0..2 {
+            snapshotter.set_current(&ecs);
+        }
+        assert!(snapshotter.get_delta(max, &player, &rules).is_ok());
+        match snapshotter.get_delta(max - 1, &player, &rules) {
+            Err(SnapshotError::ClientCaughtUp) => (),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn delta_animator_test() {
+        let old = AnimatorComponent::new("player/armature".to_string());
+        let mut current = old.clone();
+
+        // Time goes on, nothing to send.
+        current.advance(0.5);
+        assert!(component_delta(Some(&old), &current).is_none());
+
+        current.play("player/run", true, 0.2);
+        let delta = component_delta(Some(&old), &current).unwrap();
+        let mut client = old.clone();
+        assert!(apply_component_delta(&mut client, &delta));
+        assert_eq!(Some("player/run".to_string()), client.clip);
+        assert_eq!(0.2, client.blend_duration);
+        assert_eq!(current.previous_time, client.previous_time);
+    }
+
+    #[test]
+    fn replicated_components_test() {
+        let rules = DistanceRelevancy::new(None);
+        let mut ecs = ECS::new();
+        let player = ecs.new_entity();
+        ecs.components
+            .transforms
+            .set(&player, TransformComponent::default());
+        ecs.components.players.set(
+            &player,
+            PlayerComponent {
+                team: 2,
+                ..PlayerComponent::default()
+            },
+        );
+        ecs.components.names.set(
+            &player,
+            NameComponent {
+                name: "Benoit".to_string(),
+            },
+        );
+        ecs.components
+            .models
+            .set(&player, ModelComponent::default());
+
+        let mut client = ECS::new();
+        apply_delta(
+            &mut client,
+            compute_delta(&ECS::new(), &ecs, &player, &rules),
+        );
+        assert_eq!(2, client.components.players.get(&player).unwrap().team);
+        assert_eq!("Benoit", client.components.names.get(&player).unwrap().name);
+        assert!(client.components.models.get(&player).is_some());
+        // Not replicated.
+        assert!(client.components.relevancies.get(&player).is_none());
+
+        // Only the name changed.
+        let before = ECS::new_from_existing(&ecs);
+        ecs.components.names.get_mut(&player).unwrap().name = "Ben".to_string();
+        let delta = compute_delta(&before, &ecs, &player, &rules);
+        assert_eq!(1, delta.deltas.len());
+        assert_eq!(1, delta.deltas[0].components.len());
+        apply_delta(&mut client, delta);
+        assert_eq!("Ben", client.components.names.get(&player).unwrap().name);
     }
 }
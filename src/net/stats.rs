@@ -0,0 +1,315 @@
+// Statistics of a connection, measured by the game on each side.
+//
+// The server keeps one tracker per client, the client one for the server.
+// Rates, loss and snapshot sizes are measured over windows of one second;
+// the values shown are those of the last complete window.
+//
+// The round-trip time comes from the snapshot acknowledgements, so no packet
+// is sent for it. The server times a snapshot from when it is sent until a
+// client packet says it was received. The client times an acknowledgement
+// from when it is sent until a delta based on that state arrives. Either way
+// the time includes up to one frame of the other side. The jitter is the mean
+// deviation of the round-trip time, as TCP computes it.
+//
+// Loss is seen on the receiving side, from the holes in the sequence numbers.
+// A packet that arrives late fills its hole, even though it is then dropped
+// as out of order.
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::sequence::{is_newer, sequence_diff};
+use crate::time::dt_as_secs;
+
+/// Rates and loss are computed over that long.
+pub const STATS_WINDOW: Duration = Duration::from_secs(1);
+
+/// Snapshots waiting for their acknowledgement. Older ones are forgotten.
+const MAX_PROBES: usize = 64;
+
+/// Weight of a new sample in the smoothed round-trip time and its deviation.
+const RTT_SMOOTHING: f32 = 0.125;
+const JITTER_SMOOTHING: f32 = 0.25;
+
+/// What is known about a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetworkStats {
+    /// Smoothed round-trip time, in milliseconds.
+    pub rtt_ms: f32,
+    /// Mean deviation of the round-trip time, in milliseconds.
+    pub jitter_ms: f32,
+    /// Part of the packets of the remote that did not arrive, from 0 to 1.
+    pub loss: f32,
+    pub bytes_in_per_sec: f32,
+    pub bytes_out_per_sec: f32,
+    /// Average size of the encoded snapshots, all parts included.
+    pub snapshot_size: f32,
+    /// Packets dropped since the start because a newer one arrived first.
+    pub out_of_order: u64,
+}
+
+impl fmt::Display for NetworkStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "rtt {:.1} ms, jitter {:.1} ms, loss {:.1}%, in {:.0} B/s, out {:.0} B/s, snapshot {:.0} B, out of order {}",
+            self.rtt_ms,
+            self.jitter_ms,
+            self.loss * 100.0,
+            self.bytes_in_per_sec,
+            self.bytes_out_per_sec,
+            self.snapshot_size,
+            self.out_of_order
+        )
+    }
+}
+
+/// Counters of the current window.
+#[derive(Debug, Clone, Copy, Default)]
+struct Window {
+    bytes_in: usize,
+    bytes_out: usize,
+    // Sequence numbers come from the remote and can jump by up to 2^31, so
+    // a u32 could overflow within one window.
+    expected: u64,
+    received: u64,
+    snapshots: usize,
+    snapshot_bytes: usize,
+}
+
+/// Measures one connection. The game tells it what it sends and receives.
+#[derive(Debug, Clone)]
+pub struct StatsTracker {
+    stats: NetworkStats,
+    has_rtt: bool,
+
+    /// Id and time of the packets whose acknowledgement is timed.
+    probes: VecDeque<(u32, Instant)>,
+
+    /// Newest sequence number received.
+    last_seq_number: Option<u32>,
+
+    window: Window,
+    window_start: Instant,
+}
+
+impl StatsTracker {
+    pub fn new(now: Instant) -> Self {
+        StatsTracker {
+            stats: NetworkStats::default(),
+            has_rtt: false,
+            probes: VecDeque::with_capacity(MAX_PROBES),
+            last_seq_number: None,
+            window: Window::default(),
+            window_start: now,
+        }
+    }
+
+    /// Stats of the last complete window.
+    pub fn stats(&self) -> NetworkStats {
+        self.stats
+    }
+
+    /// A packet was received, before it is checked for order.
+    pub fn on_received(&mut self, seq_number: u32, size: usize, now: Instant) {
+        self.update(now);
+        self.window.bytes_in += size;
+        match self.last_seq_number {
+            None => {
+                self.window.expected += 1;
+                self.window.received += 1;
+                self.last_seq_number = Some(seq_number);
+            }
+            Some(last) if is_newer(seq_number, last) => {
+                self.window.expected += sequence_diff(seq_number, last) as u64;
+                self.window.received += 1;
+                self.last_seq_number = Some(seq_number);
+            }
+            // Late, it fills a hole. Duplicates do not.
+            Some(last) if seq_number != last => self.window.received += 1,
+            Some(_) => (),
+        }
+    }
+
+    /// A packet was dropped because it is older than the last one.
+    pub fn on_out_of_order(&mut self) {
+        self.stats.out_of_order += 1;
+    }
+
+    pub fn on_sent(&mut self, size: usize, now: Instant) {
+        self.update(now);
+        self.window.bytes_out += size;
+    }
+
+    /// A whole snapshot was sent or received.
+    pub fn on_snapshot(&mut self, size: usize) {
+        self.window.snapshots += 1;
+        self.window.snapshot_bytes += size;
+    }
+
+    /// Start timing the acknowledgement of `id`. Does nothing if it is
+    /// already timed.
+    pub fn probe_sent(&mut self, id: u32, now: Instant) {
+        if self.probes.iter().any(|(probe, _)| *probe == id) {
+            return;
+        }
+        if self.probes.len() == MAX_PROBES {
+            self.probes.pop_front();
+        }
+        self.probes.push_back((id, now));
+    }
+
+    /// `id` was acknowledged. The older probes will not be, so they are
+    /// forgotten.
+    pub fn probe_acked(&mut self, id: u32, now: Instant) {
+        let position = match self.probes.iter().position(|(probe, _)| *probe == id) {
+            Some(position) => position,
+            None => return,
+        };
+        let (_, sent_at) = self.probes[position];
+        self.probes.drain(..=position);
+        self.add_rtt_sample(now.duration_since(sent_at));
+    }
+
+    fn add_rtt_sample(&mut self, rtt: Duration) {
+        let rtt = dt_as_secs(rtt) as f32 * 1000.0;
+        let stats = &mut self.stats;
+        if self.has_rtt {
+            let deviation = (rtt - stats.rtt_ms).abs();
+            stats.jitter_ms += JITTER_SMOOTHING * (deviation - stats.jitter_ms);
+            stats.rtt_ms += RTT_SMOOTHING * (rtt - stats.rtt_ms);
+        } else {
+            stats.rtt_ms = rtt;
+            stats.jitter_ms = rtt / 2.0;
+            self.has_rtt = true;
+        }
+    }
+
+    /// Close the window if it is over. Called by the other methods; call it
+    /// also when nothing happens, so that the rates go down.
+    pub fn update(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < STATS_WINDOW {
+            return;
+        }
+
+        let secs = dt_as_secs(elapsed) as f32;
+        let w = self.window;
+        let stats = &mut self.stats;
+        stats.bytes_in_per_sec = w.bytes_in as f32 / secs;
+        stats.bytes_out_per_sec = w.bytes_out as f32 / secs;
+        stats.loss = if w.expected > 0 {
+            1.0 - (w.received as f32 / w.expected as f32).min(1.0)
+        } else {
+            0.0
+        };
+        stats.snapshot_size = if w.snapshots > 0 {
+            w.snapshot_bytes as f32 / w.snapshots as f32
+        } else {
+            0.0
+        };
+
+        self.window = Window::default();
+        self.window_start = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn rtt_test() {
+        let start = Instant::now();
+        let mut tracker = StatsTracker::new(start);
+        tracker.probe_sent(1, start);
+        tracker.probe_sent(2, start + ms(10));
+        // Sent again with the next packet, still timed from the first time.
+        tracker.probe_sent(2, start + ms(20));
+        tracker.probe_sent(3, start + ms(30));
+
+        tracker.probe_acked(2, start + ms(110));
+        assert_eq!(100.0, tracker.stats().rtt_ms.round());
+        assert_eq!(50.0, tracker.stats().jitter_ms.round());
+
+        // 1 was forgotten with 2, and 2 is already acknowledged.
+        tracker.probe_acked(1, start + ms(500));
+        tracker.probe_acked(2, start + ms(500));
+        assert_eq!(100.0, tracker.stats().rtt_ms.round());
+
+        tracker.probe_acked(3, start + ms(230));
+        let stats = tracker.stats();
+        assert!(stats.rtt_ms > 100.0 && stats.rtt_ms < 200.0);
+    }
+
+    #[test]
+    fn loss_test() {
+        let start = Instant::now();
+        let mut tracker = StatsTracker::new(start);
+        // 10 packets, 3 and 7 lost, 5 arrives late, 6 twice.
+        for seq in &[0, 1, 2, 4, 6, 5, 6, 8, 9] {
+            tracker.on_received(*seq, 100, start);
+        }
+        tracker.on_out_of_order();
+        tracker.on_out_of_order();
+        tracker.update(start + STATS_WINDOW);
+
+        let stats = tracker.stats();
+        assert_eq!(0.2, (stats.loss * 10.0).round() / 10.0);
+        assert_eq!(2, stats.out_of_order);
+        assert_eq!(900.0, stats.bytes_in_per_sec);
+    }
+
+    #[test]
+    fn loss_wraparound_test() {
+        let start = Instant::now();
+        let mut tracker = StatsTracker::new(start);
+        let max = u32::max_value();
+        for seq in &[max - 1, max, 1] {
+            tracker.on_received(*seq, 10, start);
+        }
+        tracker.update(start + STATS_WINDOW);
+        assert_eq!(0.25, tracker.stats().loss);
+    }
+
+    #[test]
+    fn sequence_jump_test() {
+        let start = Instant::now();
+        let mut tracker = StatsTracker::new(start);
+        let mut seq = 0u32;
+        for _ in 0..10 {
+            tracker.on_received(seq, 10, start);
+            seq = seq.wrapping_add(i32::max_value() as u32);
+        }
+        tracker.update(start + STATS_WINDOW);
+        assert!(tracker.stats().loss > 0.99);
+    }
+
+    #[test]
+    fn window_test() {
+        let start = Instant::now();
+        let mut tracker = StatsTracker::new(start);
+        tracker.on_sent(300, start);
+        tracker.on_snapshot(100);
+        tracker.on_snapshot(200);
+
+        // Not over yet.
+        tracker.update(start + ms(500));
+        assert_eq!(0.0, tracker.stats().bytes_out_per_sec);
+
+        tracker.on_sent(300, start + ms(999));
+        tracker.update(start + ms(2000));
+        let stats = tracker.stats();
+        assert_eq!(300.0, stats.bytes_out_per_sec);
+        assert_eq!(150.0, stats.snapshot_size);
+
+        // Nothing during the next window.
+        tracker.update(start + ms(3000));
+        assert_eq!(0.0, tracker.stats().bytes_out_per_sec);
+        assert_eq!(0.0, tracker.stats().snapshot_size);
+    }
+}
@@ -0,0 +1,320 @@
+// How the datagrams go between the game and the remotes.
+//
+// The network systems only see a Transport: they send datagrams to an
+// address and poll what was received, with the address of the sender. The
+// transport does not know the protocol. Encryption, link conditions and
+// everything above are done by the systems, the same way whatever carries
+// the bytes.
+//
+// UdpTransport is the one used over a real network. The game thread splits
+// the packets in fragments and queues them for the socket; tokio writes them,
+// reads the socket and puts the received fragments back together. The tokio
+// tasks of the transports run on a UdpRuntime, which can be shared: a process
+// with many clients, like the load test, runs all their sockets on the same
+// threads. Dropping a transport stops its tasks and closes the socket. See
+// `loopback` for the transport without socket.
+use bytes::Bytes;
+use futures::sync::mpsc as futmpsc;
+use futures::sync::oneshot;
+use log::{debug, error, info};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::net::{UdpFramed, UdpSocket};
+use tokio::prelude::*;
+use tokio_codec::BytesCodec;
+
+use super::fragment::{fragment, Reassembler, FRAGMENT_TIMEOUT};
+use super::protocol::PacketSizes;
+use crate::sync::SharedDeque;
+
+/// How often the size of sent packets is written to the log.
+const PACKET_SIZE_REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Received datagrams waiting for the game. The oldest are dropped after
+/// that.
+const RECEIVE_QUEUE_SIZE: usize = 1024;
+
+/// Fragments waiting for the socket. More are dropped, like a full network
+/// buffer would.
+const SEND_QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(io::Error),
+    /// The transport cannot send anymore.
+    Closed,
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TransportError::Io(ref e) => write!(f, "Transport error: {}", e),
+            TransportError::Closed => write!(f, "Transport is closed"),
+        }
+    }
+}
+
+impl Error for TransportError {
+    fn description(&self) -> &str {
+        match *self {
+            TransportError::Io(_) => "Transport error",
+            TransportError::Closed => "Transport is closed",
+        }
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+/// Unreliable datagrams, like UDP. They can be lost, duplicated or arrive
+/// out of order; the protocol deals with it.
+pub trait Transport: Send {
+    /// Queue a datagram for `addr`. Errors only if the transport itself is
+    /// broken: a datagram lost on the way is not an error.
+    fn send(&mut self, bytes: Bytes, addr: SocketAddr) -> Result<(), TransportError>;
+
+    /// Datagrams received since the last call, with who sent them. Does not
+    /// block.
+    fn receive(&mut self) -> Vec<(Bytes, SocketAddr)>;
+
+    /// Where the remotes send to reach this transport.
+    fn local_addr(&self) -> SocketAddr;
+}
+
+/// Work of the UDP transports for tokio.
+type Task = Box<Future<Item = (), Error = ()> + Send>;
+
+/// Threads that run the sockets of the UDP transports. Cloning it gives
+/// another handle to the same threads. They stop once all the handles and
+/// all the transports started on them are dropped.
+#[derive(Clone)]
+pub struct UdpRuntime {
+    tasks: futmpsc::UnboundedSender<Task>,
+}
+
+impl Default for UdpRuntime {
+    fn default() -> Self {
+        let (tasks, rx) = futmpsc::unbounded::<Task>();
+        thread::spawn(move || {
+            tokio::run(rx.for_each(|task| {
+                tokio::spawn(task);
+                Ok(())
+            }))
+        });
+        UdpRuntime { tasks }
+    }
+}
+
+impl UdpRuntime {
+    pub fn new() -> Self {
+        UdpRuntime::default()
+    }
+
+    fn spawn(&self, task: Task) -> Result<(), TransportError> {
+        self.tasks
+            .unbounded_send(task)
+            .map_err(|_| TransportError::Closed)
+    }
+}
+
+/// Closes its socket when dropped.
+pub struct UdpTransport {
+    received: SharedDeque<(Bytes, SocketAddr)>,
+    to_send: futmpsc::Sender<(Bytes, SocketAddr)>,
+    local_addr: SocketAddr,
+    mtu: usize,
+
+    /// Stops the task that reads the socket. The one that writes stops with
+    /// `to_send`.
+    shutdown: Option<oneshot::Sender<()>>,
+
+    packet_sizes: PacketSizes,
+    last_report: Instant,
+    packet_id: u16,
+}
+
+impl UdpTransport {
+    /// Bind a socket to `addr`, on threads of its own; port 0 picks a free
+    /// port. Packets bigger than `mtu` are sent in fragments. Received
+    /// fragments bigger than `remote_mtu`, the MTU of the remotes, are
+    /// dropped.
+    pub fn bind(addr: SocketAddr, mtu: usize, remote_mtu: usize) -> Result<Self, TransportError> {
+        UdpTransport::bind_on(&UdpRuntime::new(), addr, mtu, remote_mtu)
+    }
+
+    /// Like `bind`, with the socket run by `runtime`.
+    pub fn bind_on(
+        runtime: &UdpRuntime,
+        addr: SocketAddr,
+        mtu: usize,
+        remote_mtu: usize,
+    ) -> Result<Self, TransportError> {
+        let socket = UdpSocket::bind(&addr)?;
+        let local_addr = socket.local_addr()?;
+        info!("UDP socket bound to {}", local_addr);
+
+        let received = SharedDeque::new(RECEIVE_QUEUE_SIZE);
+        let mut net_to_game = received.clone();
+        let (to_send, int_rx) = futmpsc::channel(SEND_QUEUE_SIZE);
+        let int_rx = int_rx.map_err(|_| -> io::Error { panic!("Error not possible on rx") });
+        let (shutdown, stop) = oneshot::channel();
+
+        let (sink, stream) = UdpFramed::new(socket, BytesCodec::new()).split();
+
+        // All bytes from the channel go to the socket. Like with TCP this is
+        // spawned concurrently
+        let forward = int_rx.forward(sink).then(|result| -> Result<(), ()> {
+            if let Err(e) = result {
+                error!("failed to write to socket: {}", e)
+            }
+            Ok(())
+        });
+
+        let mut reassembler = Reassembler::new(remote_mtu, FRAGMENT_TIMEOUT);
+        let receive = stream
+            .for_each(move |(buf, from)| {
+                match reassembler.receive(from, buf.into(), Instant::now()) {
+                    Ok(Some(buf)) => net_to_game.push((buf, from)),
+                    Ok(None) => (),
+                    Err(e) => error!("Received bad fragment from {}: {}", from, e),
+                }
+                Ok(())
+            })
+            .map_err(|e| error!("{:?}", e))
+            .select(stop.then(|_| Ok(())))
+            .then(move |_| -> Result<(), ()> {
+                debug!("Transport of {} closed, stop receiving", local_addr);
+                Ok(())
+            });
+
+        runtime.spawn(Box::new(forward))?;
+        runtime.spawn(Box::new(receive))?;
+
+        Ok(UdpTransport {
+            received,
+            to_send,
+            local_addr,
+            mtu,
+            shutdown: Some(shutdown),
+            packet_sizes: PacketSizes::new(),
+            last_report: Instant::now(),
+            packet_id: 0,
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, bytes: Bytes, addr: SocketAddr) -> Result<(), TransportError> {
+        self.packet_sizes.record_bytes(&bytes);
+        if self.last_report.elapsed() >= PACKET_SIZE_REPORT_INTERVAL {
+            info!(
+                "Sent packets by message type:\n{}",
+                self.packet_sizes.report()
+            );
+            self.packet_sizes.clear();
+            self.last_report = Instant::now();
+        }
+
+        let fragments = match fragment(bytes, self.packet_id, self.mtu) {
+            Ok(fragments) => fragments,
+            Err(e) => {
+                error!("Cannot send packet to {}: {}", addr, e);
+                return Ok(());
+            }
+        };
+        self.packet_id = self.packet_id.wrapping_add(1);
+
+        for f in fragments {
+            if let Err(e) = self.to_send.try_send((f, addr)) {
+                if e.is_disconnected() {
+                    return Err(TransportError::Closed);
+                }
+                error!(
+                    "Too many packets waiting for the socket, drop one to {}",
+                    addr
+                );
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Vec<(Bytes, SocketAddr)> {
+        self.received.drain()
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for UdpTransport {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+/// Any port, on the same IP version as `remote`.
+pub fn any_local_addr(remote: SocketAddr) -> SocketAddr {
+    if remote.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receive_one(transport: &mut UdpTransport) -> Option<(Bytes, SocketAddr)> {
+        for _ in 0..100 {
+            if let Some(datagram) = transport.receive().pop() {
+                return Some(datagram);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    #[test]
+    fn udp_test() {
+        let runtime = UdpRuntime::new();
+        let localhost: SocketAddr = (Ipv4Addr::LOCALHOST, 0).into();
+        let mut a = UdpTransport::bind_on(&runtime, localhost, 1200, 1200).unwrap();
+        let mut b = UdpTransport::bind_on(&runtime, localhost, 1200, 1200).unwrap();
+        let b_addr = b.local_addr();
+
+        // Big enough to be fragmented.
+        let packet = Bytes::from(vec![7u8; 3000]);
+        a.send(packet.clone(), b_addr).unwrap();
+        let (bytes, from) = receive_one(&mut b).unwrap();
+        assert_eq!(packet, bytes);
+        assert_eq!(a.local_addr().port(), from.port());
+
+        // Dropping the transport closes its socket, so the port can be bound
+        // again.
+        drop(b);
+        let mut rebound = None;
+        for _ in 0..100 {
+            if let Ok(b) = UdpTransport::bind_on(&runtime, b_addr, 1200, 1200) {
+                rebound = Some(b);
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut b = rebound.expect("Socket of the dropped transport is still open");
+
+        a.send(packet.clone(), b_addr).unwrap();
+        assert_eq!(packet, receive_one(&mut b).unwrap().0);
+    }
+}
@@ -0,0 +1,347 @@
+// Validation of the player inputs on the server.
+//
+// Inputs come from the clients, so they can contain anything: NaN vectors,
+// huge frame durations or thousands of commands. Each input is checked
+// before it is applied. Invalid inputs are dropped and give violation
+// points to the player. The points are forgiven slowly; a player with too
+// many of them is kicked.
+//
+// Speed hacks send more game time than real time passes. Each player has a
+// budget of input time that fills up with real time, and inputs that go over
+// it are dropped. The budget can go a bit ahead of real time because inputs
+// do not arrive at a regular pace.
+use cgmath::{InnerSpace, Vector3};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Instant;
+
+use super::prediction::PlayerInput;
+use crate::ecs::systems::{MAX_INPUT_DT, PLAYER_SPEED};
+use crate::scene::ClientCommand;
+use crate::time::dt_as_secs;
+
+/// Look vectors closer to the vertical are rejected. The camera of the
+/// client stops at 89 degrees.
+const MAX_VERTICAL_LOOK: f32 = 0.9999;
+
+/// Rounding errors allowed when comparing a move with the player speed.
+const MOVE_TOLERANCE: f32 = 1e-3;
+
+/// Limits of the inputs of one player.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputLimits {
+    /// Inputs accepted from a player during one server frame. Clients send
+    /// one input per frame, so they send several per server frame when they
+    /// run faster than the server.
+    pub max_inputs_per_tick: usize,
+
+    /// Commands in one input.
+    pub max_commands_per_input: usize,
+
+    /// How much input time can be ahead of real time, in seconds.
+    pub max_time_ahead: f32,
+
+    /// Players with that many violation points are kicked.
+    pub max_violations: f32,
+
+    /// Violation points forgiven per second.
+    pub violation_decay: f32,
+}
+
+impl Default for InputLimits {
+    fn default() -> Self {
+        InputLimits {
+            max_inputs_per_tick: 32,
+            max_commands_per_input: 16,
+            max_time_ahead: 0.5,
+            max_violations: 20.0,
+            violation_decay: 1.0,
+        }
+    }
+}
+
+impl InputLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_inputs_per_tick == 0 || self.max_commands_per_input == 0 {
+            return Err(
+                "max_inputs_per_tick and max_commands_per_input should be at least 1".to_string(),
+            );
+        }
+        if !(self.max_time_ahead >= MAX_INPUT_DT) {
+            return Err(format!(
+                "max_time_ahead should be at least {}, got {}",
+                MAX_INPUT_DT, self.max_time_ahead
+            ));
+        }
+        if !(self.max_violations >= 1.0) || !(self.violation_decay >= 0.0) {
+            return Err(
+                "max_violations should be at least 1 and violation_decay positive".to_string(),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Why an input was dropped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Violation {
+    TooManyInputs,
+    TooManyCommands(usize),
+    InvalidDt(f32),
+    InvalidLookAt([f32; 3]),
+    /// More input time than real time.
+    TooFast,
+    /// The player moved further than its speed allows.
+    InvalidMove(f32),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::TooManyInputs => write!(f, "Too many inputs during this frame"),
+            Violation::TooManyCommands(nb) => write!(f, "Too many commands in input ({})", nb),
+            Violation::InvalidDt(dt) => write!(f, "Invalid input duration {}", dt),
+            Violation::InvalidLookAt(direction) => {
+                write!(f, "Invalid look direction {:?}", direction)
+            }
+            Violation::TooFast => write!(f, "Inputs are ahead of real time"),
+            Violation::InvalidMove(distance) => write!(f, "Moved too far ({})", distance),
+        }
+    }
+}
+
+/// A direction the player can look at: finite, not null and not vertical.
+pub fn is_valid_look_at(direction: [f32; 3]) -> bool {
+    let direction = Vector3::new(direction[0], direction[1], direction[2]);
+    let length = direction.magnitude();
+    length.is_finite() && length > 1e-3 && (direction.y / length).abs() < MAX_VERTICAL_LOOK
+}
+
+/// Could the player go from `from` to `to` with inputs that last `dt`
+/// seconds? Players go faster in diagonal.
+pub fn is_possible_move(from: Vector3<f32>, to: Vector3<f32>, dt: f32) -> bool {
+    let distance = (to - from).magnitude();
+    distance.is_finite()
+        && distance <= PLAYER_SPEED * std::f32::consts::SQRT_2 * dt + MOVE_TOLERANCE
+}
+
+/// Checks the inputs of one player.
+#[derive(Debug, Clone)]
+pub struct InputValidator {
+    limits: InputLimits,
+    inputs_this_tick: usize,
+
+    /// Input time that can still be applied, in seconds.
+    time_credit: f32,
+
+    violations: f32,
+    last_tick: Instant,
+}
+
+impl InputValidator {
+    pub fn new(limits: InputLimits, now: Instant) -> Self {
+        InputValidator {
+            limits,
+            inputs_this_tick: 0,
+            time_credit: limits.max_time_ahead,
+            violations: 0.0,
+            last_tick: now,
+        }
+    }
+
+    /// Should be called at the beginning of each server frame.
+    pub fn start_tick(&mut self, now: Instant) {
+        let elapsed = dt_as_secs(now.duration_since(self.last_tick)) as f32;
+        self.last_tick = now;
+        self.inputs_this_tick = 0;
+        self.time_credit = (self.time_credit + elapsed).min(self.limits.max_time_ahead);
+        self.violations = (self.violations - elapsed * self.limits.violation_decay).max(0.0);
+    }
+
+    /// Ok if the input can be applied. Otherwise, the violation is counted.
+    pub fn check(&mut self, input: &PlayerInput) -> Result<(), Violation> {
+        let result = self.validate(input);
+        if result.is_err() {
+            self.count_violation();
+        }
+        result
+    }
+
+    /// Count a violation found somewhere else.
+    pub fn count_violation(&mut self) {
+        self.violations += 1.0;
+    }
+
+    pub fn violations(&self) -> f32 {
+        self.violations
+    }
+
+    pub fn should_kick(&self) -> bool {
+        self.violations >= self.limits.max_violations
+    }
+
+    fn validate(&mut self, input: &PlayerInput) -> Result<(), Violation> {
+        self.inputs_this_tick += 1;
+        if self.inputs_this_tick > self.limits.max_inputs_per_tick {
+            return Err(Violation::TooManyInputs);
+        }
+
+        if input.commands.len() > self.limits.max_commands_per_input {
+            return Err(Violation::TooManyCommands(input.commands.len()));
+        }
+
+        if !input.dt.is_finite() || input.dt < 0.0 {
+            return Err(Violation::InvalidDt(input.dt));
+        }
+
+        for command in input.commands.iter() {
+            if let ClientCommand::LookAt(direction) = *command {
+                if !is_valid_look_at(direction) {
+                    return Err(Violation::InvalidLookAt(direction));
+                }
+            }
+        }
+
+        // Same clamp as when the input is applied.
+        let dt = input.dt.min(MAX_INPUT_DT);
+        if dt > self.time_credit {
+            return Err(Violation::TooFast);
+        }
+        self.time_credit -= dt;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::CameraDirection;
+    use std::time::Duration;
+
+    fn input(dt: f32, commands: Vec<ClientCommand>) -> PlayerInput {
+        PlayerInput {
+            seq_number: 0,
+            dt,
+            commands,
+        }
+    }
+
+    fn forward(dt: f32) -> PlayerInput {
+        input(dt, vec![ClientCommand::Move(CameraDirection::Forward)])
+    }
+
+    #[test]
+    fn look_at_test() {
+        assert!(is_valid_look_at([0.0, 0.0, -1.0]));
+        assert!(is_valid_look_at([0.0, 0.5, -3.0]));
+        assert!(!is_valid_look_at([std::f32::NAN, 0.0, -1.0]));
+        assert!(!is_valid_look_at([std::f32::INFINITY, 0.0, 0.0]));
+        assert!(!is_valid_look_at([0.0, 0.0, 0.0]));
+        assert!(!is_valid_look_at([0.0, -1.0, 0.0]));
+        assert!(!is_valid_look_at([std::f32::MAX, std::f32::MAX, 0.0]));
+    }
+
+    #[test]
+    fn invalid_input_test() {
+        let mut validator = InputValidator::new(InputLimits::default(), Instant::now());
+        assert_eq!(Ok(()), validator.check(&forward(0.016)));
+
+        let look = ClientCommand::LookAt([std::f32::NAN, 0.0, 1.0]);
+        match validator.check(&input(0.016, vec![look])) {
+            Err(Violation::InvalidLookAt(_)) => (),
+            other => panic!("NaN look vector was accepted: {:?}", other),
+        }
+        assert!(validator.check(&forward(std::f32::NAN)).is_err());
+        assert_eq!(
+            Err(Violation::InvalidDt(-1.0)),
+            validator.check(&forward(-1.0))
+        );
+        assert_eq!(
+            Err(Violation::TooManyCommands(100)),
+            validator.check(&input(0.016, vec![look; 100]))
+        );
+        assert_eq!(4.0, validator.violations());
+    }
+
+    #[test]
+    fn inputs_per_tick_test() {
+        let limits = InputLimits {
+            max_inputs_per_tick: 4,
+            ..InputLimits::default()
+        };
+        let now = Instant::now();
+        let mut validator = InputValidator::new(limits, now);
+        for _ in 0..4 {
+            assert!(validator.check(&forward(0.0)).is_ok());
+        }
+        assert_eq!(
+            Err(Violation::TooManyInputs),
+            validator.check(&forward(0.0))
+        );
+
+        validator.start_tick(now + Duration::from_millis(16));
+        assert!(validator.check(&forward(0.0)).is_ok());
+    }
+
+    #[test]
+    fn speed_hack_test() {
+        let now = Instant::now();
+        let mut validator = InputValidator::new(InputLimits::default(), now);
+
+        // Normal client: as much input time as real time.
+        for i in 1..100 {
+            validator.start_tick(now + Duration::from_millis(i * 20));
+            assert!(validator.check(&forward(0.02)).is_ok());
+        }
+        assert_eq!(0.0, validator.violations());
+
+        // Twice faster. The budget covers a bit then the inputs are dropped.
+        let mut dropped = 0;
+        for i in 100..200 {
+            validator.start_tick(now + Duration::from_millis(i * 20));
+            for _ in 0..2 {
+                if validator.check(&forward(0.02)) == Err(Violation::TooFast) {
+                    dropped += 1;
+                }
+            }
+        }
+        assert!(dropped > 60 && dropped <= 100);
+        assert!(validator.should_kick());
+    }
+
+    #[test]
+    fn violation_decay_test() {
+        let limits = InputLimits {
+            max_violations: 3.0,
+            ..InputLimits::default()
+        };
+        let now = Instant::now();
+        let mut validator = InputValidator::new(limits, now);
+        validator.check(&forward(-1.0)).unwrap_err();
+        validator.check(&forward(-1.0)).unwrap_err();
+        assert!(!validator.should_kick());
+
+        validator.start_tick(now + Duration::from_secs(2));
+        assert_eq!(0.0, validator.violations());
+        for _ in 0..3 {
+            validator.check(&forward(-1.0)).unwrap_err();
+        }
+        assert!(validator.should_kick());
+    }
+
+    #[test]
+    fn possible_move_test() {
+        let from = Vector3::new(0.0, 0.0, 0.0);
+        let diagonal = Vector3::new(1.0, 0.0, 1.0) * PLAYER_SPEED * 0.1;
+        assert!(is_possible_move(from, diagonal, 0.1));
+        assert!(!is_possible_move(from, diagonal, 0.05));
+        assert!(!is_possible_move(
+            from,
+            Vector3::new(std::f32::NAN, 0.0, 0.0),
+            0.1
+        ));
+    }
+}
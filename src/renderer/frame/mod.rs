@@ -32,7 +32,7 @@ use super::GBufferComponent;
 // Renderpass description takes a lot of place so it is created here.
 use super::AttachmentType;
 use crate::camera::Camera;
-use crate::ecs::components::{ModelComponent, TransformComponent};
+use crate::ecs::components::{AnimatorComponent, ModelComponent, TransformComponent};
 use crate::event::Event;
 use crate::resource::Resources;
 mod renderpass;
@@ -468,7 +468,7 @@ impl<'f, 's: 'f> ShadowPass<'f, 's> {
         &mut self,
         resources: &Resources,
         light_transform: &TransformComponent,
-        objects: &Vec<(&ModelComponent, &TransformComponent)>,
+        objects: &Vec<(&ModelComponent, &TransformComponent, Option<&AnimatorComponent>)>,
     ) {
         let buf =
             self.frame
@@ -0,0 +1,668 @@
+// Import glTF 2.0 files (.gltf and .glb).
+//
+// As opposed to the obj loader, a glTF file contains a full scene: meshes,
+// images and the node hierarchy. Meshes and images are uploaded to the GPU
+// and registered in the model/texture managers, and the node hierarchy is
+// kept as a `GltfScene` that can be instantiated in an ECS.
+//
+// Skins and animations are loaded as skeletons and clips in the
+// `AnimationManager`.
+//
+// Names of the resources are prefixed by the file name so that two files
+// can have meshes with the same name. For example, the mesh `Cube` in
+// `arena.gltf` will be `arena/Cube.0` (one model per primitive).
+use cgmath::{Matrix3, Matrix4, Quaternion, SquareMatrix, Vector3};
+use log::*;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use vulkano::device::Queue;
+
+use crate::animation::{
+    AnimationClip, AnimationManager, Channel, ChannelValues, Joint, JointTransform, Skeleton,
+    MAX_JOINTS,
+};
+use crate::ecs::components::{
+    AnimatorComponent, ModelComponent, NameComponent, TransformComponent,
+};
+use crate::ecs::{Entity, ECS};
+use crate::error::{TwError, TwResult};
+use crate::renderer::model::{Model, ModelManager, Vertex};
+use crate::renderer::texture::TextureManager;
+
+/// Texture used when a primitive does not have a base color texture.
+const DEFAULT_TEXTURE: &str = "white";
+
+/// One node of the glTF scene. Transforms are already flattened in world space
+/// as the ECS does not have a notion of hierarchy.
+#[derive(Debug, Clone)]
+pub struct SceneNode {
+    pub name: Option<String>,
+    pub transform: TransformComponent,
+
+    /// One model per primitive of the node's mesh. Empty if the node
+    /// does not have a mesh.
+    pub models: Vec<ModelComponent>,
+
+    /// Name of the skeleton if the mesh is skinned.
+    pub skeleton: Option<String>,
+}
+
+/// The node tree of a glTF file, ready to be added to an ECS.
+#[derive(Debug, Clone)]
+pub struct GltfScene {
+    pub nodes: Vec<SceneNode>,
+}
+
+impl GltfScene {
+    /// Create the entities for all the nodes that have a mesh. A node with
+    /// several primitives will create one entity per primitive.
+    pub fn spawn(&self, ecs: &mut ECS) -> Vec<Entity> {
+        let mut entities = Vec::new();
+        for node in &self.nodes {
+            for model in &node.models {
+                let entity = ecs.new_entity();
+                ecs.components
+                    .transforms
+                    .set(&entity, node.transform.clone());
+                ecs.components.models.set(&entity, model.clone());
+                if let Some(ref name) = node.name {
+                    ecs.components
+                        .names
+                        .set(&entity, NameComponent { name: name.clone() });
+                }
+                if let Some(ref skeleton) = node.skeleton {
+                    ecs.components
+                        .animators
+                        .set(&entity, AnimatorComponent::new(skeleton.clone()));
+                }
+                entities.push(entity);
+            }
+        }
+
+        entities
+    }
+}
+
+/// World transforms and parents of the nodes, by node index.
+struct Hierarchy {
+    world: HashMap<usize, Matrix4<f32>>,
+    parents: HashMap<usize, usize>,
+}
+
+/// Load a glTF file. Meshes, images, skins and animations are added to the
+/// managers and the node hierarchy is returned.
+pub fn load_gltf<P: AsRef<Path>>(
+    path: P,
+    models: &mut ModelManager,
+    textures: &mut TextureManager,
+    animations: &mut AnimationManager,
+    queue: Arc<Queue>,
+) -> TwResult<GltfScene> {
+    let path = path.as_ref();
+    let prefix = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or(TwError::ModelLoading(format!(
+            "Invalid glTF path {:?}",
+            path
+        )))?
+        .to_string();
+
+    let (document, buffers, images) = gltf::import(path)?;
+
+    // 1. Images -> textures.
+    // ---------------------
+    let mut texture_names = Vec::with_capacity(images.len());
+    for (image, data) in document.images().zip(images.into_iter()) {
+        let name = format!(
+            "{}/{}",
+            prefix,
+            image
+                .name()
+                .map(|n| n.to_string())
+                .unwrap_or(image.index().to_string())
+        );
+
+        let width = data.width;
+        let height = data.height;
+        let (texture_name, pixels) = image_texture(name, data);
+        if let Some(pixels) = pixels {
+            textures.load_texture_from_rgba(
+                texture_name.clone(),
+                pixels,
+                width,
+                height,
+                queue.device().clone(),
+                queue.clone(),
+            )?;
+        }
+        texture_names.push(texture_name);
+    }
+
+    // 2. Meshes -> models. Each primitive is a model.
+    // -----------------------------------------------
+    let mut mesh_models = Vec::new();
+    for mesh in document.meshes() {
+        let mesh_name = mesh
+            .name()
+            .map(|n| n.to_string())
+            .unwrap_or(mesh.index().to_string());
+        let mut primitive_models = Vec::new();
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                warn!(
+                    "Primitive {} of mesh {} is not a triangle list. Skip it.",
+                    primitive.index(),
+                    mesh_name
+                );
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let positions: Vec<[f32; 3]> = reader
+                .read_positions()
+                .ok_or(TwError::ModelLoading(format!(
+                    "Mesh {} does not have positions",
+                    mesh_name
+                )))?
+                .collect();
+            let normals: Vec<[f32; 3]> = reader
+                .read_normals()
+                .map(|n| n.collect())
+                .unwrap_or(vec![[0.0, 1.0, 0.0]; positions.len()]);
+            let texcoords: Vec<[f32; 2]> = reader
+                .read_tex_coords(0)
+                .map(|t| t.into_f32().collect())
+                .unwrap_or(vec![[0.0, 0.0]; positions.len()]);
+            let indices: Vec<u32> = reader
+                .read_indices()
+                .map(|i| i.into_u32().collect())
+                .unwrap_or((0..positions.len() as u32).collect());
+            let joints: Vec<[u16; 4]> = reader
+                .read_joints(0)
+                .map(|j| j.into_u16().collect())
+                .unwrap_or(vec![[0; 4]; positions.len()]);
+            check_vertex_joints(&mesh_name, &joints)?;
+            let weights: Vec<[f32; 4]> = reader
+                .read_weights(0)
+                .map(|w| w.into_f32().collect())
+                .unwrap_or(vec![[0.0; 4]; positions.len()]);
+
+            let vertices = positions
+                .iter()
+                .zip(normals.iter())
+                .zip(texcoords.iter())
+                .zip(joints.iter().zip(weights.iter()))
+                .map(|(((p, n), t), (j, w))| {
+                    Vertex::new(p[0], p[1], p[2], t[0], t[1], n[0], n[1], n[2]).with_skin(*j, *w)
+                })
+                .collect();
+
+            let model_name = format!("{}/{}.{}", prefix, mesh_name, primitive.index());
+            let model = Model::load_from_vec(queue.device().clone(), vertices, indices)?;
+            models.add_model(model_name.clone(), model);
+
+            let texture_name = primitive
+                .material()
+                .pbr_metallic_roughness()
+                .base_color_texture()
+                .and_then(|info| texture_names.get(info.texture().source().index()))
+                .map(|n| n.clone())
+                .unwrap_or(DEFAULT_TEXTURE.to_string());
+
+            primitive_models.push(ModelComponent {
+                mesh_name: model_name,
+                texture_name,
+            });
+        }
+
+        mesh_models.push(primitive_models);
+    }
+
+    // 3. Node tree -> flat list of nodes in world space.
+    // --------------------------------------------------
+    let skeleton_names: Vec<String> = document
+        .skins()
+        .map(|skin| {
+            format!(
+                "{}/{}",
+                prefix,
+                skin.name()
+                    .map(|n| n.to_string())
+                    .unwrap_or(skin.index().to_string())
+            )
+        })
+        .collect();
+
+    let mut nodes = Vec::new();
+    let mut hierarchy = Hierarchy {
+        world: HashMap::new(),
+        parents: HashMap::new(),
+    };
+    let scene = document
+        .default_scene()
+        .or(document.scenes().next())
+        .ok_or(TwError::ModelLoading(format!(
+            "glTF file {:?} does not contain any scene",
+            path
+        )))?;
+    for root in scene.nodes() {
+        visit_node(
+            &root,
+            Matrix4::identity(),
+            &mesh_models,
+            &skeleton_names,
+            &mut hierarchy,
+            &mut nodes,
+        );
+    }
+
+    // 4. Skins -> skeletons, animations -> clips
+    // ------------------------------------------
+    for (skin, name) in document.skins().zip(skeleton_names.iter()) {
+        check_skin(name, skin.joints().count())?;
+        let skeleton = load_skeleton(&document, &skin, &buffers, &hierarchy);
+        animations.skeletons.insert(name.clone(), skeleton);
+    }
+
+    for animation in document.animations() {
+        let name = format!(
+            "{}/{}",
+            prefix,
+            animation
+                .name()
+                .map(|n| n.to_string())
+                .unwrap_or(animation.index().to_string())
+        );
+        if let Some(clip) = load_clip(name.clone(), &animation, &buffers) {
+            animations.clips.insert(name, clip);
+        }
+    }
+
+    debug!(
+        "Loaded glTF {:?}: {} textures, {} meshes, {} nodes, {} skins, {} animations",
+        path,
+        texture_names.len(),
+        mesh_models.len(),
+        nodes.len(),
+        skeleton_names.len(),
+        document.animations().count()
+    );
+    Ok(GltfScene { nodes })
+}
+
+fn visit_node(
+    node: &gltf::Node,
+    parent_transform: Matrix4<f32>,
+    mesh_models: &Vec<Vec<ModelComponent>>,
+    skeleton_names: &Vec<String>,
+    hierarchy: &mut Hierarchy,
+    nodes: &mut Vec<SceneNode>,
+) {
+    let local: Matrix4<f32> = node.transform().matrix().into();
+    let world = parent_transform * local;
+    hierarchy.world.insert(node.index(), world);
+
+    let models = node
+        .mesh()
+        .and_then(|m| mesh_models.get(m.index()))
+        .map(|m| m.clone())
+        .unwrap_or(Vec::new());
+
+    nodes.push(SceneNode {
+        name: node.name().map(|n| n.to_string()),
+        transform: to_transform(world),
+        models,
+        skeleton: node
+            .skin()
+            .and_then(|s| skeleton_names.get(s.index()))
+            .map(|s| s.clone()),
+    });
+
+    for child in node.children() {
+        hierarchy.parents.insert(child.index(), node.index());
+        visit_node(&child, world, mesh_models, skeleton_names, hierarchy, nodes);
+    }
+}
+
+/// The shaders have MAX_JOINTS joint matrices. A bigger skin would be cut
+/// and its vertices would read past the array.
+fn check_skin(name: &str, nb_joints: usize) -> TwResult<()> {
+    if nb_joints > MAX_JOINTS {
+        return Err(TwError::ModelLoading(format!(
+            "Skin {} has {} joints, at most {} are supported",
+            name, nb_joints, MAX_JOINTS
+        )));
+    }
+    Ok(())
+}
+
+/// Same for the joint indices of the vertices, whatever the skin.
+fn check_vertex_joints(mesh_name: &str, joints: &[[u16; 4]]) -> TwResult<()> {
+    match joints.iter().flatten().max() {
+        Some(&j) if j as usize >= MAX_JOINTS => Err(TwError::ModelLoading(format!(
+            "Mesh {} uses joint {}, at most {} joints are supported",
+            mesh_name, j, MAX_JOINTS
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn load_skeleton(
+    document: &gltf::Document,
+    skin: &gltf::Skin,
+    buffers: &Vec<gltf::buffer::Data>,
+    hierarchy: &Hierarchy,
+) -> Skeleton {
+    let reader = skin.reader(|buffer| Some(&buffers[buffer.index()]));
+    let inverse_bind_matrices: Vec<Matrix4<f32>> = reader
+        .read_inverse_bind_matrices()
+        .map(|m| m.map(|m| m.into()).collect())
+        .unwrap_or(Vec::new());
+
+    let joint_nodes: Vec<usize> = skin.joints().map(|j| j.index()).collect();
+    let joints = skin
+        .joints()
+        .enumerate()
+        .map(|(i, node)| {
+            let (t, r, s) = node.transform().decomposed();
+            Joint {
+                name: node
+                    .name()
+                    .map(|n| n.to_string())
+                    .unwrap_or(node.index().to_string()),
+                node: node.index(),
+                parent: hierarchy
+                    .parents
+                    .get(&node.index())
+                    .and_then(|p| joint_nodes.iter().position(|j| j == p)),
+                inverse_bind_matrix: inverse_bind_matrices
+                    .get(i)
+                    .map(|m| *m)
+                    .unwrap_or(Matrix4::identity()),
+                rest: JointTransform {
+                    translation: Vector3::new(t[0], t[1], t[2]),
+                    rotation: Quaternion::new(r[3], r[0], r[1], r[2]),
+                    scale: Vector3::new(s[0], s[1], s[2]),
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Joints are relative to the armature but the vertices are relative to
+    // the skinned mesh.
+    let armature = joints
+        .iter()
+        .find(|j| j.parent.is_none())
+        .and_then(|j| hierarchy.parents.get(&j.node))
+        .and_then(|p| hierarchy.world.get(p))
+        .map(|m| *m)
+        .unwrap_or(Matrix4::identity());
+    let mesh_world = document
+        .nodes()
+        .find(|n| n.skin().map(|s| s.index()) == Some(skin.index()))
+        .and_then(|n| hierarchy.world.get(&n.index()))
+        .and_then(|m| m.invert())
+        .unwrap_or(Matrix4::identity());
+
+    Skeleton {
+        joints,
+        root_transform: mesh_world * armature,
+    }
+}
+
+fn load_clip(
+    name: String,
+    animation: &gltf::Animation,
+    buffers: &Vec<gltf::buffer::Data>,
+) -> Option<AnimationClip> {
+    use gltf::animation::util::ReadOutputs;
+    use gltf::animation::Interpolation;
+
+    let mut channels = Vec::new();
+    for channel in animation.channels() {
+        let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+        let times: Vec<f32> = match reader.read_inputs() {
+            Some(inputs) => inputs.collect(),
+            None => continue,
+        };
+
+        // Cubic splines have an in-tangent, a value and an out-tangent for each
+        // keyframe. Only keep the value and interpolate linearly.
+        let cubic = channel.sampler().interpolation() == Interpolation::CubicSpline;
+        let keep = |i: &usize| !cubic || i % 3 == 1;
+
+        let values = match reader.read_outputs() {
+            Some(ReadOutputs::Translations(t)) => ChannelValues::Translations(
+                t.enumerate()
+                    .filter(|(i, _)| keep(i))
+                    .map(|(_, t)| Vector3::new(t[0], t[1], t[2]))
+                    .collect(),
+            ),
+            Some(ReadOutputs::Rotations(r)) => ChannelValues::Rotations(
+                r.into_f32()
+                    .enumerate()
+                    .filter(|(i, _)| keep(i))
+                    .map(|(_, r)| Quaternion::new(r[3], r[0], r[1], r[2]))
+                    .collect(),
+            ),
+            Some(ReadOutputs::Scales(s)) => ChannelValues::Scales(
+                s.enumerate()
+                    .filter(|(i, _)| keep(i))
+                    .map(|(_, s)| Vector3::new(s[0], s[1], s[2]))
+                    .collect(),
+            ),
+            // Morph targets are not supported.
+            _ => continue,
+        };
+
+        let node = channel.target().node().index();
+        if let Some(channel) = check_channel(&name, node, times, values) {
+            channels.push(channel);
+        }
+    }
+
+    if channels.is_empty() {
+        warn!("Animation {} does not have any keyframe. Skip it.", name);
+        return None;
+    }
+    let duration = channels
+        .iter()
+        .filter_map(|c| c.times.last())
+        .fold(0.0f32, |d, t| d.max(*t));
+    Some(AnimationClip {
+        name,
+        duration,
+        channels,
+    })
+}
+
+/// Sampling reads one value per time, so a sampler with another number of
+/// outputs would panic in the render loop.
+fn check_channel(
+    clip: &str,
+    node: usize,
+    times: Vec<f32>,
+    values: ChannelValues,
+) -> Option<Channel> {
+    if times.is_empty() || times.len() != values.len() {
+        warn!(
+            "Channel of node {} in animation {} has {} times and {} values. Skip it.",
+            node,
+            clip,
+            times.len(),
+            values.len()
+        );
+        return None;
+    }
+    Some(Channel {
+        node,
+        times,
+        values,
+    })
+}
+
+/// Decompose a world matrix into our transform component. Rotation is stored
+/// as euler angles (in radians).
+fn to_transform(world: Matrix4<f32>) -> TransformComponent {
+    let (t, r, s) = gltf::scene::Transform::Matrix {
+        matrix: world.into(),
+    }
+    .decomposed();
+
+    TransformComponent {
+        position: Vector3::new(t[0], t[1], t[2]),
+        rotation: euler_angles(Quaternion::new(r[3], r[0], r[1], r[2])),
+        scale: Vector3::new(s[0], s[1], s[2]),
+    }
+}
+
+/// Euler angles (radians) of a rotation, in the order that
+/// `TransformComponent::model_matrix` applies them back (x, then y, then z).
+fn euler_angles(rotation: Quaternion<f32>) -> Vector3<f32> {
+    let m = Matrix3::from(rotation);
+    let y = m[2][0].max(-1.0).min(1.0).asin();
+    if m[2][0].abs() < 0.9999 {
+        Vector3::new((-m[2][1]).atan2(m[2][2]), y, (-m[1][0]).atan2(m[0][0]))
+    } else {
+        // Gimbal lock, x and z turn around the same axis.
+        Vector3::new(m[1][2].atan2(m[1][1]), y, 0.0)
+    }
+}
+
+/// Name of the texture the models use for an image, with the pixels to load
+/// under that name. Images that cannot be converted use the default texture,
+/// which is already loaded.
+fn image_texture(name: String, data: gltf::image::Data) -> (String, Option<Vec<u8>>) {
+    match to_rgba(data) {
+        Some(pixels) => (name, Some(pixels)),
+        None => {
+            error!(
+                "Image {} has an unsupported pixel format, use {} instead",
+                name, DEFAULT_TEXTURE
+            );
+            (DEFAULT_TEXTURE.to_string(), None)
+        }
+    }
+}
+
+/// Our textures are all RGBA.
+fn to_rgba(data: gltf::image::Data) -> Option<Vec<u8>> {
+    use gltf::image::Format;
+    let pixels = data.pixels;
+    let rgba = match data.format {
+        Format::R8G8B8A8 => pixels,
+        Format::R8G8B8 => pixels
+            .chunks(3)
+            .flat_map(|p| vec![p[0], p[1], p[2], 255])
+            .collect(),
+        Format::B8G8R8A8 => pixels
+            .chunks(4)
+            .flat_map(|p| vec![p[2], p[1], p[0], p[3]])
+            .collect(),
+        Format::B8G8R8 => pixels
+            .chunks(3)
+            .flat_map(|p| vec![p[2], p[1], p[0], 255])
+            .collect(),
+        Format::R8G8 => pixels
+            .chunks(2)
+            .flat_map(|p| vec![p[0], p[1], 0, 255])
+            .collect(),
+        Format::R8 => pixels.iter().flat_map(|p| vec![*p, *p, *p, 255]).collect(),
+        _ => return None,
+    };
+
+    Some(rgba)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Euler, InnerSpace, Rad, Vector4};
+
+    fn image(format: gltf::image::Format, pixels: Vec<u8>) -> gltf::image::Data {
+        gltf::image::Data {
+            pixels,
+            format,
+            width: 1,
+            height: 1,
+        }
+    }
+
+    #[test]
+    fn unsupported_image_test() {
+        use gltf::image::Format;
+
+        let (name, pixels) =
+            image_texture("arena/0".to_string(), image(Format::R8G8B8, vec![1, 2, 3]));
+        assert_eq!("arena/0", name);
+        assert_eq!(Some(vec![1, 2, 3, 255]), pixels);
+
+        // 16 bits per channel is not converted. The models use the default
+        // texture instead of a texture that does not exist.
+        let (name, pixels) = image_texture("arena/1".to_string(), image(Format::R16, vec![0; 2]));
+        assert_eq!(DEFAULT_TEXTURE, name);
+        assert_eq!(None, pixels);
+    }
+
+    #[test]
+    fn rotated_node_test() {
+        // Node turned a quarter around Y, moved and scaled.
+        let world = Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0))
+            * Matrix4::from_angle_y(Deg(90.0))
+            * Matrix4::from_scale(2.0);
+
+        let transform = to_transform(world);
+        assert!((transform.rotation.y - std::f32::consts::FRAC_PI_2).abs() < 1e-3);
+
+        // The model matrix of the game object is the one of the node.
+        let model = transform.model_matrix();
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((model[i][j] - world[i][j]).abs() < 1e-3);
+            }
+        }
+
+        // +X of the mesh ends up along -Z.
+        let x = model * Vector4::new(1.0, 0.0, 0.0, 0.0);
+        assert!((x - Vector4::new(0.0, 0.0, -2.0, 0.0)).magnitude() < 1e-3);
+
+        // Any rotation goes through.
+        let world = Matrix4::from(Euler::new(Rad(0.3), Rad(-0.5), Rad(1.2)));
+        let model = to_transform(world).model_matrix();
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((model[i][j] - world[i][j]).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn keyframe_count_test() {
+        let translations = |n| ChannelValues::Translations(vec![Vector3::new(0.0, 0.0, 0.0); n]);
+
+        let channel = check_channel("arena/Run", 3, vec![0.0, 1.0], translations(2)).unwrap();
+        assert_eq!(2, channel.times.len());
+
+        // More inputs than outputs, or the opposite.
+        assert!(check_channel("arena/Run", 3, vec![0.0, 1.0, 2.0], translations(2)).is_none());
+        assert!(check_channel("arena/Run", 3, vec![0.0], translations(2)).is_none());
+        assert!(check_channel("arena/Run", 3, vec![], translations(0)).is_none());
+    }
+
+    #[test]
+    fn too_many_joints_test() {
+        assert!(check_skin("arena/Armature", MAX_JOINTS).is_ok());
+        match check_skin("arena/Armature", MAX_JOINTS + 1) {
+            Err(TwError::ModelLoading(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let mut joints = vec![[0, 1, 2, 3]; 10];
+        assert!(check_vertex_joints("Cube", &joints).is_ok());
+        joints[5][2] = MAX_JOINTS as u16;
+        assert!(check_vertex_joints("Cube", &joints).is_err());
+    }
+}
@@ -3,6 +3,7 @@ mod attachments;
 mod debug_system;
 mod directional_lighting_system;
 mod frame;
+pub mod gltf_loader;
 pub mod model;
 pub mod pick;
 mod point_lighting_system;
@@ -36,7 +37,9 @@ use std::sync::Arc;
 use crate::camera::Camera;
 use crate::config::GameConfig;
 use crate::config::RenderOptions;
-use crate::ecs::components::{LightComponent, LightType, ModelComponent, TransformComponent};
+use crate::ecs::components::{
+    AnimatorComponent, LightComponent, LightType, ModelComponent, TransformComponent,
+};
 use crate::ecs::{Entity, ECS};
 use crate::error::{TwError, TwResult};
 use crate::event::{EditorEvent, Event};
@@ -261,7 +264,7 @@ impl<'a> Renderer<'a> {
         ui: Ui<'ui>,
         camera: &mut Camera,
         lights: Vec<(&LightComponent, &TransformComponent)>,
-        objects: Vec<(&ModelComponent, &TransformComponent)>,
+        objects: Vec<(&ModelComponent, &TransformComponent, Option<&AnimatorComponent>)>,
     ) {
         self.previous_frame_end.as_mut().unwrap().cleanup_finished();
         let window = self.surface.window();
@@ -14,10 +14,15 @@ pub struct Vertex {
     position: [f32; 3],
     texcoords: [f32; 2],
     normals: [f32; 3],
+
+    // For skinned meshes. Index of the 4 joints that influence this vertex
+    // and their weights. Weights are all 0 for static meshes.
+    joints: [f32; 4],
+    weights: [f32; 4],
 }
 
 impl Vertex {
-    fn new(x: f32, y: f32, z: f32, tx: f32, ty: f32, nx: f32, ny: f32, nz: f32) -> Self {
+    pub fn new(x: f32, y: f32, z: f32, tx: f32, ty: f32, nx: f32, ny: f32, nz: f32) -> Self {
         let position = [x, y, z];
         let texcoords = [tx, ty];
         let normals = [nx, ny, nz];
@@ -25,10 +30,24 @@ impl Vertex {
             position,
             texcoords,
             normals,
+            joints: [0.0; 4],
+            weights: [0.0; 4],
         }
     }
+
+    /// Attach the vertex to joints of a skeleton.
+    pub fn with_skin(mut self, joints: [u16; 4], weights: [f32; 4]) -> Self {
+        self.joints = [
+            joints[0] as f32,
+            joints[1] as f32,
+            joints[2] as f32,
+            joints[3] as f32,
+        ];
+        self.weights = weights;
+        self
+    }
 }
-vulkano::impl_vertex!(Vertex, position, texcoords, normals);
+vulkano::impl_vertex!(Vertex, position, texcoords, normals, joints, weights);
 
 /*
  * Model that are loaded in GPU memory
@@ -145,4 +164,10 @@ impl ModelManager {
 
         Ok(())
     }
+
+    /// Add a model that was already loaded in GPU memory (from a glTF file
+    /// for example). Replace the existing one if any.
+    pub fn add_model(&mut self, model_name: String, model: Model) {
+        self.models.insert(model_name, model);
+    }
 }
@@ -17,6 +17,7 @@ use std::iter;
 use crate::ecs::{Entity, ECS, gen_index::GenerationalIndex};
 use super::model::{ModelManager, Vertex};
 use super::scene_system::{create_mvp, vs};
+use crate::animation::joint_uniform;
 
 /*
  * This module will implement a technique to get the entity when clicking on a
@@ -277,7 +278,8 @@ impl Object3DPicker {
             ).unwrap();
 
             let uniform_buffer_subbuffer = {
-                let uniform_data = create_mvp(transform, &view, &proj);
+                // Picking uses the bind pose.
+                let uniform_data = create_mvp(transform, &view, &proj, joint_uniform(None));
                 self.uniform_buffer.next(uniform_data).unwrap()
             };
 
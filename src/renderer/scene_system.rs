@@ -17,8 +17,9 @@ use cgmath::Matrix4;
 use std::iter;
 use std::sync::Arc;
 
+use crate::animation::{joint_uniform, JointMatrices};
 use crate::camera::Camera;
-use crate::ecs::components::{ModelComponent, TransformComponent};
+use crate::ecs::components::{AnimatorComponent, ModelComponent, TransformComponent};
 use crate::event::{Event, ResourceEvent};
 use crate::renderer::model::Vertex;
 use crate::resource::Resources;
@@ -108,7 +109,11 @@ impl SceneDrawSystem {
         &self,
         resources: &Resources,
         camera: &mut Camera,
-        objects: &Vec<(&ModelComponent, &TransformComponent)>,
+        objects: &Vec<(
+            &ModelComponent,
+            &TransformComponent,
+            Option<&AnimatorComponent>,
+        )>,
     ) -> AutoCommandBuffer {
         trace!("----------------------------------------------");
         trace!("begin scene rendering");
@@ -126,7 +131,7 @@ impl SceneDrawSystem {
 
         // 2. Draw all objects in the scene
         // --------------------------------
-        for (model, transform) in objects.iter() {
+        for (model, transform, animator) in objects.iter() {
             // I don't want to crash if the texture does not exist/is not loaded
             // just print a warning and do not render this object.
             let texture = resources.textures.textures.get(&model.texture_name);
@@ -148,7 +153,7 @@ impl SceneDrawSystem {
             // One is for the position,
             // Other is for fragment
             let uniform_buffer_subbuffer = {
-                let uniform_data = create_mvp(transform, &view, &proj);
+                let uniform_data = create_mvp(transform, &view, &proj, joint_uniform(*animator));
                 self.uniform_buffer.next(uniform_data).unwrap()
             };
             trace!("Render object at position {:?}", transform);
@@ -222,6 +227,14 @@ pub mod vs {
             {
                 name: "normals",
                 format: R32G32B32Sfloat
+            },
+            {
+                name: "joints",
+                format: R32G32B32A32Sfloat
+            },
+            {
+                name: "weights",
+                format: R32G32B32A32Sfloat
             }
         ],
         output: [
@@ -251,7 +264,8 @@ pub mod vs {
                 data: [
                     (model, "mat4"),
                     (view, "mat4"),
-                    (proj, "mat4")
+                    (proj, "mat4"),
+                    (joint_matrices, "mat4[32]")
                 ]
             }
         ]
@@ -309,18 +323,15 @@ pub fn create_mvp(
     t: &TransformComponent,
     view: &Matrix4<f32>,
     proj: &Matrix4<f32>,
+    joint_matrices: JointMatrices,
 ) -> vs::ty::Data {
-    let scale = t.scale;
-    let from_t = Matrix4::from_translation(t.position);
-    let from_s = Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z);
-    trace!("From translation: {:?}", from_t);
-    trace!("From scale: {:?}", from_s);
-    let model = from_t * from_s;
+    let model = t.model_matrix();
 
     trace!("Model {:?}, View {:?}, Projection {:?}", model, view, proj);
     vs::ty::Data {
         model: model.into(),
         view: (*view).into(),
         proj: (*proj).into(),
+        joint_matrices,
     }
 }
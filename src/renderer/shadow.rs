@@ -16,7 +16,8 @@ use vulkano::sync::GpuFuture;
 use image::ImageBuffer;
 
 use super::GBufferComponent;
-use crate::ecs::components::{ModelComponent, TransformComponent};
+use crate::animation::{joint_uniform, JointMatrices};
+use crate::ecs::components::{AnimatorComponent, ModelComponent, TransformComponent};
 use crate::event::{Event, ResourceEvent};
 use crate::renderer::model::Vertex;
 use crate::resource::Resources;
@@ -179,7 +180,11 @@ impl ShadowSystem {
         &self,
         resources: &Resources,
         light_transform: &TransformComponent,
-        objects: &Vec<(&ModelComponent, &TransformComponent)>,
+        objects: &Vec<(
+            &ModelComponent,
+            &TransformComponent,
+            Option<&AnimatorComponent>,
+        )>,
     ) -> AutoCommandBuffer {
         let (view, proj) = ShadowSystem::get_vp(light_transform);
 
@@ -195,7 +200,7 @@ impl ShadowSystem {
         // 2. Draw all objects in the scene
         // --------------------------------
         debug!("Start Drawing shadow map ------------------");
-        for (model, transform) in objects.iter() {
+        for (model, transform, animator) in objects.iter() {
             let texture = resources.textures.textures.get(&model.texture_name);
             if !texture.is_some() {
                 error!("Texture {} is not loaded", model.texture_name);
@@ -210,12 +215,9 @@ impl ShadowSystem {
             let model = model_buf.unwrap();
 
             let uniform_buffer_subbuffer = {
-                let uniform_data = create_mvp(transform, &view, &proj);
+                let uniform_data = create_mvp(transform, &view, &proj, joint_uniform(*animator));
                 if log_enabled!(Level::Debug) {
-                    let scale = transform.scale;
-                    let from_t = Matrix4::from_translation(transform.position);
-                    let from_s = Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z);
-                    let model = from_t * from_s;
+                    let model = transform.model_matrix();
 
                     debug!("Transform = {:?}", transform);
                     debug!("Model = {:?}", model);
@@ -289,16 +291,19 @@ impl ShadowSystem {
     }
 }
 
-fn create_mvp(t: &TransformComponent, view: &Matrix4<f32>, proj: &Matrix4<f32>) -> vs::ty::Data {
-    let scale = t.scale;
-    let from_t = Matrix4::from_translation(t.position);
-    let from_s = Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z);
-    let model = from_t * from_s;
+fn create_mvp(
+    t: &TransformComponent,
+    view: &Matrix4<f32>,
+    proj: &Matrix4<f32>,
+    joint_matrices: JointMatrices,
+) -> vs::ty::Data {
+    let model = t.model_matrix();
 
     vs::ty::Data {
         model: model.into(),
         view: (*view).into(),
         proj: (*proj).into(),
+        joint_matrices,
     }
 }
 
@@ -318,6 +323,14 @@ mod vs {
             {
                 name: "normals",
                 format: R32G32B32Sfloat
+            },
+            {
+                name: "joints",
+                format: R32G32B32A32Sfloat
+            },
+            {
+                name: "weights",
+                format: R32G32B32A32Sfloat
             }
         ],
         output: [
@@ -334,7 +347,8 @@ mod vs {
                 data: [
                     (model, "mat4"),
                     (view, "mat4"),
-                    (proj, "mat4")
+                    (proj, "mat4"),
+                    (joint_matrices, "mat4[32]")
                 ]
             }
         ]
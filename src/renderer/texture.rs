@@ -29,23 +29,28 @@ impl Texture {
         device: Arc<Device>,
         queue: Arc<Queue>,
     ) -> TwResult<(Texture, Box<GpuFuture>)> {
-        let ((texture, tex_future), width, height) = {
-            let image = image::open(filename)?.to_rgba();
-            let width = image.width();
-            let height = image.height();
-            let image_data = image.into_raw().clone();
+        let image = image::open(filename)?.to_rgba();
+        let width = image.width();
+        let height = image.height();
+        Texture::from_rgba(image.into_raw(), width, height, device, queue)
+    }
 
-            (
-                ImmutableImage::from_iter(
-                    image_data.iter().cloned(),
-                    Dimensions::Dim2d { width, height },
-                    Format::R8G8B8A8Srgb,
-                    queue.clone(),
-                )?,
-                width,
-                height,
-            )
-        };
+    /// Create a texture from raw RGBA pixels (8 bits per channel). This is used
+    /// when the image does not come from a file, for example images embedded in
+    /// a glTF file.
+    pub fn from_rgba(
+        pixels: Vec<u8>,
+        width: u32,
+        height: u32,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+    ) -> TwResult<(Texture, Box<GpuFuture>)> {
+        let (texture, tex_future) = ImmutableImage::from_iter(
+            pixels.into_iter(),
+            Dimensions::Dim2d { width, height },
+            Format::R8G8B8A8Srgb,
+            queue.clone(),
+        )?;
 
         let sampler = Sampler::new(
             device.clone(),
@@ -103,4 +108,22 @@ impl TextureManager {
         self.textures.insert(texture_name, texture);
         Ok(())
     }
+
+    // Same as above, but from pixels already in memory.
+    pub fn load_texture_from_rgba(
+        &mut self,
+        texture_name: String,
+        pixels: Vec<u8>,
+        width: u32,
+        height: u32,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+    ) -> TwResult<()> {
+        let (texture, gpu_future) = Texture::from_rgba(pixels, width, height, device, queue)?;
+
+        gpu_future.then_signal_fence_and_flush()?.wait(None)?;
+
+        self.textures.insert(texture_name, texture);
+        Ok(())
+    }
 }
@@ -6,16 +6,24 @@ use std::sync::Arc;
 use std::time::Duration;
 use vulkano::device::Queue;
 
+use crate::animation::AnimationManager;
 use crate::event::{Event, ResourceEvent};
+use crate::renderer::gltf_loader::{load_gltf, GltfScene};
 use crate::renderer::model::ModelManager;
 use crate::renderer::texture::TextureManager;
 
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs;
 
 pub struct Resources {
     pub models: ModelManager,
     pub textures: TextureManager,
+    pub animations: AnimationManager,
+
+    /// Node hierarchy of the glTF files, by file name. Use `GltfScene::spawn`
+    /// to add one to an ECS.
+    pub scenes: HashMap<String, GltfScene>,
 
     // Need to keep that in order to load new textures or models.
     queue: Arc<Queue>,
@@ -40,6 +48,8 @@ impl Resources {
         let mut r = Resources {
             models,
             textures,
+            animations: AnimationManager::new(),
+            scenes: HashMap::new(),
             queue,
             rx,
             watcher,
@@ -48,6 +58,7 @@ impl Resources {
 
         timed!(r.init_textures());
         timed!(r.init_models());
+        timed!(r.init_gltf_scenes());
 
         // Create a watcher object, delivering debounced events.
         // The notification back-end is selected based on the platform.
@@ -190,6 +201,47 @@ impl Resources {
         debug!("Finished reading models");
     }
 
+    /// Load all the glTF files in the `models` folder.
+    fn init_gltf_scenes(&mut self) {
+        let models_path = self.resource_path.join("models");
+        match std::fs::read_dir(&models_path) {
+            Ok(readdir) => {
+                for res in readdir {
+                    match res {
+                        Ok(dir_entry) => {
+                            let path = dir_entry.path();
+                            if is_gltf(&path) {
+                                self.load_gltf(&path);
+                            }
+                        }
+                        Err(e) => error!("Error while reading DirEntry = {:?}", e),
+                    }
+                }
+            }
+            Err(e) => error!("Error while reading {:?} = {:?}", models_path, e),
+        }
+    }
+
+    /// Load (or reload) a glTF file. Its meshes, images, skins and animations
+    /// are added to the managers, and the node tree is stored in `scenes`.
+    fn load_gltf(&mut self, path: &PathBuf) {
+        if let Some(filename) = path.file_stem().and_then(|osstr| osstr.to_str()) {
+            debug!("Will load glTF: {}", filename);
+            match load_gltf(
+                path,
+                &mut self.models,
+                &mut self.textures,
+                &mut self.animations,
+                self.queue.clone(),
+            ) {
+                Ok(scene) => {
+                    self.scenes.insert(filename.to_string(), scene);
+                }
+                Err(err) => error!("Error while loading glTF {:?}: {:?}", path, err),
+            }
+        }
+    }
+
     fn reload_model(&mut self, path: &PathBuf) {
         if let Some(filename) = path.file_stem().and_then(|osstr| osstr.to_str()) {
             debug!("Will reload: {}", filename);
@@ -238,6 +290,9 @@ impl Resources {
                                 x if x == OsStr::new("obj") => {
                                     self.reload_model(&path);
                                 }
+                                _ if is_gltf(&path) => {
+                                    self.load_gltf(&path);
+                                }
                                 x if (x == OsStr::new("png"))
                                     || (x == OsStr::new("jpg"))
                                     || (x == OsStr::new("jpeg"))
@@ -261,3 +316,10 @@ impl Resources {
         events
     }
 }
+
+fn is_gltf(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("gltf") || ext.eq_ignore_ascii_case("glb"),
+        None => false,
+    }
+}
@@ -0,0 +1,150 @@
+use cgmath::Vector3;
+use imgui::{im_str, ImGuiCond, ImGuiSelectableFlags, ImVec2, Ui};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::Scene;
+use crate::camera::{Camera, CameraInputHandler};
+use crate::ecs::{components::TransformComponent, systems::RenderingSystem, ECS};
+use crate::event::{Event, GameEvent};
+use crate::input::Input;
+use crate::net::discovery::{DiscoveredServer, DiscoveryMode, ServerBrowser};
+use crate::resource::Resources;
+use crate::ui::Gui;
+
+/// List of the servers found on the LAN.
+pub struct BrowserUi {
+    /// Copied from the browser at each update.
+    servers: Vec<DiscoveredServer>,
+    mode: DiscoveryMode,
+
+    /// Game address of the selected server.
+    selected: Option<SocketAddr>,
+
+    /// Set by the buttons. The scene handles them.
+    connect_to: Option<SocketAddr>,
+    refresh: bool,
+}
+
+impl BrowserUi {
+    fn new(mode: DiscoveryMode) -> Self {
+        BrowserUi {
+            servers: Vec::new(),
+            mode,
+            selected: None,
+            connect_to: None,
+            refresh: false,
+        }
+    }
+}
+
+impl Gui for BrowserUi {
+    fn run_ui(&mut self, ui: &Ui, _ecs: &mut ECS) -> bool {
+        ui.window(im_str!("Servers"))
+            .size((500.0, 300.0), ImGuiCond::FirstUseEver)
+            .build(|| {
+                match self.mode {
+                    DiscoveryMode::Broadcast => ui.text(im_str!("Servers of the local network")),
+                    DiscoveryMode::Loopback => ui.text(im_str!("Servers of this machine")),
+                }
+                ui.separator();
+
+                if self.servers.is_empty() {
+                    ui.text(im_str!("Looking for servers..."));
+                }
+                for server in self.servers.iter() {
+                    let a = &server.announcement;
+                    let selected = self.selected == Some(server.addr);
+                    if ui.selectable(
+                        im_str!(
+                            "{} - {} - {}/{} players - {}",
+                            a.name,
+                            a.map,
+                            a.players,
+                            a.max_players,
+                            server.addr
+                        ),
+                        selected,
+                        ImGuiSelectableFlags::empty(),
+                        ImVec2::new(0.0, 0.0),
+                    ) {
+                        self.selected = Some(server.addr);
+                    }
+                }
+
+                ui.separator();
+                if ui.button(im_str!("Connect"), (0.0, 0.0)) {
+                    self.connect_to = self.selected;
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Refresh"), (0.0, 0.0)) {
+                    self.refresh = true;
+                }
+            });
+        true
+    }
+}
+
+/// Server browser. It looks for the servers of the LAN and asks the
+/// application to connect to the one the player picks.
+pub struct BrowserScene {
+    pub ecs: ECS,
+    pub browser_ui: BrowserUi,
+
+    browser: ServerBrowser,
+}
+
+impl BrowserScene {
+    pub fn new<'a>(mode: DiscoveryMode, render_system: &RenderingSystem<'a>) -> Self {
+        let mut ecs = ECS::new();
+        let transform = TransformComponent {
+            position: Vector3::new(0.0, 1.0, 0.0),
+            rotation: Vector3::new(0.0, 0.0, 0.0),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        };
+
+        let dimensions = render_system.dimensions();
+        let aspect = (dimensions[0] as f32) / (dimensions[1] as f32);
+        ecs.camera = Camera::new(transform, aspect, CameraInputHandler::free_handler());
+
+        BrowserScene {
+            ecs,
+            browser_ui: BrowserUi::new(mode),
+            browser: ServerBrowser::new(mode).expect("Cannot start the server browser"),
+        }
+    }
+}
+
+impl Scene for BrowserScene {
+    fn update(&mut self, _dt: Duration) -> Option<Vec<Event>> {
+        let now = Instant::now();
+        if self.browser_ui.refresh {
+            self.browser_ui.refresh = false;
+            self.browser.query(now);
+        }
+        self.browser.update(now);
+
+        self.browser_ui.servers = self.browser.servers().to_vec();
+        None
+    }
+
+    fn process_input(
+        &mut self,
+        _input: Option<&Input>,
+        _resources: Option<&Resources>,
+        _dt: Duration,
+    ) -> Option<Vec<Event>> {
+        self.browser_ui
+            .connect_to
+            .take()
+            .map(|addr| vec![Event::GameEvent(GameEvent::ConnectTo(addr))])
+    }
+
+    fn get_parts_mut(&mut self) -> (&mut ECS, Option<&mut Gui>) {
+        (&mut self.ecs, Some(&mut self.browser_ui))
+    }
+
+    fn get_ecs(&self) -> &ECS {
+        &self.ecs
+    }
+}
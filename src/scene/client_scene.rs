@@ -1,10 +1,13 @@
 use cgmath::Vector3;
-use imgui::Ui;
-use log::debug;
+use imgui::{im_str, ImGuiCond, ImString, Ui};
+use log::{debug, info};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::net::ToSocketAddrs;
 use std::time::Duration;
 
 use super::Scene;
+use crate::animation::AnimationSystem;
 use crate::camera::{Camera, CameraDirection, CameraInputHandler};
 use crate::ecs::{
     components::TransformComponent,
@@ -16,18 +19,190 @@ use crate::input::{Axis, Input, KeyType};
 use crate::resource::Resources;
 use crate::ui::Gui;
 
+use crate::net::chat::{ChatLine, ChatRequest, CHAT_CHANNEL, MAX_CHAT_LENGTH};
+use crate::net::conditioner::LinkConditions;
+use crate::net::crypto::ConnectToken;
+use crate::net::demo::DemoError;
+use crate::net::interpolation::InterpolationSettings;
+use crate::net::protocol::DisconnectReason;
+use crate::net::reliable::ReliableContent;
+use crate::net::stats::NetworkStats;
 use crate::net::ClientSystem;
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+/// Only the last lines are kept in the chat window.
+const MAX_CHAT_LINES: usize = 50;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum ClientCommand {
     Move(CameraDirection),
     LookAt([f32; 3]),
 }
 
-pub struct GameUi {}
+pub struct GameUi {
+    chat_lines: VecDeque<ChatLine>,
+    chat_input: ImString,
+    team_only: bool,
+
+    /// Messages typed by the player. The scene sends them to the server.
+    outgoing_chat: Vec<ChatRequest>,
+
+    /// Why the connection with the server is over.
+    disconnected: Option<DisconnectReason>,
+
+    /// Simulated network, edited in the debug window. Latency and jitter
+    /// are edited as floats.
+    link_conditions: LinkConditions,
+    link_latency: f32,
+    link_jitter: f32,
+
+    /// Set when the conditions were edited. The scene gives them to the
+    /// network system.
+    new_link_conditions: Option<LinkConditions>,
+
+    /// Copied from the network system at each update. Shown with F3.
+    stats: NetworkStats,
+    show_stats: bool,
+
+    /// Set while the level of the server is loaded.
+    loading: bool,
+}
+
+impl GameUi {
+    pub fn new(link_conditions: LinkConditions) -> Self {
+        GameUi {
+            chat_lines: VecDeque::with_capacity(MAX_CHAT_LINES),
+            chat_input: ImString::with_capacity(MAX_CHAT_LENGTH),
+            team_only: false,
+            outgoing_chat: Vec::new(),
+            disconnected: None,
+            link_conditions,
+            link_latency: link_conditions.latency_ms as f32,
+            link_jitter: link_conditions.jitter_ms as f32,
+            new_link_conditions: None,
+            stats: NetworkStats::default(),
+            show_stats: false,
+            loading: false,
+        }
+    }
+
+    pub fn add_chat_line(&mut self, line: ChatLine) {
+        if self.chat_lines.len() == MAX_CHAT_LINES {
+            self.chat_lines.pop_front();
+        }
+        self.chat_lines.push_back(line);
+    }
+
+    fn submit_chat(&mut self) {
+        let text = String::from(self.chat_input.to_str());
+        if !text.trim().is_empty() {
+            self.outgoing_chat.push(ChatRequest {
+                text,
+                team_only: self.team_only,
+            });
+        }
+        self.chat_input.clear();
+    }
+
+    fn submit_link_conditions(&mut self) {
+        let c = &mut self.link_conditions;
+        c.latency_ms = self.link_latency.max(0.0) as u32;
+        c.jitter_ms = self.link_jitter.max(0.0) as u32;
+        let probability = |p: f32| p.max(0.0).min(1.0);
+        c.loss = probability(c.loss);
+        c.duplication = probability(c.duplication);
+        c.reordering = probability(c.reordering);
+        self.new_link_conditions = Some(*c);
+    }
+}
+
 impl Gui for GameUi {
-    fn run_ui(&mut self, _ui: &Ui, _ecs: &mut ECS) -> bool {
-        // maybe add debug console :D
+    fn run_ui(&mut self, ui: &Ui, _ecs: &mut ECS) -> bool {
+        if let Some(reason) = self.disconnected {
+            ui.window(im_str!("Disconnected"))
+                .size((300.0, 80.0), ImGuiCond::FirstUseEver)
+                .build(|| {
+                    ui.text_wrapped(im_str!("{}", reason));
+                    ui.text_wrapped(im_str!("Press Escape to quit."));
+                });
+        } else if self.loading {
+            ui.window(im_str!("Loading"))
+                .size((300.0, 60.0), ImGuiCond::FirstUseEver)
+                .build(|| {
+                    ui.text(im_str!("Loading the level of the server..."));
+                });
+        }
+
+        ui.window(im_str!("Chat"))
+            .size((400.0, 250.0), ImGuiCond::FirstUseEver)
+            .build(|| {
+                for line in self.chat_lines.iter() {
+                    let team = if line.team_only { "[team] " } else { "" };
+                    match line.from {
+                        Some(ref from) => {
+                            ui.text_wrapped(im_str!("{}{}: {}", team, from, line.text))
+                        }
+                        None => ui.text_wrapped(im_str!("* {}", line.text)),
+                    }
+                }
+
+                ui.separator();
+                let mut send = ui
+                    .input_text(im_str!("##chat_input"), &mut self.chat_input)
+                    .enter_returns_true(true)
+                    .build();
+                ui.same_line(0.0);
+                send |= ui.button(im_str!("Send"), (0.0, 0.0));
+                ui.checkbox(im_str!("Team only"), &mut self.team_only);
+
+                if send {
+                    self.submit_chat();
+                }
+            });
+
+        if self.show_stats {
+            let stats = &self.stats;
+            ui.window(im_str!("Network statistics"))
+                .size((250.0, 170.0), ImGuiCond::FirstUseEver)
+                .build(|| {
+                    ui.text(im_str!("rtt: {:.1} ms", stats.rtt_ms));
+                    ui.text(im_str!("jitter: {:.1} ms", stats.jitter_ms));
+                    ui.text(im_str!("loss: {:.1}%", stats.loss * 100.0));
+                    ui.text(im_str!("in: {:.0} B/s", stats.bytes_in_per_sec));
+                    ui.text(im_str!("out: {:.0} B/s", stats.bytes_out_per_sec));
+                    ui.text(im_str!("snapshot: {:.0} B", stats.snapshot_size));
+                    ui.text(im_str!("out of order: {}", stats.out_of_order));
+                });
+        }
+
+        ui.window(im_str!("Network conditions"))
+            .size((300.0, 190.0), ImGuiCond::FirstUseEver)
+            .build(|| {
+                let mut changed = ui
+                    .input_float(im_str!("latency (ms)"), &mut self.link_latency)
+                    .build();
+                changed |= ui
+                    .input_float(im_str!("jitter (ms)"), &mut self.link_jitter)
+                    .build();
+                changed |= ui
+                    .input_float(im_str!("loss"), &mut self.link_conditions.loss)
+                    .build();
+                changed |= ui
+                    .input_float(
+                        im_str!("duplication"),
+                        &mut self.link_conditions.duplication,
+                    )
+                    .build();
+                changed |= ui
+                    .input_float(im_str!("reordering"), &mut self.link_conditions.reordering)
+                    .build();
+
+                if changed {
+                    self.submit_link_conditions();
+                }
+
+                ui.separator();
+                ui.checkbox(im_str!("Show statistics (F3)"), &mut self.show_stats);
+            });
         true
     }
 }
@@ -38,12 +213,20 @@ pub struct ClientScene {
 
     // All systems for this Scene.
     // dummy_system: DummySystem,
+    animation_system: AnimationSystem,
     backend: ClientSystem,
     commands: Vec<ClientCommand>,
 }
 
 impl ClientScene {
-    pub fn new<'a>(server_addr: &str, render_system: &RenderingSystem<'a>) -> Self {
+    pub fn new<'a>(
+        server_addr: &str,
+        token: Option<ConnectToken>,
+        interpolation: InterpolationSettings,
+        link_conditions: LinkConditions,
+        link_seed: u64,
+        render_system: &RenderingSystem<'a>,
+    ) -> Self {
         let mut ecs = ECS::new();
         let transform = TransformComponent {
             position: Vector3::new(0.0, 1.0, 0.0),
@@ -55,22 +238,74 @@ impl ClientScene {
         let aspect = (dimensions[0] as f32) / (dimensions[1] as f32);
         ecs.camera = Camera::new(transform, aspect, CameraInputHandler::fps_handler());
 
-        let backend = ClientSystem::connect(server_addr.parse().unwrap()).unwrap();
+        // Accept host names as well as IPv4 and IPv6 addresses.
+        let server_addr = server_addr
+            .to_socket_addrs()
+            .expect("Invalid server address")
+            .next()
+            .expect("Server address did not resolve");
+        let mut backend = ClientSystem::connect(server_addr, token).unwrap();
+        backend.set_interpolation_settings(interpolation);
+        backend.set_link_conditions(link_conditions, link_seed);
         let commands = Vec::with_capacity(10);
 
         ClientScene {
             ecs,
-            game_ui: GameUi {},
+            game_ui: GameUi::new(link_conditions),
+            animation_system: AnimationSystem::new(),
             backend,
             commands,
         }
     }
+
+    /// Write what the server sends to a demo, until the scene is dropped.
+    pub fn record_demo(&mut self, path: &str) -> Result<(), DemoError> {
+        info!("Record demo to {}", path);
+        self.backend.record_demo(path)
+    }
+
+    /// Where the levels asked by the server are read.
+    pub fn set_levels_dir(&mut self, dir: &str) {
+        self.backend.set_levels_dir(dir);
+    }
 }
 
 impl Scene for ClientScene {
-    fn update(&mut self, _dt: Duration) -> Option<Vec<Event>> {
+    fn update(&mut self, dt: Duration) -> Option<Vec<Event>> {
         //self.dummy_system.do_dumb_thing(dt, &mut self.ecs);
-        self.backend.poll_events(&mut self.ecs);
+        for ev in self.backend.poll_events(&mut self.ecs) {
+            match ev {
+                Event::ReliableMessage(_, ReliableContent::ChatLine(line)) => {
+                    self.game_ui.add_chat_line(line)
+                }
+                ev => debug!("Received from server: {:?}", ev),
+            }
+        }
+
+        if self.game_ui.disconnected.is_none() {
+            if let Some(reason) = self.backend.disconnected() {
+                info!("Connection with the server is over: {}", reason);
+                self.game_ui.disconnected = Some(reason);
+            }
+        }
+
+        // Camera is basically the player position :)
+        self.ecs.camera.state.transform.position = self.backend.player_position();
+        self.game_ui.stats = self.backend.stats();
+        self.game_ui.loading = self.backend.is_loading();
+
+        for request in self.game_ui.outgoing_chat.drain(..) {
+            self.backend
+                .send_reliable(CHAT_CHANNEL, ReliableContent::ChatRequest(request));
+        }
+
+        if let Some(conditions) = self.game_ui.new_link_conditions.take() {
+            info!("Simulate network conditions: {:?}", conditions);
+            self.backend.update_link_conditions(conditions);
+        }
+
+        // Remote players keep animating between snapshots.
+        self.animation_system.update(dt, &mut self.ecs);
         None
     }
 
@@ -82,6 +317,10 @@ impl Scene for ClientScene {
     ) -> Option<Vec<Event>> {
         let input = input.unwrap();
 
+        if input.get_key_down(KeyType::NetworkStats) {
+            self.game_ui.show_stats = !self.game_ui.show_stats;
+        }
+
         self.commands.clear();
         if input.get_key(KeyType::Up) {
             self.commands
@@ -114,7 +353,9 @@ impl Scene for ClientScene {
                 .push(ClientCommand::LookAt(self.ecs.camera.state.front.into()));
         }
 
-        self.backend.send_commands(&self.commands);
+        self.backend.send_commands(&self.commands, dt);
+        self.backend.apply_prediction(&mut self.ecs);
+        self.ecs.camera.state.transform.position = self.backend.player_position();
         None
     }
 
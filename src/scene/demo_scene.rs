@@ -0,0 +1,226 @@
+use cgmath::Vector3;
+use imgui::{im_str, ImGuiCond, Ui};
+use std::mem;
+use std::time::Duration;
+
+use super::Scene;
+use crate::animation::AnimationSystem;
+use crate::camera::{Camera, CameraDirection, CameraInputHandler};
+use crate::ecs::{components::TransformComponent, systems::RenderingSystem, ECS};
+use crate::event::Event;
+use crate::input::{Axis, Input, KeyType};
+use crate::net::demo::{Demo, DemoPlayer, Playback};
+use crate::net::snapshot::apply_delta;
+use crate::resource::Resources;
+use crate::time::dt_as_secs;
+use crate::ui::Gui;
+
+const MAX_SPEED: f32 = 8.0;
+
+/// Controls of the playback. The scene copies the state of the player here
+/// and applies what was changed.
+pub struct DemoUi {
+    time: f32,
+    duration: f32,
+    speed: f32,
+    paused: bool,
+
+    /// Set when the slider was moved.
+    seek_to: Option<f32>,
+    speed_changed: bool,
+    pause_toggled: bool,
+}
+
+impl DemoUi {
+    fn new(duration: f32) -> Self {
+        DemoUi {
+            time: 0.0,
+            duration,
+            speed: 1.0,
+            paused: false,
+            seek_to: None,
+            speed_changed: false,
+            pause_toggled: false,
+        }
+    }
+}
+
+impl Gui for DemoUi {
+    fn run_ui(&mut self, ui: &Ui, _ecs: &mut ECS) -> bool {
+        ui.window(im_str!("Demo"))
+            .size((400.0, 130.0), ImGuiCond::FirstUseEver)
+            .build(|| {
+                ui.text(im_str!("{:.1} / {:.1} s", self.time, self.duration));
+
+                let mut time = self.time;
+                if ui
+                    .slider_float(im_str!("time"), &mut time, 0.0, self.duration)
+                    .build()
+                {
+                    self.seek_to = Some(time);
+                }
+
+                if ui
+                    .slider_float(im_str!("speed"), &mut self.speed, 0.0, MAX_SPEED)
+                    .build()
+                {
+                    self.speed_changed = true;
+                }
+
+                let label = if self.paused {
+                    im_str!("Play")
+                } else {
+                    im_str!("Pause")
+                };
+                if ui.button(label, (0.0, 0.0)) {
+                    self.pause_toggled = true;
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Restart"), (0.0, 0.0)) {
+                    self.seek_to = Some(0.0);
+                }
+                ui.text_wrapped(im_str!("Space to pause. Hold Ctrl to move the camera."));
+            });
+        true
+    }
+}
+
+/// Watch a demo recorded by the client. There is no server: the recorded
+/// deltas are applied on the ECS, and the camera is free.
+pub struct DemoScene {
+    pub ecs: ECS,
+    pub demo_ui: DemoUi,
+
+    player: DemoPlayer,
+    animation_system: AnimationSystem,
+}
+
+impl DemoScene {
+    pub fn new<'a>(demo: Demo, render_system: &RenderingSystem<'a>) -> Self {
+        let mut ecs = ECS::new();
+        let transform = TransformComponent {
+            position: Vector3::new(0.0, 1.0, 0.0),
+            rotation: Vector3::new(0.0, 0.0, 0.0),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        };
+
+        let dimensions = render_system.dimensions();
+        let aspect = (dimensions[0] as f32) / (dimensions[1] as f32);
+        ecs.camera = Camera::new(transform, aspect, CameraInputHandler::free_handler());
+
+        DemoScene {
+            ecs,
+            demo_ui: DemoUi::new(demo.duration()),
+            player: DemoPlayer::new(demo),
+            animation_system: AnimationSystem::new(),
+        }
+    }
+}
+
+/// Apply the frames given by the player. Restarting keeps the camera where
+/// it is.
+fn apply_playback(ecs: &mut ECS, playback: Playback) {
+    let frames = match playback {
+        Playback::Apply(frames) => frames,
+        Playback::Restart(frames) => {
+            let camera = mem::replace(&mut ecs.camera, Camera::default());
+            *ecs = ECS::new();
+            ecs.camera = camera;
+            frames
+        }
+    };
+
+    for frame in frames {
+        apply_delta(ecs, frame.delta.clone());
+    }
+}
+
+impl Scene for DemoScene {
+    fn update(&mut self, dt: Duration) -> Option<Vec<Event>> {
+        if self.demo_ui.speed_changed {
+            self.demo_ui.speed_changed = false;
+            self.player.set_speed(self.demo_ui.speed);
+        }
+        if self.demo_ui.pause_toggled {
+            self.demo_ui.pause_toggled = false;
+            let paused = self.player.is_paused();
+            self.player.set_paused(!paused);
+        }
+
+        let dt_secs = dt_as_secs(dt) as f32;
+        match self.demo_ui.seek_to.take() {
+            Some(time) => apply_playback(&mut self.ecs, self.player.seek(time)),
+            None => apply_playback(&mut self.ecs, self.player.update(dt_secs)),
+        }
+        let demo_dt = if self.player.is_paused() || self.player.is_finished() {
+            0.0
+        } else {
+            dt_secs * self.player.speed()
+        };
+
+        self.demo_ui.time = self.player.time();
+        self.demo_ui.speed = self.player.speed();
+        self.demo_ui.paused = self.player.is_paused();
+
+        // Animations follow the speed of the demo.
+        self.animation_system.update(
+            Duration::from_micros((demo_dt * 1_000_000.0) as u64),
+            &mut self.ecs,
+        );
+        None
+    }
+
+    fn process_input(
+        &mut self,
+        input: Option<&Input>,
+        _resources: Option<&Resources>,
+        dt: Duration,
+    ) -> Option<Vec<Event>> {
+        let input = input.unwrap();
+
+        if input.get_key_down(KeyType::Space) {
+            self.demo_ui.pause_toggled = true;
+        }
+
+        // Same free camera as in the editor.
+        if input.modifiers.ctrl {
+            if input.get_key(KeyType::Up) {
+                self.ecs
+                    .camera
+                    .process_keyboard(dt, CameraDirection::Forward);
+            }
+
+            if input.get_key(KeyType::Down) {
+                self.ecs
+                    .camera
+                    .process_keyboard(dt, CameraDirection::Backward);
+            }
+
+            if input.get_key(KeyType::Left) {
+                self.ecs.camera.process_keyboard(dt, CameraDirection::Left);
+            }
+
+            if input.get_key(KeyType::Right) {
+                self.ecs.camera.process_keyboard(dt, CameraDirection::Right);
+            }
+
+            let (h_axis, v_axis) = (
+                input.get_axis(Axis::Horizontal),
+                input.get_axis(Axis::Vertical),
+            );
+            if h_axis != 0.0 || v_axis != 0.0 {
+                self.ecs.camera.process_mouse(dt, h_axis, v_axis);
+            }
+        }
+
+        None
+    }
+
+    fn get_parts_mut(&mut self) -> (&mut ECS, Option<&mut Gui>) {
+        (&mut self.ecs, Some(&mut self.demo_ui))
+    }
+
+    fn get_ecs(&self) -> &ECS {
+        &self.ecs
+    }
+}
@@ -4,6 +4,7 @@ use log::debug;
 use std::time::Duration;
 
 use super::Scene;
+use crate::animation::AnimationSystem;
 use crate::camera::{Camera, CameraDirection, CameraInputHandler};
 use crate::ecs::{
     components::TransformComponent,
@@ -28,6 +29,7 @@ pub struct GameScene {
     pub game_ui: GameUi,
     // All systems for this Scene.
     // dummy_system: DummySystem,
+    animation_system: AnimationSystem,
 }
 
 impl GameScene {
@@ -56,14 +58,16 @@ impl GameScene {
         GameScene {
             ecs,
             game_ui: GameUi {},
+            animation_system: AnimationSystem::new(),
             //dummy_system: DummySystem::new(),
         }
     }
 }
 
 impl Scene for GameScene {
-    fn update(&mut self, _dt: Duration) -> Option<Vec<Event>> {
+    fn update(&mut self, dt: Duration) -> Option<Vec<Event>> {
         //self.dummy_system.do_dumb_thing(dt, &mut self.ecs);
+        self.animation_system.update(dt, &mut self.ecs);
         None
     }
 
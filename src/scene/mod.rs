@@ -23,12 +23,16 @@ pub trait Scene {
     fn get_ecs(&self) -> &ECS;
 }
 
+pub use browser_scene::BrowserScene;
 pub use client_scene::{ClientCommand, ClientScene};
+pub use demo_scene::DemoScene;
 pub use editor::EditorScene;
 pub use game::GameScene;
 pub use netscene::NetworkScene;
 
+mod browser_scene;
 mod client_scene;
+mod demo_scene;
 mod editor;
 mod game;
 mod netscene;
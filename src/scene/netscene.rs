@@ -1,13 +1,19 @@
 use super::Scene;
+use crate::animation::AnimationSystem;
+use crate::config::ServerConfig;
 use crate::ecs::systems::PlayerSystem;
 /// Just store the ECS and systems.
 use crate::ecs::ECS;
 use crate::event::Event;
 use crate::input::Input;
-use crate::net::NetworkSystem;
+use crate::net::level::{Level, LevelError};
+use crate::net::transport::Transport;
+use crate::net::{ChatSystem, NetworkSystem};
 use crate::resource::Resources;
 use crate::ui::Gui;
-use log::debug;
+use log::{debug, info};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time::Duration;
 
 pub struct NetworkScene {
@@ -17,29 +23,65 @@ pub struct NetworkScene {
     // My nice systems
     network: NetworkSystem,
     player_system: PlayerSystem,
+    chat_system: ChatSystem,
+    animation_system: AnimationSystem,
+
+    // Where change_map looks for the levels.
+    levels_dir: PathBuf,
 }
 
 impl NetworkScene {
-    pub fn new(port: usize, max_clients: usize) -> Self {
+    pub fn new(config: &ServerConfig) -> Self {
         // can crash if problem with network. Don't worry, that is life.
-        let network = NetworkSystem::new(port, max_clients);
+        NetworkScene::with_network(config, NetworkSystem::new(config))
+    }
+
+    /// Serve on another transport than UDP, e.g. a loopback for a listen
+    /// server or the tests.
+    pub fn with_transport(config: &ServerConfig, transport: Box<Transport>) -> Self {
+        NetworkScene::with_network(config, NetworkSystem::with_transport(config, transport))
+    }
 
+    fn with_network(config: &ServerConfig, network: NetworkSystem) -> Self {
         NetworkScene {
             network,
             ecs: ECS::new(),
-            player_system: PlayerSystem::new(),
+            player_system: PlayerSystem::new(config.input_limits),
+            chat_system: ChatSystem::new(),
+            animation_system: AnimationSystem::new(),
+            levels_dir: PathBuf::from(&config.levels_dir),
         }
     }
 
-    pub fn from_file(port: usize, max_clients: usize, filename: String) -> Self {
-        let network = NetworkSystem::new(port, max_clients);
-        let ecs = ECS::load(filename).expect("Cannot load ECS from file");
-        let player_system = PlayerSystem::new();
-        NetworkScene {
-            network,
-            ecs,
-            player_system,
-        }
+    /// Load the map of the configuration.
+    pub fn from_file(config: &ServerConfig) -> Self {
+        let mut scene = NetworkScene::new(config);
+        scene
+            .change_map(&config.map)
+            .expect("Cannot load map of the configuration");
+        scene
+    }
+
+    /// Switch to another level of the levels directory. The players are
+    /// moved to it and their clients load it.
+    pub fn change_map(&mut self, name: &str) -> Result<(), LevelError> {
+        let level = Level::load(&self.levels_dir, name)?;
+        let mut ecs = level.new_ecs();
+        let moved = self.network.change_level(&level, &mut ecs);
+        self.ecs.replace(ecs);
+        self.player_system.replace_entities(&moved);
+        self.chat_system.replace_entities(&moved);
+        info!("Map is now {}", level.name);
+        Ok(())
+    }
+
+    pub fn network(&self) -> &NetworkSystem {
+        &self.network
+    }
+
+    /// Where the clients should connect.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.network.local_addr()
     }
 }
 
@@ -47,11 +89,18 @@ impl Scene for NetworkScene {
     fn update(&mut self, dt: Duration) -> Option<Vec<Event>> {
         // Get the latest event from the clients.
         let events = self.network.poll_events(&mut self.ecs);
-        self.player_system
+        let cheaters = self
+            .player_system
             .handle_network_events(&mut self.ecs, &events);
+        for player in cheaters.iter() {
+            self.network.kick(player, &mut self.ecs);
+        }
+        self.chat_system
+            .handle_network_events(&self.ecs, &events, &mut self.network);
 
         // All the systems.
         self.player_system.update(dt, &mut self.ecs);
+        self.animation_system.update(dt, &mut self.ecs);
 
         // Finish by sending latest state.
         self.network.send_state(&mut self.ecs);
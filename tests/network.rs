@@ -0,0 +1,323 @@
+// Server and clients in the same process: on a loopback network with the
+// harness, over UDP for the load test.
+use cgmath::{InnerSpace, Vector3};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use twgraph::camera::CameraDirection;
+use twgraph::ecs::components::TransformComponent;
+use twgraph::ecs::systems::{IDLE_CLIP, PLAYER_SKELETON, RUN_CLIP};
+use twgraph::ecs::{Entity, ECS};
+use twgraph::net::crypto::{self, ConnectToken};
+use twgraph::net::harness::{harness_config, TestHarness};
+use twgraph::net::loadtest::{Behavior, LoadTest, LoadTestSettings, LocalServer};
+use twgraph::net::protocol::{DisconnectReason, RefuseReason};
+use twgraph::net::NetworkError;
+use twgraph::scene::ClientCommand;
+
+/// Generous: the steps are short, but the tests run in parallel.
+const MAX_STEPS: usize = 500;
+
+fn position(ecs: &ECS, entity: Entity) -> Option<Vector3<f32>> {
+    ecs.components.transforms.get(&entity).map(|t| t.position)
+}
+
+fn clip(ecs: &ECS, entity: Entity) -> Option<String> {
+    ecs.components
+        .animators
+        .get(&entity)
+        .and_then(|a| a.clip.clone())
+}
+
+fn connected_harness(nb_clients: usize) -> TestHarness {
+    let mut harness = TestHarness::new(nb_clients);
+    assert!(harness.connect_all(MAX_STEPS), "Clients did not connect");
+    harness
+}
+
+/// Each test has its own directory, as they run in parallel.
+fn levels_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("twgraph-{}-{}", test, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A level with a row of entities.
+fn save_level(dir: &Path, name: &str, nb_entities: usize) {
+    let mut ecs = ECS::new();
+    for i in 0..nb_entities {
+        let entity = ecs.new_entity();
+        ecs.components.transforms.set(
+            &entity,
+            TransformComponent {
+                position: Vector3::new(i as f32, 0.0, 5.0),
+                rotation: Vector3::new(0.0, 0.0, 0.0),
+                scale: Vector3::new(1.0, 1.0, 1.0),
+            },
+        );
+    }
+    ecs.save(dir.join(name)).unwrap();
+}
+
+#[test]
+fn connect_test() {
+    let harness = connected_harness(3);
+
+    let players = harness.server_players();
+    assert_eq!(3, players.len());
+    for i in 0..3 {
+        let entity = harness.client(i).player_entity().unwrap();
+        assert!(players.contains(&entity));
+    }
+
+    // The server and the clients, without socket.
+    assert_eq!(4, harness.loopback().nb_bound());
+}
+
+#[test]
+fn move_test() {
+    let mut harness = connected_harness(1);
+    let entity = harness.client(0).player_entity().unwrap();
+    let start = position(harness.server_ecs(), entity).unwrap();
+
+    harness.set_commands(0, vec![ClientCommand::Move(CameraDirection::Forward)]);
+    harness.run(30);
+    harness.set_commands(0, Vec::new());
+
+    let moved = harness.run_until(MAX_STEPS, |h| {
+        let now = position(h.server_ecs(), entity).unwrap();
+        (now - start).magnitude() > 1.0
+    });
+    assert!(moved, "Player did not move on the server");
+
+    // Once the server processed all the inputs, the prediction agrees.
+    let agree = harness.run_until(MAX_STEPS, |h| {
+        let server = position(h.server_ecs(), entity).unwrap();
+        (h.client(0).player_position() - server).magnitude() < 0.1
+    });
+    assert!(agree, "Prediction does not match the server");
+}
+
+#[test]
+fn local_player_test() {
+    let mut harness = connected_harness(1);
+    let entity = harness.client(0).player_entity().unwrap();
+
+    // The model of the player is drawn where the camera is, not in the past
+    // like the other entities.
+    harness.set_commands(0, vec![ClientCommand::Move(CameraDirection::Forward)]);
+    for _ in 0..30 {
+        harness.step();
+        let client = harness.client(0);
+        let drawn = position(client.ecs(), entity).unwrap();
+        assert!((drawn - client.player_position()).magnitude() < 1e-4);
+    }
+    harness.set_commands(0, Vec::new());
+
+    let agree = harness.run_until(MAX_STEPS, |h| {
+        let server = position(h.server_ecs(), entity).unwrap();
+        let drawn = position(h.client(0).ecs(), entity).unwrap();
+        (drawn - server).magnitude() < 0.1
+    });
+    assert!(agree, "Player is not drawn where the server has it");
+}
+
+#[test]
+fn replication_test() {
+    let mut harness = connected_harness(2);
+    let mover = harness.client(0).player_entity().unwrap();
+
+    harness.set_commands(0, vec![ClientCommand::Move(CameraDirection::Right)]);
+    harness.run(30);
+    harness.set_commands(0, Vec::new());
+
+    // The other client sees the player where the server has it, after
+    // interpolation.
+    let replicated = harness.run_until(MAX_STEPS, |h| {
+        let server = position(h.server_ecs(), mover).unwrap();
+        match position(h.client(1).ecs(), mover) {
+            Some(seen) => (seen - server).magnitude() < 0.1,
+            None => false,
+        }
+    });
+    assert!(replicated, "Move was not replicated to the other client");
+}
+
+#[test]
+fn animation_test() {
+    let mut harness = connected_harness(2);
+    let mover = harness.client(0).player_entity().unwrap();
+    let seen = harness.run_until(MAX_STEPS, |h| h.client(1).ecs().is_entity_alive(&mover));
+    assert!(seen, "Other player was not replicated");
+
+    let animator = harness.server_ecs().components.animators.get(&mover);
+    assert_eq!(Some(PLAYER_SKELETON), animator.map(|a| a.skeleton.as_str()));
+    assert_eq!(
+        Some(IDLE_CLIP.to_string()),
+        clip(harness.server_ecs(), mover)
+    );
+
+    // The other client sees the player run, then stop.
+    harness.set_commands(0, vec![ClientCommand::Move(CameraDirection::Forward)]);
+    let running = harness.run_until(MAX_STEPS, |h| {
+        clip(h.client(1).ecs(), mover) == Some(RUN_CLIP.to_string())
+    });
+    assert!(running, "Moving player does not run");
+
+    harness.set_commands(0, Vec::new());
+    let idle = harness.run_until(MAX_STEPS, |h| {
+        clip(h.client(1).ecs(), mover) == Some(IDLE_CLIP.to_string())
+    });
+    assert!(idle, "Player still runs after stopping");
+}
+
+#[test]
+fn disconnect_test() {
+    let mut harness = connected_harness(2);
+    let leaving = harness.client(0).player_entity().unwrap();
+    let seen = harness.run_until(MAX_STEPS, |h| h.client(1).ecs().is_entity_alive(&leaving));
+    assert!(seen, "Other player was not replicated");
+
+    harness.client_mut(0).disconnect();
+    let removed = harness.run_until(MAX_STEPS, |h| {
+        h.server_players().len() == 1 && !h.client(1).ecs().is_entity_alive(&leaving)
+    });
+    assert!(removed, "Player was not removed");
+    assert!(harness.client(1).is_connected());
+}
+
+#[test]
+fn used_token_test() {
+    let dir = levels_dir("used_token");
+    crypto::init();
+    let key = crypto::generate_key();
+    let key_file = dir.join("connect.key");
+    crypto::save_key(&key_file, &key).unwrap();
+    let mut config = harness_config();
+    config.connect_key_file = Some(key_file.to_str().unwrap().to_string());
+    config.connection_attempts_per_sec = 100;
+    let token = ConnectToken::generate(1, Duration::from_secs(60), &key);
+
+    let mut harness = TestHarness::with_config(&config, 0);
+    harness.add_client_with_token(Some(token.clone()));
+    assert!(harness.connect_all(MAX_STEPS), "Client did not connect");
+    harness.client_mut(0).disconnect();
+    let removed = harness.run_until(MAX_STEPS, |h| h.server_players().is_empty());
+    assert!(removed, "Player was not removed");
+
+    // The same ConnectionRequest again, once the session is gone.
+    let replay = harness.add_client_with_token(Some(token));
+    let mut refusal = None;
+    for _ in 0..MAX_STEPS {
+        harness.step();
+        if let Err(NetworkError::ConnectionRefused(reason)) = harness.client_mut(replay).update() {
+            refusal = Some(reason);
+            break;
+        }
+    }
+    assert_eq!(Some(RefuseReason::InvalidConnectToken), refusal);
+    assert!(harness.server_players().is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn stats_test() {
+    let mut harness = connected_harness(1);
+    let player = harness.client(0).player_entity().unwrap();
+
+    // Rates are known once a window of one second is over.
+    let measured = harness.run_until(4 * MAX_STEPS, |h| {
+        let client = h.client(0).stats();
+        let server = h.server_stats(&player).unwrap();
+        client.rtt_ms > 0.0
+            && client.bytes_in_per_sec > 0.0
+            && client.snapshot_size > 0.0
+            && server.rtt_ms > 0.0
+            && server.bytes_in_per_sec > 0.0
+            && server.bytes_out_per_sec > 0.0
+    });
+    assert!(measured, "Statistics were not measured");
+}
+
+#[test]
+fn map_change_test() {
+    let dir = levels_dir("map_change");
+    save_level(&dir, "level.json", 3);
+    let mut config = harness_config();
+    config.levels_dir = dir.to_str().unwrap().to_string();
+    let mut harness = TestHarness::with_config(&config, 2);
+    assert!(harness.connect_all(MAX_STEPS), "Clients did not connect");
+
+    harness.server().change_map("level.json").unwrap();
+    let changed = harness.run_until(MAX_STEPS, |h| {
+        (0..2).all(|i| {
+            let client = h.client(i);
+            let level = client.level().map(|l| l.name.as_str());
+            level == Some("level.json") && !client.is_loading()
+        })
+    });
+    assert!(changed, "Clients did not load the new level");
+
+    // The level and the two players.
+    let replicated = harness.run_until(MAX_STEPS, |h| {
+        (0..2).all(|i| h.client(i).ecs().nb_entities().len() == 5)
+    });
+    assert!(replicated, "Level and players are not on the clients");
+    let players = harness.server_players();
+    assert_eq!(2, players.len());
+    for i in 0..2 {
+        assert!(players.contains(&harness.client(i).player_entity().unwrap()));
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn level_mismatch_test() {
+    let server_dir = levels_dir("mismatch_server");
+    let client_dir = levels_dir("mismatch_client");
+    save_level(&server_dir, "level.json", 3);
+    save_level(&client_dir, "level.json", 2);
+    let mut config = harness_config();
+    config.levels_dir = server_dir.to_str().unwrap().to_string();
+    let mut harness = TestHarness::with_config(&config, 1);
+    harness
+        .client_mut(0)
+        .backend_mut()
+        .set_levels_dir(&client_dir);
+    assert!(harness.connect_all(MAX_STEPS), "Client did not connect");
+
+    harness.server().change_map("level.json").unwrap();
+    let refused = harness.run_until(MAX_STEPS, |h| {
+        h.client(0).disconnected() == Some(DisconnectReason::LevelMismatch)
+            && h.server_players().is_empty()
+    });
+    assert!(refused, "Client with another level was not disconnected");
+
+    fs::remove_dir_all(&server_dir).unwrap();
+    fs::remove_dir_all(&client_dir).unwrap();
+}
+
+#[test]
+fn load_test_test() {
+    let mut config = harness_config();
+    config.map = String::new();
+    config.max_players = 4;
+    config.connection_attempts_per_sec = 100;
+    let server = LocalServer::start(&config);
+
+    let settings = LoadTestSettings {
+        nb_clients: 4,
+        behavior: Behavior::Square,
+        ..LoadTestSettings::default()
+    };
+    let mut test = LoadTest::new(server.addr(), settings);
+    assert!(
+        test.wait_for_clients(Duration::from_secs(30)),
+        "Simulated clients did not join"
+    );
+    assert_eq!(4, test.report().nb_in_game());
+    assert!(server.frame_times().nb_frames > 0);
+    test.disconnect_all();
+}